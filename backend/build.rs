@@ -0,0 +1,5 @@
+// Rebuild when migrations change, as they are embedded into the binary
+fn main() {
+    println!("cargo:rerun-if-changed=migrations");
+    println!("cargo:rerun-if-changed=sqlite_migrations");
+}
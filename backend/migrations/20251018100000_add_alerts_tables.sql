@@ -0,0 +1,25 @@
+-- Add migration script here
+CREATE TABLE alert_rules(
+    id SERIAL UNIQUE NOT NULL,
+    name TEXT NOT NULL,
+    device_id INTEGER NOT NULL REFERENCES devices (id) ON DELETE CASCADE,
+    sensor_id INTEGER NOT NULL REFERENCES sensors (id) ON DELETE CASCADE,
+    operator TEXT NOT NULL,
+    threshold REAL NOT NULL,
+    for_seconds INTEGER NOT NULL DEFAULT 0,
+    enabled BOOLEAN NOT NULL DEFAULT TRUE,
+    state TEXT NOT NULL DEFAULT 'ok',
+    since TIMESTAMP with time zone,
+    PRIMARY KEY (id)
+);
+
+CREATE TABLE alert_events(
+    id SERIAL UNIQUE NOT NULL,
+    rule_id INTEGER NOT NULL REFERENCES alert_rules (id) ON DELETE CASCADE,
+    ts TIMESTAMP with time zone NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    state TEXT NOT NULL,
+    value REAL,
+    PRIMARY KEY (id)
+);
+
+CREATE INDEX alert_events_rule_id_ts ON alert_events (rule_id, ts);
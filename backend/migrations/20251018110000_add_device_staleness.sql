@@ -0,0 +1,7 @@
+-- Add migration script here
+ALTER TABLE devices ADD COLUMN expected_interval_seconds INTEGER;
+ALTER TABLE devices ADD COLUMN last_seen TIMESTAMP with time zone;
+ALTER TABLE devices ADD COLUMN online BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE sensors ADD COLUMN expected_interval_seconds INTEGER;
+
+UPDATE devices d SET last_seen = (SELECT max(m.ts) FROM measurements m WHERE m.device_id = d.id);
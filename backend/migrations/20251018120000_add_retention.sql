@@ -0,0 +1,15 @@
+-- Add migration script here
+ALTER TABLE sensors ADD COLUMN retention_days INTEGER;
+
+CREATE INDEX measurements_sensor_id_ts ON measurements (sensor_id, ts);
+
+CREATE TABLE measurements_hourly(
+    device_id INTEGER NOT NULL REFERENCES devices (id) ON DELETE CASCADE,
+    sensor_id INTEGER NOT NULL REFERENCES sensors (id) ON DELETE CASCADE,
+    bucket TIMESTAMP with time zone NOT NULL,
+    count BIGINT NOT NULL,
+    sum DOUBLE PRECISION NOT NULL,
+    min REAL NOT NULL,
+    max REAL NOT NULL,
+    PRIMARY KEY (device_id, sensor_id, bucket)
+);
@@ -0,0 +1,18 @@
+-- Add migration script here
+CREATE TABLE measurements_daily(
+    device_id INTEGER NOT NULL REFERENCES devices (id) ON DELETE CASCADE,
+    sensor_id INTEGER NOT NULL REFERENCES sensors (id) ON DELETE CASCADE,
+    bucket TIMESTAMP with time zone NOT NULL,
+    count BIGINT NOT NULL,
+    sum DOUBLE PRECISION NOT NULL,
+    min REAL NOT NULL,
+    max REAL NOT NULL,
+    PRIMARY KEY (device_id, sensor_id, bucket)
+);
+
+CREATE TABLE rollup_state(
+    id BOOLEAN PRIMARY KEY DEFAULT TRUE CHECK (id),
+    last_measurement_id INTEGER NOT NULL
+);
+
+INSERT INTO rollup_state (last_measurement_id) VALUES (0);
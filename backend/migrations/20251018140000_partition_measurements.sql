@@ -0,0 +1,47 @@
+-- Add migration script here
+-- Rebuilds measurements as a table partitioned by month on ts. Existing rows are copied,
+-- so on large tables this runs for a while and should be done in a maintenance window.
+DROP MATERIALIZED VIEW device_sensors;
+
+ALTER SEQUENCE measurements_id_seq OWNED BY NONE;
+ALTER TABLE measurements RENAME TO measurements_unpartitioned;
+ALTER TABLE measurements_unpartitioned DROP CONSTRAINT measurements_pkey;
+ALTER TABLE measurements_unpartitioned DROP CONSTRAINT IF EXISTS measurements_id_key;
+DROP INDEX measurements_sensor_id_ts;
+
+CREATE TABLE measurements(
+    id INTEGER NOT NULL DEFAULT nextval('measurements_id_seq'),
+    ts TIMESTAMP with time zone NOT NULL,
+    device_id INTEGER NOT NULL REFERENCES devices (id),
+    sensor_id INTEGER NOT NULL REFERENCES sensors (id),
+    value REAL NOT NULL,
+    PRIMARY KEY (id, ts)
+) PARTITION BY RANGE (ts);
+ALTER SEQUENCE measurements_id_seq OWNED BY measurements.id;
+
+CREATE INDEX measurements_sensor_id_ts ON measurements (sensor_id, ts);
+CREATE INDEX measurements_device_id_sensor_id_ts ON measurements (device_id, sensor_id, ts);
+
+-- Catches measurements outside of all partitions, they are moved when their partition is created
+CREATE TABLE measurements_default PARTITION OF measurements DEFAULT;
+
+DO $$
+DECLARE
+    month TIMESTAMP := date_trunc('month', COALESCE((SELECT min(ts) FROM measurements_unpartitioned), now()) AT TIME ZONE 'UTC');
+BEGIN
+    WHILE month <= date_trunc('month', now() AT TIME ZONE 'UTC') + interval '2 months' LOOP
+        EXECUTE format(
+            'CREATE TABLE %I PARTITION OF measurements FOR VALUES FROM (%L) TO (%L)',
+            'measurements_' || to_char(month, 'YYYY_MM'),
+            month AT TIME ZONE 'UTC',
+            (month + interval '1 month') AT TIME ZONE 'UTC'
+        );
+        month := month + interval '1 month';
+    END LOOP;
+END $$;
+
+INSERT INTO measurements (id, ts, device_id, sensor_id, value)
+SELECT id, ts, device_id, sensor_id, value FROM measurements_unpartitioned;
+DROP TABLE measurements_unpartitioned;
+
+create materialized view device_sensors as select m.device_id, s.id as sensor_id, s.name, s.unit from measurements m join sensors s on m.sensor_id = s.id group by (m.device_id, s.id) order by m.device_id;
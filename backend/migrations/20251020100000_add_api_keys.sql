@@ -0,0 +1,9 @@
+-- Only a hash of each key is stored, the key itself is shown once on creation
+CREATE TABLE api_keys(
+    id SERIAL PRIMARY KEY,
+    name TEXT NOT NULL,
+    scope TEXT NOT NULL CHECK (scope IN ('ingest', 'read', 'admin')),
+    prefix TEXT NOT NULL,
+    hash TEXT UNIQUE NOT NULL,
+    created_at TIMESTAMP with time zone NOT NULL DEFAULT now()
+);
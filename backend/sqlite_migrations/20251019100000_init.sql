@@ -0,0 +1,31 @@
+-- Add migration script here
+CREATE TABLE devices(
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    name TEXT NOT NULL,
+    location TEXT NOT NULL,
+    expected_interval_seconds INTEGER,
+    last_seen TEXT,
+    online BOOLEAN NOT NULL DEFAULT FALSE
+);
+
+CREATE TABLE sensors(
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    name TEXT UNIQUE NOT NULL,
+    unit TEXT NOT NULL,
+    expected_interval_seconds INTEGER,
+    retention_days INTEGER
+);
+
+-- Timestamps are stored as RFC 3339 text in UTC, which sorts chronologically
+CREATE TABLE measurements(
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    ts TEXT NOT NULL,
+    device_id INTEGER NOT NULL REFERENCES devices (id),
+    sensor_id INTEGER NOT NULL REFERENCES sensors (id),
+    value REAL NOT NULL
+);
+
+CREATE INDEX measurements_sensor_id_ts ON measurements (sensor_id, ts);
+CREATE INDEX measurements_device_id_sensor_id_ts ON measurements (device_id, sensor_id, ts);
+
+CREATE VIEW device_sensors AS SELECT m.device_id, s.id AS sensor_id, s.name, s.unit FROM measurements m JOIN sensors s ON m.sensor_id = s.id GROUP BY m.device_id, s.id;
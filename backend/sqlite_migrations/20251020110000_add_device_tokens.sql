@@ -0,0 +1,3 @@
+-- Hash of the token a device posts its own measurements with
+ALTER TABLE devices ADD COLUMN token_hash TEXT;
+CREATE UNIQUE INDEX devices_token_hash ON devices (token_hash);
@@ -0,0 +1,429 @@
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgPool};
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize, sqlx::Type)]
+#[sqlx(type_name = "text")]
+pub enum Operator {
+    #[serde(rename = "<")]
+    #[sqlx(rename = "<")]
+    Below,
+    #[serde(rename = "<=")]
+    #[sqlx(rename = "<=")]
+    BelowOrEqual,
+    #[serde(rename = ">")]
+    #[sqlx(rename = ">")]
+    Above,
+    #[serde(rename = ">=")]
+    #[sqlx(rename = ">=")]
+    AboveOrEqual,
+}
+
+impl Operator {
+    pub fn matches(&self, value: f32, threshold: f32) -> bool {
+        match self {
+            Operator::Below => value < threshold,
+            Operator::BelowOrEqual => value <= threshold,
+            Operator::Above => value > threshold,
+            Operator::AboveOrEqual => value >= threshold,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "text", rename_all = "lowercase")]
+pub enum AlertState {
+    Ok,
+    Pending,
+    Firing,
+    Resolved,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct NewAlertRule {
+    pub name: String,
+    pub device_id: i32,
+    pub sensor_id: i32,
+    pub operator: Operator,
+    pub threshold: f32,
+    #[serde(default)]
+    pub for_seconds: i32,
+}
+
+/// A threshold on the latest measurement of a device/sensor pair, that has to hold
+/// for `for_seconds` before the alert fires.
+#[derive(Clone, Debug, Serialize, Deserialize, FromRow)]
+pub struct AlertRule {
+    pub id: i32,
+    pub name: String,
+    pub device_id: i32,
+    pub sensor_id: i32,
+    pub operator: Operator,
+    pub threshold: f32,
+    pub for_seconds: i32,
+    pub enabled: bool,
+    #[serde(default = "default_state")]
+    pub state: AlertState,
+    #[serde(default)]
+    pub since: Option<DateTime<Utc>>,
+}
+
+fn default_state() -> AlertState {
+    AlertState::Ok
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, FromRow)]
+pub struct AlertEvent {
+    pub id: i32,
+    pub rule_id: i32,
+    #[sqlx(rename = "ts")]
+    pub timestamp: DateTime<Utc>,
+    pub state: AlertState,
+    pub value: Option<f32>,
+}
+
+/// Outcome of evaluating a rule against its latest measurement
+#[derive(Clone, Debug, PartialEq)]
+pub struct Transition {
+    pub state: AlertState,
+    pub since: Option<DateTime<Utc>>,
+    /// Set when the alert fired or resolved and a notification should be sent
+    pub event: Option<AlertState>,
+}
+
+impl AlertRule {
+    /// Computes the next state from the latest `(value, timestamp)`, `None` if nothing changed
+    pub fn evaluate(
+        &self,
+        latest: Option<(f32, DateTime<Utc>)>,
+        now: DateTime<Utc>,
+    ) -> Option<Transition> {
+        let matching = latest.filter(|(value, _)| self.operator.matches(*value, self.threshold));
+        let hold = chrono::Duration::seconds(self.for_seconds.into());
+        match (self.state, matching) {
+            (AlertState::Firing, Some(_)) => None,
+            (AlertState::Firing, None) => Some(Transition {
+                state: AlertState::Ok,
+                since: None,
+                event: Some(AlertState::Resolved),
+            }),
+            (AlertState::Pending, None) => Some(Transition {
+                state: AlertState::Ok,
+                since: None,
+                event: None,
+            }),
+            // Pending from when it was first seen, a reading taken long ago does not count
+            // towards the duration
+            (state, Some(_)) => {
+                let since = match state {
+                    AlertState::Pending => self.since.unwrap_or(now),
+                    _ => now,
+                };
+                if now - since >= hold {
+                    Some(Transition {
+                        state: AlertState::Firing,
+                        since: Some(since),
+                        event: Some(AlertState::Firing),
+                    })
+                } else if state == AlertState::Pending {
+                    None
+                } else {
+                    Some(Transition {
+                        state: AlertState::Pending,
+                        since: Some(since),
+                        event: None,
+                    })
+                }
+            }
+            (_, None) => None,
+        }
+    }
+
+    pub async fn read(pool: &PgPool) -> Result<Vec<AlertRule>> {
+        let rules = sqlx::query_as::<_, AlertRule>(
+            "SELECT id, name, device_id, sensor_id, operator, threshold, for_seconds, enabled, state, since FROM alert_rules ORDER BY id",
+        )
+        .fetch_all(pool)
+        .await?;
+        Ok(rules)
+    }
+
+    pub async fn read_enabled(pool: &PgPool) -> Result<Vec<AlertRule>> {
+        let rules = sqlx::query_as::<_, AlertRule>(
+            "SELECT id, name, device_id, sensor_id, operator, threshold, for_seconds, enabled, state, since FROM alert_rules WHERE enabled ORDER BY id",
+        )
+        .fetch_all(pool)
+        .await?;
+        Ok(rules)
+    }
+
+    pub async fn read_by_id(pool: &PgPool, rule_id: i32) -> Result<AlertRule> {
+        let rule = sqlx::query_as::<_, AlertRule>(
+            "SELECT id, name, device_id, sensor_id, operator, threshold, for_seconds, enabled, state, since FROM alert_rules WHERE id = $1",
+        )
+        .bind(rule_id)
+        .fetch_one(pool)
+        .await?;
+        Ok(rule)
+    }
+
+    pub async fn delete(self, pool: &PgPool) -> Result<()> {
+        sqlx::query("DELETE FROM alert_rules WHERE id = $1")
+            .bind(self.id)
+            .execute(pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Updates the definition of the rule, which resets its state
+    pub async fn update(self, pool: &PgPool) -> Result<()> {
+        sqlx::query(
+            "UPDATE alert_rules SET name = $1, device_id = $2, sensor_id = $3, operator = $4, threshold = $5, for_seconds = $6, enabled = $7, state = 'ok', since = NULL WHERE id = $8",
+        )
+        .bind(self.name)
+        .bind(self.device_id)
+        .bind(self.sensor_id)
+        .bind(self.operator)
+        .bind(self.threshold)
+        .bind(self.for_seconds)
+        .bind(self.enabled)
+        .bind(self.id)
+        .execute(pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Stores the new state, and the fired or resolved event in the history
+    pub async fn apply(
+        &self,
+        pool: &PgPool,
+        transition: &Transition,
+        value: Option<f32>,
+    ) -> Result<()> {
+        let mut tx = pool.begin().await?;
+        sqlx::query("UPDATE alert_rules SET state = $1, since = $2 WHERE id = $3")
+            .bind(transition.state)
+            .bind(transition.since)
+            .bind(self.id)
+            .execute(&mut *tx)
+            .await?;
+        if let Some(event) = transition.event {
+            sqlx::query("INSERT INTO alert_events (rule_id, state, value) VALUES ($1, $2, $3)")
+                .bind(self.id)
+                .bind(event)
+                .bind(value)
+                .execute(&mut *tx)
+                .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+}
+
+impl NewAlertRule {
+    pub async fn insert(self, pool: &PgPool) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO alert_rules (name, device_id, sensor_id, operator, threshold, for_seconds) VALUES ($1, $2, $3, $4, $5, $6)",
+        )
+        .bind(self.name)
+        .bind(self.device_id)
+        .bind(self.sensor_id)
+        .bind(self.operator)
+        .bind(self.threshold)
+        .bind(self.for_seconds)
+        .execute(pool)
+        .await?;
+        Ok(())
+    }
+}
+
+impl AlertEvent {
+    pub async fn read(pool: &PgPool) -> Result<Vec<AlertEvent>> {
+        let events = sqlx::query_as::<_, AlertEvent>(
+            "SELECT id, rule_id, ts, state, value FROM alert_events ORDER BY ts DESC, id DESC",
+        )
+        .fetch_all(pool)
+        .await?;
+        Ok(events)
+    }
+
+    pub async fn read_by_rule_id(pool: &PgPool, rule_id: i32) -> Result<Vec<AlertEvent>> {
+        let events = sqlx::query_as::<_, AlertEvent>(
+            "SELECT id, rule_id, ts, state, value FROM alert_events WHERE rule_id = $1 ORDER BY ts DESC, id DESC",
+        )
+        .bind(rule_id)
+        .fetch_all(pool)
+        .await?;
+        Ok(events)
+    }
+
+    /// Events from `from` up to `to` inclusive, oldest first
+    pub async fn read_between(
+        pool: &PgPool,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> Result<Vec<AlertEvent>> {
+        let events = sqlx::query_as::<_, AlertEvent>(
+            "SELECT id, rule_id, ts, state, value FROM alert_events WHERE ts BETWEEN $1 AND $2 ORDER BY ts, id",
+        )
+        .bind(from)
+        .bind(to)
+        .fetch_all(pool)
+        .await?;
+        Ok(events)
+    }
+}
+
+/// Body posted to the alert webhook when a rule fires or resolves
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AlertNotification {
+    pub rule: AlertRule,
+    pub state: AlertState,
+    pub value: Option<f32>,
+    pub timestamp: DateTime<Utc>,
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+    use sqlx::PgPool;
+
+    use super::*;
+    use crate::{devices::NewDevice, sensors::NewSensor};
+
+    fn rule(state: AlertState, since: Option<DateTime<Utc>>) -> AlertRule {
+        AlertRule {
+            id: 1,
+            name: "cold".to_string(),
+            device_id: 1,
+            sensor_id: 1,
+            operator: Operator::Below,
+            threshold: 16.0,
+            for_seconds: 600,
+            enabled: true,
+            state,
+            since,
+        }
+    }
+
+    #[test]
+    fn should_fire_after_condition_holds() {
+        let now = Utc::now();
+        let transition = rule(AlertState::Ok, None)
+            .evaluate(Some((15.0, now - Duration::minutes(1))), now)
+            .unwrap();
+        assert_eq!(transition.state, AlertState::Pending);
+        assert_eq!(transition.event, None);
+
+        let pending = rule(AlertState::Pending, transition.since);
+        assert!(pending.evaluate(Some((15.5, now)), now).is_none());
+
+        let transition = pending
+            .evaluate(Some((15.5, now)), now + Duration::minutes(10))
+            .unwrap();
+        assert_eq!(transition.state, AlertState::Firing);
+        assert_eq!(transition.event, Some(AlertState::Firing));
+    }
+
+    #[test]
+    fn should_not_fire_on_an_old_reading() {
+        let now = Utc::now();
+        let transition = rule(AlertState::Ok, None)
+            .evaluate(Some((15.0, now - Duration::hours(1))), now)
+            .unwrap();
+        assert_eq!(transition.state, AlertState::Pending);
+        assert_eq!(transition.since, Some(now));
+    }
+
+    #[test]
+    fn should_resolve_when_condition_clears() {
+        let now = Utc::now();
+        let firing = rule(AlertState::Firing, Some(now - Duration::hours(1)));
+        assert!(firing.evaluate(Some((10.0, now)), now).is_none());
+
+        let transition = firing.evaluate(Some((20.0, now)), now).unwrap();
+        assert_eq!(transition.state, AlertState::Ok);
+        assert_eq!(transition.event, Some(AlertState::Resolved));
+
+        let pending = rule(AlertState::Pending, Some(now));
+        let transition = pending.evaluate(Some((20.0, now)), now).unwrap();
+        assert_eq!(transition.state, AlertState::Ok);
+        assert_eq!(transition.event, None);
+
+        assert!(rule(AlertState::Ok, None).evaluate(None, now).is_none());
+    }
+
+    #[test]
+    fn should_fire_immediately_without_duration() {
+        let now = Utc::now();
+        let mut co2 = rule(AlertState::Ok, None);
+        co2.operator = Operator::Above;
+        co2.threshold = 1200.0;
+        co2.for_seconds = 0;
+        let transition = co2.evaluate(Some((1300.0, now)), now).unwrap();
+        assert_eq!(transition.state, AlertState::Firing);
+        assert_eq!(transition.event, Some(AlertState::Firing));
+    }
+
+    #[sqlx::test]
+    async fn should_store_rules_and_history(pool: PgPool) {
+        let device = NewDevice::new("test".to_string(), "test".to_string());
+        device.insert(&pool).await.unwrap();
+        let sensor = NewSensor::new("test".to_string(), "test".to_string());
+        sensor.insert(&pool).await.unwrap();
+
+        let rule = NewAlertRule {
+            name: "co2".to_string(),
+            device_id: 1,
+            sensor_id: 1,
+            operator: Operator::Above,
+            threshold: 1200.0,
+            for_seconds: 0,
+        };
+        rule.insert(&pool).await.unwrap();
+
+        let rules = AlertRule::read(&pool).await.unwrap();
+        assert_eq!(rules.len(), 1);
+        assert_eq!(rules[0].operator, Operator::Above);
+        assert_eq!(rules[0].state, AlertState::Ok);
+
+        let transition = rules[0].evaluate(Some((1500.0, Utc::now())), Utc::now());
+        rules[0]
+            .apply(&pool, &transition.unwrap(), Some(1500.0))
+            .await
+            .unwrap();
+        let rule = AlertRule::read_by_id(&pool, rules[0].id).await.unwrap();
+        assert_eq!(rule.state, AlertState::Firing);
+
+        let events = AlertEvent::read_by_rule_id(&pool, rule.id).await.unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].state, AlertState::Firing);
+        assert_eq!(events[0].value, Some(1500.0));
+        let now = Utc::now();
+        let between = AlertEvent::read_between(&pool, now - Duration::hours(1), now)
+            .await
+            .unwrap();
+        assert_eq!(between.len(), 1);
+        assert!(AlertEvent::read_between(
+            &pool,
+            now - Duration::hours(2),
+            now - Duration::hours(1)
+        )
+        .await
+        .unwrap()
+        .is_empty());
+
+        let mut updated = rule.clone();
+        updated.threshold = 1000.0;
+        updated.update(&pool).await.unwrap();
+        let rule = AlertRule::read_by_id(&pool, rule.id).await.unwrap();
+        assert_eq!(rule.threshold, 1000.0);
+        assert_eq!(rule.state, AlertState::Ok);
+
+        rule.delete(&pool).await.unwrap();
+        assert!(AlertRule::read(&pool).await.unwrap().is_empty());
+        assert!(AlertEvent::read(&pool).await.unwrap().is_empty());
+    }
+}
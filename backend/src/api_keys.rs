@@ -0,0 +1,227 @@
+use std::{fmt, str::FromStr};
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::FromRow;
+use structopt::StructOpt;
+
+use crate::storage::Storage;
+
+/// Prefix of every generated key, so leaked keys are easy to search for
+const KEY_PREFIX: &str = "hemrs_";
+
+/// Characters of a key kept in the clear, to tell keys apart in listings
+const VISIBLE_LENGTH: usize = KEY_PREFIX.len() + 8;
+
+/// What a key may do. `admin` includes both `read` and `ingest`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    /// Posting measurements
+    Ingest,
+    /// Every `GET` endpoint
+    Read,
+    /// Everything, including changes to devices, sensors, alerts and keys
+    Admin,
+}
+
+impl Scope {
+    pub fn allows(self, required: Scope) -> bool {
+        self == Scope::Admin || self == required
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Scope::Ingest => "ingest",
+            Scope::Read => "read",
+            Scope::Admin => "admin",
+        })
+    }
+}
+
+impl FromStr for Scope {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "ingest" => Ok(Scope::Ingest),
+            "read" => Ok(Scope::Read),
+            "admin" => Ok(Scope::Admin),
+            _ => Err(anyhow!(
+                "unknown scope {}, expected ingest, read or admin",
+                s
+            )),
+        }
+    }
+}
+
+impl TryFrom<String> for Scope {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct NewApiKey {
+    pub name: String,
+    pub scope: Scope,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, FromRow)]
+pub struct ApiKey {
+    pub id: i32,
+    pub name: String,
+    #[sqlx(try_from = "String")]
+    pub scope: Scope,
+    /// Start of the key
+    pub prefix: String,
+    pub created_at: DateTime<Utc>,
+}
+
+/// A newly created key, the only time the key itself is available
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CreatedApiKey {
+    #[serde(flatten)]
+    pub api_key: ApiKey,
+    pub key: String,
+}
+
+/// Keys and tokens are random, so a plain hash is enough to keep them safe at rest
+pub fn hash(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
+
+/// A random secret starting with `prefix`
+pub fn generate(prefix: &str) -> String {
+    let bytes: [u8; 32] = rand::rng().random();
+    format!("{}{}", prefix, hex::encode(bytes))
+}
+
+impl NewApiKey {
+    pub async fn insert(self, storage: &dyn Storage) -> Result<CreatedApiKey> {
+        let key = generate(KEY_PREFIX);
+        let api_key = storage
+            .insert_api_key(self, &key[..VISIBLE_LENGTH], &hash(&key))
+            .await?;
+        Ok(CreatedApiKey { api_key, key })
+    }
+}
+
+impl ApiKey {
+    pub async fn read(storage: &dyn Storage) -> Result<Vec<ApiKey>> {
+        storage.read_api_keys().await
+    }
+
+    /// The stored key matching `key`, fails with `RowNotFound` for unknown keys
+    pub async fn authenticate(storage: &dyn Storage, key: &str) -> Result<ApiKey> {
+        storage.read_api_key_by_hash(&hash(key)).await
+    }
+
+    /// Fails with `RowNotFound` for unknown keys
+    pub async fn delete(storage: &dyn Storage, key_id: i32) -> Result<()> {
+        storage.delete_api_key(key_id).await
+    }
+}
+
+#[derive(Debug, Clone, StructOpt)]
+pub enum ApiKeyCommand {
+    /// Creates a key and prints it, it cannot be shown again
+    Create {
+        #[structopt(long)]
+        name: String,
+
+        /// ingest, read or admin
+        #[structopt(long)]
+        scope: Scope,
+    },
+    /// Lists all keys
+    List,
+    /// Deletes a key, requests using it are rejected from then on
+    Revoke { id: i32 },
+}
+
+pub async fn run_command(storage: &dyn Storage, command: ApiKeyCommand) -> Result<()> {
+    match command {
+        ApiKeyCommand::Create { name, scope } => {
+            let created = NewApiKey { name, scope }.insert(storage).await?;
+            println!("{}", created.key);
+        }
+        ApiKeyCommand::List => {
+            for key in ApiKey::read(storage).await? {
+                println!(
+                    "{:<6} {:<8} {:<16} {} {}",
+                    key.id,
+                    key.scope,
+                    key.prefix,
+                    key.created_at.to_rfc3339(),
+                    key.name
+                );
+            }
+        }
+        ApiKeyCommand::Revoke { id } => ApiKey::delete(storage, id).await.map_err(|e| {
+            match e.downcast_ref::<sqlx::Error>() {
+                Some(sqlx::Error::RowNotFound) => anyhow!("No API key with id {}", id),
+                _ => e,
+            }
+        })?,
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::storage::storage_tests;
+
+    #[test]
+    fn should_check_scopes() {
+        assert!(Scope::Admin.allows(Scope::Read));
+        assert!(Scope::Admin.allows(Scope::Ingest));
+        assert!(Scope::Read.allows(Scope::Read));
+        assert!(!Scope::Read.allows(Scope::Ingest));
+        assert!(!Scope::Ingest.allows(Scope::Admin));
+        assert_eq!("read".parse::<Scope>().unwrap(), Scope::Read);
+        assert!("write".parse::<Scope>().is_err());
+    }
+
+    async fn should_authenticate_api_keys(storage: Arc<dyn Storage>) {
+        let created = NewApiKey {
+            name: "grafana".to_string(),
+            scope: Scope::Read,
+        }
+        .insert(storage.as_ref())
+        .await
+        .unwrap();
+        assert!(created.key.starts_with(&created.api_key.prefix));
+
+        let key = ApiKey::authenticate(storage.as_ref(), &created.key)
+            .await
+            .unwrap();
+        assert_eq!(key.id, created.api_key.id);
+        assert_eq!(key.scope, Scope::Read);
+        assert!(ApiKey::authenticate(storage.as_ref(), "hemrs_wrong")
+            .await
+            .is_err());
+
+        let keys = ApiKey::read(storage.as_ref()).await.unwrap();
+        assert_eq!(keys.len(), 1);
+        assert_eq!(keys[0].name, "grafana");
+
+        ApiKey::delete(storage.as_ref(), key.id).await.unwrap();
+        assert!(ApiKey::authenticate(storage.as_ref(), &created.key)
+            .await
+            .is_err());
+        assert!(ApiKey::delete(storage.as_ref(), key.id).await.is_err());
+    }
+
+    storage_tests!(should_authenticate_api_keys);
+}
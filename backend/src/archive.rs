@@ -0,0 +1,519 @@
+use std::{fs::File, io::Write, path::PathBuf, str::FromStr, sync::Arc};
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use parquet::{
+    basic::Compression,
+    data_type::{ByteArray, ByteArrayType, FloatType, Int32Type, Int64Type},
+    file::{
+        properties::WriterProperties,
+        reader::{FileReader, SerializedFileReader},
+        writer::SerializedFileWriter,
+    },
+    record::{Row, RowAccessor},
+    schema::parser::parse_message_type,
+};
+use serde::{Deserialize, Deserializer};
+use structopt::StructOpt;
+use tokio::sync::mpsc;
+
+use crate::{
+    devices::{Device, NewDevice},
+    measurements::{Measurement, MeasurementQuery, NamedMeasurement, NewMeasurement},
+    provisioning::Resolver,
+    sensors::{NewSensor, Sensor},
+    storage::Storage,
+};
+
+/// Schema of archives, the fields of `Measurement` followed by the ids
+const SCHEMA: &str = "
+message measurement {
+    REQUIRED INT64 timestamp (TIMESTAMP(MICROS,true));
+    REQUIRED FLOAT value;
+    REQUIRED BYTE_ARRAY unit (UTF8);
+    REQUIRED BYTE_ARRAY device_name (UTF8);
+    REQUIRED BYTE_ARRAY device_location (UTF8);
+    REQUIRED BYTE_ARRAY sensor_name (UTF8);
+    REQUIRED INT32 device_id;
+    REQUIRED INT32 sensor_id;
+}";
+
+/// Column names of the schema, in order
+const COLUMNS: [&str; 8] = [
+    "timestamp",
+    "value",
+    "unit",
+    "device_name",
+    "device_location",
+    "sensor_name",
+    "device_id",
+    "sensor_id",
+];
+
+/// Rows per row group, buffered in memory until the group is written
+const ROW_GROUP_SIZE: usize = 100_000;
+
+/// Rows buffered between the database and the Parquet writer
+const ROW_BUFFER: usize = 1024;
+
+/// Measurements inserted per statement during an import
+const IMPORT_BATCH_SIZE: usize = 10_000;
+
+/// Comma separated list of ids, e.g. `1,2,3`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Ids(pub Vec<i32>);
+
+impl FromStr for Ids {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|id| !id.is_empty())
+            .map(|id| id.parse().map_err(|_| anyhow!("invalid id {id}")))
+            .collect::<Result<_>>()
+            .map(Self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Ids {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize, StructOpt)]
+pub struct ArchiveQuery {
+    /// Inclusive start, RFC 3339
+    #[structopt(long)]
+    pub from: Option<DateTime<Utc>>,
+    /// Exclusive end, RFC 3339
+    #[structopt(long)]
+    pub to: Option<DateTime<Utc>>,
+    /// Comma separated device ids
+    #[structopt(long)]
+    pub devices: Option<Ids>,
+    /// Comma separated sensor ids
+    #[structopt(long)]
+    pub sensors: Option<Ids>,
+}
+
+/// A row of an archive
+#[derive(Debug, Clone)]
+pub struct ArchiveRow {
+    pub device_id: i32,
+    pub sensor_id: i32,
+    pub measurement: Measurement,
+}
+
+impl ArchiveRow {
+    fn from_row(row: &Row) -> Result<Self> {
+        let micros = row.get_timestamp_micros(0)?;
+        Ok(Self {
+            measurement: Measurement {
+                timestamp: DateTime::from_timestamp_micros(micros)
+                    .ok_or_else(|| anyhow!("invalid timestamp {micros}"))?,
+                value: row.get_float(1)?,
+                unit: row.get_string(2)?.clone(),
+                device_name: row.get_string(3)?.clone(),
+                device_location: row.get_string(4)?.clone(),
+                sensor_name: row.get_string(5)?.clone(),
+            },
+            device_id: row.get_int(6)?,
+            sensor_id: row.get_int(7)?,
+        })
+    }
+
+    /// Drops the ids, which are looked up by name again on import
+    pub fn into_named(self) -> NamedMeasurement {
+        let measurement = self.measurement;
+        NamedMeasurement {
+            timestamp: Some(measurement.timestamp),
+            device: NewDevice::new(measurement.device_name, measurement.device_location),
+            sensor: NewSensor::new(measurement.sensor_name, measurement.unit),
+            measurement: measurement.value,
+        }
+    }
+}
+
+/// Sends the rows of every selected device and sensor pair to `rows`, pair by pair
+pub async fn read_rows(
+    storage: &dyn Storage,
+    query: &ArchiveQuery,
+    rows: &mpsc::Sender<Result<ArchiveRow>>,
+) -> Result<()> {
+    let devices = match &query.devices {
+        Some(Ids(ids)) => Device::read_by_ids(storage, ids).await?,
+        None => Device::read(storage).await?,
+    };
+    let measurement_query = MeasurementQuery {
+        from: query.from,
+        to: query.to,
+        ..Default::default()
+    };
+    for device in devices {
+        for sensor in Sensor::read_by_device_id(storage, device.id).await? {
+            if query
+                .sensors
+                .as_ref()
+                .is_some_and(|Ids(ids)| !ids.contains(&sensor.id))
+            {
+                continue;
+            }
+            let (device_id, sensor_id) = (device.id, sensor.id);
+            let measurement_query = &measurement_query;
+            let (tx, mut rx) = mpsc::channel(ROW_BUFFER);
+            // Owns the sender, so the receiver ends once all rows are read
+            let read = async move {
+                Measurement::stream(
+                    storage,
+                    Some(device_id),
+                    Some(sensor_id),
+                    measurement_query,
+                    &tx,
+                )
+                .await
+            };
+            let forward = async {
+                while let Some(measurement) = rx.recv().await {
+                    let row = measurement.map(|measurement| ArchiveRow {
+                        device_id,
+                        sensor_id,
+                        measurement,
+                    });
+                    rows.send(row)
+                        .await
+                        .map_err(|_| anyhow!("the archive writer stopped"))?;
+                }
+                Ok(())
+            };
+            tokio::try_join!(read, forward)?;
+        }
+    }
+    Ok(())
+}
+
+/// Columns of the rows of one row group
+#[derive(Debug, Default)]
+struct RowGroup {
+    timestamps: Vec<i64>,
+    values: Vec<f32>,
+    units: Vec<ByteArray>,
+    device_names: Vec<ByteArray>,
+    device_locations: Vec<ByteArray>,
+    sensor_names: Vec<ByteArray>,
+    device_ids: Vec<i32>,
+    sensor_ids: Vec<i32>,
+}
+
+impl RowGroup {
+    fn len(&self) -> usize {
+        self.timestamps.len()
+    }
+
+    fn push(&mut self, row: ArchiveRow) {
+        let measurement = row.measurement;
+        self.timestamps
+            .push(measurement.timestamp.timestamp_micros());
+        self.values.push(measurement.value);
+        self.units.push(measurement.unit.into_bytes().into());
+        self.device_names
+            .push(measurement.device_name.into_bytes().into());
+        self.device_locations
+            .push(measurement.device_location.into_bytes().into());
+        self.sensor_names
+            .push(measurement.sensor_name.into_bytes().into());
+        self.device_ids.push(row.device_id);
+        self.sensor_ids.push(row.sensor_id);
+    }
+
+    fn write<W: Write + Send>(&self, writer: &mut SerializedFileWriter<W>) -> Result<()> {
+        let mut row_group = writer.next_row_group()?;
+        let mut index = 0;
+        while let Some(mut column) = row_group.next_column()? {
+            match index {
+                0 => {
+                    column
+                        .typed::<Int64Type>()
+                        .write_batch(&self.timestamps, None, None)?;
+                }
+                1 => {
+                    column
+                        .typed::<FloatType>()
+                        .write_batch(&self.values, None, None)?;
+                }
+                2..=5 => {
+                    let values = [
+                        &self.units,
+                        &self.device_names,
+                        &self.device_locations,
+                        &self.sensor_names,
+                    ][index - 2];
+                    column
+                        .typed::<ByteArrayType>()
+                        .write_batch(values, None, None)?;
+                }
+                _ => {
+                    let values = [&self.device_ids, &self.sensor_ids][index - 6];
+                    column
+                        .typed::<Int32Type>()
+                        .write_batch(values, None, None)?;
+                }
+            }
+            column.close()?;
+            index += 1;
+        }
+        row_group.close()?;
+        Ok(())
+    }
+}
+
+/// Writes the rows received from `rows` to `sink` as a Snappy compressed Parquet file,
+/// returning how many were written. Blocks, so it is meant for a blocking thread. Fails
+/// with the first error received
+pub fn write_archive<W: Write + Send>(
+    sink: W,
+    mut rows: mpsc::Receiver<Result<ArchiveRow>>,
+) -> Result<u64> {
+    let schema = Arc::new(parse_message_type(SCHEMA)?);
+    let properties = WriterProperties::builder()
+        .set_compression(Compression::SNAPPY)
+        .set_created_by(format!("hemrs {}", env!("CARGO_PKG_VERSION")))
+        .build();
+    let mut writer = SerializedFileWriter::new(sink, schema, Arc::new(properties))?;
+    let mut group = RowGroup::default();
+    let mut written = 0;
+    while let Some(row) = rows.blocking_recv() {
+        group.push(row?);
+        if group.len() >= ROW_GROUP_SIZE {
+            group.write(&mut writer)?;
+            written += group.len() as u64;
+            group = RowGroup::default();
+        }
+    }
+    if group.len() > 0 {
+        group.write(&mut writer)?;
+        written += group.len() as u64;
+    }
+    writer.close()?;
+    Ok(written)
+}
+
+/// Writes the selected measurements to `sink` as a Parquet file, returning how many were
+/// written. Rows are read from the database while they are written
+pub async fn export<W: Write + Send + 'static>(
+    storage: &dyn Storage,
+    query: &ArchiveQuery,
+    sink: W,
+) -> Result<u64> {
+    let (tx, rx) = mpsc::channel(ROW_BUFFER);
+    let writer = tokio::task::spawn_blocking(move || write_archive(sink, rx));
+    if let Err(e) = read_rows(storage, query, &tx).await {
+        // Fails the writer, unless it failed first
+        let _ = tx.send(Err(e)).await;
+    }
+    drop(tx);
+    writer.await?
+}
+
+/// Reads the rows of an archive written by `export`
+pub fn read_archive(file: File) -> Result<impl Iterator<Item = Result<ArchiveRow>>> {
+    let reader = SerializedFileReader::new(file)?;
+    let columns: Vec<&str> = reader
+        .metadata()
+        .file_metadata()
+        .schema_descr()
+        .columns()
+        .iter()
+        .map(|column| column.name())
+        .collect();
+    if columns != COLUMNS {
+        return Err(anyhow!(
+            "not a measurement archive, expected the columns {}",
+            COLUMNS.join(", ")
+        ));
+    }
+    Ok(reader.into_iter().map(|row| ArchiveRow::from_row(&row?)))
+}
+
+/// Inserts the measurements of an archive, resolving devices and sensors by name, and
+/// returns how many were inserted. Unknown devices and sensors fail the import, unless
+/// `auto_provision` is set. Measurements are not deduplicated
+pub async fn import(storage: Arc<dyn Storage>, file: File, auto_provision: bool) -> Result<u64> {
+    let resolver = Resolver::new(storage.clone(), auto_provision);
+    let mut rows = read_archive(file)?;
+    let mut imported = 0;
+    loop {
+        let batch = rows
+            .by_ref()
+            .take(IMPORT_BATCH_SIZE)
+            .map(|row| row.map(ArchiveRow::into_named))
+            .collect::<Result<Vec<_>>>()?;
+        if batch.is_empty() {
+            break;
+        }
+        let resolved = resolver.resolve(batch, auto_provision).await?;
+        imported += NewMeasurement::insert_many(&resolved, storage.as_ref()).await?;
+    }
+    Device::refresh_device_sensors_view(storage.as_ref()).await?;
+    Ok(imported)
+}
+
+#[derive(Debug, Clone, StructOpt)]
+pub enum ArchiveCommand {
+    /// Writes measurements to a Parquet file, of every device and sensor unless limited
+    Export {
+        /// Path of the Parquet file, it is overwritten
+        #[structopt(long, parse(from_os_str))]
+        output: PathBuf,
+
+        #[structopt(flatten)]
+        query: ArchiveQuery,
+    },
+    /// Loads measurements from a Parquet file written by `export`, devices and sensors are
+    /// matched by name
+    Import {
+        #[structopt(parse(from_os_str))]
+        input: PathBuf,
+
+        /// Create devices and sensors that do not exist
+        #[structopt(long)]
+        auto_provision: bool,
+    },
+}
+
+pub async fn run_command(storage: Arc<dyn Storage>, command: ArchiveCommand) -> Result<()> {
+    match command {
+        ArchiveCommand::Export { output, query } => {
+            let file = File::create(&output)?;
+            let exported = export(storage.as_ref(), &query, file).await?;
+            println!("Exported {} measurements to {}", exported, output.display());
+        }
+        ArchiveCommand::Import {
+            input,
+            auto_provision,
+        } => {
+            let imported = import(storage, File::open(&input)?, auto_provision).await?;
+            println!(
+                "Imported {} measurements from {}",
+                imported,
+                input.display()
+            );
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{storage_tests, MemoryStorage};
+
+    #[test]
+    fn should_parse_ids() {
+        assert_eq!("1, 2,3".parse::<Ids>().unwrap(), Ids(vec![1, 2, 3]));
+        assert_eq!("".parse::<Ids>().unwrap(), Ids(Vec::new()));
+        assert!("1,kitchen".parse::<Ids>().is_err());
+    }
+
+    async fn should_export_and_import_archives(storage: Arc<dyn Storage>) {
+        for (name, location) in [("esp32", "Kitchen"), ("esp32", "Attic")] {
+            NewDevice::new(name.to_string(), location.to_string())
+                .insert(storage.as_ref())
+                .await
+                .unwrap();
+        }
+        for (name, unit) in [("temperature", "°C"), ("humidity", "%")] {
+            NewSensor::new(name.to_string(), unit.to_string())
+                .insert(storage.as_ref())
+                .await
+                .unwrap();
+        }
+        let start = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let measurement = |device, sensor, i: i64| {
+            let timestamp = start + chrono::Duration::seconds(i);
+            NewMeasurement::new(Some(timestamp), device, sensor, i as f32)
+        };
+        let mut measurements: Vec<NewMeasurement> = (0..5).map(|i| measurement(1, 1, i)).collect();
+        measurements.push(measurement(1, 2, 0));
+        measurements.push(measurement(2, 1, 0));
+        NewMeasurement::insert_many(&measurements, storage.as_ref())
+            .await
+            .unwrap();
+        Device::refresh_device_sensors_view(storage.as_ref())
+            .await
+            .unwrap();
+
+        let path = std::env::temp_dir().join(format!(
+            "hemrs-archive-{}-{}.parquet",
+            std::process::id(),
+            rand::random::<u64>()
+        ));
+        let query = ArchiveQuery {
+            from: Some(start + chrono::Duration::seconds(1)),
+            devices: Some(Ids(vec![1])),
+            sensors: Some(Ids(vec![1])),
+            ..Default::default()
+        };
+        let exported = export(storage.as_ref(), &query, File::create(&path).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(exported, 4);
+        let rows = read_archive(File::open(&path).unwrap())
+            .unwrap()
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(rows.len(), 4);
+        assert_eq!((rows[0].device_id, rows[0].sensor_id), (1, 1));
+        assert_eq!(
+            rows[0].measurement.timestamp,
+            start + chrono::Duration::seconds(1)
+        );
+        assert_eq!(rows[0].measurement.value, 1.0);
+        assert_eq!(rows[0].measurement.device_location, "Kitchen");
+        assert_eq!(rows[0].measurement.unit, "°C");
+
+        let exported = export(
+            storage.as_ref(),
+            &ArchiveQuery::default(),
+            File::create(&path).unwrap(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(exported, 7);
+
+        // Names are resolved to the ids of the importing database
+        let empty: Arc<dyn Storage> = Arc::new(MemoryStorage::default());
+        assert!(import(empty.clone(), File::open(&path).unwrap(), false)
+            .await
+            .is_err());
+        let imported = import(empty.clone(), File::open(&path).unwrap(), true)
+            .await
+            .unwrap();
+        assert_eq!(imported, 7);
+        let attic = Device::read_by_name(empty.as_ref(), "esp32", "Attic")
+            .await
+            .unwrap();
+        let page =
+            Measurement::read_by_device_id(attic.id, &MeasurementQuery::default(), empty.as_ref())
+                .await
+                .unwrap();
+        assert_eq!(page.measurements.len(), 1);
+        assert_eq!(page.measurements[0].sensor_name, "temperature");
+
+        let imported = import(storage.clone(), File::open(&path).unwrap(), false)
+            .await
+            .unwrap();
+        assert_eq!(imported, 7);
+        assert_eq!(
+            Measurement::read_total_measurements(storage.as_ref())
+                .await
+                .unwrap(),
+            14
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    storage_tests!(should_export_and_import_archives);
+}
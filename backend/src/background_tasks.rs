@@ -1,23 +1,34 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
 use metrics::{counter, gauge};
 use moka::future::Cache;
-use sqlx::PgPool;
-use tokio::sync::mpsc::Receiver;
+use sqlx::{error::ErrorKind, PgPool};
+use tokio::{
+    sync::{broadcast, mpsc::Receiver},
+    task::JoinSet,
+    time::Instant,
+};
 use tracing::{debug, info, warn};
 
 use crate::{
+    alerts::{AlertNotification, AlertRule},
     devices::Device,
-    measurements::{Measurement, NewMeasurement},
+    measurements::{Measurement, MeasurementEvent, NewMeasurement},
+    partitions::{maintain_partitions, Partition},
+    rollups::roll_up,
     sensors::Sensor,
+    spool::Spool,
+    storage::Storage,
 };
 
 /// Updates metrics in background
-pub async fn update_metrics(pool: &PgPool, cache: &Cache<(i32, i32), Measurement>) {
+pub async fn update_metrics(storage: &dyn Storage, cache: &Cache<(i32, i32), Measurement>) {
     loop {
         debug!("Running background thread");
-        let devices = Device::read(pool).await.unwrap();
+        let devices = Device::read(storage).await.unwrap();
         let mut device_sensors: Vec<(Device, Sensor)> = Vec::new();
         for device in devices {
-            let sensors = Sensor::read_by_device_id(pool, device.id).await.unwrap();
+            let sensors = Sensor::read_by_device_id(storage, device.id).await.unwrap();
             for sensor in sensors {
                 device_sensors.push((device.clone(), sensor));
             }
@@ -41,10 +52,11 @@ pub async fn update_metrics(pool: &PgPool, cache: &Cache<(i32, i32), Measurement
                 }
             } else {
                 // If not in cache, read from DB
-                let measurement =
-                    Measurement::read_latest_by_device_id_and_sensor_id(device.id, sensor.id, pool)
-                        .await
-                        .unwrap();
+                let measurement = Measurement::read_latest_by_device_id_and_sensor_id(
+                    device.id, sensor.id, storage,
+                )
+                .await
+                .unwrap();
                 if measurement.timestamp >= now - chrono::Duration::seconds(300) {
                     let lables = [
                         ("device_name", measurement.device_name.clone()),
@@ -60,56 +72,967 @@ pub async fn update_metrics(pool: &PgPool, cache: &Cache<(i32, i32), Measurement
                 }
             }
         }
-        counter!("hemrs_pg_pool_size").absolute(pool.size() as u64);
+        counter!("hemrs_pg_pool_size").absolute(storage.pool_size() as u64);
         counter!("hemrs_cache_size").absolute(cache.entry_count());
         debug!("Background thread finished");
         tokio::time::sleep(tokio::time::Duration::from_secs(10)).await;
     }
 }
 
-/// Handles inserting new measurements in a background thread
+/// Handles inserting new measurements in a background thread.
+///
+/// Measurements are drained from the channel into batches of at most `batch_size`,
+/// a batch is written once it is full or `flush_interval` after its first measurement.
+/// With a spool, batches failing on a lost connection are retried and only acknowledged once stored,
+/// and the backlog of measurements that did not fit into the channel is read back once it is drained.
 pub async fn handle_insert_measurement_bg_thread(
     mut rx: Receiver<NewMeasurement>,
-    pool: PgPool,
+    storage: Arc<dyn Storage>,
     cache: Cache<(i32, i32), Measurement>,
+    batch_size: usize,
+    flush_interval: Duration,
+    spool: Option<Arc<Spool>>,
+    events: broadcast::Sender<MeasurementEvent>,
 ) {
-    while let Some(measurement) = rx.recv().await {
-        info!("Received new measurement: {:?}", measurement);
+    let mut batch = Vec::with_capacity(batch_size);
+    loop {
+        if let Some(spool) = &spool {
+            let backlog_spool = spool.clone();
+            let backlog = tokio::task::spawn_blocking(move || backlog_spool.read_backlog())
+                .await
+                .map_err(anyhow::Error::from)
+                .and_then(|backlog| backlog);
+            match backlog {
+                Ok(backlog) if !backlog.is_empty() => {
+                    info!("Inserting {} measurements from the spool", backlog.len());
+                    for chunk in backlog.chunks(batch_size) {
+                        store_batch(chunk, storage.as_ref(), &cache, &events, true).await;
+                        if let Err(e) = spool.ack(chunk.len()) {
+                            warn!("Failed to acknowledge spooled measurements: {}", e);
+                        }
+                    }
+                    continue;
+                }
+                Ok(_) => {}
+                Err(e) => warn!("Failed to read spooled measurements: {}", e),
+            }
+        }
+        if rx.recv_many(&mut batch, batch_size).await == 0 {
+            break;
+        }
+        let deadline = Instant::now() + flush_interval;
+        while batch.len() < batch_size {
+            let remaining = batch_size - batch.len();
+            match tokio::time::timeout_at(deadline, rx.recv_many(&mut batch, remaining)).await {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {}
+            }
+        }
+        info!("Inserting batch of {} measurements", batch.len());
         info!("Current queue size: {}", rx.len());
-        if let Err(e) = insert_measurement(measurement, &pool, &cache).await {
-            warn!("Failed to insert measurement: {}", e);
-        } else {
-            counter!("new_measurements").increment(1);
+        store_batch(&batch, storage.as_ref(), &cache, &events, spool.is_some()).await;
+        if let Some(spool) = &spool {
+            if let Err(e) = spool.ack(batch.len()) {
+                warn!("Failed to acknowledge spooled measurements: {}", e);
+            }
         }
+        batch.clear();
     }
 }
 
-async fn insert_measurement(
-    measurement: NewMeasurement,
+/// Inserts a batch, splitting it until the measurements the database rejects are found and dropped.
+///
+/// Other errors, like a lost connection, are retried with backoff if `retry` is set, otherwise
+/// the part of the batch that failed is dropped.
+async fn store_batch(
+    batch: &[NewMeasurement],
+    storage: &dyn Storage,
+    cache: &Cache<(i32, i32), Measurement>,
+    events: &broadcast::Sender<MeasurementEvent>,
+    retry: bool,
+) {
+    let mut pending = vec![batch];
+    let mut backoff = Duration::from_secs(1);
+    while let Some(chunk) = pending.pop() {
+        match insert_measurements(chunk, storage, cache, events).await {
+            Ok(inserted) => {
+                counter!("new_measurements").increment(inserted);
+                backoff = Duration::from_secs(1);
+            }
+            Err(e) if is_rejected(&e) => {
+                if let [measurement] = chunk {
+                    warn!(
+                        "Dropping measurement rejected by the database: {}: {}",
+                        measurement, e
+                    );
+                    counter!("hemrs_rejected_measurements").increment(1);
+                } else {
+                    let (first, second) = chunk.split_at(chunk.len() / 2);
+                    pending.push(second);
+                    pending.push(first);
+                }
+            }
+            Err(e) if retry => {
+                warn!(
+                    "Failed to insert measurements, retrying in {}s: {}",
+                    backoff.as_secs(),
+                    e
+                );
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(Duration::from_secs(60));
+                pending.push(chunk);
+            }
+            Err(e) => {
+                warn!("Failed to insert {} measurements: {}", chunk.len(), e);
+                counter!("hemrs_rejected_measurements").increment(chunk.len() as u64);
+            }
+        }
+    }
+}
+
+/// Whether the database refused the data itself, so retrying it can never succeed
+fn is_rejected(e: &anyhow::Error) -> bool {
+    match e.downcast_ref::<sqlx::Error>() {
+        Some(sqlx::Error::Database(db)) => {
+            // Constraint violations and, on Postgres, data exceptions. Anything else, like a
+            // canceled query or a lock timeout, may go away on its own
+            db.kind() != ErrorKind::Other
+                || db
+                    .code()
+                    .is_some_and(|code| code.starts_with("22") || code.starts_with("23"))
+        }
+        _ => false,
+    }
+}
+
+async fn insert_measurements(
+    measurements: &[NewMeasurement],
+    storage: &dyn Storage,
+    cache: &Cache<(i32, i32), Measurement>,
+    events: &broadcast::Sender<MeasurementEvent>,
+) -> anyhow::Result<u64> {
+    let mut device_ids: Vec<i32> = measurements.iter().map(|m| m.device).collect();
+    device_ids.sort_unstable();
+    device_ids.dedup();
+    let mut sensor_ids: Vec<i32> = measurements.iter().map(|m| m.sensor).collect();
+    sensor_ids.sort_unstable();
+    sensor_ids.dedup();
+
+    let devices: HashMap<i32, Device> = Device::read_by_ids(storage, &device_ids)
+        .await?
+        .into_iter()
+        .map(|d| (d.id, d))
+        .collect();
+    let sensors: HashMap<i32, Sensor> = Sensor::read_by_ids(storage, &sensor_ids)
+        .await?
+        .into_iter()
+        .map(|s| (s.id, s))
+        .collect();
+
+    let now = chrono::Utc::now();
+    let mut valid = Vec::with_capacity(measurements.len());
+    let mut stored = Vec::with_capacity(measurements.len());
+    let mut latest: HashMap<(i32, i32), Measurement> = HashMap::new();
+    for measurement in measurements {
+        let (Some(device), Some(sensor)) = (
+            devices.get(&measurement.device),
+            sensors.get(&measurement.sensor),
+        ) else {
+            warn!(
+                "Dropping measurement for unknown device or sensor: {}",
+                measurement
+            );
+            continue;
+        };
+        let entry = Measurement {
+            value: measurement.measurement,
+            timestamp: measurement.timestamp.unwrap_or(now),
+            device_name: device.name.clone(),
+            device_location: device.location.clone(),
+            sensor_name: sensor.name.clone(),
+            unit: sensor.unit.clone(),
+        };
+        match latest.get(&(device.id, sensor.id)) {
+            Some(current) if current.timestamp > entry.timestamp => {}
+            _ => {
+                latest.insert((device.id, sensor.id), entry.clone());
+            }
+        }
+        valid.push(measurement.clone());
+        stored.push(MeasurementEvent {
+            device_id: device.id,
+            sensor_id: sensor.id,
+            measurement: entry,
+        });
+    }
+
+    if valid.is_empty() {
+        return Ok(0);
+    }
+    let inserted = NewMeasurement::insert_many(&valid, storage).await?;
+    let seen: Vec<(i32, chrono::DateTime<chrono::Utc>)> = latest
+        .iter()
+        .map(|((device_id, _), entry)| (*device_id, entry.timestamp))
+        .collect();
+    if let Err(e) = Device::update_last_seen(storage, &seen).await {
+        warn!("Failed to update last seen of devices: {}", e);
+    }
+    for (key, entry) in latest {
+        cache.insert(key, entry).await;
+    }
+    // Sending only fails without subscribers, and never waits for slow ones
+    for event in stored {
+        let _ = events.send(event);
+    }
+    Ok(inserted)
+}
+
+/// Marks devices as online or stale based on when they last reported, in background
+pub async fn detect_stale_devices(
+    storage: &dyn Storage,
+    cache: &Cache<(i32, i32), Measurement>,
+    default_interval: chrono::Duration,
+    check_interval: Duration,
+) {
+    loop {
+        debug!("Checking device status");
+        if let Err(e) = check_device_status(storage, cache, default_interval).await {
+            warn!("Failed to check device status: {}", e);
+        }
+        tokio::time::sleep(check_interval).await;
+    }
+}
+
+async fn check_device_status(
+    storage: &dyn Storage,
+    cache: &Cache<(i32, i32), Measurement>,
+    default_interval: chrono::Duration,
+) -> anyhow::Result<()> {
+    let now = chrono::Utc::now();
+    for device in Device::read(storage).await? {
+        let device_interval = device
+            .expected_interval_seconds
+            .map(|seconds| chrono::Duration::seconds(seconds.into()))
+            .unwrap_or(default_interval);
+        let online = device
+            .last_seen
+            .is_some_and(|last_seen| now - last_seen <= device_interval);
+        let labels = [
+            ("device_name", device.name.clone()),
+            ("device_location", device.location.clone()),
+        ];
+        gauge!("hemrs_device_up", &labels).set(if online { 1.0 } else { 0.0 });
+        if online != device.online {
+            let status = if online { "online" } else { "offline" };
+            info!(
+                device_id = device.id,
+                last_seen = ?device.last_seen,
+                "Device {} at {} is now {}",
+                device.name,
+                device.location,
+                status
+            );
+            counter!("hemrs_device_status_changes", "status" => status).increment(1);
+            Device::update_online(storage, device.id, online).await?;
+        }
+
+        for sensor in Sensor::read_by_device_id(storage, device.id).await? {
+            let interval = sensor
+                .expected_interval_seconds
+                .map(|seconds| chrono::Duration::seconds(seconds.into()))
+                .unwrap_or(device_interval);
+            let last_seen = match cache.get(&(device.id, sensor.id)).await {
+                Some(measurement) => Some(measurement.timestamp),
+                None => Measurement::read_latest_by_device_id_and_sensor_id(
+                    device.id, sensor.id, storage,
+                )
+                .await
+                .ok()
+                .map(|measurement| measurement.timestamp),
+            };
+            let up = last_seen.is_some_and(|last_seen| now - last_seen <= interval);
+            let labels = [
+                ("device_name", device.name.clone()),
+                ("device_location", device.location.clone()),
+                ("sensor_name", sensor.name),
+            ];
+            gauge!("hemrs_sensor_up", &labels).set(if up { 1.0 } else { 0.0 });
+        }
+    }
+    Ok(())
+}
+
+/// Attempts at delivering an alert notification before it is given up on
+const WEBHOOK_ATTEMPTS: u32 = 3;
+
+/// Evaluates enabled alert rules against the latest measurements in background.
+///
+/// Rules whose latest measurement is older than `stale_after` are left as they are, and
+/// notifications are delivered alongside, so a slow webhook does not hold up evaluation.
+pub async fn evaluate_alerts(
+    pool: &PgPool,
+    cache: &Cache<(i32, i32), Measurement>,
+    client: reqwest::Client,
+    webhook_url: Option<String>,
+    stale_after: chrono::Duration,
+    interval: Duration,
+) {
+    let mut deliveries = JoinSet::new();
+    loop {
+        debug!("Evaluating alert rules");
+        while deliveries.try_join_next().is_some() {}
+        if let Err(e) = evaluate_alert_rules(
+            pool,
+            cache,
+            &client,
+            webhook_url.as_deref(),
+            stale_after,
+            &mut deliveries,
+        )
+        .await
+        {
+            warn!("Failed to evaluate alert rules: {}", e);
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+async fn evaluate_alert_rules(
     pool: &PgPool,
     cache: &Cache<(i32, i32), Measurement>,
+    client: &reqwest::Client,
+    webhook_url: Option<&str>,
+    stale_after: chrono::Duration,
+    deliveries: &mut JoinSet<()>,
 ) -> anyhow::Result<()> {
-    let device = Device::read_by_id(pool, measurement.device).await?;
-
-    let sensor = Sensor::read_by_id(pool, measurement.sensor).await?;
-    let entry = Measurement {
-        value: measurement.measurement,
-        timestamp: measurement.timestamp.unwrap_or_else(chrono::Utc::now),
-        device_name: device.name,
-        device_location: device.location,
-        sensor_name: sensor.name,
-        unit: sensor.unit,
-    };
-    cache.insert((device.id, sensor.id), entry.clone()).await;
-    measurement.insert(pool).await?;
+    let now = chrono::Utc::now();
+    for rule in AlertRule::read_enabled(pool).await? {
+        let latest = match cache.get(&(rule.device_id, rule.sensor_id)).await {
+            Some(measurement) => Some(measurement),
+            None => Measurement::read_latest_by_device_id_and_sensor_id(
+                rule.device_id,
+                rule.sensor_id,
+                pool,
+            )
+            .await
+            .ok(),
+        }
+        .map(|measurement| (measurement.value, measurement.timestamp));
+        if latest.is_some_and(|(_, timestamp)| now - timestamp > stale_after) {
+            debug!(
+                "Skipping alert {}, its sensor did not report recently",
+                rule.name
+            );
+            continue;
+        }
+
+        let Some(transition) = rule.evaluate(latest, now) else {
+            continue;
+        };
+        let value = latest.map(|(value, _)| value);
+        rule.apply(pool, &transition, value).await?;
+
+        let Some(event) = transition.event else {
+            continue;
+        };
+        info!("Alert {} is now {:?}", rule.name, event);
+        counter!("hemrs_alert_events", "state" => format!("{event:?}").to_lowercase()).increment(1);
+        if let Some(url) = webhook_url {
+            let notification = AlertNotification {
+                rule: AlertRule {
+                    state: transition.state,
+                    since: transition.since,
+                    ..rule
+                },
+                state: event,
+                value,
+                timestamp: now,
+            };
+            let client = client.clone();
+            let url = url.to_string();
+            deliveries
+                .spawn(async move { deliver_notification(&client, &url, &notification).await });
+        }
+    }
     Ok(())
 }
 
-pub async fn refresh_views(pool: &PgPool) -> anyhow::Result<()> {
+/// Posts a notification to the webhook, retrying a few times with backoff before dropping it
+async fn deliver_notification(
+    client: &reqwest::Client,
+    url: &str,
+    notification: &AlertNotification,
+) {
+    let mut backoff = Duration::from_secs(1);
+    for attempt in 1..=WEBHOOK_ATTEMPTS {
+        let res = client
+            .post(url)
+            .json(notification)
+            .send()
+            .await
+            .and_then(|res| res.error_for_status());
+        match res {
+            Ok(_) => return,
+            Err(e) if attempt < WEBHOOK_ATTEMPTS => {
+                warn!(
+                    "Failed to deliver alert notification, retrying in {}s: {}",
+                    backoff.as_secs(),
+                    e
+                );
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+            }
+            Err(e) => {
+                warn!("Failed to deliver alert notification, dropping it: {}", e);
+                counter!("hemrs_alert_notifications_dropped").increment(1);
+            }
+        }
+    }
+}
+
+/// Deletes raw measurements past their retention in background
+pub async fn prune_measurements(
+    storage: &dyn Storage,
+    default_retention: Option<chrono::Duration>,
+    batch_size: i64,
+    keep_rollups: bool,
+    interval: Duration,
+) {
+    loop {
+        debug!("Pruning expired measurements");
+        if let Err(e) =
+            prune_expired_measurements(storage, default_retention, batch_size, keep_rollups).await
+        {
+            warn!("Failed to prune measurements: {}", e);
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+async fn prune_expired_measurements(
+    storage: &dyn Storage,
+    default_retention: Option<chrono::Duration>,
+    batch_size: i64,
+    keep_rollups: bool,
+) -> anyhow::Result<u64> {
+    let now = chrono::Utc::now();
+    let mut total = 0;
+    for sensor in Sensor::read(storage).await? {
+        let retention = sensor
+            .retention_days
+            .map(|days| chrono::Duration::days(days.into()))
+            .or(default_retention);
+        // Nothing is older than a retention reaching past the earliest representable time
+        let Some(before) = retention.and_then(|retention| now.checked_sub_signed(retention)) else {
+            continue;
+        };
+
+        // Small batches keep each delete short so inserts are not blocked for long
+        let mut deleted = 0;
+        loop {
+            let batch =
+                Measurement::delete_expired(storage, sensor.id, before, batch_size, keep_rollups)
+                    .await?;
+            deleted += batch;
+            if batch < batch_size as u64 {
+                break;
+            }
+            tokio::task::yield_now().await;
+        }
+        if deleted > 0 {
+            info!(
+                "Pruned {} measurements of sensor {} older than {}",
+                deleted, sensor.name, before
+            );
+            counter!("hemrs_measurements_pruned", "sensor_name" => sensor.name.clone())
+                .increment(deleted);
+        }
+        total += deleted;
+    }
+    Ok(total)
+}
+
+/// Keeps the hourly and daily rollups up to date in background
+pub async fn update_rollups(pool: &PgPool, batch_size: i64, interval: Duration) {
+    loop {
+        debug!("Updating rollups");
+        loop {
+            match roll_up(pool, batch_size).await {
+                Ok(rolled_up) => {
+                    counter!("hemrs_measurements_rolled_up").increment(rolled_up);
+                    if rolled_up < batch_size as u64 {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    warn!("Failed to update rollups: {}", e);
+                    break;
+                }
+            }
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+/// Creates upcoming measurement partitions and removes expired ones in background
+pub async fn manage_partitions(
+    pool: &PgPool,
+    ahead: u32,
+    keep: Option<u32>,
+    detach: bool,
+    interval: Duration,
+) {
+    loop {
+        debug!("Maintaining partitions");
+        match maintain_partitions(pool, chrono::Utc::now(), ahead, keep, detach).await {
+            Ok(()) => match Partition::read(pool).await {
+                Ok(partitions) => {
+                    gauge!("hemrs_measurement_partitions").set(partitions.len() as f64)
+                }
+                Err(e) => warn!("Failed to read partitions: {}", e),
+            },
+            Err(e) => warn!("Failed to maintain partitions: {}", e),
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+pub async fn refresh_views(storage: &dyn Storage) -> anyhow::Result<()> {
     loop {
         debug!("Refreshing view");
-        Device::refresh_device_sensors_view(pool).await?;
+        Device::refresh_device_sensors_view(storage).await?;
         info!("View refreshed successfully");
         tokio::time::sleep(tokio::time::Duration::from_secs(6000)).await;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use axum::{routing::post, Json, Router};
+    use sqlx::PgPool;
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::{
+        alerts::{AlertEvent, AlertState, NewAlertRule, Operator},
+        devices::NewDevice,
+        sensors::NewSensor,
+        storage::storage_tests,
+    };
+
+    #[sqlx::test]
+    async fn should_notify_webhook_when_alert_fires(pool: PgPool) {
+        let device = NewDevice::new("test".to_string(), "test".to_string());
+        device.insert(&pool).await.unwrap();
+        let sensor = NewSensor::new("test".to_string(), "test".to_string());
+        sensor.insert(&pool).await.unwrap();
+        NewMeasurement::insert_many(&[NewMeasurement::new(None, 1, 1, 1500.0)], &pool)
+            .await
+            .unwrap();
+        let rule = NewAlertRule {
+            name: "co2".to_string(),
+            device_id: 1,
+            sensor_id: 1,
+            operator: Operator::Above,
+            threshold: 1200.0,
+            for_seconds: 0,
+        };
+        rule.insert(&pool).await.unwrap();
+
+        let (tx, mut rx) = tokio::sync::mpsc::channel::<AlertNotification>(4);
+        let app = Router::new().route(
+            "/hook",
+            post(|Json(notification): Json<AlertNotification>| async move {
+                tx.send(notification).await.unwrap();
+            }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let cache = Cache::builder().max_capacity(16).build();
+        let client = reqwest::Client::new();
+        let stale_after = chrono::Duration::minutes(5);
+        let mut deliveries = JoinSet::new();
+        evaluate_alert_rules(
+            &pool,
+            &cache,
+            &client,
+            Some(&url),
+            stale_after,
+            &mut deliveries,
+        )
+        .await
+        .unwrap();
+
+        let notification = rx.recv().await.unwrap();
+        assert_eq!(notification.state, AlertState::Firing);
+        assert_eq!(notification.value, Some(1500.0));
+        assert_eq!(notification.rule.name, "co2");
+
+        // Nothing changed, so nothing is sent
+        evaluate_alert_rules(
+            &pool,
+            &cache,
+            &client,
+            Some(&url),
+            stale_after,
+            &mut deliveries,
+        )
+        .await
+        .unwrap();
+        deliveries.join_all().await;
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[sqlx::test]
+    async fn should_retry_failed_notifications(pool: PgPool) {
+        let device = NewDevice::new("test".to_string(), "test".to_string());
+        device.insert(&pool).await.unwrap();
+        let sensor = NewSensor::new("test".to_string(), "test".to_string());
+        sensor.insert(&pool).await.unwrap();
+        NewMeasurement::insert_many(&[NewMeasurement::new(None, 1, 1, 1500.0)], &pool)
+            .await
+            .unwrap();
+        let rule = NewAlertRule {
+            name: "co2".to_string(),
+            device_id: 1,
+            sensor_id: 1,
+            operator: Operator::Above,
+            threshold: 1200.0,
+            for_seconds: 0,
+        };
+        rule.insert(&pool).await.unwrap();
+
+        // Fails the first delivery
+        let attempts = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let hook_attempts = attempts.clone();
+        let app = Router::new().route(
+            "/hook",
+            post(move || async move {
+                if hook_attempts.fetch_add(1, std::sync::atomic::Ordering::SeqCst) == 0 {
+                    axum::http::StatusCode::SERVICE_UNAVAILABLE
+                } else {
+                    axum::http::StatusCode::OK
+                }
+            }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let cache = Cache::builder().max_capacity(16).build();
+        let mut deliveries = JoinSet::new();
+        evaluate_alert_rules(
+            &pool,
+            &cache,
+            &reqwest::Client::new(),
+            Some(&url),
+            chrono::Duration::minutes(5),
+            &mut deliveries,
+        )
+        .await
+        .unwrap();
+        // Delivered alongside, evaluation does not wait for the retry
+        assert_eq!(deliveries.len(), 1);
+        deliveries.join_all().await;
+        assert_eq!(attempts.load(std::sync::atomic::Ordering::SeqCst), 2);
+    }
+
+    #[sqlx::test]
+    async fn should_skip_alerts_of_stale_sensors(pool: PgPool) {
+        let device = NewDevice::new("test".to_string(), "test".to_string());
+        device.insert(&pool).await.unwrap();
+        let sensor = NewSensor::new("test".to_string(), "test".to_string());
+        sensor.insert(&pool).await.unwrap();
+        let an_hour_ago = chrono::Utc::now() - chrono::Duration::hours(1);
+        NewMeasurement::insert_many(
+            &[NewMeasurement::new(Some(an_hour_ago), 1, 1, 1500.0)],
+            &pool,
+        )
+        .await
+        .unwrap();
+        let rule = NewAlertRule {
+            name: "co2".to_string(),
+            device_id: 1,
+            sensor_id: 1,
+            operator: Operator::Above,
+            threshold: 1200.0,
+            for_seconds: 0,
+        };
+        rule.insert(&pool).await.unwrap();
+
+        let cache = Cache::builder().max_capacity(16).build();
+        let mut deliveries = JoinSet::new();
+        evaluate_alert_rules(
+            &pool,
+            &cache,
+            &reqwest::Client::new(),
+            Some("http://127.0.0.1:9/hook"),
+            chrono::Duration::minutes(5),
+            &mut deliveries,
+        )
+        .await
+        .unwrap();
+        assert!(deliveries.is_empty());
+        let rule = AlertRule::read_by_id(&pool, 1).await.unwrap();
+        assert_eq!(rule.state, AlertState::Ok);
+        assert!(AlertEvent::read_by_rule_id(&pool, 1)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    async fn should_mark_stale_devices_offline(storage: Arc<dyn Storage>) {
+        for name in ["fresh", "stale"] {
+            let device = NewDevice::new(name.to_string(), "test".to_string());
+            device.insert(storage.as_ref()).await.unwrap();
+        }
+        let now = chrono::Utc::now();
+        Device::update_last_seen(
+            storage.as_ref(),
+            &[(1, now), (2, now - chrono::Duration::hours(1))],
+        )
+        .await
+        .unwrap();
+        Device::update_online(storage.as_ref(), 2, true)
+            .await
+            .unwrap();
+
+        let cache = Cache::builder().max_capacity(16).build();
+        check_device_status(storage.as_ref(), &cache, chrono::Duration::minutes(5))
+            .await
+            .unwrap();
+        assert!(
+            Device::read_by_id(storage.as_ref(), 1)
+                .await
+                .unwrap()
+                .online
+        );
+        assert!(
+            !Device::read_by_id(storage.as_ref(), 2)
+                .await
+                .unwrap()
+                .online
+        );
+
+        // A longer expected interval keeps the device online
+        let mut device = Device::read_by_id(storage.as_ref(), 2).await.unwrap();
+        device.expected_interval_seconds = Some(7200);
+        device.update(storage.as_ref()).await.unwrap();
+        check_device_status(storage.as_ref(), &cache, chrono::Duration::minutes(5))
+            .await
+            .unwrap();
+        assert!(
+            Device::read_by_id(storage.as_ref(), 2)
+                .await
+                .unwrap()
+                .online
+        );
+    }
+
+    async fn should_insert_measurements_in_batches(storage: Arc<dyn Storage>) {
+        let device = NewDevice::new("test".to_string(), "test".to_string());
+        device.insert(storage.as_ref()).await.unwrap();
+        let sensor = NewSensor::new("test".to_string(), "test".to_string());
+        sensor.insert(storage.as_ref()).await.unwrap();
+        let cache = Cache::builder().max_capacity(16).build();
+
+        let (tx, rx) = tokio::sync::mpsc::channel(16);
+        for i in 0..5 {
+            tx.send(NewMeasurement::new(None, 1, 1, i as f32))
+                .await
+                .unwrap();
+        }
+        // Unknown device, dropped without failing the rest of the batch
+        tx.send(NewMeasurement::new(None, 2, 1, 1.0)).await.unwrap();
+        drop(tx);
+        let (events, mut subscriber) = broadcast::channel(16);
+
+        handle_insert_measurement_bg_thread(
+            rx,
+            storage.clone(),
+            cache.clone(),
+            2,
+            Duration::from_millis(10),
+            None,
+            events,
+        )
+        .await;
+
+        let count = Measurement::read_total_measurements(storage.as_ref())
+            .await
+            .unwrap();
+        assert_eq!(count, 5);
+        assert!(cache.get(&(1, 1)).await.is_some());
+        assert!(Device::read_by_id(storage.as_ref(), 1)
+            .await
+            .unwrap()
+            .last_seen
+            .is_some());
+
+        let event = subscriber.recv().await.unwrap();
+        assert_eq!((event.device_id, event.sensor_id), (1, 1));
+        assert_eq!(event.measurement.value, 0.0);
+    }
+
+    #[sqlx::test]
+    async fn should_drop_rejected_measurements_from_spooled_batches(pool: PgPool) {
+        let device = NewDevice::new("test".to_string(), "test".to_string());
+        device.insert(&pool).await.unwrap();
+        let sensor = NewSensor::new("test".to_string(), "test".to_string());
+        sensor.insert(&pool).await.unwrap();
+        let dir = std::env::temp_dir().join(format!("hemrs-rejected-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let (spool, _) = Spool::open(&dir).unwrap();
+        let spool = Arc::new(spool);
+
+        // Valid in chrono, but out of the range of timestamptz
+        let out_of_range = chrono::DateTime::from_timestamp_millis(-300_000_000_000_000);
+        let mut measurements: Vec<NewMeasurement> = (0..4)
+            .map(|i| NewMeasurement::new(None, 1, 1, i as f32))
+            .collect();
+        measurements.insert(2, NewMeasurement::new(out_of_range, 1, 1, 9.0));
+        // Left to the backlog, as if the channel had been full
+        spool.append(&measurements).unwrap();
+        let (tx, rx) = tokio::sync::mpsc::channel(16);
+        drop(tx);
+
+        let (events, _) = broadcast::channel(16);
+        handle_insert_measurement_bg_thread(
+            rx,
+            Arc::new(pool.clone()),
+            Cache::builder().max_capacity(16).build(),
+            8,
+            Duration::from_millis(10),
+            Some(spool.clone()),
+            events,
+        )
+        .await;
+
+        assert_eq!(
+            Measurement::read_total_measurements(&pool).await.unwrap(),
+            4
+        );
+        drop(spool);
+        let (_spool, pending) = Spool::open(&dir).unwrap();
+        assert!(pending.is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[sqlx::test]
+    async fn should_only_reject_invalid_data(pool: PgPool) {
+        let error = |sql: &'static str| {
+            let pool = pool.clone();
+            async move {
+                let mut conn = pool.acquire().await.unwrap();
+                sqlx::query("SET statement_timeout = 10")
+                    .execute(&mut *conn)
+                    .await
+                    .unwrap();
+                anyhow::Error::from(sqlx::query(sql).execute(&mut *conn).await.unwrap_err())
+            }
+        };
+        assert!(is_rejected(&error("SELECT 1 / 0").await));
+        assert!(is_rejected(
+            &error("INSERT INTO measurements (ts, device_id, sensor_id, value) VALUES (now(), 42, 42, 1.0)").await
+        ));
+        // query_canceled
+        assert!(!is_rejected(&error("SELECT pg_sleep(1)").await));
+        // internal_error
+        assert!(!is_rejected(
+            &error("DO $$ BEGIN RAISE EXCEPTION 'failed' USING ERRCODE = 'XX000'; END $$").await
+        ));
+        assert!(!is_rejected(&anyhow::Error::from(
+            sqlx::Error::PoolTimedOut
+        )));
+    }
+
+    #[sqlx::test]
+    async fn should_prune_expired_measurements(pool: PgPool) {
+        let device = NewDevice::new("test".to_string(), "test".to_string());
+        device.insert(&pool).await.unwrap();
+        for name in ["temperature", "humidity"] {
+            let sensor = NewSensor::new(name.to_string(), "test".to_string());
+            sensor.insert(&pool).await.unwrap();
+        }
+        let mut humidity = Sensor::read_by_id(&pool, 2).await.unwrap();
+        humidity.retention_days = Some(1);
+        humidity.update(&pool).await.unwrap();
+
+        let now = chrono::Utc::now();
+        let old = now - chrono::Duration::days(3);
+        let mut measurements = Vec::new();
+        for sensor in [1, 2] {
+            for (i, value) in [1.0, 2.0, 3.0].into_iter().enumerate() {
+                let ts = old + chrono::Duration::minutes(i as i64);
+                measurements.push(NewMeasurement::new(Some(ts), 1, sensor, value));
+            }
+            measurements.push(NewMeasurement::new(Some(now), 1, sensor, 4.0));
+        }
+        NewMeasurement::insert_many(&measurements, &pool)
+            .await
+            .unwrap();
+
+        // Nothing is pruned before it is rolled up
+        let pruned = prune_expired_measurements(&pool, None, 2, true)
+            .await
+            .unwrap();
+        assert_eq!(pruned, 0);
+        roll_up(&pool, 100).await.unwrap();
+
+        // Only the sensor with its own retention is pruned without a global one
+        let pruned = prune_expired_measurements(&pool, None, 2, true)
+            .await
+            .unwrap();
+        assert_eq!(pruned, 3);
+        assert_eq!(
+            Measurement::read_total_measurements(&pool).await.unwrap(),
+            5
+        );
+
+        let (count, sum, min, max): (i64, f64, f32, f32) = sqlx::query_as(
+            "SELECT sum(count)::int8, sum(sum), min(min), max(max) FROM measurements_hourly WHERE device_id = 1 AND sensor_id = 2 AND bucket < now() - interval '1 day'",
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        assert_eq!((count, sum, min, max), (3, 6.0, 1.0, 3.0));
+
+        let pruned = prune_expired_measurements(&pool, Some(chrono::Duration::days(2)), 2, true)
+            .await
+            .unwrap();
+        assert_eq!(pruned, 3);
+        assert_eq!(
+            Measurement::read_total_measurements(&pool).await.unwrap(),
+            2
+        );
+    }
+
+    #[sqlx::test]
+    async fn should_prune_without_keeping_rollups(pool: PgPool) {
+        let device = NewDevice::new("test".to_string(), "test".to_string());
+        device.insert(&pool).await.unwrap();
+        let sensor = NewSensor::new("test".to_string(), "test".to_string());
+        sensor.insert(&pool).await.unwrap();
+        let old = chrono::Utc::now() - chrono::Duration::days(3);
+        NewMeasurement::insert_many(&[NewMeasurement::new(Some(old), 1, 1, 1.0)], &pool)
+            .await
+            .unwrap();
+
+        let pruned = prune_expired_measurements(&pool, Some(chrono::Duration::days(1)), 2, false)
+            .await
+            .unwrap();
+        assert_eq!(pruned, 1);
+        // Retentions reaching past the earliest timestamp prune nothing
+        let pruned = prune_expired_measurements(&pool, Some(chrono::Duration::MAX), 2, false)
+            .await
+            .unwrap();
+        assert_eq!(pruned, 0);
+    }
+
+    storage_tests!(
+        should_mark_stale_devices_offline,
+        should_insert_measurements_in_batches
+    );
+}
@@ -0,0 +1,140 @@
+use std::{f32::consts::TAU, time::Duration};
+
+use anyhow::Result;
+use chrono::{DateTime, Timelike, Utc};
+use tracing::{info, warn};
+
+use crate::{
+    devices::{Device, NewDevice},
+    measurements::NewMeasurement,
+    queue::MeasurementQueue,
+    sensors::{NewSensor, Sensor},
+    storage::Storage,
+};
+
+const DEVICES: [(&str, &str); 3] = [
+    ("esp32-1", "Living room"),
+    ("esp32-2", "Bedroom"),
+    ("esp32-3", "Balcony"),
+];
+
+const SENSORS: [(&str, &str); 3] = [("temperature", "°C"), ("humidity", "%"), ("co2", "ppm")];
+
+/// History generated on startup, so queries have something to show right away
+const HISTORY: chrono::Duration = chrono::Duration::hours(24);
+const HISTORY_INTERVAL: chrono::Duration = chrono::Duration::minutes(5);
+
+/// A plausible reading following the time of day, with a little wobble
+fn value(device: &Device, sensor: &Sensor, ts: DateTime<Utc>) -> f32 {
+    let day = ts.num_seconds_from_midnight() as f32 / 86400.0;
+    // Warmest in the afternoon, around 15:00
+    let daily = (TAU * (day - 0.625)).cos();
+    let wobble = (ts.timestamp() as f32 / 600.0 + device.id as f32).sin() * 0.3;
+    let outdoor = device.location == "Balcony";
+    match sensor.name.as_str() {
+        "temperature" if outdoor => 12.0 + 6.0 * daily + wobble,
+        "temperature" => 21.0 + device.id as f32 * 0.5 + daily + wobble,
+        "humidity" if outdoor => 70.0 - 15.0 * daily + wobble * 5.0,
+        "humidity" => 45.0 - 5.0 * daily + wobble * 3.0,
+        "co2" if outdoor => 420.0 + wobble * 10.0,
+        _ => 650.0 + 250.0 * daily.max(0.0) + wobble * 40.0,
+    }
+}
+
+/// Creates the demo devices and sensors, with a day of measurements up to `now`
+pub async fn seed(storage: &dyn Storage, now: DateTime<Utc>) -> Result<()> {
+    for (name, location) in DEVICES {
+        NewDevice::new(name.to_string(), location.to_string())
+            .insert(storage)
+            .await?;
+    }
+    for (name, unit) in SENSORS {
+        NewSensor::new(name.to_string(), unit.to_string())
+            .insert(storage)
+            .await?;
+    }
+
+    let devices = Device::read(storage).await?;
+    let sensors = Sensor::read(storage).await?;
+    let mut measurements = Vec::new();
+    let mut ts = now - HISTORY;
+    while ts <= now {
+        for device in &devices {
+            for sensor in &sensors {
+                let value = value(device, sensor, ts);
+                measurements.push(NewMeasurement::new(Some(ts), device.id, sensor.id, value));
+            }
+        }
+        ts += HISTORY_INTERVAL;
+    }
+    let inserted = NewMeasurement::insert_many(&measurements, storage).await?;
+    let last_seen: Vec<(i32, DateTime<Utc>)> = devices.iter().map(|d| (d.id, now)).collect();
+    Device::update_last_seen(storage, &last_seen).await?;
+    info!(
+        "Seeded {} devices, {} sensors and {} measurements",
+        devices.len(),
+        sensors.len(),
+        inserted
+    );
+    Ok(())
+}
+
+/// Keeps sending measurements for every demo device and sensor through the ingest queue
+pub async fn generate_measurements(
+    storage: &dyn Storage,
+    queue: MeasurementQueue,
+    interval: Duration,
+) {
+    loop {
+        tokio::time::sleep(interval).await;
+        let (devices, sensors) = match (Device::read(storage).await, Sensor::read(storage).await) {
+            (Ok(devices), Ok(sensors)) => (devices, sensors),
+            (Err(e), _) | (_, Err(e)) => {
+                warn!("Failed to read demo devices and sensors: {}", e);
+                continue;
+            }
+        };
+        let now = Utc::now();
+        let measurements = devices
+            .iter()
+            .flat_map(|device| {
+                sensors.iter().map(move |sensor| {
+                    NewMeasurement::new(Some(now), device.id, sensor.id, value(device, sensor, now))
+                })
+            })
+            .collect();
+        if let Err(e) = queue.send(measurements).await {
+            warn!("Failed to queue demo measurements: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{measurements::Measurement, storage::MemoryStorage};
+
+    #[tokio::test]
+    async fn should_seed_demo_data() {
+        let storage = MemoryStorage::default();
+        let now = Utc::now();
+        seed(&storage, now).await.unwrap();
+
+        assert_eq!(Device::read(&storage).await.unwrap().len(), 3);
+        assert_eq!(
+            Sensor::read_by_device_id(&storage, 1).await.unwrap().len(),
+            3
+        );
+        let latest = Measurement::read_all_latest_measurements(&storage)
+            .await
+            .unwrap();
+        assert_eq!(latest.len(), 9);
+        assert!(latest.iter().all(|m| m.value.is_finite()));
+        assert_eq!(
+            Measurement::read_total_measurements(&storage)
+                .await
+                .unwrap(),
+            9 * 289
+        );
+    }
+}
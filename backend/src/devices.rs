@@ -1,6 +1,15 @@
 use anyhow::Result;
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use sqlx::{FromRow, PgPool};
+use sqlx::FromRow;
+
+use crate::{
+    api_keys::{generate, hash},
+    storage::Storage,
+};
+
+/// Prefix of device tokens, telling them apart from API keys
+pub const DEVICE_TOKEN_PREFIX: &str = "hemrs_dev_";
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct NewDevice {
@@ -8,58 +17,98 @@ pub struct NewDevice {
     pub location: String,
 }
 
+/// A newly issued device token, the only time the token itself is available
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DeviceToken {
+    pub device_id: i32,
+    pub token: String,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize, FromRow)]
 pub struct Device {
     pub id: i32,
     pub name: String,
     pub location: String,
+    /// How often the device is expected to report, falls back to the global default
+    #[serde(default)]
+    pub expected_interval_seconds: Option<i32>,
+    /// Maintained by the backend, ignored on updates
+    #[serde(default)]
+    pub last_seen: Option<DateTime<Utc>>,
+    /// Maintained by the backend, ignored on updates
+    #[serde(default)]
+    pub online: bool,
 }
 
 impl Device {
     pub fn new(id: i32, name: String, location: String) -> Self {
-        Self { id, name, location }
+        Self {
+            id,
+            name,
+            location,
+            expected_interval_seconds: None,
+            last_seen: None,
+            online: false,
+        }
     }
 
-    pub async fn refresh_device_sensors_view(pool: &PgPool) -> Result<()> {
-        sqlx::query("REFRESH MATERIALIZED VIEW device_sensors")
-            .execute(pool)
-            .await?;
-        Ok(())
+    pub async fn refresh_device_sensors_view(storage: &dyn Storage) -> Result<()> {
+        storage.refresh_device_sensors_view().await
     }
 
-    pub async fn read(pool: &PgPool) -> Result<Vec<Device>> {
-        let devices = sqlx::query_as::<_, Device>("SELECT id, name, location FROM devices")
-            .fetch_all(pool)
-            .await?;
-        Ok(devices)
+    pub async fn read(storage: &dyn Storage) -> Result<Vec<Device>> {
+        storage.read_devices().await
     }
 
-    pub async fn read_by_id(pool: &PgPool, device_id: i32) -> Result<Device> {
-        let device =
-            sqlx::query_as::<_, Device>("SELECT id, name, location FROM devices WHERE id = $1")
-                .bind(device_id)
-                .fetch_one(pool)
-                .await?;
-        Ok(device)
+    pub async fn read_by_id(storage: &dyn Storage, device_id: i32) -> Result<Device> {
+        storage.read_device_by_id(device_id).await
     }
 
-    pub async fn delete(self, pool: &PgPool) -> Result<()> {
-        sqlx::query("DELETE FROM devices WHERE id = $1")
-            .bind(self.id)
-            .execute(pool)
-            .await?;
-        Ok(())
+    /// The first device with this name and location
+    pub async fn read_by_name(storage: &dyn Storage, name: &str, location: &str) -> Result<Device> {
+        storage.read_device_by_name(name, location).await
+    }
+
+    pub async fn read_by_ids(storage: &dyn Storage, device_ids: &[i32]) -> Result<Vec<Device>> {
+        storage.read_devices_by_ids(device_ids).await
+    }
+
+    pub async fn delete(self, storage: &dyn Storage) -> Result<()> {
+        storage.delete_device(self.id).await
+    }
+
+    pub async fn update(self, storage: &dyn Storage) -> Result<()> {
+        storage.update_device(self).await
+    }
+
+    /// Moves `last_seen` forward for every `(device_id, timestamp)` pair
+    pub async fn update_last_seen(
+        storage: &dyn Storage,
+        seen: &[(i32, DateTime<Utc>)],
+    ) -> Result<()> {
+        storage.update_last_seen(seen).await
+    }
+
+    pub async fn update_online(storage: &dyn Storage, device_id: i32, online: bool) -> Result<()> {
+        storage.update_online(device_id, online).await
     }
 
-    pub async fn update(self, pool: &PgPool) -> Result<()> {
-        sqlx::query("UPDATE devices SET name = $1,location = $2 WHERE id = $3")
-            .bind(self.name)
-            .bind(self.location)
-            .bind(self.id)
-            .execute(pool)
+    /// Issues a new ingest token for the device, replacing any previous one
+    pub async fn rotate_token(storage: &dyn Storage, device_id: i32) -> Result<DeviceToken> {
+        let token = generate(DEVICE_TOKEN_PREFIX);
+        storage
+            .update_device_token(device_id, Some(&hash(&token)))
             .await?;
-        Self::refresh_device_sensors_view(pool).await?;
-        Ok(())
+        Ok(DeviceToken { device_id, token })
+    }
+
+    pub async fn revoke_token(storage: &dyn Storage, device_id: i32) -> Result<()> {
+        storage.update_device_token(device_id, None).await
+    }
+
+    /// The device `token` belongs to, fails with `RowNotFound` for unknown tokens
+    pub async fn authenticate(storage: &dyn Storage, token: &str) -> Result<Device> {
+        storage.read_device_by_token_hash(&hash(token)).await
     }
 }
 
@@ -68,57 +117,109 @@ impl NewDevice {
         Self { name, location }
     }
 
-    pub async fn insert(self, pool: &PgPool) -> Result<()> {
-        sqlx::query("INSERT INTO devices (name, location) VALUES ($1, $2)")
-            .bind(self.name)
-            .bind(self.location)
-            .execute(pool)
-            .await?;
-        Device::refresh_device_sensors_view(pool).await?;
-        Ok(())
+    pub async fn insert(self, storage: &dyn Storage) -> Result<()> {
+        storage.insert_device(self).await
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use sqlx::PgPool;
+    use std::sync::Arc;
 
-    use crate::devices::{Device, NewDevice};
+    use crate::{
+        devices::{Device, NewDevice},
+        storage::{storage_tests, Storage},
+    };
 
-    #[sqlx::test]
-    async fn insert(pool: PgPool) {
+    async fn insert(storage: Arc<dyn Storage>) {
         let device = NewDevice::new("test".to_string(), "test".to_string());
-        device.insert(&pool).await.unwrap();
-        let devices = Device::read(&pool).await.unwrap();
+        device.insert(storage.as_ref()).await.unwrap();
+        let devices = Device::read(storage.as_ref()).await.unwrap();
         assert!(!devices.is_empty());
         assert_eq!(devices[0].name, "test");
         assert_eq!(devices[0].location, "test");
     }
 
-    #[sqlx::test]
-    async fn delete(pool: PgPool) {
+    async fn delete(storage: Arc<dyn Storage>) {
         let device = NewDevice::new("test".to_string(), "test".to_string());
-        device.clone().insert(&pool).await.unwrap();
-        let devices = Device::read(&pool).await.unwrap();
-        let device = devices[0].clone().delete(&pool).await;
+        device.clone().insert(storage.as_ref()).await.unwrap();
+        let devices = Device::read(storage.as_ref()).await.unwrap();
+        let device = devices[0].clone().delete(storage.as_ref()).await;
         assert!(device.is_ok());
 
-        let devices = Device::read(&pool).await.unwrap();
+        let devices = Device::read(storage.as_ref()).await.unwrap();
         assert_eq!(devices.len(), 0);
     }
 
-    #[sqlx::test]
-    async fn update(pool: PgPool) {
+    async fn update_last_seen(storage: Arc<dyn Storage>) {
         let device = NewDevice::new("test".to_string(), "test".to_string());
-        device.clone().insert(&pool).await.unwrap();
-        let devices = Device::read(&pool).await.unwrap();
+        device.insert(storage.as_ref()).await.unwrap();
+        let now = chrono::Utc::now();
+        let earlier = now - chrono::Duration::minutes(5);
+
+        Device::update_last_seen(storage.as_ref(), &[(1, earlier), (1, now)])
+            .await
+            .unwrap();
+        Device::update_last_seen(storage.as_ref(), &[(1, earlier)])
+            .await
+            .unwrap();
+        let device = Device::read_by_id(storage.as_ref(), 1).await.unwrap();
+        assert_eq!(
+            device.last_seen.map(|ts| ts.timestamp_micros()),
+            Some(now.timestamp_micros())
+        );
+
+        Device::update_online(storage.as_ref(), 1, true)
+            .await
+            .unwrap();
+        assert!(
+            Device::read_by_id(storage.as_ref(), 1)
+                .await
+                .unwrap()
+                .online
+        );
+    }
+
+    async fn update(storage: Arc<dyn Storage>) {
+        let device = NewDevice::new("test".to_string(), "test".to_string());
+        device.clone().insert(storage.as_ref()).await.unwrap();
+        let devices = Device::read(storage.as_ref()).await.unwrap();
         let device = devices[0].clone();
         let device = Device::new(device.id, "test2".to_string(), "test2".to_string());
-        device.clone().update(&pool).await.unwrap();
+        device.clone().update(storage.as_ref()).await.unwrap();
 
-        let devices = Device::read(&pool).await.unwrap();
+        let devices = Device::read(storage.as_ref()).await.unwrap();
         assert_eq!(devices.len(), 1);
         assert_eq!(devices[0].name, "test2");
         assert_eq!(devices[0].location, "test2");
     }
+
+    async fn rotate_token(storage: Arc<dyn Storage>) {
+        let device = NewDevice::new("test".to_string(), "test".to_string());
+        device.insert(storage.as_ref()).await.unwrap();
+
+        let first = Device::rotate_token(storage.as_ref(), 1).await.unwrap();
+        let device = Device::authenticate(storage.as_ref(), &first.token)
+            .await
+            .unwrap();
+        assert_eq!(device.id, 1);
+
+        // Rotating invalidates the previous token
+        let second = Device::rotate_token(storage.as_ref(), 1).await.unwrap();
+        assert_ne!(first.token, second.token);
+        assert!(Device::authenticate(storage.as_ref(), &first.token)
+            .await
+            .is_err());
+        assert!(Device::authenticate(storage.as_ref(), &second.token)
+            .await
+            .is_ok());
+
+        Device::revoke_token(storage.as_ref(), 1).await.unwrap();
+        assert!(Device::authenticate(storage.as_ref(), &second.token)
+            .await
+            .is_err());
+        assert!(Device::rotate_token(storage.as_ref(), 42).await.is_err());
+    }
+
+    storage_tests!(insert, delete, update_last_seen, update, rotate_token);
 }
@@ -0,0 +1,138 @@
+use anyhow::Result;
+use chrono::SecondsFormat;
+use serde::Deserialize;
+
+use crate::measurements::Measurement;
+
+/// Columns of CSV exports, in the order of the `Measurement` fields
+const CSV_HEADER: &str = "timestamp,value,unit,device_name,device_location,sensor_name\n";
+
+/// Formats the measurement list endpoints respond with
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    /// A page of measurements as one JSON array
+    #[default]
+    Json,
+    Csv,
+    Ndjson,
+}
+
+/// The `format` parameter of the measurement list endpoints
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct FormatQuery {
+    pub format: Option<Format>,
+}
+
+impl FormatQuery {
+    /// The `format` parameter wins over the `Accept` header, anything else is JSON
+    pub fn negotiate(&self, accept: Option<&str>) -> Format {
+        if let Some(format) = self.format {
+            return format;
+        }
+        accept
+            .into_iter()
+            .flat_map(|accept| accept.split(','))
+            .map(|media_type| media_type.split(';').next().unwrap_or_default().trim())
+            .find_map(|media_type| match media_type {
+                "text/csv" => Some(Format::Csv),
+                "application/x-ndjson" | "application/ndjson" => Some(Format::Ndjson),
+                _ => None,
+            })
+            .unwrap_or_default()
+    }
+}
+
+impl Format {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Format::Json => "application/json",
+            Format::Csv => "text/csv; charset=utf-8",
+            Format::Ndjson => "application/x-ndjson",
+        }
+    }
+
+    /// Written before the first row
+    pub fn header(&self) -> Option<&'static str> {
+        match self {
+            Format::Csv => Some(CSV_HEADER),
+            Format::Json | Format::Ndjson => None,
+        }
+    }
+
+    /// One row, terminated by a newline. JSON is only written as whole pages
+    pub fn encode(&self, measurement: &Measurement) -> Result<String> {
+        match self {
+            Format::Csv => Ok(format!(
+                "{},{},{},{},{},{}\n",
+                measurement
+                    .timestamp
+                    .to_rfc3339_opts(SecondsFormat::AutoSi, true),
+                measurement.value,
+                csv_field(&measurement.unit),
+                csv_field(&measurement.device_name),
+                csv_field(&measurement.device_location),
+                csv_field(&measurement.sensor_name)
+            )),
+            Format::Json | Format::Ndjson => {
+                let mut line = serde_json::to_string(measurement)?;
+                line.push('\n');
+                Ok(line)
+            }
+        }
+    }
+}
+
+/// Quotes a field as RFC 4180 requires, if it contains separators, quotes or line breaks
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::DateTime;
+
+    use super::*;
+
+    #[test]
+    fn should_negotiate_format() {
+        let query = FormatQuery::default();
+        assert_eq!(query.negotiate(None), Format::Json);
+        assert_eq!(query.negotiate(Some("application/json")), Format::Json);
+        assert_eq!(query.negotiate(Some("text/csv")), Format::Csv);
+        assert_eq!(
+            query.negotiate(Some("text/html, application/x-ndjson;q=0.9")),
+            Format::Ndjson
+        );
+        let query = FormatQuery {
+            format: Some(Format::Csv),
+        };
+        assert_eq!(query.negotiate(Some("application/x-ndjson")), Format::Csv);
+        assert!(serde_json::from_str::<FormatQuery>(r#"{"format": "xml"}"#).is_err());
+    }
+
+    #[test]
+    fn should_encode_rows() {
+        let measurement = Measurement {
+            timestamp: DateTime::from_timestamp(1_700_000_000, 0).unwrap(),
+            value: 21.5,
+            unit: "°C".to_string(),
+            device_name: "esp32, \"old\"".to_string(),
+            device_location: "Kitchen".to_string(),
+            sensor_name: "temperature".to_string(),
+        };
+        assert_eq!(
+            Format::Csv.encode(&measurement).unwrap(),
+            "2023-11-14T22:13:20Z,21.5,°C,\"esp32, \"\"old\"\"\",Kitchen,temperature\n"
+        );
+        let line = Format::Ndjson.encode(&measurement).unwrap();
+        assert!(line.ends_with('\n'));
+        let value: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(value["device_location"], "Kitchen");
+        assert_eq!(value["value"], 21.5);
+    }
+}
@@ -0,0 +1,589 @@
+use std::{collections::BTreeSet, fmt, str::FromStr};
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+
+use crate::{
+    alerts::{AlertEvent, AlertRule, AlertState},
+    measurements::{AggregateFunctions, AggregateQuery, Bucket, Measurement, MAX_BUCKETS},
+    prometheus::{LabelMatcher, MatchType, Matcher, Series},
+    storage::Storage,
+};
+
+/// Labels of a series that can be searched and filtered on, as on `/metrics`
+pub const TAG_KEYS: [&str; 4] = ["device_name", "device_location", "sensor_name", "unit"];
+
+/// Points per series if Grafana does not send `maxDataPoints`
+const DEFAULT_MAX_DATA_POINTS: i64 = 1000;
+
+/// A device and sensor pair, written as `device_id:sensor_id` in Grafana targets
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Target {
+    pub device_id: i32,
+    pub sensor_id: i32,
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.device_id, self.sensor_id)
+    }
+}
+
+impl FromStr for Target {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || anyhow!("invalid target {s}, expected device_id:sensor_id");
+        let (device_id, sensor_id) = s.trim().split_once(':').ok_or_else(invalid)?;
+        Ok(Self {
+            device_id: device_id.parse().map_err(|_| invalid())?,
+            sensor_id: sensor_id.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+impl From<&Series> for Target {
+    fn from(series: &Series) -> Self {
+        Self {
+            device_id: series.device_id,
+            sensor_id: series.sensor_id,
+        }
+    }
+}
+
+/// Name of a series in Grafana, e.g. `esp32 (Kitchen) temperature`
+fn display_name(series: &Series) -> String {
+    format!(
+        "{} ({}) {}",
+        label(series, "device_name"),
+        label(series, "device_location"),
+        label(series, "sensor_name")
+    )
+}
+
+fn label<'a>(series: &'a Series, name: &str) -> &'a str {
+    series
+        .labels
+        .iter()
+        .find(|label| label.name == name)
+        .map_or("", |label| label.value.as_str())
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Range {
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct SearchRequest {
+    /// Matched case insensitively against the series names, empty lists all of them
+    #[serde(default)]
+    pub target: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SearchResult {
+    pub text: String,
+    pub value: String,
+}
+
+/// Lists the series whose names contain the searched text
+pub async fn search(storage: &dyn Storage, request: &SearchRequest) -> Result<Vec<SearchResult>> {
+    let text = request.target.to_lowercase();
+    Ok(Series::find(storage, &[])
+        .await?
+        .iter()
+        .map(|series| SearchResult {
+            text: display_name(series),
+            value: Target::from(series).to_string(),
+        })
+        .filter(|result| result.text.to_lowercase().contains(&text))
+        .collect())
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+pub enum TargetType {
+    #[default]
+    #[serde(rename = "timeserie", alias = "timeseries")]
+    TimeSeries,
+    #[serde(rename = "table")]
+    Table,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QueryTarget {
+    /// Unset while the query is being edited
+    pub target: Option<String>,
+    #[serde(default, rename = "type")]
+    pub target_type: TargetType,
+    #[serde(default)]
+    pub hide: bool,
+}
+
+/// A filter set on the dashboard, applied to every target of a query
+#[derive(Debug, Clone, Deserialize)]
+pub struct AdhocFilter {
+    pub key: String,
+    pub operator: String,
+    pub value: String,
+}
+
+impl TryFrom<&AdhocFilter> for Matcher {
+    type Error = anyhow::Error;
+
+    fn try_from(filter: &AdhocFilter) -> Result<Self> {
+        let match_type = match filter.operator.as_str() {
+            "=" => MatchType::Eq,
+            "!=" => MatchType::Neq,
+            "=~" => MatchType::Re,
+            "!~" => MatchType::Nre,
+            operator => return Err(anyhow!("unsupported filter operator {operator}")),
+        };
+        Matcher::try_from(&LabelMatcher {
+            r#type: match_type.into(),
+            name: filter.key.clone(),
+            value: filter.value.clone(),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QueryRequest {
+    pub range: Range,
+    /// The shortest interval between points Grafana asks for
+    #[serde(default)]
+    pub interval_ms: i64,
+    #[serde(default)]
+    pub max_data_points: Option<i64>,
+    pub targets: Vec<QueryTarget>,
+    #[serde(default)]
+    pub adhoc_filters: Vec<AdhocFilter>,
+}
+
+impl QueryRequest {
+    /// Buckets wide enough to stay within `maxDataPoints`
+    pub fn bucket(&self) -> Bucket {
+        Bucket::for_max_points(
+            self.range.to - self.range.from,
+            self.max_data_points
+                .unwrap_or(DEFAULT_MAX_DATA_POINTS)
+                .min(MAX_BUCKETS),
+            chrono::Duration::milliseconds(self.interval_ms),
+        )
+    }
+}
+
+/// A query with its targets parsed and ad hoc filters compiled. Hidden and unset targets
+/// are left out
+#[derive(Debug, Clone)]
+pub struct TargetQuery {
+    pub targets: Vec<(Target, TargetType)>,
+    pub matchers: Vec<Matcher>,
+    pub aggregate: AggregateQuery,
+}
+
+impl TryFrom<&QueryRequest> for TargetQuery {
+    type Error = anyhow::Error;
+
+    fn try_from(request: &QueryRequest) -> Result<Self> {
+        Ok(Self {
+            targets: request
+                .targets
+                .iter()
+                .filter(|target| !target.hide)
+                .filter_map(|target| Some((target.target.as_deref()?, target.target_type)))
+                .filter(|(target, _)| !target.trim().is_empty())
+                .map(|(target, target_type)| Ok((target.parse()?, target_type)))
+                .collect::<Result<_>>()?,
+            matchers: request
+                .adhoc_filters
+                .iter()
+                .map(Matcher::try_from)
+                .collect::<Result<_>>()?,
+            aggregate: AggregateQuery {
+                bucket: Some(request.bucket()),
+                functions: AggregateFunctions::default(),
+                from: Some(request.range.from),
+                to: Some(request.range.to),
+            },
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Column {
+    pub text: String,
+    #[serde(rename = "type")]
+    pub column_type: &'static str,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+pub enum QueryResponse {
+    TimeSeries {
+        target: String,
+        /// Pairs of value and milliseconds since the epoch
+        datapoints: Vec<(f64, i64)>,
+    },
+    Table {
+        #[serde(rename = "type")]
+        table_type: &'static str,
+        columns: Vec<Column>,
+        rows: Vec<(i64, f64)>,
+    },
+}
+
+/// Reads every target as averages over buckets, so no series has more than
+/// `maxDataPoints` points. Targets the ad hoc filters rule out are left out of the response
+pub async fn query(storage: &dyn Storage, query: &TargetQuery) -> Result<Vec<QueryResponse>> {
+    let series = Series::find(storage, &query.matchers).await?;
+    let mut responses = Vec::new();
+    for &(target, target_type) in &query.targets {
+        let Some(series) = series.iter().find(|series| Target::from(*series) == target) else {
+            continue;
+        };
+        let points = Measurement::read_aggregate_by_device_id_and_sensor_id(
+            storage,
+            target.device_id,
+            target.sensor_id,
+            &query.aggregate,
+        )
+        .await?
+        .into_iter()
+        .filter_map(|bucket| Some((bucket.avg?, bucket.bucket.timestamp_millis())));
+        responses.push(match target_type {
+            TargetType::TimeSeries => QueryResponse::TimeSeries {
+                target: display_name(series),
+                datapoints: points.collect(),
+            },
+            TargetType::Table => QueryResponse::Table {
+                table_type: "table",
+                columns: vec![
+                    Column {
+                        text: "Time".to_string(),
+                        column_type: "time",
+                    },
+                    Column {
+                        text: display_name(series),
+                        column_type: "number",
+                    },
+                ],
+                rows: points.map(|(value, time)| (time, value)).collect(),
+            },
+        });
+    }
+    Ok(responses)
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TagKey {
+    #[serde(rename = "type")]
+    pub key_type: &'static str,
+    pub text: &'static str,
+}
+
+pub fn tag_keys() -> Vec<TagKey> {
+    TAG_KEYS
+        .iter()
+        .map(|&text| TagKey {
+            key_type: "string",
+            text,
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TagValuesRequest {
+    pub key: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TagValue {
+    pub text: String,
+}
+
+/// The distinct values of one of the `TAG_KEYS` over all series, sorted
+pub async fn tag_values(
+    storage: &dyn Storage,
+    request: &TagValuesRequest,
+) -> Result<Vec<TagValue>> {
+    let values: BTreeSet<String> = Series::find(storage, &[])
+        .await?
+        .iter()
+        .map(|series| label(series, &request.key).to_string())
+        .collect();
+    Ok(values.into_iter().map(|text| TagValue { text }).collect())
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct AnnotationQuery {
+    /// Only events of rules whose names contain it, case insensitively
+    #[serde(default)]
+    pub query: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct AnnotationRequest {
+    pub range: Range,
+    #[serde(default)]
+    pub annotation: AnnotationQuery,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Annotation {
+    /// Milliseconds since the epoch
+    pub time: i64,
+    pub title: String,
+    pub text: String,
+    pub tags: Vec<String>,
+}
+
+/// The alert events in the range, titled by their rules
+pub async fn annotations(pool: &PgPool, request: &AnnotationRequest) -> Result<Vec<Annotation>> {
+    let query = request
+        .annotation
+        .query
+        .as_deref()
+        .unwrap_or_default()
+        .to_lowercase();
+    let rules = AlertRule::read(pool).await?;
+    let events = AlertEvent::read_between(pool, request.range.from, request.range.to).await?;
+    Ok(events
+        .into_iter()
+        .filter_map(|event| {
+            let rule = rules.iter().find(|rule| rule.id == event.rule_id)?;
+            if !rule.name.to_lowercase().contains(&query) {
+                return None;
+            }
+            let state = match event.state {
+                AlertState::Firing => "firing",
+                AlertState::Resolved => "resolved",
+                AlertState::Ok => "ok",
+                AlertState::Pending => "pending",
+            };
+            let text = match event.value {
+                Some(value) => format!("{} {} at {}", rule.name, state, value),
+                None => format!("{} {}", rule.name, state),
+            };
+            Some(Annotation {
+                time: event.timestamp.timestamp_millis(),
+                title: rule.name.clone(),
+                text,
+                tags: vec!["alert".to_string(), state.to_string()],
+            })
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        alerts::{NewAlertRule, Operator},
+        devices::{Device, NewDevice},
+        measurements::NewMeasurement,
+        sensors::NewSensor,
+        storage::storage_tests,
+    };
+
+    #[test]
+    fn should_parse_targets() {
+        let target: Target = "3:14".parse().unwrap();
+        assert_eq!(
+            target,
+            Target {
+                device_id: 3,
+                sensor_id: 14
+            }
+        );
+        assert_eq!(target.to_string(), "3:14");
+        assert!("3".parse::<Target>().is_err());
+        assert!("esp32:temperature".parse::<Target>().is_err());
+    }
+
+    fn request(body: serde_json::Value) -> QueryRequest {
+        serde_json::from_value(body).unwrap()
+    }
+
+    async fn should_search_and_query_series(storage: Arc<dyn Storage>) {
+        for (name, location) in [("esp32", "Kitchen"), ("esp32", "Attic")] {
+            NewDevice::new(name.to_string(), location.to_string())
+                .insert(storage.as_ref())
+                .await
+                .unwrap();
+        }
+        for (name, unit) in [("temperature", "°C"), ("humidity", "%")] {
+            NewSensor::new(name.to_string(), unit.to_string())
+                .insert(storage.as_ref())
+                .await
+                .unwrap();
+        }
+        let start = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let measurement = |device, sensor, i: i64| {
+            let timestamp = start + chrono::Duration::seconds(i * 15);
+            NewMeasurement::new(Some(timestamp), device, sensor, i as f32)
+        };
+        let mut measurements: Vec<NewMeasurement> =
+            (0..130).map(|i| measurement(1, 1, i)).collect();
+        measurements.push(measurement(1, 2, 0));
+        measurements.push(measurement(2, 1, 0));
+        NewMeasurement::insert_many(&measurements, storage.as_ref())
+            .await
+            .unwrap();
+        Device::refresh_device_sensors_view(storage.as_ref())
+            .await
+            .unwrap();
+
+        let search_for = |target: &str| SearchRequest {
+            target: target.to_string(),
+        };
+        assert_eq!(
+            search(storage.as_ref(), &search_for(""))
+                .await
+                .unwrap()
+                .len(),
+            3
+        );
+        let results = search(storage.as_ref(), &search_for("ATTIC"))
+            .await
+            .unwrap();
+        assert_eq!(
+            results,
+            vec![SearchResult {
+                text: "esp32 (Attic) temperature".to_string(),
+                value: "2:1".to_string(),
+            }]
+        );
+
+        let range = json!({"from": start, "to": start + chrono::Duration::hours(1)});
+        let full = request(json!({
+            "range": range,
+            "intervalMs": 15_000,
+            "maxDataPoints": 1000,
+            "targets": [{"target": "1:1"}, {"target": "1:2", "hide": true}, {}],
+        }));
+        let responses = query(storage.as_ref(), &TargetQuery::try_from(&full).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(responses.len(), 1);
+        let QueryResponse::TimeSeries { target, datapoints } = &responses[0] else {
+            panic!("Expected a time series");
+        };
+        assert_eq!(target, "esp32 (Kitchen) temperature");
+        // Buckets are no shorter than the interval, so every point is kept
+        assert_eq!(datapoints.len(), 130);
+        assert_eq!(datapoints[5].0, 5.0);
+
+        let downsampled = request(json!({
+            "range": range,
+            "intervalMs": 15_000,
+            "maxDataPoints": 10,
+            "targets": [{"target": "1:1"}],
+        }));
+        let responses = query(
+            storage.as_ref(),
+            &TargetQuery::try_from(&downsampled).unwrap(),
+        )
+        .await
+        .unwrap();
+        let QueryResponse::TimeSeries { datapoints, .. } = &responses[0] else {
+            panic!("Expected a time series");
+        };
+        assert!(datapoints.len() > 1 && datapoints.len() <= 10);
+
+        let filtered = request(json!({
+            "range": range,
+            "targets": [{"target": "1:1"}, {"target": "2:1", "type": "table"}],
+            "adhocFilters": [{"key": "device_location", "operator": "=", "value": "Attic"}],
+        }));
+        let responses = query(storage.as_ref(), &TargetQuery::try_from(&filtered).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(responses.len(), 1);
+        let QueryResponse::Table { columns, rows, .. } = &responses[0] else {
+            panic!("Expected a table");
+        };
+        assert_eq!(columns[1].text, "esp32 (Attic) temperature");
+        assert_eq!(rows.len(), 1);
+
+        let invalid = request(json!({
+            "range": range,
+            "targets": [{"target": "1:1"}],
+            "adhocFilters": [{"key": "unit", "operator": "<", "value": "%"}],
+        }));
+        assert!(TargetQuery::try_from(&invalid).is_err());
+        let invalid = request(json!({"range": range, "targets": [{"target": "$sensor"}]}));
+        assert!(TargetQuery::try_from(&invalid).is_err());
+
+        let values = tag_values(
+            storage.as_ref(),
+            &TagValuesRequest {
+                key: "device_location".to_string(),
+            },
+        )
+        .await
+        .unwrap();
+        let values: Vec<_> = values.into_iter().map(|value| value.text).collect();
+        assert_eq!(values, vec!["Attic", "Kitchen"]);
+    }
+
+    storage_tests!(should_search_and_query_series);
+
+    #[sqlx::test]
+    async fn should_annotate_alert_events(pool: PgPool) {
+        NewDevice::new("esp32".to_string(), "Kitchen".to_string())
+            .insert(&pool)
+            .await
+            .unwrap();
+        NewSensor::new("co2".to_string(), "ppm".to_string())
+            .insert(&pool)
+            .await
+            .unwrap();
+        NewAlertRule {
+            name: "Kitchen CO2".to_string(),
+            device_id: 1,
+            sensor_id: 1,
+            operator: Operator::Above,
+            threshold: 1200.0,
+            for_seconds: 0,
+        }
+        .insert(&pool)
+        .await
+        .unwrap();
+        let rule = &AlertRule::read(&pool).await.unwrap()[0];
+        let transition = rule.evaluate(Some((1500.0, Utc::now())), Utc::now());
+        rule.apply(&pool, &transition.unwrap(), Some(1500.0))
+            .await
+            .unwrap();
+
+        let now = Utc::now();
+        let request = |query: &str| AnnotationRequest {
+            range: Range {
+                from: now - chrono::Duration::hours(1),
+                to: now,
+            },
+            annotation: AnnotationQuery {
+                query: Some(query.to_string()),
+            },
+        };
+        let found = annotations(&pool, &request("co2")).await.unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].title, "Kitchen CO2");
+        assert_eq!(found[0].text, "Kitchen CO2 firing at 1500");
+        assert_eq!(found[0].tags, vec!["alert", "firing"]);
+        assert!(annotations(&pool, &request("humidity"))
+            .await
+            .unwrap()
+            .is_empty());
+    }
+}
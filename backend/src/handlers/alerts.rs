@@ -0,0 +1,146 @@
+use axum::{
+    extract::{Path, State},
+    Json,
+};
+use sqlx::PgPool;
+use tracing::instrument;
+
+use crate::alerts::{AlertEvent, AlertRule, NewAlertRule};
+
+use super::error::HandlerError;
+
+#[instrument]
+pub async fn fetch_alerts(
+    State(pool): State<PgPool>,
+) -> Result<Json<Vec<AlertRule>>, HandlerError> {
+    let rules = AlertRule::read(&pool).await.map_err(HandlerError::from)?;
+    Ok(Json(rules))
+}
+
+#[instrument]
+pub async fn fetch_alert_by_id(
+    State(pool): State<PgPool>,
+    Path(alert_id): Path<i32>,
+) -> Result<Json<AlertRule>, HandlerError> {
+    let rule = AlertRule::read_by_id(&pool, alert_id)
+        .await
+        .map_err(HandlerError::from)?;
+    Ok(Json(rule))
+}
+
+#[instrument]
+pub async fn insert_alert(
+    State(pool): State<PgPool>,
+    Json(rule): Json<NewAlertRule>,
+) -> Result<String, HandlerError> {
+    if rule.name.is_empty() || rule.for_seconds < 0 {
+        return Err(HandlerError::BadRequest("Invalid input".to_string()));
+    }
+    rule.insert(&pool).await.map_err(HandlerError::from)?;
+    Ok("OK".to_string())
+}
+
+#[instrument]
+pub async fn delete_alert(
+    State(pool): State<PgPool>,
+    Json(rule): Json<AlertRule>,
+) -> Result<String, HandlerError> {
+    rule.delete(&pool).await.map_err(HandlerError::from)?;
+    Ok("OK".to_string())
+}
+
+#[instrument]
+pub async fn update_alert(
+    State(pool): State<PgPool>,
+    Json(rule): Json<AlertRule>,
+) -> Result<String, HandlerError> {
+    if rule.name.is_empty() || rule.for_seconds < 0 {
+        return Err(HandlerError::BadRequest("Invalid input".to_string()));
+    }
+    rule.update(&pool).await.map_err(HandlerError::from)?;
+    Ok("OK".to_string())
+}
+
+#[instrument]
+pub async fn fetch_alert_history(
+    State(pool): State<PgPool>,
+) -> Result<Json<Vec<AlertEvent>>, HandlerError> {
+    let events = AlertEvent::read(&pool).await.map_err(HandlerError::from)?;
+    Ok(Json(events))
+}
+
+#[instrument]
+pub async fn fetch_alert_history_by_id(
+    State(pool): State<PgPool>,
+    Path(alert_id): Path<i32>,
+) -> Result<Json<Vec<AlertEvent>>, HandlerError> {
+    let events = AlertEvent::read_by_rule_id(&pool, alert_id)
+        .await
+        .map_err(HandlerError::from)?;
+    Ok(Json(events))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{alerts::Operator, devices::NewDevice, sensors::NewSensor};
+    use axum::http::StatusCode;
+
+    fn new_rule(name: &str) -> NewAlertRule {
+        NewAlertRule {
+            name: name.to_string(),
+            device_id: 1,
+            sensor_id: 1,
+            operator: Operator::Below,
+            threshold: 16.0,
+            for_seconds: 600,
+        }
+    }
+
+    #[sqlx::test]
+    async fn should_insert_alert(pool: PgPool) {
+        let device = NewDevice::new("test".to_string(), "test".to_string());
+        device.insert(&pool).await.unwrap();
+        let sensor = NewSensor::new("test".to_string(), "test".to_string());
+        sensor.insert(&pool).await.unwrap();
+
+        let result = insert_alert(State(pool.clone()), Json(new_rule("cold"))).await;
+        assert!(result.is_ok());
+
+        let result = insert_alert(State(pool.clone()), Json(new_rule(""))).await;
+        assert_eq!(result.unwrap_err().status(), StatusCode::BAD_REQUEST);
+
+        let rules = fetch_alerts(State(pool)).await.unwrap().0;
+        assert_eq!(rules.len(), 1);
+        assert_eq!(rules[0].name, "cold");
+    }
+
+    #[sqlx::test]
+    async fn should_update_and_delete_alert(pool: PgPool) {
+        let device = NewDevice::new("test".to_string(), "test".to_string());
+        device.insert(&pool).await.unwrap();
+        let sensor = NewSensor::new("test".to_string(), "test".to_string());
+        sensor.insert(&pool).await.unwrap();
+        new_rule("cold").insert(&pool).await.unwrap();
+
+        let mut rule = AlertRule::read(&pool).await.unwrap()[0].clone();
+        rule.enabled = false;
+        let result = update_alert(State(pool.clone()), Json(rule.clone())).await;
+        assert!(result.is_ok());
+        let updated = fetch_alert_by_id(State(pool.clone()), Path(rule.id))
+            .await
+            .unwrap()
+            .0;
+        assert!(!updated.enabled);
+
+        let history = fetch_alert_history_by_id(State(pool.clone()), Path(rule.id))
+            .await
+            .unwrap()
+            .0;
+        assert!(history.is_empty());
+
+        let result = delete_alert(State(pool.clone()), Json(updated)).await;
+        assert!(result.is_ok());
+        assert!(fetch_alerts(State(pool)).await.unwrap().0.is_empty());
+    }
+}
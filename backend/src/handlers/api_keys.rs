@@ -0,0 +1,83 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, State},
+    Json,
+};
+use tracing::instrument;
+
+use crate::{
+    api_keys::{ApiKey, CreatedApiKey, NewApiKey},
+    storage::Storage,
+};
+
+use super::error::HandlerError;
+
+#[instrument]
+pub async fn fetch_api_keys(
+    State(storage): State<Arc<dyn Storage>>,
+) -> Result<Json<Vec<ApiKey>>, HandlerError> {
+    let keys = ApiKey::read(storage.as_ref())
+        .await
+        .map_err(HandlerError::from)?;
+    Ok(Json(keys))
+}
+
+/// Responds with the key itself, which cannot be read again
+#[instrument(skip(key))]
+pub async fn insert_api_key(
+    State(storage): State<Arc<dyn Storage>>,
+    Json(key): Json<NewApiKey>,
+) -> Result<Json<CreatedApiKey>, HandlerError> {
+    if key.name.is_empty() {
+        return Err(HandlerError::BadRequest("Invalid input".to_string()));
+    }
+    let created = key
+        .insert(storage.as_ref())
+        .await
+        .map_err(HandlerError::from)?;
+    Ok(Json(created))
+}
+
+#[instrument]
+pub async fn delete_api_key(
+    State(storage): State<Arc<dyn Storage>>,
+    Path(key_id): Path<i32>,
+) -> Result<String, HandlerError> {
+    ApiKey::delete(storage.as_ref(), key_id)
+        .await
+        .map_err(HandlerError::from)?;
+    Ok("OK".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+
+    use super::*;
+    use crate::{api_keys::Scope, storage::storage_tests};
+
+    async fn should_create_and_revoke_api_keys(storage: Arc<dyn Storage>) {
+        let key = NewApiKey {
+            name: "esp32".to_string(),
+            scope: Scope::Ingest,
+        };
+        let created = insert_api_key(State(storage.clone()), Json(key))
+            .await
+            .unwrap()
+            .0;
+
+        let keys = fetch_api_keys(State(storage.clone())).await.unwrap().0;
+        assert_eq!(keys.len(), 1);
+        assert_eq!(keys[0].scope, Scope::Ingest);
+
+        let id = created.api_key.id;
+        assert!(delete_api_key(State(storage.clone()), Path(id))
+            .await
+            .is_ok());
+        let result = delete_api_key(State(storage), Path(id)).await;
+        assert_eq!(result.unwrap_err().status(), StatusCode::NOT_FOUND);
+    }
+
+    storage_tests!(should_create_and_revoke_api_keys);
+}
@@ -0,0 +1,75 @@
+use std::{io, sync::Arc};
+
+use axum::{
+    body::Body,
+    extract::{Query, State},
+    http::header::{CONTENT_DISPOSITION, CONTENT_TYPE},
+    response::{IntoResponse, Response},
+};
+use tokio::sync::mpsc;
+use tracing::{instrument, warn};
+
+use crate::{
+    archive::{self, ArchiveQuery},
+    storage::Storage,
+};
+
+use super::error::HandlerError;
+
+/// Chunks buffered while the client is reading
+const CHUNK_BUFFER: usize = 16;
+
+/// Passes whatever the Parquet writer writes on to the response body. Blocks while the
+/// client is behind, so it must only be used on a blocking thread
+struct BodyWriter(mpsc::Sender<anyhow::Result<Vec<u8>>>);
+
+impl io::Write for BodyWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0
+            .blocking_send(Ok(buf.to_vec()))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "the client disconnected"))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Downloads measurements as a Parquet file, written while they are read
+#[instrument]
+pub async fn download_archive(
+    State(storage): State<Arc<dyn Storage>>,
+    Query(query): Query<ArchiveQuery>,
+) -> Result<Response, HandlerError> {
+    if let (Some(from), Some(to)) = (query.from, query.to) {
+        if from > to {
+            return Err(HandlerError::BadRequest(
+                "from must be before to".to_string(),
+            ));
+        }
+    }
+    let (tx, rx) = mpsc::channel(CHUNK_BUFFER);
+    let writer = BodyWriter(tx.clone());
+    tokio::spawn(async move {
+        if let Err(e) = archive::export(storage.as_ref(), &query, writer).await {
+            warn!("Failed to export archive: {}", e);
+            // Aborts the response, so the client does not take it as complete
+            let _ = tx.send(Err(e)).await;
+        }
+    });
+    let chunks = futures::stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|chunk| (chunk, rx))
+    });
+    Ok((
+        [
+            (CONTENT_TYPE, "application/vnd.apache.parquet"),
+            (
+                CONTENT_DISPOSITION,
+                "attachment; filename=\"measurements.parquet\"",
+            ),
+        ],
+        Body::from_stream(chunks),
+    )
+        .into_response())
+}
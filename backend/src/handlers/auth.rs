@@ -0,0 +1,179 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Request, State},
+    http::{header::AUTHORIZATION, Method},
+    middleware::Next,
+    response::Response,
+};
+
+use crate::{
+    api_keys::{ApiKey, Scope},
+    devices::{Device, DEVICE_TOKEN_PREFIX},
+    storage::Storage,
+};
+
+use super::error::HandlerError;
+
+/// Header API keys can be sent in, besides `Authorization: Bearer`
+const API_KEY_HEADER: &str = "x-api-key";
+
+/// Endpoints accepting measurements, including the InfluxDB and Prometheus write APIs
+const INGEST_PATHS: [&str; 6] = [
+    "/",
+    "/api/measurements",
+    "/api/write",
+    "/write",
+    "/api/v2/write",
+    "/api/prom/write",
+];
+
+/// Endpoints that only read, but take their query in a `POST` body
+const READ_PATHS: [&str; 6] = [
+    "/api/prom/read",
+    "/api/grafana/search",
+    "/api/grafana/query",
+    "/api/grafana/annotations",
+    "/api/grafana/tag-keys",
+    "/api/grafana/tag-values",
+];
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AuthOptions {
+    /// Require an API key on every request
+    pub enabled: bool,
+    /// Serve `/metrics` without an API key
+    pub public_metrics: bool,
+}
+
+/// The device a request was authenticated with a device token of, it may only post
+/// measurements of that device
+#[derive(Debug, Clone)]
+pub struct AuthenticatedDevice(pub Device);
+
+#[derive(Debug, Clone)]
+pub struct Auth {
+    pub storage: Arc<dyn Storage>,
+    pub options: AuthOptions,
+}
+
+/// The scope a request needs, `None` for public endpoints
+fn required_scope(options: &AuthOptions, method: &Method, path: &str) -> Option<Scope> {
+    if path == "/metrics" && options.public_metrics {
+        return None;
+    }
+    if path == "/api/keys" || path.starts_with("/api/keys/") {
+        return Some(Scope::Admin);
+    }
+    if method == Method::POST && INGEST_PATHS.contains(&path) {
+        return Some(Scope::Ingest);
+    }
+    if method == Method::GET || method == Method::HEAD || READ_PATHS.contains(&path) {
+        return Some(Scope::Read);
+    }
+    Some(Scope::Admin)
+}
+
+/// Unknown keys are rejected as unauthorized, other failures are passed on
+fn unauthorized(message: &str) -> impl FnOnce(anyhow::Error) -> HandlerError + '_ {
+    move |e| match e.downcast_ref::<sqlx::Error>() {
+        Some(sqlx::Error::RowNotFound) => HandlerError::Unauthorized(message.to_string()),
+        _ => HandlerError::from(e),
+    }
+}
+
+fn api_key(request: &Request) -> Option<&str> {
+    let headers = request.headers();
+    if let Some(key) = headers.get(API_KEY_HEADER) {
+        return key.to_str().ok();
+    }
+    headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        // InfluxDB clients send `Token`
+        .and_then(|value| {
+            value
+                .strip_prefix("Bearer ")
+                .or_else(|| value.strip_prefix("Token "))
+        })
+}
+
+/// Checks the API key of every request against the scope the endpoint needs. The key is
+/// added to the request extensions for handlers to use, as is the `AuthenticatedDevice` of
+/// device tokens, which are only accepted for posting measurements
+pub async fn authorize(
+    State(auth): State<Auth>,
+    mut request: Request,
+    next: Next,
+) -> Result<Response, HandlerError> {
+    if !auth.options.enabled {
+        return Ok(next.run(request).await);
+    }
+    let Some(required) = required_scope(&auth.options, request.method(), request.uri().path())
+    else {
+        return Ok(next.run(request).await);
+    };
+
+    let key = api_key(&request)
+        .ok_or_else(|| HandlerError::Unauthorized("Missing API key".to_string()))?
+        .to_string();
+    if key.starts_with(DEVICE_TOKEN_PREFIX) {
+        if required != Scope::Ingest {
+            return Err(HandlerError::Forbidden(
+                "Device tokens can only post measurements".to_string(),
+            ));
+        }
+        let device = Device::authenticate(auth.storage.as_ref(), &key)
+            .await
+            .map_err(unauthorized("Invalid device token"))?;
+        request.extensions_mut().insert(AuthenticatedDevice(device));
+        return Ok(next.run(request).await);
+    }
+
+    let api_key = ApiKey::authenticate(auth.storage.as_ref(), &key)
+        .await
+        .map_err(unauthorized("Invalid API key"))?;
+    if !api_key.scope.allows(required) {
+        return Err(HandlerError::Forbidden(format!(
+            "The {} scope is required",
+            required
+        )));
+    }
+    request.extensions_mut().insert(api_key);
+    Ok(next.run(request).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_require_scope_by_endpoint() {
+        let options = AuthOptions {
+            enabled: true,
+            public_metrics: false,
+        };
+        let scope = |method, path| required_scope(&options, &method, path);
+        assert_eq!(scope(Method::POST, "/"), Some(Scope::Ingest));
+        assert_eq!(
+            scope(Method::POST, "/api/measurements"),
+            Some(Scope::Ingest)
+        );
+        assert_eq!(scope(Method::POST, "/api/write"), Some(Scope::Ingest));
+        assert_eq!(scope(Method::POST, "/api/v2/write"), Some(Scope::Ingest));
+        assert_eq!(scope(Method::POST, "/api/prom/write"), Some(Scope::Ingest));
+        assert_eq!(scope(Method::POST, "/api/prom/read"), Some(Scope::Read));
+        assert_eq!(scope(Method::POST, "/api/grafana/query"), Some(Scope::Read));
+        assert_eq!(scope(Method::GET, "/api/measurements"), Some(Scope::Read));
+        assert_eq!(scope(Method::GET, "/metrics"), Some(Scope::Read));
+        assert_eq!(scope(Method::DELETE, "/api/devices"), Some(Scope::Admin));
+        assert_eq!(scope(Method::POST, "/api/sensors"), Some(Scope::Admin));
+        assert_eq!(scope(Method::GET, "/api/keys"), Some(Scope::Admin));
+
+        let options = AuthOptions {
+            public_metrics: true,
+            ..options
+        };
+        assert_eq!(required_scope(&options, &Method::GET, "/metrics"), None);
+    }
+}
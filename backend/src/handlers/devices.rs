@@ -1,99 +1,133 @@
+use std::sync::Arc;
+
 use axum::{
     extract::{Path, State},
     Json,
 };
-use sqlx::PgPool;
-use tracing::{instrument, warn};
+use tracing::instrument;
 
-use crate::devices::{Device, NewDevice};
+use crate::{
+    devices::{Device, DeviceToken, NewDevice},
+    provisioning::Resolver,
+    storage::Storage,
+};
 
 use super::error::HandlerError;
 
 #[instrument]
-pub async fn fetch_devices(State(pool): State<PgPool>) -> Result<Json<Vec<Device>>, HandlerError> {
-    let devices = Device::read(&pool).await.map_err(|e| {
-        warn!("Failed with error: {}", e);
-        HandlerError::new(500, format!("Failed to fetch data from database: {e}"))
-    })?;
+pub async fn fetch_devices(
+    State(storage): State<Arc<dyn Storage>>,
+) -> Result<Json<Vec<Device>>, HandlerError> {
+    let devices = Device::read(storage.as_ref())
+        .await
+        .map_err(HandlerError::from)?;
     Ok(Json(devices))
 }
 
 #[instrument]
 pub async fn fetch_devices_by_id(
-    State(pool): State<PgPool>,
+    State(storage): State<Arc<dyn Storage>>,
     Path(device_id): Path<i32>,
 ) -> Result<Json<Device>, HandlerError> {
-    let device = Device::read_by_id(&pool, device_id).await.map_err(|e| {
-        warn!("Failed with error: {}", e);
-        HandlerError::new(500, format!("Failed to fetch data from database: {e}"))
-    })?;
+    let device = Device::read_by_id(storage.as_ref(), device_id)
+        .await
+        .map_err(HandlerError::from)?;
     Ok(Json(device))
 }
 
 #[instrument]
 pub async fn insert_device(
-    State(pool): State<PgPool>,
+    State(storage): State<Arc<dyn Storage>>,
     Json(device): Json<NewDevice>,
 ) -> Result<String, HandlerError> {
     if device.name.is_empty() || device.location.is_empty() {
-        return Err(HandlerError::new(400, "Invalid input".to_string()));
+        return Err(HandlerError::BadRequest("Invalid input".to_string()));
     }
-    device.insert(&pool).await.map_err(|e| {
-        warn!("Failed with error: {}", e);
-        HandlerError::new(500, format!("Failed to store data in database: {e}"))
-    })?;
+    device
+        .insert(storage.as_ref())
+        .await
+        .map_err(HandlerError::from)?;
     Ok("OK".to_string())
 }
 
 #[instrument]
 pub async fn delete_device(
-    State(pool): State<PgPool>,
+    State((storage, resolver)): State<(Arc<dyn Storage>, Resolver)>,
     Json(device): Json<Device>,
 ) -> Result<String, HandlerError> {
     if device.name.is_empty() || device.location.is_empty() {
-        return Err(HandlerError::new(400, "Invalid input".to_string()));
+        return Err(HandlerError::BadRequest("Invalid input".to_string()));
     }
-    device.delete(&pool).await.map_err(|e| {
-        warn!("Failed with error: {}", e);
-        HandlerError::new(500, format!("Failed to store data in database: {e}"))
-    })?;
+    device
+        .delete(storage.as_ref())
+        .await
+        .map_err(HandlerError::from)?;
+    resolver.invalidate();
     Ok("OK".to_string())
 }
 
 #[instrument]
 pub async fn update_device(
-    State(pool): State<PgPool>,
+    State((storage, resolver)): State<(Arc<dyn Storage>, Resolver)>,
     Json(device): Json<Device>,
 ) -> Result<String, HandlerError> {
     if device.name.is_empty() || device.location.is_empty() {
-        return Err(HandlerError::new(400, "Invalid input".to_string()));
+        return Err(HandlerError::BadRequest("Invalid input".to_string()));
     }
-    device.update(&pool).await.map_err(|e| {
-        warn!("Failed with error: {}", e);
-        HandlerError::new(500, format!("Failed to store data in database: {e}"))
-    })?;
+    device
+        .update(storage.as_ref())
+        .await
+        .map_err(HandlerError::from)?;
+    resolver.invalidate();
     Ok("OK".to_string())
 }
 
-#[cfg(test)]
+/// Issues a new ingest token for the device, the previous one stops working
+#[instrument]
+pub async fn rotate_device_token(
+    State(storage): State<Arc<dyn Storage>>,
+    Path(device_id): Path<i32>,
+) -> Result<Json<DeviceToken>, HandlerError> {
+    let token = Device::rotate_token(storage.as_ref(), device_id)
+        .await
+        .map_err(HandlerError::from)?;
+    Ok(Json(token))
+}
+
+#[instrument]
+pub async fn revoke_device_token(
+    State(storage): State<Arc<dyn Storage>>,
+    Path(device_id): Path<i32>,
+) -> Result<String, HandlerError> {
+    Device::revoke_token(storage.as_ref(), device_id)
+        .await
+        .map_err(HandlerError::from)?;
+    Ok("OK".to_string())
+}
 
+#[cfg(test)]
 mod tests {
+    use axum::http::StatusCode;
+
     use super::*;
+    use crate::{
+        measurements::{NamedMeasurement, NewMeasurement},
+        sensors::NewSensor,
+        storage::storage_tests,
+    };
 
-    #[sqlx::test]
-    async fn should_insert_device(pool: PgPool) {
+    async fn should_insert_device(storage: Arc<dyn Storage>) {
         let device = NewDevice::new("test".to_string(), "test".to_string());
 
-        let result = insert_device(State(pool), Json(device)).await;
+        let result = insert_device(State(storage), Json(device)).await;
         assert!(result.is_ok());
     }
 
-    #[sqlx::test]
-    async fn should_fetch_devices(pool: PgPool) {
+    async fn should_fetch_devices(storage: Arc<dyn Storage>) {
         let device = NewDevice::new("test".to_string(), "test".to_string());
-        device.insert(&pool).await.unwrap();
+        device.insert(storage.as_ref()).await.unwrap();
 
-        let result = fetch_devices(State(pool)).await;
+        let result = fetch_devices(State(storage)).await;
         assert!(result.is_ok());
         let devices = result.unwrap().0;
         assert!(!devices.is_empty());
@@ -101,32 +135,124 @@ mod tests {
         assert_eq!(devices[0].location, "test");
     }
 
-    #[sqlx::test]
-    async fn should_delete_device(pool: PgPool) {
+    async fn should_delete_device(storage: Arc<dyn Storage>) {
         let device = NewDevice::new("test".to_string(), "test".to_string());
-        device.insert(&pool).await.unwrap();
+        device.insert(storage.as_ref()).await.unwrap();
 
-        let devices = Device::read(&pool).await.unwrap();
-        let result = delete_device(State(pool.clone()), Json(devices[0].clone())).await;
+        let devices = Device::read(storage.as_ref()).await.unwrap();
+        let resolver = Resolver::new(storage.clone(), false);
+        let result =
+            delete_device(State((storage.clone(), resolver)), Json(devices[0].clone())).await;
         assert!(result.is_ok());
 
-        let devices_after_delete = Device::read(&pool).await.unwrap();
+        let devices_after_delete = Device::read(storage.as_ref()).await.unwrap();
         assert!(devices_after_delete.is_empty());
     }
 
-    #[sqlx::test]
-    async fn should_update_device(pool: PgPool) {
+    async fn should_update_device(storage: Arc<dyn Storage>) {
         let device = NewDevice::new("test".to_string(), "test".to_string());
-        device.insert(&pool).await.unwrap();
+        device.insert(storage.as_ref()).await.unwrap();
 
-        let devices = Device::read(&pool).await.unwrap();
+        let devices = Device::read(storage.as_ref()).await.unwrap();
         let updated_device =
             Device::new(devices[0].id, "updated".to_string(), "updated".to_string());
-        let result = update_device(State(pool.clone()), Json(updated_device)).await;
+        let resolver = Resolver::new(storage.clone(), false);
+        let result = update_device(State((storage.clone(), resolver)), Json(updated_device)).await;
         assert!(result.is_ok());
 
-        let devices_after_update = Device::read(&pool).await.unwrap();
+        let devices_after_update = Device::read(storage.as_ref()).await.unwrap();
         assert_eq!(devices_after_update[0].name, "updated");
         assert_eq!(devices_after_update[0].location, "updated");
     }
+
+    async fn should_forget_resolved_names_of_deleted_device(storage: Arc<dyn Storage>) {
+        NewSensor::new("temperature".to_string(), "°C".to_string())
+            .insert(storage.as_ref())
+            .await
+            .unwrap();
+        let resolver = Resolver::new(storage.clone(), false);
+        let named = NamedMeasurement {
+            timestamp: None,
+            device: NewDevice::new("esp32".to_string(), "Kitchen".to_string()),
+            sensor: NewSensor::new("temperature".to_string(), "°C".to_string()),
+            measurement: 21.5,
+        };
+        NewDevice::new("esp32".to_string(), "Kitchen".to_string())
+            .insert(storage.as_ref())
+            .await
+            .unwrap();
+        resolver.resolve(vec![named.clone()], false).await.unwrap();
+
+        let device = Device::read_by_id(storage.as_ref(), 1).await.unwrap();
+        delete_device(State((storage.clone(), resolver.clone())), Json(device))
+            .await
+            .unwrap();
+        assert!(resolver.resolve(vec![named], false).await.is_err());
+    }
+
+    async fn should_not_find_missing_device(storage: Arc<dyn Storage>) {
+        let result = fetch_devices_by_id(State(storage), Path(42)).await;
+        assert_eq!(result.unwrap_err().status(), StatusCode::NOT_FOUND);
+    }
+
+    async fn should_conflict_when_deleting_device_with_measurements(storage: Arc<dyn Storage>) {
+        NewDevice::new("test".to_string(), "test".to_string())
+            .insert(storage.as_ref())
+            .await
+            .unwrap();
+        NewSensor::new("test".to_string(), "test".to_string())
+            .insert(storage.as_ref())
+            .await
+            .unwrap();
+        NewMeasurement::new(None, 1, 1, 1.0)
+            .insert(storage.as_ref())
+            .await
+            .unwrap();
+
+        let devices = Device::read(storage.as_ref()).await.unwrap();
+        let resolver = Resolver::new(storage.clone(), false);
+        let result =
+            delete_device(State((storage.clone(), resolver)), Json(devices[0].clone())).await;
+        let error = result.unwrap_err();
+        assert_eq!(error.status(), StatusCode::CONFLICT);
+        assert_eq!(error.code(), "conflict");
+        assert_eq!(Device::read(storage.as_ref()).await.unwrap().len(), 1);
+    }
+
+    async fn should_rotate_and_revoke_device_token(storage: Arc<dyn Storage>) {
+        NewDevice::new("test".to_string(), "test".to_string())
+            .insert(storage.as_ref())
+            .await
+            .unwrap();
+
+        let token = rotate_device_token(State(storage.clone()), Path(1))
+            .await
+            .unwrap()
+            .0;
+        assert_eq!(token.device_id, 1);
+        assert!(Device::authenticate(storage.as_ref(), &token.token)
+            .await
+            .is_ok());
+
+        assert!(revoke_device_token(State(storage.clone()), Path(1))
+            .await
+            .is_ok());
+        assert!(Device::authenticate(storage.as_ref(), &token.token)
+            .await
+            .is_err());
+
+        let result = rotate_device_token(State(storage), Path(42)).await;
+        assert_eq!(result.unwrap_err().status(), StatusCode::NOT_FOUND);
+    }
+
+    storage_tests!(
+        should_insert_device,
+        should_fetch_devices,
+        should_delete_device,
+        should_update_device,
+        should_forget_resolved_names_of_deleted_device,
+        should_not_find_missing_device,
+        should_conflict_when_deleting_device_with_measurements,
+        should_rotate_and_revoke_device_token
+    );
 }
@@ -1,29 +1,219 @@
 use std::{error::Error, fmt};
 
-use axum::{http::StatusCode, response::IntoResponse};
+use axum::{
+    body::to_bytes,
+    extract::Request,
+    http::{
+        header::{CONTENT_TYPE, WWW_AUTHENTICATE},
+        HeaderValue, StatusCode,
+    },
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::error::ErrorKind;
+use tracing::warn;
 
-#[derive(Debug, Clone)]
-pub struct HandlerError {
-    pub status: u16,
+/// Header carrying the id of a request, generated unless the client sets it
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Error bodies of rejected requests are small, anything larger is cut off
+const MAX_REJECTION_SIZE: usize = 64 * 1024;
+
+tokio::task_local! {
+    static REQUEST_ID: Option<String>;
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum HandlerError {
+    /// Malformed or invalid input
+    BadRequest(String),
+    /// Missing or unknown API key
+    Unauthorized(String),
+    /// The API key lacks the scope needed
+    Forbidden(String),
+    NotFound(String),
+    /// Conflicts with stored data, like a duplicate name or a device that still has measurements
+    Conflict(String),
+    /// Well-formed, but rejected by the database
+    Unprocessable(String),
+    Internal(String),
+}
+
+/// Body of every error response
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ErrorBody {
+    pub code: String,
     pub message: String,
+    pub request_id: Option<String>,
 }
 
 impl HandlerError {
-    pub fn new(status: u16, message: String) -> Self {
-        Self { status, message }
+    pub fn status(&self) -> StatusCode {
+        match self {
+            HandlerError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            HandlerError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            HandlerError::Forbidden(_) => StatusCode::FORBIDDEN,
+            HandlerError::NotFound(_) => StatusCode::NOT_FOUND,
+            HandlerError::Conflict(_) => StatusCode::CONFLICT,
+            HandlerError::Unprocessable(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            HandlerError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Machine-readable code, stable across versions
+    pub fn code(&self) -> &'static str {
+        match self {
+            HandlerError::BadRequest(_) => "bad_request",
+            HandlerError::Unauthorized(_) => "unauthorized",
+            HandlerError::Forbidden(_) => "forbidden",
+            HandlerError::NotFound(_) => "not_found",
+            HandlerError::Conflict(_) => "conflict",
+            HandlerError::Unprocessable(_) => "unprocessable",
+            HandlerError::Internal(_) => "internal",
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        match self {
+            HandlerError::BadRequest(message)
+            | HandlerError::Unauthorized(message)
+            | HandlerError::Forbidden(message)
+            | HandlerError::NotFound(message)
+            | HandlerError::Conflict(message)
+            | HandlerError::Unprocessable(message)
+            | HandlerError::Internal(message) => message,
+        }
     }
 }
 
 impl fmt::Display for HandlerError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "Error {}: {}", self.status, self.message)
+        write!(f, "Error {}: {}", self.status().as_u16(), self.message())
     }
 }
 
 impl Error for HandlerError {}
 
+/// Maps storage errors by what went wrong in the database
+impl From<anyhow::Error> for HandlerError {
+    fn from(e: anyhow::Error) -> Self {
+        warn!("Failed with error: {}", e);
+        match e.downcast_ref::<sqlx::Error>() {
+            Some(sqlx::Error::RowNotFound) => HandlerError::NotFound("Not found".to_string()),
+            Some(sqlx::Error::Database(db)) => match db.kind() {
+                ErrorKind::UniqueViolation | ErrorKind::ForeignKeyViolation => {
+                    HandlerError::Conflict(db.message().to_string())
+                }
+                ErrorKind::NotNullViolation | ErrorKind::CheckViolation => {
+                    HandlerError::Unprocessable(db.message().to_string())
+                }
+                // Data exceptions, like a value out of range
+                _ if db.code().is_some_and(|code| code.starts_with("22")) => {
+                    HandlerError::Unprocessable(db.message().to_string())
+                }
+                _ => HandlerError::Internal(format!("Database error: {e}")),
+            },
+            _ => HandlerError::Internal(format!("Database error: {e}")),
+        }
+    }
+}
+
 impl IntoResponse for HandlerError {
-    fn into_response(self) -> axum::response::Response {
-        (StatusCode::from_u16(self.status).unwrap(), self.message).into_response()
+    fn into_response(self) -> Response {
+        let body = ErrorBody {
+            code: self.code().to_string(),
+            message: self.message().to_string(),
+            request_id: REQUEST_ID.try_with(Clone::clone).ok().flatten(),
+        };
+        let mut response = (self.status(), Json(body)).into_response();
+        if let HandlerError::Unauthorized(_) = self {
+            response
+                .headers_mut()
+                .insert(WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+        }
+        response
+    }
+}
+
+fn from_status(status: StatusCode, message: String) -> HandlerError {
+    match status {
+        StatusCode::UNAUTHORIZED => HandlerError::Unauthorized(message),
+        StatusCode::FORBIDDEN => HandlerError::Forbidden(message),
+        StatusCode::NOT_FOUND => HandlerError::NotFound(message),
+        StatusCode::CONFLICT => HandlerError::Conflict(message),
+        StatusCode::UNPROCESSABLE_ENTITY => HandlerError::Unprocessable(message),
+        status if status.is_client_error() => HandlerError::BadRequest(message),
+        _ => HandlerError::Internal(message),
+    }
+}
+
+/// Makes the request id available to error responses, and turns the plain-text errors of
+/// rejected requests, such as unknown routes or unparsable bodies, into JSON errors
+pub async fn json_errors(request: Request, next: Next) -> Response {
+    let request_id = request
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|id| id.to_str().ok())
+        .map(str::to_string);
+    REQUEST_ID
+        .scope(request_id, async move {
+            let response = next.run(request).await;
+            let status = response.status();
+            let is_json = response
+                .headers()
+                .get(CONTENT_TYPE)
+                .is_some_and(|content_type| content_type == "application/json");
+            if !(status.is_client_error() || status.is_server_error()) || is_json {
+                return response;
+            }
+
+            let (parts, body) = response.into_parts();
+            let message = match to_bytes(body, MAX_REJECTION_SIZE).await {
+                Ok(bytes) if !bytes.is_empty() => String::from_utf8_lossy(&bytes).into_owned(),
+                _ => status.canonical_reason().unwrap_or_default().to_string(),
+            };
+            let mut response = from_status(status, message).into_response();
+            *response.status_mut() = status;
+            for (name, value) in parts.headers.iter() {
+                if name != CONTENT_TYPE && name != "content-length" {
+                    response.headers_mut().insert(name, value.clone());
+                }
+            }
+            response
+        })
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_map_database_errors() {
+        let not_found = HandlerError::from(anyhow::Error::from(sqlx::Error::RowNotFound));
+        assert_eq!(not_found.status(), StatusCode::NOT_FOUND);
+        assert_eq!(not_found.code(), "not_found");
+
+        let other = HandlerError::from(anyhow::anyhow!("connection refused"));
+        assert_eq!(other.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[tokio::test]
+    async fn should_render_json_with_request_id() {
+        let response = REQUEST_ID
+            .scope(Some("abc".to_string()), async {
+                HandlerError::Conflict("taken".to_string()).into_response()
+            })
+            .await;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        let body = to_bytes(response.into_body(), MAX_REJECTION_SIZE)
+            .await
+            .unwrap();
+        let body: ErrorBody = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body.code, "conflict");
+        assert_eq!(body.message, "taken");
+        assert_eq!(body.request_id.as_deref(), Some("abc"));
     }
 }
@@ -0,0 +1,83 @@
+use std::sync::Arc;
+
+use axum::{extract::State, http::StatusCode, Json};
+use sqlx::PgPool;
+use tracing::instrument;
+
+use crate::{
+    grafana::{
+        self, Annotation, AnnotationRequest, QueryRequest, QueryResponse, SearchRequest,
+        SearchResult, TagKey, TagValue, TagValuesRequest, TargetQuery, TAG_KEYS,
+    },
+    storage::Storage,
+};
+
+use super::error::HandlerError;
+
+/// Answers the connection test of the Grafana JSON datasource
+#[instrument]
+pub async fn test_grafana() -> StatusCode {
+    StatusCode::OK
+}
+
+#[instrument]
+pub async fn search_grafana(
+    State((storage, _)): State<(Arc<dyn Storage>, Option<PgPool>)>,
+    request: Option<Json<SearchRequest>>,
+) -> Result<Json<Vec<SearchResult>>, HandlerError> {
+    let request = request.map(|Json(request)| request).unwrap_or_default();
+    let results = grafana::search(storage.as_ref(), &request)
+        .await
+        .map_err(HandlerError::from)?;
+    Ok(Json(results))
+}
+
+#[instrument]
+pub async fn query_grafana(
+    State((storage, _)): State<(Arc<dyn Storage>, Option<PgPool>)>,
+    Json(request): Json<QueryRequest>,
+) -> Result<Json<Vec<QueryResponse>>, HandlerError> {
+    let query =
+        TargetQuery::try_from(&request).map_err(|e| HandlerError::BadRequest(e.to_string()))?;
+    let responses = grafana::query(storage.as_ref(), &query)
+        .await
+        .map_err(HandlerError::from)?;
+    Ok(Json(responses))
+}
+
+/// Alert events, there are none without Postgres
+#[instrument]
+pub async fn fetch_grafana_annotations(
+    State((_, postgres)): State<(Arc<dyn Storage>, Option<PgPool>)>,
+    Json(request): Json<AnnotationRequest>,
+) -> Result<Json<Vec<Annotation>>, HandlerError> {
+    let Some(pool) = postgres else {
+        return Ok(Json(Vec::new()));
+    };
+    let annotations = grafana::annotations(&pool, &request)
+        .await
+        .map_err(HandlerError::from)?;
+    Ok(Json(annotations))
+}
+
+#[instrument]
+pub async fn fetch_grafana_tag_keys() -> Json<Vec<TagKey>> {
+    Json(grafana::tag_keys())
+}
+
+#[instrument]
+pub async fn fetch_grafana_tag_values(
+    State((storage, _)): State<(Arc<dyn Storage>, Option<PgPool>)>,
+    Json(request): Json<TagValuesRequest>,
+) -> Result<Json<Vec<TagValue>>, HandlerError> {
+    if !TAG_KEYS.contains(&request.key.as_str()) {
+        return Err(HandlerError::BadRequest(format!(
+            "Unknown tag key {}",
+            request.key
+        )));
+    }
+    let values = grafana::tag_values(storage.as_ref(), &request)
+        .await
+        .map_err(HandlerError::from)?;
+    Ok(Json(values))
+}
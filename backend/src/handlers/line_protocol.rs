@@ -0,0 +1,180 @@
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    Extension,
+};
+use serde::Deserialize;
+use tracing::instrument;
+
+use crate::{
+    line_protocol::{lines, Point, Precision},
+    provisioning::Unresolved,
+};
+
+use super::{
+    auth::AuthenticatedDevice,
+    error::HandlerError,
+    measurements::{check_named_devices, queue_measurements, Ingest},
+};
+
+/// Rejected lines listed in an error message, the rest are only counted
+const MAX_REPORTED_LINES: usize = 10;
+
+/// Query parameters of the InfluxDB write APIs. `db`, `org` and `bucket` are accepted, but
+/// ignored
+#[derive(Debug, Default, Deserialize)]
+pub struct WriteQuery {
+    #[serde(default)]
+    pub precision: Precision,
+}
+
+/// Accepts InfluxDB line protocol, like the InfluxDB v1 and v2 write APIs. As there, valid
+/// points are written even if some lines are rejected, which fails the request with a
+/// `partial write` message listing them and the number of `dropped` points
+#[instrument(skip(body))]
+pub async fn write_line_protocol(
+    State(ingest): State<Ingest>,
+    Query(query): Query<WriteQuery>,
+    device: Option<Extension<AuthenticatedDevice>>,
+    body: String,
+) -> Result<StatusCode, HandlerError> {
+    let device = device.map(|Extension(device)| device);
+    // A device token can not create devices or sensors
+    let create = device.is_none();
+    let mut measurements = Vec::new();
+    let mut rejected = Vec::new();
+    for line in lines(&body) {
+        let named = match Point::parse(line, query.precision).and_then(Point::into_named) {
+            Ok(named) => named,
+            Err(reason) => {
+                rejected.push(format!("unable to parse '{}': {}", line, reason));
+                continue;
+            }
+        };
+        check_named_devices(device.as_ref(), &named)?;
+        match ingest.resolver.resolve(named, create).await {
+            Ok(resolved) => measurements.extend(resolved),
+            Err(e) => match e.downcast_ref::<Unresolved>() {
+                Some(unresolved) => {
+                    rejected.push(format!("unable to write '{}': {}", line, unresolved))
+                }
+                None => return Err(HandlerError::from(e)),
+            },
+        }
+    }
+    let written = !measurements.is_empty();
+    if written {
+        queue_measurements(&ingest, device, measurements).await?;
+    }
+    if rejected.is_empty() {
+        return Ok(StatusCode::NO_CONTENT);
+    }
+    let dropped = rejected.len();
+    rejected.truncate(MAX_REPORTED_LINES);
+    Err(HandlerError::BadRequest(format!(
+        "{}{} dropped={}",
+        if written { "partial write: " } else { "" },
+        rejected.join("; "),
+        dropped
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::sync::mpsc::{self, Receiver, Sender};
+
+    use super::*;
+    use crate::{
+        devices::{Device, NewDevice},
+        measurements::NewMeasurement,
+        prometheus::PrometheusLabels,
+        provisioning::Resolver,
+        queue::MeasurementQueue,
+        sensors::Sensor,
+        storage::{storage_tests, Storage},
+    };
+
+    async fn should_write_valid_lines_and_report_dropped_ones(storage: Arc<dyn Storage>) {
+        let (tx, mut rx): (Sender<NewMeasurement>, Receiver<NewMeasurement>) = mpsc::channel(100);
+        let ingest = Ingest {
+            queue: MeasurementQueue::new(tx, None),
+            resolver: Resolver::new(storage.clone(), true),
+            prometheus: PrometheusLabels::default(),
+        };
+
+        let body = "temperature,device=esp32,location=Attic value=21.5 1700000000\n\
+                    climate,device=esp32,location=Attic humidity=40i 1700000000";
+        let query = WriteQuery {
+            precision: Precision::Seconds,
+        };
+        let status =
+            write_line_protocol(State(ingest.clone()), Query(query), None, body.to_string())
+                .await
+                .unwrap();
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let first = rx.recv().await.unwrap();
+        assert_eq!(first.measurement, 21.5);
+        assert_eq!(first.timestamp.unwrap().timestamp(), 1_700_000_000);
+        assert_eq!(rx.recv().await.unwrap().measurement, 40.0);
+
+        let body = "temperature,device=esp32,location=Attic value=22\n\
+                    temperature,device=esp32 value=23\n\
+                    temperature value=";
+        let error = write_line_protocol(
+            State(ingest.clone()),
+            Query(WriteQuery::default()),
+            None,
+            body.to_string(),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(error.status(), StatusCode::BAD_REQUEST);
+        assert!(error.message().starts_with("partial write: "));
+        assert!(error.message().ends_with("dropped=2"));
+        assert_eq!(rx.recv().await.unwrap().measurement, 22.0);
+
+        // Device tokens can only name their own device, whether others exist or not
+        let device = NewDevice::new("esp32-2".to_string(), "Attic".to_string());
+        device.insert(storage.as_ref()).await.unwrap();
+        let token = || {
+            Some(Extension(AuthenticatedDevice(Device::new(
+                2,
+                "esp32-2".to_string(),
+                "Attic".to_string(),
+            ))))
+        };
+        for line in [
+            "temperature,device=esp32-3,location=Attic value=1",
+            "temperature,device=esp32,location=Attic value=1",
+        ] {
+            let error = write_line_protocol(
+                State(ingest.clone()),
+                Query(WriteQuery::default()),
+                token(),
+                line.to_string(),
+            )
+            .await
+            .unwrap_err();
+            assert_eq!(error.status(), StatusCode::FORBIDDEN);
+        }
+
+        // Nor create sensors
+        let error = write_line_protocol(
+            State(ingest),
+            Query(WriteQuery::default()),
+            token(),
+            "pressure,device=esp32-2,location=Attic value=1013".to_string(),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(error.status(), StatusCode::BAD_REQUEST);
+        assert!(Sensor::read_by_name(storage.as_ref(), "pressure")
+            .await
+            .is_err());
+        assert!(rx.try_recv().is_err(), "Nothing should be queued");
+    }
+
+    storage_tests!(should_write_valid_lines_and_report_dropped_ones);
+}
@@ -1,56 +1,150 @@
+use std::{convert::Infallible, sync::Arc};
+
 use axum::{
-    extract::{Path, State},
-    response::{IntoResponse, Response},
-    Json,
+    body::Body,
+    extract::{Path, Query, State},
+    http::{
+        header::{ACCEPT, CONTENT_TYPE},
+        HeaderMap, HeaderValue,
+    },
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+    Extension, Json,
 };
+use futures::{Stream, StreamExt};
 use moka::future::Cache;
-use sqlx::PgPool;
-use tokio::sync::mpsc::Sender;
+use tokio::sync::{
+    broadcast::{self, error::RecvError},
+    mpsc,
+};
 use tracing::{instrument, warn};
 
-use crate::measurements::{Measurement, MeasurementStats, NewMeasurement, NewMeasurements};
+use crate::{
+    export::{Format, FormatQuery},
+    measurements::{
+        AggregateQuery, Measurement, MeasurementBucket, MeasurementEvent, MeasurementPage,
+        MeasurementQuery, MeasurementStats, NamedMeasurement, NewMeasurement, NewMeasurements,
+        StatsQuery, StreamQuery,
+    },
+    prometheus::PrometheusLabels,
+    provisioning::{Resolver, Unresolved},
+    queue::MeasurementQueue,
+    storage::Storage,
+};
+
+use super::{auth::AuthenticatedDevice, error::HandlerError};
+
+type ApplicationState = State<(Arc<dyn Storage>, Cache<(i32, i32), Measurement>)>;
+
+/// Everything needed to accept measurements
+#[derive(Debug, Clone)]
+pub struct Ingest {
+    pub queue: MeasurementQueue,
+    pub resolver: Resolver,
+    /// Labels Prometheus remote write series are mapped by
+    pub prometheus: PrometheusLabels,
+}
+
+/// Queues measurements, unless they were posted with the token of another device
+pub(super) async fn queue_measurements(
+    ingest: &Ingest,
+    device: Option<AuthenticatedDevice>,
+    measurements: Vec<NewMeasurement>,
+) -> Result<(), HandlerError> {
+    // Device tokens only cover measurements of their own device
+    if let Some(AuthenticatedDevice(device)) = device {
+        if let Some(measurement) = measurements.iter().find(|m| m.device != device.id) {
+            return Err(HandlerError::Forbidden(format!(
+                "The token of device {} cannot post measurements of device {}",
+                device.id, measurement.device
+            )));
+        }
+    }
+    ingest.queue.send(measurements).await.map_err(|e| {
+        warn!("Failed with error: {}", e);
+        HandlerError::Internal(format!(
+            "Failed to send measurement to background thread: {e}"
+        ))
+    })
+}
 
-use super::error::HandlerError;
+/// Rejects measurements posted by name with the token of another device. Checked before any
+/// name is looked up, so the answer does not tell whether the other device exists
+pub(super) fn check_named_devices(
+    device: Option<&AuthenticatedDevice>,
+    named: &[NamedMeasurement],
+) -> Result<(), HandlerError> {
+    let Some(AuthenticatedDevice(device)) = device else {
+        return Ok(());
+    };
+    let foreign = named
+        .iter()
+        .find(|m| m.device.name != device.name || m.device.location != device.location);
+    match foreign {
+        Some(measurement) => Err(HandlerError::Forbidden(format!(
+            "The token of device {} cannot post measurements of device {} in {}",
+            device.id, measurement.device.name, measurement.device.location
+        ))),
+        None => Ok(()),
+    }
+}
 
-type ApplicationState = State<(PgPool, Cache<(i32, i32), Measurement>)>;
+/// Looks up the ids of measurements posted by name
+async fn resolve(
+    resolver: &Resolver,
+    device: Option<&AuthenticatedDevice>,
+    named: Vec<NamedMeasurement>,
+) -> Result<Vec<NewMeasurement>, HandlerError> {
+    let invalid = named.iter().any(|m| {
+        m.device.name.is_empty()
+            || m.device.location.is_empty()
+            || m.sensor.name.is_empty()
+            || m.sensor.unit.is_empty()
+    });
+    if invalid {
+        return Err(HandlerError::BadRequest("Invalid input".to_string()));
+    }
+    check_named_devices(device, &named)?;
+    // A device token can not create devices or sensors
+    resolver
+        .resolve(named, device.is_none())
+        .await
+        .map_err(|e| match e.downcast_ref::<Unresolved>() {
+            Some(unresolved) => HandlerError::Unprocessable(unresolved.to_string()),
+            None => HandlerError::from(e),
+        })
+}
 
 #[instrument]
 pub async fn store_measurements(
-    State(tx): State<Sender<NewMeasurement>>,
+    State(ingest): State<Ingest>,
+    device: Option<Extension<AuthenticatedDevice>>,
     Json(measurement): Json<NewMeasurements>,
 ) -> Result<Response, HandlerError>
 where
     Response: IntoResponse,
 {
-    match measurement {
-        NewMeasurements::Measurement(new_measurement) => {
-            tx.send(new_measurement).await.map_err(|e| {
-                warn!("Failed with error: {}", e);
-                HandlerError::new(
-                    500,
-                    format!("Failed to send measurement to background thread: {e}"),
-                )
-            })?;
+    let device = device.map(|Extension(device)| device);
+    let measurements = match measurement {
+        NewMeasurements::Measurement(new_measurement) => vec![new_measurement],
+        NewMeasurements::Measurements(new_measurements) => new_measurements,
+        NewMeasurements::NamedMeasurement(named) => {
+            resolve(&ingest.resolver, device.as_ref(), vec![named]).await?
         }
-        NewMeasurements::Measurements(new_measurements) => {
-            for measurement in new_measurements {
-                tx.send(measurement).await.map_err(|e| {
-                    warn!("Failed with error: {}", e);
-                    HandlerError::new(
-                        500,
-                        format!("Failed to send measurement to background thread: {e}"),
-                    )
-                })?;
-            }
+        NewMeasurements::NamedMeasurements(named) => {
+            resolve(&ingest.resolver, device.as_ref(), named).await?
         }
     };
+    queue_measurements(&ingest, device, measurements).await?;
 
     let resp = Response::builder()
         .status(201)
         .body("Measurement(s) inserted successfully".into())
         .map_err(|e| {
             warn!("Failed with error: {}", e);
-            HandlerError::new(500, format!("Failed to build response: {e}"))
+            HandlerError::Internal(format!("Failed to build response: {e}"))
         })?;
 
     Ok(resp)
@@ -60,12 +154,11 @@ where
 pub async fn fetch_latest_measurement(
     State(app_state): ApplicationState,
 ) -> Result<Json<Measurement>, HandlerError> {
-    let (pool, _cache) = app_state;
+    let (storage, _cache) = app_state;
 
-    let entry = Measurement::read_latest(&pool).await.map_err(|e| {
-        warn!("Failed with error: {}", e);
-        HandlerError::new(500, format!("Failed to fetch data from database: {e}"))
-    })?;
+    let entry = Measurement::read_latest(storage.as_ref())
+        .await
+        .map_err(HandlerError::from)?;
 
     Ok(Json(entry))
 }
@@ -74,42 +167,140 @@ pub async fn fetch_latest_measurement(
 pub async fn fetch_measurements_count(
     State(app_state): ApplicationState,
 ) -> Result<Json<usize>, HandlerError> {
-    let (pool, _cache) = app_state;
-    let count = Measurement::read_total_measurements(&pool)
+    let (storage, _cache) = app_state;
+    let count = Measurement::read_total_measurements(storage.as_ref())
         .await
-        .map_err(|e| {
-            warn!("Failed with error: {}", e);
-            HandlerError::new(500, format!("Failed to fetch data from database: {e}"))
-        })?;
+        .map_err(HandlerError::from)?;
     Ok(Json(count as usize))
 }
 
+/// Header carrying the cursor for the next page of a measurement list
+pub const NEXT_CURSOR_HEADER: &str = "x-next-cursor";
+
+/// Rows buffered between the database and the client during an export
+const EXPORT_BUFFER: usize = 1024;
+
+/// Rows written to the response at once, if they are ready
+const EXPORT_BATCH: usize = 256;
+
+fn validate_query(query: &MeasurementQuery) -> Result<(), HandlerError> {
+    if query.limit.is_some_and(|limit| limit <= 0) {
+        return Err(HandlerError::BadRequest(
+            "limit must be positive".to_string(),
+        ));
+    }
+    if let (Some(from), Some(to)) = (query.from, query.to) {
+        if from > to {
+            return Err(HandlerError::BadRequest(
+                "from must be before to".to_string(),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Pages are returned as `{"measurements": [...], "next": ...}` when paginating with `limit`
+/// or `cursor`, otherwise as a bare list, as before pagination
+fn page_response(page: MeasurementPage, query: &MeasurementQuery) -> Response {
+    let next = page.next;
+    let mut response = if query.limit.is_some() || query.cursor.is_some() {
+        Json(page).into_response()
+    } else {
+        Json(page.measurements).into_response()
+    };
+    if let Some(next) = next {
+        if let Ok(value) = HeaderValue::from_str(&next.to_string()) {
+            response.headers_mut().insert(NEXT_CURSOR_HEADER, value);
+        }
+    }
+    response
+}
+
+fn accept(headers: &HeaderMap) -> Option<&str> {
+    headers.get(ACCEPT).and_then(|accept| accept.to_str().ok())
+}
+
+/// Streams every measurement matching `query` as CSV or NDJSON, rows are sent as they are
+/// read from the database. The limit and cursor still apply, but no next cursor is returned
+fn export_response(
+    storage: Arc<dyn Storage>,
+    device_id: Option<i32>,
+    sensor_id: Option<i32>,
+    query: MeasurementQuery,
+    format: Format,
+) -> Response {
+    let (tx, rx) = mpsc::channel(EXPORT_BUFFER);
+    tokio::spawn(async move {
+        let streamed =
+            Measurement::stream(storage.as_ref(), device_id, sensor_id, &query, &tx).await;
+        if let Err(e) = streamed {
+            warn!("Failed to export measurements: {}", e);
+            // Aborts the response, so the client does not take it as complete
+            let _ = tx.send(Err(e)).await;
+        }
+    });
+    let header = futures::stream::iter(format.header().map(|header| Ok(header.to_string())));
+    let rows = futures::stream::unfold(
+        rx,
+        |mut rx| async move { rx.recv().await.map(|row| (row, rx)) },
+    )
+    .ready_chunks(EXPORT_BATCH)
+    .map(move |rows| {
+        rows.into_iter()
+            .map(|row| row.and_then(|measurement| format.encode(&measurement)))
+            .collect::<anyhow::Result<String>>()
+    });
+    (
+        [(CONTENT_TYPE, format.content_type())],
+        Body::from_stream(header.chain(rows)),
+    )
+        .into_response()
+}
+
 #[instrument]
 pub async fn fetch_all_measurements(
     State(app_state): ApplicationState,
-) -> Result<Json<Vec<Measurement>>, HandlerError> {
-    let (pool, _cache) = app_state;
-    let entries = Measurement::read_all(&pool).await.map_err(|e| {
-        warn!("Failed with error: {}", e);
-        HandlerError::new(500, format!("Failed to fetch data from database: {e}"))
-    })?;
+    Query(query): Query<MeasurementQuery>,
+    Query(format): Query<FormatQuery>,
+    headers: HeaderMap,
+) -> Result<Response, HandlerError> {
+    let (storage, _cache) = app_state;
+    validate_query(&query)?;
+    let format = format.negotiate(accept(&headers));
+    if format != Format::Json {
+        return Ok(export_response(storage, None, None, query, format));
+    }
+    let page = Measurement::read_all(storage.as_ref(), &query)
+        .await
+        .map_err(HandlerError::from)?;
 
-    Ok(Json(entries))
+    Ok(page_response(page, &query))
 }
 
 #[instrument]
 pub async fn fetch_measurement_by_device_id(
     State(app_state): ApplicationState,
     Path(device_id): Path<i32>,
-) -> Result<Json<Vec<Measurement>>, HandlerError> {
-    let (pool, _cache) = app_state;
-    let measurements = Measurement::read_by_device_id(device_id, &pool)
+    Query(query): Query<MeasurementQuery>,
+    Query(format): Query<FormatQuery>,
+    headers: HeaderMap,
+) -> Result<Response, HandlerError> {
+    let (storage, _cache) = app_state;
+    validate_query(&query)?;
+    let format = format.negotiate(accept(&headers));
+    if format != Format::Json {
+        return Ok(export_response(
+            storage,
+            Some(device_id),
+            None,
+            query,
+            format,
+        ));
+    }
+    let page = Measurement::read_by_device_id(device_id, &query, storage.as_ref())
         .await
-        .map_err(|e| {
-            warn!("Failed with error: {}", e);
-            HandlerError::new(500, format!("Failed to fetch data from database: {e}"))
-        })?;
-    Ok(Json(measurements))
+        .map_err(HandlerError::from)?;
+    Ok(page_response(page, &query))
 }
 
 #[instrument]
@@ -117,18 +308,15 @@ pub async fn fetch_latest_measurement_by_device_id_and_sensor_id(
     State(app_state): ApplicationState,
     Path((device_id, sensor_id)): Path<(i32, i32)>,
 ) -> Result<Json<Measurement>, HandlerError> {
-    let (pool, cache) = app_state;
+    let (storage, cache) = app_state;
     // Check cache first
     if let Some(measurement) = cache.get(&(device_id, sensor_id)).await {
         return Ok(Json(measurement));
     }
     let measurement =
-        Measurement::read_latest_by_device_id_and_sensor_id(device_id, sensor_id, &pool)
+        Measurement::read_latest_by_device_id_and_sensor_id(device_id, sensor_id, storage.as_ref())
             .await
-            .map_err(|e| {
-                warn!("Failed with error: {}", e);
-                HandlerError::new(500, format!("Failed to fetch data from database: {e}"))
-            })?;
+            .map_err(HandlerError::from)?;
     // Insert into cache
     cache
         .insert((device_id, sensor_id), measurement.clone())
@@ -140,28 +328,41 @@ pub async fn fetch_latest_measurement_by_device_id_and_sensor_id(
 pub async fn fetch_measurement_by_device_id_and_sensor_id(
     State(app_state): ApplicationState,
     Path((device_id, sensor_id)): Path<(i32, i32)>,
-) -> Result<Json<Vec<Measurement>>, HandlerError> {
-    let (pool, _cache) = app_state;
-    let measurements = Measurement::read_by_device_id_and_sensor_id(device_id, sensor_id, &pool)
-        .await
-        .map_err(|e| {
-            warn!("Failed with error: {}", e);
-            HandlerError::new(500, format!("Failed to fetch data from database: {e}"))
-        })?;
-    Ok(Json(measurements))
+    Query(query): Query<MeasurementQuery>,
+    Query(format): Query<FormatQuery>,
+    headers: HeaderMap,
+) -> Result<Response, HandlerError> {
+    let (storage, _cache) = app_state;
+    validate_query(&query)?;
+    let format = format.negotiate(accept(&headers));
+    if format != Format::Json {
+        return Ok(export_response(
+            storage,
+            Some(device_id),
+            Some(sensor_id),
+            query,
+            format,
+        ));
+    }
+    let page = Measurement::read_by_device_id_and_sensor_id(
+        device_id,
+        sensor_id,
+        &query,
+        storage.as_ref(),
+    )
+    .await
+    .map_err(HandlerError::from)?;
+    Ok(page_response(page, &query))
 }
 
 #[instrument]
 pub async fn fetch_all_latest_measurements(
     State(app_state): ApplicationState,
 ) -> Result<Json<Vec<Measurement>>, HandlerError> {
-    let (pool, _cache) = app_state;
-    let measurements = Measurement::read_all_latest_measurements(&pool)
+    let (storage, _cache) = app_state;
+    let measurements = Measurement::read_all_latest_measurements(storage.as_ref())
         .await
-        .map_err(|e| {
-            warn!("Failed with error: {}", e);
-            HandlerError::new(500, format!("Failed to fetch data from database: {e}"))
-        })?;
+        .map_err(HandlerError::from)?;
     // Insert all latest measurements into cache
     Ok(Json(measurements))
 }
@@ -170,22 +371,82 @@ pub async fn fetch_all_latest_measurements(
 pub async fn fetch_stats_by_device_id_and_sensor_id(
     State(app_state): ApplicationState,
     Path((device_id, sensor_id)): Path<(i32, i32)>,
+    Query(query): Query<StatsQuery>,
 ) -> Result<Json<MeasurementStats>, HandlerError> {
-    let (pool, _cache) = app_state;
-    let stats = Measurement::read_stats_by_device_id_and_sensor_id(&pool, device_id, sensor_id)
-        .await
-        .map_err(|e| {
-            warn!("Failed with error: {}", e);
-            HandlerError::new(500, format!("Failed to fetch data from database: {e}"))
-        })?;
+    let (storage, _cache) = app_state;
+    let stats = Measurement::read_stats_by_device_id_and_sensor_id(
+        storage.as_ref(),
+        device_id,
+        sensor_id,
+        &query,
+    )
+    .await
+    .map_err(HandlerError::from)?;
     Ok(Json(stats))
 }
 
+#[instrument]
+pub async fn stream_measurements(
+    State(events): State<broadcast::Sender<MeasurementEvent>>,
+    Query(query): Query<StreamQuery>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let rx = events.subscribe();
+    let stream = futures::stream::unfold((rx, query), |(mut rx, query)| async move {
+        loop {
+            match rx.recv().await {
+                Ok(event) if query.matches(&event) => {
+                    match Event::default().event("measurement").json_data(&event) {
+                        Ok(event) => return Some((Ok(event), (rx, query))),
+                        Err(e) => warn!("Failed to serialize measurement: {}", e),
+                    }
+                }
+                Ok(_) => {}
+                // The subscriber fell behind and the oldest measurements were dropped for it
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!("Stream subscriber lagged, skipped {} measurements", skipped);
+                    let event = Event::default().event("lagged").data(skipped.to_string());
+                    return Some((Ok(event), (rx, query)));
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    });
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+#[instrument]
+pub async fn fetch_aggregate_by_device_id_and_sensor_id(
+    State(app_state): ApplicationState,
+    Path((device_id, sensor_id)): Path<(i32, i32)>,
+    Query(query): Query<AggregateQuery>,
+) -> Result<Json<Vec<MeasurementBucket>>, HandlerError> {
+    let (storage, _cache) = app_state;
+    query
+        .check()
+        .map_err(|e| HandlerError::BadRequest(e.to_string()))?;
+    let buckets = Measurement::read_aggregate_by_device_id_and_sensor_id(
+        storage.as_ref(),
+        device_id,
+        sensor_id,
+        &query,
+    )
+    .await
+    .map_err(HandlerError::from)?;
+    Ok(Json(buckets))
+}
+
 #[cfg(test)]
 mod tests {
-    use tokio::sync::mpsc::Receiver;
+    use axum::{body::to_bytes, http::StatusCode};
+    use sqlx::PgPool;
+    use tokio::sync::mpsc::{Receiver, Sender};
 
-    use crate::{devices::NewDevice, measurements::NewMeasurement, sensors::NewSensor};
+    use crate::{
+        devices::{Device, NewDevice},
+        measurements::{NewMeasurement, Order},
+        sensors::{NewSensor, Sensor},
+        storage::{storage_tests, MemoryStorage},
+    };
 
     use super::*;
 
@@ -200,7 +461,12 @@ mod tests {
             tokio::sync::mpsc::channel(100);
 
         let result = store_measurements(
-            State(tx),
+            State(Ingest {
+                queue: MeasurementQueue::new(tx, None),
+                resolver: Resolver::new(Arc::new(db), false),
+                prometheus: PrometheusLabels::default(),
+            }),
+            None,
             Json(NewMeasurements::Measurement(new_measurement)),
         )
         .await
@@ -223,7 +489,12 @@ mod tests {
             tokio::sync::mpsc::channel(100);
         let new_measurement = NewMeasurement::new(Some(chrono::Utc::now()), 1, 1, 1.0);
         let result = store_measurements(
-            State(tx),
+            State(Ingest {
+                queue: MeasurementQueue::new(tx, None),
+                resolver: Resolver::new(Arc::new(db), false),
+                prometheus: PrometheusLabels::default(),
+            }),
+            None,
             Json(NewMeasurements::Measurement(new_measurement)),
         )
         .await
@@ -252,7 +523,12 @@ mod tests {
             tokio::sync::mpsc::channel(100);
 
         let result = store_measurements(
-            State(tx),
+            State(Ingest {
+                queue: MeasurementQueue::new(tx, None),
+                resolver: Resolver::new(Arc::new(db), false),
+                prometheus: PrometheusLabels::default(),
+            }),
+            None,
             Json(NewMeasurements::Measurements(new_measurements)),
         )
         .await
@@ -279,7 +555,12 @@ mod tests {
             NewMeasurement::new(Some(chrono::Utc::now()), 1, 1, 2.0),
         ];
         let result = store_measurements(
-            State(tx),
+            State(Ingest {
+                queue: MeasurementQueue::new(tx, None),
+                resolver: Resolver::new(Arc::new(db), false),
+                prometheus: PrometheusLabels::default(),
+            }),
+            None,
             Json(NewMeasurements::Measurements(new_measurements)),
         )
         .await
@@ -295,4 +576,294 @@ mod tests {
             "Second measurement should be sent to background thread"
         );
     }
+
+    #[tokio::test]
+    async fn should_reject_measurements_of_other_devices() {
+        let (tx, mut rx): (Sender<NewMeasurement>, Receiver<NewMeasurement>) =
+            tokio::sync::mpsc::channel(100);
+        let ingest = Ingest {
+            queue: MeasurementQueue::new(tx, None),
+            resolver: Resolver::new(Arc::new(MemoryStorage::default()), false),
+            prometheus: PrometheusLabels::default(),
+        };
+        let new_measurements = vec![
+            NewMeasurement::new(None, 1, 1, 1.0),
+            NewMeasurement::new(None, 2, 1, 2.0),
+        ];
+
+        let result = store_measurements(
+            State(ingest.clone()),
+            Some(Extension(AuthenticatedDevice(Device::new(
+                1,
+                "test".to_string(),
+                "test".to_string(),
+            )))),
+            Json(NewMeasurements::Measurements(new_measurements)),
+        )
+        .await;
+        assert_eq!(result.unwrap_err().status(), StatusCode::FORBIDDEN);
+        assert!(rx.try_recv().is_err(), "Nothing should be queued");
+
+        let result = store_measurements(
+            State(ingest),
+            Some(Extension(AuthenticatedDevice(Device::new(
+                2,
+                "test".to_string(),
+                "test".to_string(),
+            )))),
+            Json(NewMeasurements::Measurement(NewMeasurement::new(
+                None, 2, 1, 2.0,
+            ))),
+        )
+        .await
+        .unwrap();
+        assert_eq!(result.status(), 201);
+    }
+
+    async fn should_store_measurements_by_name(storage: Arc<dyn Storage>) {
+        let (tx, mut rx): (Sender<NewMeasurement>, Receiver<NewMeasurement>) =
+            tokio::sync::mpsc::channel(100);
+        let queue = MeasurementQueue::new(tx, None);
+        let named = NamedMeasurement {
+            timestamp: None,
+            device: NewDevice::new("esp32".to_string(), "Kitchen".to_string()),
+            sensor: NewSensor::new("temperature".to_string(), "°C".to_string()),
+            measurement: 21.5,
+        };
+
+        let result = store_measurements(
+            State(Ingest {
+                queue: queue.clone(),
+                resolver: Resolver::new(storage.clone(), false),
+                prometheus: PrometheusLabels::default(),
+            }),
+            None,
+            Json(NewMeasurements::NamedMeasurement(named.clone())),
+        )
+        .await;
+        assert_eq!(
+            result.unwrap_err().status(),
+            StatusCode::UNPROCESSABLE_ENTITY
+        );
+
+        let result = store_measurements(
+            State(Ingest {
+                queue,
+                resolver: Resolver::new(storage.clone(), true),
+                prometheus: PrometheusLabels::default(),
+            }),
+            None,
+            Json(NewMeasurements::NamedMeasurements(vec![named])),
+        )
+        .await
+        .unwrap();
+        assert_eq!(result.status(), 201);
+        let queued = rx.recv().await.unwrap();
+        let device = Device::read_by_id(storage.as_ref(), queued.device)
+            .await
+            .unwrap();
+        assert_eq!(device.name, "esp32");
+        let sensor = Sensor::read_by_id(storage.as_ref(), queued.sensor)
+            .await
+            .unwrap();
+        assert_eq!(sensor.unit, "°C");
+    }
+
+    async fn should_return_next_cursor_when_limit_is_reached(storage: Arc<dyn Storage>) {
+        let device = NewDevice::new("test".to_string(), "test".to_string());
+        device.insert(storage.as_ref()).await.unwrap();
+        let sensor = NewSensor::new("test".to_string(), "test".to_string());
+        sensor.insert(storage.as_ref()).await.unwrap();
+        let measurements = vec![
+            NewMeasurement::new(None, 1, 1, 1.0),
+            NewMeasurement::new(None, 1, 1, 2.0),
+        ];
+        NewMeasurement::insert_many(&measurements, storage.as_ref())
+            .await
+            .unwrap();
+        let cache = Cache::builder().max_capacity(16).build();
+
+        let query = MeasurementQuery {
+            limit: Some(1),
+            ..Default::default()
+        };
+        let response = fetch_measurement_by_device_id_and_sensor_id(
+            State((storage.clone(), cache.clone())),
+            Path((1, 1)),
+            Query(query),
+            Query(FormatQuery::default()),
+            HeaderMap::new(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), 200);
+        assert!(response.headers().contains_key(NEXT_CURSOR_HEADER));
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let page: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(page["measurements"][0]["value"], 1.0);
+        let next = page["next"].as_str().unwrap().parse().unwrap();
+
+        // A page short of the limit has no next cursor
+        let query = MeasurementQuery {
+            limit: Some(2),
+            cursor: Some(next),
+            ..Default::default()
+        };
+        let response = fetch_measurement_by_device_id_and_sensor_id(
+            State((storage.clone(), cache.clone())),
+            Path((1, 1)),
+            Query(query),
+            Query(FormatQuery::default()),
+            HeaderMap::new(),
+        )
+        .await
+        .unwrap();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let page: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(page["measurements"][0]["value"], 2.0);
+        assert!(page["next"].is_null());
+
+        // Without pagination the list is returned as is
+        let response = fetch_measurement_by_device_id_and_sensor_id(
+            State((storage.clone(), cache.clone())),
+            Path((1, 1)),
+            Query(MeasurementQuery::default()),
+            Query(FormatQuery::default()),
+            HeaderMap::new(),
+        )
+        .await
+        .unwrap();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let measurements: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(measurements.as_array().unwrap().len(), 2);
+
+        let query = MeasurementQuery {
+            limit: Some(0),
+            ..Default::default()
+        };
+        let result = fetch_measurement_by_device_id_and_sensor_id(
+            State((storage, cache)),
+            Path((1, 1)),
+            Query(query),
+            Query(FormatQuery::default()),
+            HeaderMap::new(),
+        )
+        .await;
+        assert_eq!(result.unwrap_err().status(), StatusCode::BAD_REQUEST);
+    }
+
+    async fn should_export_csv_and_ndjson(storage: Arc<dyn Storage>) {
+        let device = NewDevice::new("esp32, kitchen".to_string(), "Kitchen".to_string());
+        device.insert(storage.as_ref()).await.unwrap();
+        let sensor = NewSensor::new("temperature".to_string(), "°C".to_string());
+        sensor.insert(storage.as_ref()).await.unwrap();
+        let start = chrono::DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let measurements: Vec<_> = (0..3)
+            .map(|i| {
+                let timestamp = start + chrono::Duration::seconds(i);
+                NewMeasurement::new(Some(timestamp), 1, 1, i as f32)
+            })
+            .collect();
+        NewMeasurement::insert_many(&measurements, storage.as_ref())
+            .await
+            .unwrap();
+        let cache = Cache::builder().max_capacity(16).build();
+
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT, HeaderValue::from_static("text/csv"));
+        let response = fetch_measurement_by_device_id(
+            State((storage.clone(), cache.clone())),
+            Path(1),
+            Query(MeasurementQuery::default()),
+            Query(FormatQuery::default()),
+            headers,
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.headers()[CONTENT_TYPE], "text/csv; charset=utf-8");
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let csv = String::from_utf8(body.to_vec()).unwrap();
+        let lines: Vec<_> = csv.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(
+            lines[0],
+            "timestamp,value,unit,device_name,device_location,sensor_name"
+        );
+        assert_eq!(
+            lines[1],
+            "2023-11-14T22:13:20Z,0,°C,\"esp32, kitchen\",Kitchen,temperature"
+        );
+
+        let query = MeasurementQuery {
+            limit: Some(2),
+            order: Order::Desc,
+            ..Default::default()
+        };
+        let response = fetch_all_measurements(
+            State((storage, cache)),
+            Query(query),
+            Query(FormatQuery {
+                format: Some(Format::Ndjson),
+            }),
+            HeaderMap::new(),
+        )
+        .await
+        .unwrap();
+        assert!(!response.headers().contains_key(NEXT_CURSOR_HEADER));
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let values: Vec<f64> = String::from_utf8(body.to_vec())
+            .unwrap()
+            .lines()
+            .map(|line| {
+                serde_json::from_str::<serde_json::Value>(line).unwrap()["value"]
+                    .as_f64()
+                    .unwrap()
+            })
+            .collect();
+        assert_eq!(values, vec![2.0, 1.0]);
+    }
+
+    #[tokio::test]
+    async fn should_stream_matching_measurements() {
+        let (events, _) = broadcast::channel(16);
+        let query = StreamQuery {
+            device: Some(2),
+            sensor: None,
+        };
+        let response = stream_measurements(State(events.clone()), Query(query))
+            .await
+            .into_response();
+        assert_eq!(response.status(), 200);
+
+        let measurement = Measurement {
+            timestamp: chrono::Utc::now(),
+            value: 21.5,
+            unit: "C".to_string(),
+            device_name: "test".to_string(),
+            device_location: "test".to_string(),
+            sensor_name: "temperature".to_string(),
+        };
+        for device_id in [1, 2] {
+            events
+                .send(MeasurementEvent {
+                    device_id,
+                    sensor_id: 1,
+                    measurement: measurement.clone(),
+                })
+                .unwrap();
+        }
+
+        let mut body = response.into_body().into_data_stream();
+        let chunk = body.next().await.unwrap().unwrap();
+        let chunk = String::from_utf8(chunk.to_vec()).unwrap();
+        assert!(chunk.starts_with("event: measurement"));
+        assert!(chunk.contains("\"device_id\":2"));
+        assert!(chunk.contains("\"value\":21.5"));
+    }
+
+    storage_tests!(
+        should_return_next_cursor_when_limit_is_reached,
+        should_store_measurements_by_name,
+        should_export_csv_and_ndjson
+    );
 }
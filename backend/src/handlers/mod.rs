@@ -1,3 +1,10 @@
+use alerts::{
+    delete_alert, fetch_alert_by_id, fetch_alert_history, fetch_alert_history_by_id, fetch_alerts,
+    insert_alert, update_alert,
+};
+use api_keys::{delete_api_key, fetch_api_keys, insert_api_key};
+use archive::download_archive;
+use auth::{authorize, Auth};
 use axum::{
     extract::{Request, State},
     middleware::{self, Next},
@@ -5,32 +12,57 @@ use axum::{
     routing::{delete, get, post, put},
     Router,
 };
-use devices::{delete_device, fetch_devices, insert_device, update_device};
+use devices::{
+    delete_device, fetch_devices, insert_device, revoke_device_token, rotate_device_token,
+    update_device,
+};
+use error::json_errors;
+use grafana::{
+    fetch_grafana_annotations, fetch_grafana_tag_keys, fetch_grafana_tag_values, query_grafana,
+    search_grafana, test_grafana,
+};
+use line_protocol::write_line_protocol;
 use measurements::{
-    fetch_all_latest_measurements, fetch_all_measurements, fetch_latest_measurement,
+    fetch_aggregate_by_device_id_and_sensor_id, fetch_all_latest_measurements,
+    fetch_all_measurements, fetch_latest_measurement,
     fetch_latest_measurement_by_device_id_and_sensor_id, fetch_measurement_by_device_id,
     fetch_measurement_by_device_id_and_sensor_id, fetch_measurements_count,
-    fetch_stats_by_device_id_and_sensor_id, store_measurements,
+    fetch_stats_by_device_id_and_sensor_id, store_measurements, stream_measurements,
 };
 use metrics::histogram;
 use metrics_exporter_prometheus::PrometheusHandle;
 use moka::future::Cache;
+use prometheus::{read_prometheus, write_prometheus};
 use sensors::fetch_sensors_by_device_id;
 use sensors::{delete_sensor, fetch_sensors, insert_sensor, update_sensor};
-use sqlx::Pool;
-use tokio::{sync::mpsc::Sender, time::Instant};
+use sqlx::PgPool;
+use tokio::{sync::broadcast, time::Instant};
 use tower::ServiceBuilder;
-use tower_http::trace::TraceLayer;
+use tower_http::{
+    decompression::RequestDecompressionLayer,
+    request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer},
+    trace::TraceLayer,
+};
 use tracing::{info, instrument};
 
+use std::sync::Arc;
+
 use crate::{
     handlers::{devices::fetch_devices_by_id, sensors::fetch_sensor_by_sensor_id},
-    measurements::{Measurement, NewMeasurement},
+    measurements::{Measurement, MeasurementEvent},
+    storage::Storage,
 };
 
+mod alerts;
+mod api_keys;
+mod archive;
+mod auth;
 mod devices;
 mod error;
+mod grafana;
+mod line_protocol;
 mod measurements;
+mod prometheus;
 mod sensors;
 
 #[instrument]
@@ -58,11 +90,18 @@ pub async fn profile_endpoint(request: Request, next: Next) -> Response {
     response
 }
 
+pub use auth::AuthOptions;
+pub use measurements::Ingest;
+
+/// Alerts are only served with Postgres, whose pool is passed in `postgres`
 pub fn create_router(
-    connection: Pool<sqlx::Postgres>,
+    storage: Arc<dyn Storage>,
+    postgres: Option<PgPool>,
+    auth: AuthOptions,
     metrics_handler: PrometheusHandle,
     cache: Cache<(i32, i32), Measurement>,
-    tx: Sender<NewMeasurement>,
+    ingest: Ingest,
+    events: broadcast::Sender<MeasurementEvent>,
 ) -> Router {
     let measurements = Router::new()
         .route("/measurements", get(fetch_all_measurements))
@@ -72,21 +111,27 @@ pub fn create_router(
             get(fetch_all_latest_measurements),
         )
         .route("/measurements/count", get(fetch_measurements_count))
-        .with_state((connection.clone(), cache.clone()))
+        .with_state((storage.clone(), cache.clone()))
         .route("/measurements", post(store_measurements))
-        .with_state(tx.clone());
+        .with_state(ingest.clone())
+        .route("/measurements/stream", get(stream_measurements))
+        .with_state(events);
 
     let devices = Router::new()
         .route("/devices", get(fetch_devices))
         .route("/devices", post(insert_device))
-        .route("/devices", delete(delete_device))
-        .route("/devices", put(update_device))
         .route("/devices/{device_id}", get(fetch_devices_by_id))
+        .route("/devices/{device_id}/token", post(rotate_device_token))
+        .route("/devices/{device_id}/token", delete(revoke_device_token))
         .route(
             "/devices/{device_id}/sensors",
             get(fetch_sensors_by_device_id),
         )
-        .with_state(connection.clone())
+        .with_state(storage.clone())
+        // Renamed and deleted devices are dropped from the names resolved for ingest
+        .route("/devices", delete(delete_device))
+        .route("/devices", put(update_device))
+        .with_state((storage.clone(), ingest.resolver.clone()))
         .route(
             "/devices/{device_id}/measurements",
             get(fetch_measurement_by_device_id),
@@ -103,27 +148,100 @@ pub fn create_router(
             "/devices/{device_id}/sensors/{sensor_id}/measurements/stats",
             get(fetch_stats_by_device_id_and_sensor_id),
         )
-        .with_state((connection.clone(), cache.clone()));
+        .route(
+            "/devices/{device_id}/sensors/{sensor_id}/measurements/aggregate",
+            get(fetch_aggregate_by_device_id_and_sensor_id),
+        )
+        .with_state((storage.clone(), cache.clone()));
 
     let sensors = Router::new()
         .route("/sensors", get(fetch_sensors))
         .route("/sensors", post(insert_sensor))
+        .route("/sensors/{sensor_id}", get(fetch_sensor_by_sensor_id))
+        .with_state(storage.clone())
         .route("/sensors", delete(delete_sensor))
         .route("/sensors", put(update_sensor))
-        .route("/sensors/{sensor_id}", get(fetch_sensor_by_sensor_id));
+        .with_state((storage.clone(), ingest.resolver.clone()));
+
+    let archive = Router::new()
+        .route("/measurements/archive", get(download_archive))
+        .with_state(storage.clone());
+
+    let prometheus = Router::new()
+        .route("/prom/read", post(read_prometheus))
+        .with_state(storage.clone());
+
+    // The Grafana JSON datasource contract, the datasource URL tests with `GET /`
+    let grafana = Router::new()
+        .route("/grafana", get(test_grafana))
+        .route("/grafana/", get(test_grafana))
+        .route("/grafana/search", post(search_grafana))
+        .route("/grafana/query", post(query_grafana))
+        .route("/grafana/annotations", post(fetch_grafana_annotations))
+        .route("/grafana/tag-keys", post(fetch_grafana_tag_keys))
+        .route("/grafana/tag-values", post(fetch_grafana_tag_values))
+        .with_state((storage.clone(), postgres.clone()));
 
-    Router::new()
+    let keys = Router::new()
+        .route("/keys", get(fetch_api_keys))
+        .route("/keys", post(insert_api_key))
+        .route("/keys/{key_id}", delete(delete_api_key))
+        .with_state(storage.clone());
+
+    // The paths of the InfluxDB v1 and v2 write APIs, Telegraf gzips by default
+    let write = Router::new()
+        .route("/api/write", post(write_line_protocol))
+        .route("/write", post(write_line_protocol))
+        .route("/api/v2/write", post(write_line_protocol))
+        .with_state(ingest.clone())
+        .layer(RequestDecompressionLayer::new());
+
+    // Remote write bodies are snappy compressed, which the handler decodes itself. The
+    // decompression layer would reject the `snappy` content encoding
+    let prometheus_write = Router::new()
+        .route("/api/prom/write", post(write_prometheus))
+        .with_state(ingest.clone());
+
+    let mut api = Router::new()
         .nest("/api", measurements)
         .nest("/api", devices)
         .nest("/api", sensors)
-        .with_state(connection.clone())
-        .route("/", post(store_measurements))
-        .with_state(tx)
+        .nest("/api", keys)
+        .nest("/api", archive)
+        .nest("/api", prometheus)
+        .nest("/api", grafana)
+        .merge(write)
+        .merge(prometheus_write);
+    if let Some(pool) = postgres {
+        let alerts = Router::new()
+            .route("/alerts", get(fetch_alerts))
+            .route("/alerts", post(insert_alert))
+            .route("/alerts", delete(delete_alert))
+            .route("/alerts", put(update_alert))
+            .route("/alerts/history", get(fetch_alert_history))
+            .route("/alerts/{alert_id}", get(fetch_alert_by_id))
+            .route("/alerts/{alert_id}/history", get(fetch_alert_history_by_id))
+            .with_state(pool);
+        api = api.nest("/api", alerts);
+    }
+
+    api.route("/", post(store_measurements))
+        .with_state(ingest)
         .route("/metrics", get(metrics))
         .with_state(metrics_handler)
         .layer(
             ServiceBuilder::new()
+                .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid))
+                .layer(PropagateRequestIdLayer::x_request_id())
                 .layer(TraceLayer::new_for_http())
+                .layer(middleware::from_fn(json_errors))
+                .layer(middleware::from_fn_with_state(
+                    Auth {
+                        storage,
+                        options: auth,
+                    },
+                    authorize,
+                ))
                 .layer(middleware::from_fn(profile_endpoint)),
         )
 }
@@ -132,3 +250,417 @@ pub fn create_router(
 async fn metrics(State(handle): State<PrometheusHandle>) -> String {
     handle.render()
 }
+
+#[cfg(test)]
+mod tests {
+    use metrics_exporter_prometheus::PrometheusBuilder;
+    use serde_json::{json, Value};
+    use tokio::{net::TcpListener, sync::mpsc};
+
+    use super::{error::ErrorBody, *};
+    use crate::{
+        api_keys::{NewApiKey, Scope},
+        background_tasks::handle_insert_measurement_bg_thread,
+        prometheus::{
+            tests::{encode_write_request, series},
+            PrometheusLabels,
+        },
+        provisioning::Resolver,
+        queue::MeasurementQueue,
+        storage::MemoryStorage,
+    };
+
+    /// Serves the full router on top of in-memory storage, returning its base URL
+    async fn serve() -> String {
+        serve_with(Arc::new(MemoryStorage::default()), AuthOptions::default()).await
+    }
+
+    async fn serve_with(storage: Arc<dyn Storage>, auth: AuthOptions) -> String {
+        let cache = Cache::builder().max_capacity(16).build();
+        let (tx, rx) = mpsc::channel(16);
+        let (events, _) = broadcast::channel(16);
+        tokio::spawn(handle_insert_measurement_bg_thread(
+            rx,
+            storage.clone(),
+            cache.clone(),
+            16,
+            std::time::Duration::from_millis(10),
+            None,
+            events.clone(),
+        ));
+        let app = create_router(
+            storage.clone(),
+            None,
+            auth,
+            PrometheusBuilder::new().build_recorder().handle(),
+            cache,
+            Ingest {
+                queue: MeasurementQueue::new(tx, None),
+                resolver: Resolver::new(storage, true),
+                prometheus: PrometheusLabels::default(),
+            },
+            events,
+        );
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        url
+    }
+
+    #[tokio::test]
+    async fn should_serve_api_without_database() {
+        let url = serve().await;
+        let client = reqwest::Client::new();
+        let post = |path: &str, body: Value| client.post(format!("{url}{path}")).json(&body).send();
+
+        let res = post(
+            "/api/devices",
+            json!({"name": "esp32", "location": "Kitchen"}),
+        )
+        .await
+        .unwrap();
+        assert_eq!(res.status(), 200);
+        let res = post("/api/sensors", json!({"name": "temperature", "unit": "C"}))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 200);
+        let res = post("/", json!({"device": 1, "sensor": 1, "measurement": 21.5}))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 201);
+
+        // Stored by the insert worker in the background
+        let mut count = 0;
+        for _ in 0..50 {
+            count = client
+                .get(format!("{url}/api/measurements/count"))
+                .send()
+                .await
+                .unwrap()
+                .json::<usize>()
+                .await
+                .unwrap();
+            if count > 0 {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        assert_eq!(count, 1);
+
+        let measurements: Vec<Value> = client
+            .get(format!("{url}/api/devices/1/sensors/1/measurements"))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(measurements[0]["value"], 21.5);
+        assert_eq!(measurements[0]["device_location"], "Kitchen");
+
+        // Alerts need Postgres
+        let res = client
+            .get(format!("{url}/api/alerts"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 404);
+    }
+
+    #[tokio::test]
+    async fn should_respond_with_json_errors() {
+        let url = serve().await;
+        let client = reqwest::Client::new();
+
+        let res = client
+            .get(format!("{url}/api/devices/42"))
+            .header("x-request-id", "my-request")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 404);
+        assert_eq!(res.headers()["x-request-id"], "my-request");
+        let body: ErrorBody = res.json().await.unwrap();
+        assert_eq!(body.code, "not_found");
+        assert_eq!(body.request_id.as_deref(), Some("my-request"));
+
+        // Rejected by axum before reaching a handler
+        let res = client
+            .post(format!("{url}/api/devices"))
+            .header("content-type", "application/json")
+            .body("{")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 400);
+        let generated = res.headers()["x-request-id"].to_str().unwrap().to_string();
+        let body: ErrorBody = res.json().await.unwrap();
+        assert_eq!(body.code, "bad_request");
+        assert_eq!(body.request_id, Some(generated));
+
+        let res = client
+            .get(format!("{url}/api/unknown"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 404);
+        let body: ErrorBody = res.json().await.unwrap();
+        assert_eq!(body.code, "not_found");
+    }
+
+    #[tokio::test]
+    async fn should_download_parquet_archives() {
+        let url = serve().await;
+        let client = reqwest::Client::new();
+
+        let res = client
+            .get(format!("{url}/api/measurements/archive?devices=1,2"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 200);
+        assert_eq!(
+            res.headers()["content-type"],
+            "application/vnd.apache.parquet"
+        );
+        let body = res.bytes().await.unwrap();
+        assert!(body.starts_with(b"PAR1") && body.ends_with(b"PAR1"));
+
+        let res = client
+            .get(format!("{url}/api/measurements/archive?devices=kitchen"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 400);
+    }
+
+    #[tokio::test]
+    async fn should_accept_snappy_encoded_remote_write() {
+        let url = serve().await;
+        let body = encode_write_request(vec![series(
+            &[
+                ("__name__", "temperature"),
+                ("device_name", "esp32"),
+                ("device_location", "Kitchen"),
+                ("unit", "°C"),
+            ],
+            &[(1_700_000_000_000, 21.5)],
+        )]);
+        let res = reqwest::Client::new()
+            .post(format!("{url}/api/prom/write"))
+            .header("content-encoding", "snappy")
+            .header("content-type", "application/x-protobuf")
+            .header("x-prometheus-remote-write-version", "0.1.0")
+            .body(body)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 204);
+    }
+
+    #[tokio::test]
+    async fn should_serve_grafana_datasource() {
+        let url = serve().await;
+        let client = reqwest::Client::new();
+        let post = |path: &str, body: Value| {
+            client
+                .post(format!("{url}/api/grafana{path}"))
+                .json(&body)
+                .send()
+        };
+
+        let res = client
+            .get(format!("{url}/api/grafana/"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 200);
+        let res = post("/search", json!({"target": ""})).await.unwrap();
+        assert_eq!(res.json::<Value>().await.unwrap(), json!([]));
+        let res = post("/tag-keys", json!({})).await.unwrap();
+        assert_eq!(res.json::<Vec<Value>>().await.unwrap().len(), 4);
+
+        let range = json!({"from": "2024-01-01T00:00:00Z", "to": "2024-01-02T00:00:00Z"});
+        let res = post(
+            "/query",
+            json!({"range": range, "targets": [{"target": "1:1"}]}),
+        )
+        .await
+        .unwrap();
+        assert_eq!(res.json::<Value>().await.unwrap(), json!([]));
+        let res = post(
+            "/query",
+            json!({"range": range, "targets": [{"target": "x"}]}),
+        )
+        .await
+        .unwrap();
+        assert_eq!(res.status(), 400);
+        let res = post("/tag-values", json!({"key": "color"})).await.unwrap();
+        assert_eq!(res.status(), 400);
+
+        // Alerts need Postgres
+        let res = post("/annotations", json!({"range": range, "annotation": {}}))
+            .await
+            .unwrap();
+        assert_eq!(res.json::<Value>().await.unwrap(), json!([]));
+    }
+
+    #[tokio::test]
+    async fn should_enforce_api_key_scopes() {
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::default());
+        let mut keys = Vec::new();
+        for scope in [Scope::Ingest, Scope::Read, Scope::Admin] {
+            let key = NewApiKey {
+                name: scope.to_string(),
+                scope,
+            };
+            keys.push(key.insert(storage.as_ref()).await.unwrap().key);
+        }
+        let [ingest, read, admin] = keys.try_into().unwrap();
+        let auth = AuthOptions {
+            enabled: true,
+            public_metrics: true,
+        };
+        let url = serve_with(storage, auth).await;
+        let client = reqwest::Client::new();
+        let get =
+            |path: &str, key: &str| client.get(format!("{url}{path}")).bearer_auth(key).send();
+
+        let res = client
+            .get(format!("{url}/api/devices"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 401);
+        let body: ErrorBody = res.json().await.unwrap();
+        assert_eq!(body.code, "unauthorized");
+        assert_eq!(
+            get("/api/devices", "hemrs_wrong").await.unwrap().status(),
+            401
+        );
+
+        assert_eq!(get("/api/devices", &read).await.unwrap().status(), 200);
+        assert_eq!(get("/api/devices", &ingest).await.unwrap().status(), 403);
+        assert_eq!(get("/api/devices", &admin).await.unwrap().status(), 200);
+        assert_eq!(get("/api/keys", &read).await.unwrap().status(), 403);
+
+        let device = json!({"name": "esp32", "location": "Kitchen"});
+        let res = client
+            .post(format!("{url}/api/devices"))
+            .header("x-api-key", &read)
+            .json(&device)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 403);
+        let res = client
+            .post(format!("{url}/api/devices"))
+            .header("x-api-key", &admin)
+            .json(&device)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 200);
+
+        let res = client
+            .post(format!("{url}/"))
+            .bearer_auth(&ingest)
+            .json(&json!({"device": 1, "sensor": 1, "measurement": 21.5}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 201);
+
+        // Public metrics
+        let res = client.get(format!("{url}/metrics")).send().await.unwrap();
+        assert_eq!(res.status(), 200);
+
+        // Revoked keys are rejected right away
+        let created: Value = client
+            .post(format!("{url}/api/keys"))
+            .bearer_auth(&admin)
+            .json(&json!({"name": "temporary", "scope": "read"}))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        let key = created["key"].as_str().unwrap();
+        assert_eq!(get("/api/sensors", key).await.unwrap().status(), 200);
+        let res = client
+            .delete(format!("{url}/api/keys/{}", created["id"]))
+            .bearer_auth(&admin)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 200);
+        assert_eq!(get("/api/sensors", key).await.unwrap().status(), 401);
+    }
+
+    #[tokio::test]
+    async fn should_bind_device_tokens_to_their_device() {
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::default());
+        let admin = NewApiKey {
+            name: "admin".to_string(),
+            scope: Scope::Admin,
+        }
+        .insert(storage.as_ref())
+        .await
+        .unwrap()
+        .key;
+        let auth = AuthOptions {
+            enabled: true,
+            public_metrics: false,
+        };
+        let url = serve_with(storage, auth).await;
+        let client = reqwest::Client::new();
+        let post = |path: &str, key: &str, body: Value| {
+            client
+                .post(format!("{url}{path}"))
+                .bearer_auth(key)
+                .json(&body)
+                .send()
+        };
+
+        for name in ["esp32-1", "esp32-2"] {
+            let device = json!({"name": name, "location": "Kitchen"});
+            let res = post("/api/devices", &admin, device).await.unwrap();
+            assert_eq!(res.status(), 200);
+        }
+        let token: Value = post("/api/devices/1/token", &admin, json!({}))
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        let token = token["token"].as_str().unwrap().to_string();
+
+        let own = json!({"device": 1, "sensor": 1, "measurement": 21.5});
+        let other = json!({"device": 2, "sensor": 1, "measurement": 21.5});
+        assert_eq!(post("/", &token, own.clone()).await.unwrap().status(), 201);
+        let res = post("/api/measurements", &token, other).await.unwrap();
+        assert_eq!(res.status(), 403);
+        let body: ErrorBody = res.json().await.unwrap();
+        assert_eq!(body.code, "forbidden");
+
+        // Only good for posting measurements
+        let res = client
+            .get(format!("{url}/api/devices"))
+            .bearer_auth(&token)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 403);
+
+        let res = client
+            .delete(format!("{url}/api/devices/1/token"))
+            .bearer_auth(&admin)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 200);
+        assert_eq!(post("/", &token, own).await.unwrap().status(), 401);
+    }
+}
@@ -0,0 +1,359 @@
+use std::sync::Arc;
+
+use axum::{
+    body::{Body, Bytes},
+    extract::State,
+    http::{
+        header::{CONTENT_ENCODING, CONTENT_TYPE},
+        HeaderMap, StatusCode,
+    },
+    response::{IntoResponse, Response},
+    Extension,
+};
+use tokio::sync::mpsc;
+use tracing::{instrument, warn};
+
+use crate::{
+    prometheus::{
+        decode_read_request, decode_write_request, encode_read_response, read_samples,
+        stream_chunks, ReadResponse, ResponseType, SeriesQuery,
+    },
+    provisioning::Unresolved,
+    storage::Storage,
+};
+
+use super::{
+    auth::AuthenticatedDevice,
+    error::HandlerError,
+    measurements::{check_named_devices, queue_measurements, Ingest},
+};
+
+/// Rejected series listed in an error message, the rest are only counted
+const MAX_REPORTED_SERIES: usize = 10;
+
+/// Content type of Prometheus remote write 2.0, which is not supported
+const REMOTE_WRITE_V2: &str = "io.prometheus.write.v2.Request";
+
+const STREAMED_CONTENT_TYPE: &str =
+    "application/x-streamed-protobuf; proto=prometheus.ChunkedReadResponse";
+
+/// Frames buffered while the client is reading
+const FRAME_BUFFER: usize = 4;
+
+/// Accepts Prometheus remote write 1.0 requests. Series that cannot be mapped to a device
+/// and sensor are dropped and reported with 400, which Prometheus does not retry, while the
+/// samples of all other series are written
+#[instrument(skip(headers, body))]
+pub async fn write_prometheus(
+    State(ingest): State<Ingest>,
+    device: Option<Extension<AuthenticatedDevice>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response, HandlerError> {
+    let is_v2 = headers
+        .get(CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .is_some_and(|content_type| content_type.contains(REMOTE_WRITE_V2));
+    if is_v2 {
+        // Tells Prometheus to fall back to remote write 1.0
+        return Ok((
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "Only remote write 1.0 is supported",
+        )
+            .into_response());
+    }
+    let request =
+        decode_write_request(&body).map_err(|e| HandlerError::BadRequest(e.to_string()))?;
+
+    let device = device.map(|Extension(device)| device);
+    // A device token can not create devices or sensors
+    let create = device.is_none();
+    let mut measurements = Vec::new();
+    let mut rejected = Vec::new();
+    for series in request.timeseries {
+        let description = series.describe();
+        let named = match series.into_named(&ingest.prometheus) {
+            Ok(named) => named,
+            Err(reason) => {
+                rejected.push(format!("{}: {}", description, reason));
+                continue;
+            }
+        };
+        check_named_devices(device.as_ref(), &named)?;
+        match ingest.resolver.resolve(named, create).await {
+            Ok(resolved) => measurements.extend(resolved),
+            Err(e) => match e.downcast_ref::<Unresolved>() {
+                Some(unresolved) => rejected.push(format!("{}: {}", description, unresolved)),
+                None => return Err(HandlerError::from(e)),
+            },
+        }
+    }
+    if !measurements.is_empty() {
+        queue_measurements(&ingest, device, measurements).await?;
+    }
+    if rejected.is_empty() {
+        return Ok(StatusCode::NO_CONTENT.into_response());
+    }
+    let dropped = rejected.len();
+    rejected.truncate(MAX_REPORTED_SERIES);
+    Err(HandlerError::BadRequest(format!(
+        "dropped {} series: {}",
+        dropped,
+        rejected.join("; ")
+    )))
+}
+
+/// Answers Prometheus remote read requests from the measurements, as the `measurements`
+/// series labelled like the gauge on `/metrics`. Streams XOR chunks if the client accepts
+/// them, otherwise all samples are sent at once
+#[instrument(skip(body))]
+pub async fn read_prometheus(
+    State(storage): State<Arc<dyn Storage>>,
+    body: Bytes,
+) -> Result<Response, HandlerError> {
+    let bad_request = |e: anyhow::Error| HandlerError::BadRequest(e.to_string());
+    let request = decode_read_request(&body).map_err(bad_request)?;
+    let queries = request
+        .queries
+        .iter()
+        .map(SeriesQuery::try_from)
+        .collect::<anyhow::Result<Vec<_>>>()
+        .map_err(bad_request)?;
+    let response_type = request
+        .accepted_response_types
+        .iter()
+        .find_map(|&accepted| ResponseType::try_from(accepted).ok())
+        .unwrap_or(ResponseType::Samples);
+
+    if response_type == ResponseType::Samples {
+        let mut response = ReadResponse::default();
+        for query in &queries {
+            let result = read_samples(storage.as_ref(), query)
+                .await
+                .map_err(HandlerError::from)?;
+            response.results.push(result);
+        }
+        let body = encode_read_response(&response).map_err(HandlerError::from)?;
+        return Ok((
+            [
+                (CONTENT_TYPE, "application/x-protobuf"),
+                (CONTENT_ENCODING, "snappy"),
+            ],
+            body,
+        )
+            .into_response());
+    }
+
+    let (tx, rx) = mpsc::channel(FRAME_BUFFER);
+    tokio::spawn(async move {
+        for (index, query) in queries.iter().enumerate() {
+            if let Err(e) = stream_chunks(storage.as_ref(), index, query, &tx).await {
+                warn!("Failed to stream remote read response: {}", e);
+                // Aborts the response, so the client does not take it as complete
+                let _ = tx.send(Err(e)).await;
+                return;
+            }
+        }
+    });
+    let frames = futures::stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|frame| (frame, rx))
+    });
+    Ok((
+        [(CONTENT_TYPE, STREAMED_CONTENT_TYPE)],
+        Body::from_stream(frames),
+    )
+        .into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::sync::mpsc::{self, Receiver, Sender};
+
+    use axum::body::to_bytes;
+    use prost::Message;
+
+    use super::*;
+    use crate::{
+        devices::{Device, NewDevice},
+        measurements::NewMeasurement,
+        prometheus::{
+            tests::{decode_frames, encode_write_request, matcher, series},
+            MatchType, PrometheusLabels, Query, ReadRequest,
+        },
+        provisioning::Resolver,
+        queue::MeasurementQueue,
+        sensors::NewSensor,
+        storage::{storage_tests, MemoryStorage, Storage},
+    };
+
+    async fn should_write_mapped_series(storage: Arc<dyn Storage>) {
+        let (tx, mut rx): (Sender<NewMeasurement>, Receiver<NewMeasurement>) = mpsc::channel(100);
+        let ingest = Ingest {
+            queue: MeasurementQueue::new(tx, None),
+            resolver: Resolver::new(storage.clone(), true),
+            prometheus: PrometheusLabels {
+                device: "instance".to_string(),
+                location: "room".to_string(),
+                ..Default::default()
+            },
+        };
+        let body = encode_write_request(vec![
+            series(
+                &[
+                    ("__name__", "node_hwmon_temp_celsius"),
+                    ("instance", "pi"),
+                    ("room", "Attic"),
+                ],
+                &[(1_700_000_000_000, 48.0), (1_700_000_015_000, 48.5)],
+            ),
+            series(&[("__name__", "up"), ("instance", "pi")], &[(0, 1.0)]),
+        ]);
+
+        let error = write_prometheus(
+            State(ingest.clone()),
+            None,
+            HeaderMap::new(),
+            Bytes::from(body),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(error.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            error.message(),
+            r#"dropped 1 series: up{instance="pi"}: missing label room"#
+        );
+        let first = rx.recv().await.unwrap();
+        assert_eq!(first.measurement, 48.0);
+        assert_eq!(first.timestamp.unwrap().timestamp(), 1_700_000_000);
+        assert_eq!(rx.recv().await.unwrap().measurement, 48.5);
+
+        let body = encode_write_request(vec![series(
+            &[
+                ("__name__", "node_hwmon_temp_celsius"),
+                ("instance", "pi"),
+                ("room", "Attic"),
+            ],
+            &[(1_700_000_030_000, 49.0)],
+        )]);
+        let response = write_prometheus(State(ingest), None, HeaderMap::new(), Bytes::from(body))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(rx.recv().await.unwrap().measurement, 49.0);
+    }
+
+    #[tokio::test]
+    async fn should_reject_unsupported_requests() {
+        let (tx, _rx): (Sender<NewMeasurement>, Receiver<NewMeasurement>) = mpsc::channel(100);
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::default());
+        let ingest = Ingest {
+            queue: MeasurementQueue::new(tx, None),
+            resolver: Resolver::new(storage, false),
+            prometheus: PrometheusLabels::default(),
+        };
+
+        let error = write_prometheus(
+            State(ingest.clone()),
+            None,
+            HeaderMap::new(),
+            Bytes::from_static(b"garbage"),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(error.status(), StatusCode::BAD_REQUEST);
+
+        let error = write_prometheus(
+            State(ingest.clone()),
+            None,
+            HeaderMap::new(),
+            Bytes::from_static(&[0xff, 0xff, 0xff, 0xff, 0x0f]),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(error.status(), StatusCode::BAD_REQUEST);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            CONTENT_TYPE,
+            "application/x-protobuf;proto=io.prometheus.write.v2.Request"
+                .parse()
+                .unwrap(),
+        );
+        let response = write_prometheus(State(ingest), None, headers, Bytes::new())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+
+    fn encode_read_request(
+        response_types: Vec<ResponseType>,
+        matchers: Vec<(&str, &str)>,
+    ) -> Bytes {
+        let request = ReadRequest {
+            queries: vec![Query {
+                start_timestamp_ms: 0,
+                end_timestamp_ms: i64::MAX,
+                matchers: matchers
+                    .into_iter()
+                    .map(|(name, value)| matcher(MatchType::Re, name, value))
+                    .collect(),
+            }],
+            accepted_response_types: response_types.into_iter().map(i32::from).collect(),
+        };
+        let body = snap::raw::Encoder::new()
+            .compress_vec(&request.encode_to_vec())
+            .unwrap();
+        Bytes::from(body)
+    }
+
+    async fn should_answer_remote_read(storage: Arc<dyn Storage>) {
+        NewDevice::new("esp32".to_string(), "Attic".to_string())
+            .insert(storage.as_ref())
+            .await
+            .unwrap();
+        NewSensor::new("temperature".to_string(), "°C".to_string())
+            .insert(storage.as_ref())
+            .await
+            .unwrap();
+        let measurements = vec![
+            NewMeasurement::new(chrono::DateTime::from_timestamp_millis(1_000), 1, 1, 21.5),
+            NewMeasurement::new(chrono::DateTime::from_timestamp_millis(2_000), 1, 1, 22.0),
+        ];
+        NewMeasurement::insert_many(&measurements, storage.as_ref())
+            .await
+            .unwrap();
+        Device::refresh_device_sensors_view(storage.as_ref())
+            .await
+            .unwrap();
+
+        let body = encode_read_request(vec![], vec![("device_name", "esp.*")]);
+        let response = read_prometheus(State(storage.clone()), body).await.unwrap();
+        assert_eq!(response.headers()[CONTENT_ENCODING], "snappy");
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body = snap::raw::Decoder::new().decompress_vec(&body).unwrap();
+        let result = &ReadResponse::decode(body.as_slice()).unwrap().results[0];
+        assert_eq!(result.timeseries.len(), 1);
+        assert_eq!(result.timeseries[0].samples[1].value, 22.0);
+        assert_eq!(result.timeseries[0].samples[1].timestamp, 2_000);
+
+        let body = encode_read_request(
+            vec![ResponseType::StreamedXorChunks, ResponseType::Samples],
+            vec![("sensor_name", "temperature")],
+        );
+        let response = read_prometheus(State(storage.clone()), body).await.unwrap();
+        assert_eq!(response.headers()[CONTENT_TYPE], STREAMED_CONTENT_TYPE);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let frames = decode_frames(&body);
+        assert_eq!(frames.len(), 1);
+        let chunk = &frames[0].chunked_series[0].chunks[0];
+        assert_eq!((chunk.min_time_ms, chunk.max_time_ms), (1_000, 2_000));
+
+        let body = encode_read_request(vec![], vec![("device_name", "(")]);
+        let error = read_prometheus(State(storage), body).await.unwrap_err();
+        assert_eq!(error.status(), StatusCode::BAD_REQUEST);
+    }
+
+    storage_tests!(should_write_mapped_series, should_answer_remote_read);
+}
@@ -1,119 +1,124 @@
+use std::sync::Arc;
+
 use axum::{
     extract::{Path, State},
     Json,
 };
-use sqlx::PgPool;
-use tracing::{instrument, warn};
+use tracing::instrument;
 
-use crate::sensors::{NewSensor, Sensor};
+use crate::{
+    provisioning::Resolver,
+    sensors::{NewSensor, Sensor},
+    storage::Storage,
+};
 
 use super::error::HandlerError;
 
 #[instrument]
-pub async fn fetch_sensors(State(pool): State<PgPool>) -> Result<Json<Vec<Sensor>>, HandlerError> {
-    let sensors = Sensor::read(&pool).await.map_err(|e| {
-        warn!("Failed with error: {}", e);
-        HandlerError::new(500, format!("Failed to fetch data from database: {e}"))
-    })?;
+pub async fn fetch_sensors(
+    State(storage): State<Arc<dyn Storage>>,
+) -> Result<Json<Vec<Sensor>>, HandlerError> {
+    let sensors = Sensor::read(storage.as_ref())
+        .await
+        .map_err(HandlerError::from)?;
     Ok(Json(sensors))
 }
 
 #[instrument]
 pub async fn fetch_sensor_by_sensor_id(
-    State(pool): State<PgPool>,
+    State(storage): State<Arc<dyn Storage>>,
     Path(sensor_id): Path<i32>,
 ) -> Result<Json<Sensor>, HandlerError> {
-    let sensor = Sensor::read_by_id(&pool, sensor_id).await.map_err(|e| {
-        warn!("Failed with error: {}", e);
-        HandlerError::new(500, format!("Failed to fetch data from database: {e}"))
-    })?;
+    let sensor = Sensor::read_by_id(storage.as_ref(), sensor_id)
+        .await
+        .map_err(HandlerError::from)?;
     Ok(Json(sensor))
 }
 
 #[instrument]
 pub async fn insert_sensor(
-    State(pool): State<PgPool>,
+    State(storage): State<Arc<dyn Storage>>,
     Json(sensor): Json<NewSensor>,
 ) -> Result<String, HandlerError> {
     if sensor.name.is_empty() || sensor.unit.is_empty() {
-        return Err(HandlerError::new(400, "Invalid input".to_string()));
+        return Err(HandlerError::BadRequest("Invalid input".to_string()));
     }
-    sensor.insert(&pool).await.map_err(|e| {
-        warn!("Failed with error: {}", e);
-        HandlerError::new(500, format!("Failed to store data in database: {e}"))
-    })?;
+    sensor
+        .insert(storage.as_ref())
+        .await
+        .map_err(HandlerError::from)?;
     Ok("OK".to_string())
 }
 
 #[instrument]
 pub async fn delete_sensor(
-    State(pool): State<PgPool>,
+    State((storage, resolver)): State<(Arc<dyn Storage>, Resolver)>,
     Json(sensor): Json<Sensor>,
 ) -> Result<String, HandlerError> {
     if sensor.name.is_empty() || sensor.unit.is_empty() {
-        return Err(HandlerError::new(400, "Invalid input".to_string()));
+        return Err(HandlerError::BadRequest("Invalid input".to_string()));
     }
-    sensor.delete(&pool).await.map_err(|e| {
-        warn!("Failed with error: {}", e);
-        HandlerError::new(500, format!("Failed to store data in database: {e}"))
-    })?;
+    sensor
+        .delete(storage.as_ref())
+        .await
+        .map_err(HandlerError::from)?;
+    resolver.invalidate();
     Ok("OK".to_string())
 }
 
 #[instrument]
 pub async fn update_sensor(
-    State(pool): State<PgPool>,
+    State((storage, resolver)): State<(Arc<dyn Storage>, Resolver)>,
     Json(sensor): Json<Sensor>,
 ) -> Result<String, HandlerError> {
     if sensor.name.is_empty() || sensor.unit.is_empty() {
-        return Err(HandlerError::new(400, "Invalid input".to_string()));
+        return Err(HandlerError::BadRequest("Invalid input".to_string()));
     }
-    sensor.update(&pool).await.map_err(|e| {
-        warn!("Failed with error: {}", e);
-        HandlerError::new(500, format!("Failed to store data in database: {e}"))
-    })?;
+    sensor
+        .update(storage.as_ref())
+        .await
+        .map_err(HandlerError::from)?;
+    resolver.invalidate();
     Ok("OK".to_string())
 }
 
 #[instrument]
 pub async fn fetch_sensors_by_device_id(
-    State(pool): State<PgPool>,
+    State(storage): State<Arc<dyn Storage>>,
     Path(device_id): Path<i32>,
 ) -> Result<Json<Vec<Sensor>>, HandlerError> {
-    let sensors = Sensor::read_by_device_id(&pool, device_id)
+    let sensors = Sensor::read_by_device_id(storage.as_ref(), device_id)
         .await
-        .map_err(|e| {
-            warn!("Failed with error: {}", e);
-            HandlerError::new(500, format!("Failed to fetch data from database: {e}"))
-        })?;
+        .map_err(HandlerError::from)?;
     Ok(Json(sensors))
 }
 
 #[cfg(test)]
 mod tests {
+    use axum::http::StatusCode;
+
     use super::*;
+    use crate::storage::storage_tests;
 
-    #[sqlx::test]
-    async fn should_insert_sensor(pool: PgPool) {
+    async fn should_insert_sensor(storage: Arc<dyn Storage>) {
         let sensor = NewSensor {
             name: "Temperature".to_string(),
             unit: "Celsius".to_string(),
         };
 
-        let result = insert_sensor(State(pool), Json(sensor)).await;
+        let result = insert_sensor(State(storage), Json(sensor)).await;
         assert!(result.is_ok());
         assert_eq!(result.unwrap(), "OK".to_string());
     }
 
-    #[sqlx::test]
-    async fn should_fetch_sensors(pool: PgPool) {
+    async fn should_fetch_sensors(storage: Arc<dyn Storage>) {
         let sensor = NewSensor {
             name: "Humidity".to_string(),
             unit: "Percent".to_string(),
         };
-        sensor.insert(&pool).await.unwrap();
+        sensor.insert(storage.as_ref()).await.unwrap();
 
-        let result = fetch_sensors(State(pool)).await;
+        let result = fetch_sensors(State(storage)).await;
         assert!(result.is_ok());
         let sensors = result.unwrap().0;
         assert!(!sensors.is_empty());
@@ -121,31 +126,30 @@ mod tests {
         assert_eq!(sensors[0].unit, "Percent");
     }
 
-    #[sqlx::test]
-    async fn should_delete_sensor(pool: PgPool) {
+    async fn should_delete_sensor(storage: Arc<dyn Storage>) {
         let sensor = NewSensor {
             name: "Pressure".to_string(),
             unit: "Pascal".to_string(),
         };
-        sensor.insert(&pool).await.unwrap();
+        sensor.insert(storage.as_ref()).await.unwrap();
 
-        let sensors = Sensor::read(&pool).await.unwrap();
+        let sensors = Sensor::read(storage.as_ref()).await.unwrap();
         assert!(!sensors.is_empty());
 
-        let result = delete_sensor(State(pool), Json(sensors[0].clone())).await;
+        let resolver = Resolver::new(storage.clone(), false);
+        let result = delete_sensor(State((storage, resolver)), Json(sensors[0].clone())).await;
         assert!(result.is_ok());
         assert_eq!(result.unwrap(), "OK".to_string());
     }
 
-    #[sqlx::test]
-    async fn should_update_sensor(pool: PgPool) {
+    async fn should_update_sensor(storage: Arc<dyn Storage>) {
         let sensor = NewSensor {
             name: "Light".to_string(),
             unit: "Lux".to_string(),
         };
-        sensor.insert(&pool).await.unwrap();
+        sensor.insert(storage.as_ref()).await.unwrap();
 
-        let sensors = Sensor::read(&pool).await.unwrap();
+        let sensors = Sensor::read(storage.as_ref()).await.unwrap();
         assert!(!sensors.is_empty());
 
         let updated_sensor = Sensor::new(
@@ -153,11 +157,30 @@ mod tests {
             "Updated Light".to_string(),
             "Updated Lux".to_string(),
         );
-        let result = update_sensor(State(pool.clone()), Json(updated_sensor)).await;
+        let resolver = Resolver::new(storage.clone(), false);
+        let result = update_sensor(State((storage.clone(), resolver)), Json(updated_sensor)).await;
         assert!(result.is_ok());
         assert_eq!(result.unwrap(), "OK".to_string());
 
-        let sensors_after_update = Sensor::read(&pool).await.unwrap();
+        let sensors_after_update = Sensor::read(storage.as_ref()).await.unwrap();
         assert_eq!(sensors_after_update[0].name, "Updated Light");
     }
+
+    async fn should_conflict_on_duplicate_sensor(storage: Arc<dyn Storage>) {
+        let sensor = NewSensor::new("Temperature".to_string(), "Celsius".to_string());
+        insert_sensor(State(storage.clone()), Json(sensor.clone()))
+            .await
+            .unwrap();
+
+        let result = insert_sensor(State(storage), Json(sensor)).await;
+        assert_eq!(result.unwrap_err().status(), StatusCode::CONFLICT);
+    }
+
+    storage_tests!(
+        should_insert_sensor,
+        should_fetch_sensors,
+        should_delete_sensor,
+        should_update_sensor,
+        should_conflict_on_duplicate_sensor
+    );
 }
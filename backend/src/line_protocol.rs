@@ -0,0 +1,389 @@
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+
+use crate::{devices::NewDevice, measurements::NamedMeasurement, sensors::NewSensor};
+
+/// Tags naming the device of a point, in order of preference. Telegraf sets `host`
+const DEVICE_TAGS: [&str; 2] = ["device", "host"];
+const LOCATION_TAG: &str = "location";
+/// Unit of sensors created for the fields of a point
+const UNIT_TAG: &str = "unit";
+/// Field stored under the measurement name, as in `temperature,device=esp32 value=21.5`
+const VALUE_FIELD: &str = "value";
+
+/// Unit of line protocol timestamps, given in the `precision` query parameter. Accepts the
+/// values of both the InfluxDB v1 and v2 write APIs
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub enum Precision {
+    #[default]
+    #[serde(rename = "ns", alias = "n")]
+    Nanoseconds,
+    #[serde(rename = "us", alias = "u")]
+    Microseconds,
+    #[serde(rename = "ms")]
+    Milliseconds,
+    #[serde(rename = "s")]
+    Seconds,
+    #[serde(rename = "m")]
+    Minutes,
+    #[serde(rename = "h")]
+    Hours,
+}
+
+impl Precision {
+    fn nanoseconds(self) -> i64 {
+        match self {
+            Precision::Nanoseconds => 1,
+            Precision::Microseconds => 1_000,
+            Precision::Milliseconds => 1_000_000,
+            Precision::Seconds => 1_000_000_000,
+            Precision::Minutes => 60_000_000_000,
+            Precision::Hours => 3_600_000_000_000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FieldValue {
+    Float(f64),
+    Integer(i64),
+    UInteger(u64),
+    Boolean(bool),
+    String(String),
+}
+
+impl FieldValue {
+    /// The value as a measurement, strings have none
+    pub fn as_measurement(&self) -> Option<f32> {
+        match self {
+            FieldValue::Float(value) => Some(*value as f32),
+            FieldValue::Integer(value) => Some(*value as f32),
+            FieldValue::UInteger(value) => Some(*value as f32),
+            FieldValue::Boolean(value) => Some(if *value { 1.0 } else { 0.0 }),
+            FieldValue::String(_) => None,
+        }
+    }
+}
+
+/// A single line of InfluxDB line protocol,
+/// `measurement[,tag=value...] field=value[,field=value...] [timestamp]`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Point {
+    pub measurement: String,
+    pub tags: Vec<(String, String)>,
+    pub fields: Vec<(String, FieldValue)>,
+    pub timestamp: Option<DateTime<Utc>>,
+}
+
+/// Byte offset of the first `separator` that is not escaped, nor with `quoted` inside a
+/// double-quoted string
+fn find_unescaped(s: &str, separator: char, quoted: bool) -> Option<usize> {
+    let mut escaped = false;
+    let mut in_string = false;
+    for (i, c) in s.char_indices() {
+        if escaped {
+            escaped = false;
+            continue;
+        }
+        match c {
+            '\\' => escaped = true,
+            '"' if quoted => in_string = !in_string,
+            c if c == separator && !in_string => return Some(i),
+            _ => {}
+        }
+    }
+    None
+}
+
+fn split_once(s: &str, separator: char, quoted: bool) -> Option<(&str, &str)> {
+    find_unescaped(s, separator, quoted).map(|i| (&s[..i], &s[i + 1..]))
+}
+
+fn split(s: &str, separator: char, quoted: bool) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut rest = s;
+    while let Some((part, tail)) = split_once(rest, separator, quoted) {
+        parts.push(part);
+        rest = tail;
+    }
+    parts.push(rest);
+    parts
+}
+
+/// Removes the backslash before any of `escapable`, other backslashes are kept
+fn unescape(s: &str, escapable: &[char]) -> String {
+    let mut unescaped = String::with_capacity(s.len());
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            if let Some(&next) = chars.peek() {
+                if escapable.contains(&next) {
+                    unescaped.push(next);
+                    chars.next();
+                    continue;
+                }
+            }
+        }
+        unescaped.push(c);
+    }
+    unescaped
+}
+
+fn unescape_key(s: &str) -> String {
+    unescape(s, &[',', '=', ' '])
+}
+
+fn parse_field_value(value: &str) -> Result<FieldValue, String> {
+    if let Some(string) = value
+        .strip_prefix('"')
+        .and_then(|value| value.strip_suffix('"'))
+    {
+        return Ok(FieldValue::String(unescape(string, &['"', '\\'])));
+    }
+    match value {
+        "t" | "T" | "true" | "True" | "TRUE" => return Ok(FieldValue::Boolean(true)),
+        "f" | "F" | "false" | "False" | "FALSE" => return Ok(FieldValue::Boolean(false)),
+        _ => {}
+    }
+    if let Some(integer) = value.strip_suffix('i') {
+        return integer
+            .parse()
+            .map(FieldValue::Integer)
+            .map_err(|_| format!("invalid integer {}", value));
+    }
+    if let Some(integer) = value.strip_suffix('u') {
+        return integer
+            .parse()
+            .map(FieldValue::UInteger)
+            .map_err(|_| format!("invalid unsigned integer {}", value));
+    }
+    match value.parse::<f64>() {
+        Ok(float) if float.is_finite() => Ok(FieldValue::Float(float)),
+        _ => Err(format!("invalid field value {}", value)),
+    }
+}
+
+fn parse_timestamp(timestamp: &str, precision: Precision) -> Result<DateTime<Utc>, String> {
+    timestamp
+        .parse::<i64>()
+        .ok()
+        .and_then(|timestamp| timestamp.checked_mul(precision.nanoseconds()))
+        .map(DateTime::from_timestamp_nanos)
+        .ok_or_else(|| format!("invalid timestamp {}", timestamp))
+}
+
+impl Point {
+    /// Parses a single line, the error describes what is wrong with it
+    pub fn parse(line: &str, precision: Precision) -> Result<Point, String> {
+        let (series, rest) = split_once(line, ' ', false).ok_or("missing fields")?;
+        let mut series = split(series, ',', false).into_iter();
+        let measurement = unescape(series.next().unwrap_or_default(), &[',', ' ']);
+        if measurement.is_empty() {
+            return Err("missing measurement".to_string());
+        }
+        let tags = series
+            .map(|tag| {
+                let (key, value) = split_once(tag, '=', false)
+                    .filter(|(key, value)| !key.is_empty() && !value.is_empty())
+                    .ok_or_else(|| format!("invalid tag {}", tag))?;
+                Ok((unescape_key(key), unescape_key(value)))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+
+        let (fields, timestamp) = match split_once(rest, ' ', true) {
+            Some((fields, timestamp)) => (fields, Some(timestamp.trim())),
+            None => (rest, None),
+        };
+        if fields.is_empty() {
+            return Err("missing fields".to_string());
+        }
+        let fields = split(fields, ',', true)
+            .into_iter()
+            .map(|field| {
+                let (key, value) = split_once(field, '=', true)
+                    .filter(|(key, _)| !key.is_empty())
+                    .ok_or_else(|| format!("invalid field {}", field))?;
+                Ok((unescape_key(key), parse_field_value(value)?))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        let timestamp = timestamp
+            .filter(|timestamp| !timestamp.is_empty())
+            .map(|timestamp| parse_timestamp(timestamp, precision))
+            .transpose()?;
+
+        Ok(Point {
+            measurement,
+            tags,
+            fields,
+            timestamp,
+        })
+    }
+
+    fn tag(&self, key: &str) -> Option<&str> {
+        self.tags
+            .iter()
+            .find(|(tag, _)| tag == key)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// One measurement per numeric field, of the device named by the `device` (or `host`)
+    /// and `location` tags. Fields map to sensors of the same name, except `value`, which
+    /// is stored under the measurement name
+    pub fn into_named(self) -> Result<Vec<NamedMeasurement>, String> {
+        let name = DEVICE_TAGS
+            .iter()
+            .find_map(|tag| self.tag(tag))
+            .ok_or("missing device tag")?;
+        let location = self.tag(LOCATION_TAG).ok_or("missing location tag")?;
+        let device = NewDevice {
+            name: name.to_string(),
+            location: location.to_string(),
+        };
+        let unit = self.tag(UNIT_TAG).unwrap_or_default().to_string();
+
+        let measurements: Vec<NamedMeasurement> = self
+            .fields
+            .iter()
+            .filter_map(|(key, value)| {
+                let sensor = if key == VALUE_FIELD {
+                    &self.measurement
+                } else {
+                    key
+                };
+                Some(NamedMeasurement {
+                    timestamp: self.timestamp,
+                    device: device.clone(),
+                    sensor: NewSensor {
+                        name: sensor.clone(),
+                        unit: unit.clone(),
+                    },
+                    measurement: value.as_measurement()?,
+                })
+            })
+            .collect();
+        if measurements.is_empty() {
+            return Err("no numeric fields".to_string());
+        }
+        Ok(measurements)
+    }
+}
+
+/// The lines of a request body carrying points, skipping blank lines and comments
+pub fn lines(body: &str) -> impl Iterator<Item = &str> {
+    body.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_parse_points() {
+        let point = Point::parse(
+            r#"weather,device=esp32,location=Attic temperature=21.5,count=3i,ok=t,note="a \"b\", c" 1700000000"#,
+            Precision::Seconds,
+        )
+        .unwrap();
+        assert_eq!(point.measurement, "weather");
+        assert_eq!(
+            point.tags,
+            vec![
+                ("device".to_string(), "esp32".to_string()),
+                ("location".to_string(), "Attic".to_string())
+            ]
+        );
+        assert_eq!(
+            point.fields,
+            vec![
+                ("temperature".to_string(), FieldValue::Float(21.5)),
+                ("count".to_string(), FieldValue::Integer(3)),
+                ("ok".to_string(), FieldValue::Boolean(true)),
+                (
+                    "note".to_string(),
+                    FieldValue::String(r#"a "b", c"#.to_string())
+                ),
+            ]
+        );
+        assert_eq!(point.timestamp.unwrap().timestamp(), 1_700_000_000);
+
+        let point = Point::parse(
+            r"air\ quality,location=Living\ room,device=a\,b pm2\=5=7u",
+            Precision::Nanoseconds,
+        )
+        .unwrap();
+        assert_eq!(point.measurement, "air quality");
+        assert_eq!(point.tag("location"), Some("Living room"));
+        assert_eq!(point.tag("device"), Some("a,b"));
+        assert_eq!(
+            point.fields,
+            vec![("pm2=5".to_string(), FieldValue::UInteger(7))]
+        );
+        assert_eq!(point.timestamp, None);
+
+        let point = Point::parse("cpu value=1 1700000000123", Precision::Milliseconds).unwrap();
+        assert_eq!(
+            point.timestamp.unwrap().timestamp_millis(),
+            1_700_000_000_123
+        );
+    }
+
+    #[test]
+    fn should_reject_invalid_lines() {
+        for line in [
+            "cpu",
+            "cpu ",
+            ",device=esp32 value=1",
+            "cpu,device value=1",
+            "cpu value=",
+            "cpu value=abc",
+            "cpu value=1.5i",
+            "cpu =1",
+            "cpu value=1 soon",
+            "cpu value=1 99999999999999999999",
+        ] {
+            assert!(
+                Point::parse(line, Precision::Nanoseconds).is_err(),
+                "{} should be rejected",
+                line
+            );
+        }
+        assert!(Point::parse("cpu value=1 9999999999999", Precision::Hours).is_err());
+    }
+
+    #[test]
+    fn should_map_points_to_named_measurements() {
+        let point = Point::parse(
+            "temperature,host=esp32,location=Attic,unit=°C value=21.5,humidity=40i,label=\"x\"",
+            Precision::Nanoseconds,
+        )
+        .unwrap();
+        let named = point.into_named().unwrap();
+        assert_eq!(named.len(), 2);
+        assert_eq!(named[0].device.name, "esp32");
+        assert_eq!(named[0].device.location, "Attic");
+        assert_eq!(named[0].sensor.name, "temperature");
+        assert_eq!(named[0].sensor.unit, "°C");
+        assert_eq!(named[0].measurement, 21.5);
+        assert_eq!(named[1].sensor.name, "humidity");
+        assert_eq!(named[1].measurement, 40.0);
+
+        let missing_location = Point::parse("cpu,host=pi usage=3", Precision::Nanoseconds);
+        assert!(missing_location.unwrap().into_named().is_err());
+        let only_strings = Point::parse(
+            "cpu,host=pi,location=Attic state=\"on\"",
+            Precision::Nanoseconds,
+        );
+        assert!(only_strings.unwrap().into_named().is_err());
+    }
+
+    #[test]
+    fn should_skip_blank_lines_and_comments() {
+        let body = "# comment\n\ncpu value=1\r\n  \ncpu value=2\n";
+        assert_eq!(
+            lines(body).collect::<Vec<_>>(),
+            ["cpu value=1", "cpu value=2"]
+        );
+    }
+}
@@ -1,23 +1,61 @@
 use measurements::Measurement;
 use metrics_exporter_prometheus::PrometheusBuilder;
 use moka::future::Cache;
-use sqlx::postgres::PgPoolOptions;
+use rumqttc::MqttOptions;
+use sqlx::{
+    postgres::PgPoolOptions,
+    sqlite::{SqliteConnectOptions, SqlitePoolOptions},
+    PgPool,
+};
 use structopt::StructOpt;
-use tokio::{net::TcpListener, sync::mpsc::channel};
+use tokio::{
+    net::TcpListener,
+    sync::{broadcast, mpsc::channel},
+};
 use tracing::{info, Level};
 use tracing_subscriber::FmtSubscriber;
 
+use std::{path::PathBuf, str::FromStr, sync::Arc};
+
 use crate::{
-    background_tasks::{handle_insert_measurement_bg_thread, refresh_views, update_metrics},
-    handlers::create_router,
+    api_keys::ApiKeyCommand,
+    archive::ArchiveCommand,
+    background_tasks::{
+        detect_stale_devices, evaluate_alerts, handle_insert_measurement_bg_thread,
+        manage_partitions, prune_measurements, refresh_views, update_metrics, update_rollups,
+    },
+    handlers::{create_router, AuthOptions, Ingest},
     measurements::NewMeasurement,
+    migrations::MigrateCommand,
+    mqtt::{mqtt_listener, topic_filters, TopicMapping},
+    prometheus::PrometheusLabels,
+    provisioning::Resolver,
+    queue::MeasurementQueue,
+    spool::Spool,
+    storage::{MemoryStorage, Storage, SQLITE_MIGRATOR},
 };
 
+mod alerts;
+mod api_keys;
+mod archive;
 mod background_tasks;
+mod demo;
 mod devices;
+mod export;
+mod grafana;
 mod handlers;
+mod line_protocol;
 mod measurements;
+mod migrations;
+mod mqtt;
+mod partitions;
+mod prometheus;
+mod provisioning;
+mod queue;
+mod rollups;
 mod sensors;
+mod spool;
+mod storage;
 
 #[derive(Debug, Clone)]
 enum LogLevel {
@@ -48,6 +86,7 @@ pub struct Opts {
     #[structopt(short, long, default_value = "0.0.0.0:65534")]
     host: String,
 
+    /// Postgres or SQLite (`sqlite://hemrs.db`) database. Alerts, rollups and partitioning need Postgres
     #[structopt(
         short,
         long,
@@ -58,6 +97,149 @@ pub struct Opts {
 
     #[structopt(short, long, default_value = "info")]
     log_level: LogLevel,
+
+    /// Serve generated demo data from memory, without any database
+    #[structopt(long)]
+    demo: bool,
+
+    /// Seconds between generated demo measurements, at least 1
+    #[structopt(long, default_value = "10")]
+    demo_interval_secs: u64,
+
+    /// Require an API key on every request, create the first one with `keys create`
+    #[structopt(long)]
+    auth: bool,
+
+    /// Serve /metrics without an API key when --auth is set
+    #[structopt(long)]
+    public_metrics: bool,
+
+    /// Apply pending migrations before serving
+    #[structopt(long, env = "MIGRATE")]
+    migrate: bool,
+
+    #[structopt(subcommand)]
+    command: Option<Command>,
+
+    /// MQTT broker to subscribe to, the listener is disabled if not set
+    #[structopt(long, env = "MQTT_HOST")]
+    mqtt_host: Option<String>,
+
+    #[structopt(long, env = "MQTT_PORT", default_value = "1883")]
+    mqtt_port: u16,
+
+    #[structopt(long, default_value = "hemrs")]
+    mqtt_client_id: String,
+
+    /// Topic filter carrying JSON measurements, may be repeated
+    #[structopt(long = "mqtt-topic")]
+    mqtt_topics: Vec<String>,
+
+    /// Topic filter carrying a bare value, as topic=device:sensor, may be repeated
+    #[structopt(long = "mqtt-map")]
+    mqtt_mappings: Vec<TopicMapping>,
+
+    /// Create unknown devices and sensors of measurements posted by name
+    #[structopt(long)]
+    auto_provision: bool,
+
+    /// Label naming the device of Prometheus remote write series
+    #[structopt(long, default_value = "device_name")]
+    prom_device_label: String,
+
+    /// Label naming the location of the device of Prometheus remote write series
+    #[structopt(long, default_value = "device_location")]
+    prom_location_label: String,
+
+    /// Label naming the sensor of Prometheus remote write series, falls back to the metric name
+    #[structopt(long, default_value = "sensor_name")]
+    prom_sensor_label: String,
+
+    /// Label carrying the unit of sensors created for Prometheus remote write series
+    #[structopt(long, default_value = "unit")]
+    prom_unit_label: String,
+
+    /// Maximum number of measurements written per insert
+    #[structopt(long, default_value = "1024")]
+    insert_batch_size: usize,
+
+    /// Maximum time a measurement waits for its batch to fill up
+    #[structopt(long, default_value = "500")]
+    insert_flush_interval_ms: u64,
+
+    /// Directory for the write-ahead log of accepted measurements, disabled if not set
+    #[structopt(long, env = "SPOOL_DIR", parse(from_os_str))]
+    spool_dir: Option<PathBuf>,
+
+    /// URL alert notifications are posted to when a rule fires or resolves
+    #[structopt(long, env = "ALERT_WEBHOOK_URL")]
+    alert_webhook_url: Option<String>,
+
+    #[structopt(long, default_value = "30")]
+    alert_interval_secs: u64,
+
+    /// Devices not reporting for this long are marked offline, unless they have their own interval
+    #[structopt(long, default_value = "300")]
+    stale_after_secs: i64,
+
+    #[structopt(long, default_value = "30")]
+    stale_check_interval_secs: u64,
+
+    /// Days raw measurements are kept, unless the sensor has its own retention. Kept forever if not set
+    #[structopt(long, env = "RETENTION_DAYS")]
+    retention_days: Option<i64>,
+
+    /// Only prune measurements once they are rolled up, so their hourly and daily rollups are kept
+    #[structopt(long)]
+    retention_rollup: bool,
+
+    /// Maximum number of measurements deleted per statement while pruning
+    #[structopt(long, default_value = "5000")]
+    retention_batch_size: i64,
+
+    #[structopt(long, default_value = "3600")]
+    retention_interval_secs: u64,
+
+    /// Maximum number of measurements rolled up per transaction
+    #[structopt(long, default_value = "50000")]
+    rollup_batch_size: i64,
+
+    #[structopt(long, default_value = "60")]
+    rollup_interval_secs: u64,
+
+    /// Number of monthly measurement partitions created ahead of time
+    #[structopt(long, default_value = "3")]
+    partition_ahead_months: u32,
+
+    /// Partitions older than this many months are dropped once rolled up. Kept forever if not set
+    #[structopt(long, env = "PARTITION_KEEP_MONTHS")]
+    partition_keep_months: Option<u32>,
+
+    /// Detach old partitions instead of dropping them, so they can be archived
+    #[structopt(long)]
+    partition_detach: bool,
+
+    #[structopt(long, default_value = "3600")]
+    partition_interval_secs: u64,
+}
+
+#[derive(Debug, Clone, StructOpt)]
+enum Command {
+    /// Manages the database schema
+    Migrate {
+        #[structopt(subcommand)]
+        command: MigrateCommand,
+    },
+    /// Manages API keys
+    Keys {
+        #[structopt(subcommand)]
+        command: ApiKeyCommand,
+    },
+    /// Exports measurements to and imports them from Parquet files
+    Archive {
+        #[structopt(subcommand)]
+        command: ArchiveCommand,
+    },
 }
 
 impl From<LogLevel> for Level {
@@ -86,37 +268,238 @@ async fn main() -> Result<(), anyhow::Error> {
         .install_recorder()
         .expect("failed to install recorder/exporter");
 
-    info!("Connecting to DB at {}", opts.db_url);
-    let connection = PgPoolOptions::new().connect(&opts.db_url).await.unwrap();
+    let (storage, postgres): (Arc<dyn Storage>, Option<PgPool>) = if opts.demo {
+        if opts.command.is_some() {
+            return Err(anyhow::anyhow!("Commands need a database"));
+        }
+        info!("Running in demo mode, nothing is stored");
+        let storage = MemoryStorage::default();
+        demo::seed(&storage, chrono::Utc::now()).await?;
+        (Arc::new(storage), None)
+    } else {
+        info!("Connecting to DB at {}", opts.db_url);
+        if opts.db_url.starts_with("sqlite:") {
+            if let Some(Command::Migrate { .. }) = opts.command {
+                return Err(anyhow::anyhow!(
+                    "SQLite databases are migrated on startup, the migrate command needs Postgres"
+                ));
+            }
+            let options = SqliteConnectOptions::from_str(&opts.db_url)?.create_if_missing(true);
+            let connection = SqlitePoolOptions::new().connect_with(options).await?;
+            SQLITE_MIGRATOR.run(&connection).await?;
+            (Arc::new(connection), None)
+        } else {
+            let connection = PgPoolOptions::new().connect(&opts.db_url).await.unwrap();
+            if let Some(Command::Migrate { command }) = opts.command.clone() {
+                return migrations::run_command(&connection, command).await;
+            }
+            if opts.migrate {
+                migrations::run(&connection).await?;
+            }
+            migrations::check(&connection, false).await?;
+            (Arc::new(connection.clone()), Some(connection))
+        }
+    };
+
+    if let Some(Command::Keys { command }) = opts.command.clone() {
+        return api_keys::run_command(storage.as_ref(), command).await;
+    }
+    if let Some(Command::Archive { command }) = opts.command.clone() {
+        return archive::run_command(storage, command).await;
+    }
 
     let measurement_cache: Cache<(i32, i32), Measurement> = Cache::builder()
         .max_capacity(128)
         .time_to_live(std::time::Duration::from_secs(60))
         .build();
 
-    let bg_pool = connection.clone();
+    let bg_storage = storage.clone();
     let measurement_cache_bg = measurement_cache.clone();
 
     tokio::spawn(async move {
-        update_metrics(&bg_pool, &measurement_cache_bg).await;
+        update_metrics(bg_storage.as_ref(), &measurement_cache_bg).await;
     });
 
     let (tx, rx) = channel::<NewMeasurement>(1 << 13);
 
-    let insert_pool = connection.clone();
+    let insert_storage = storage.clone();
     let insert_cache = measurement_cache.clone();
 
+    let batch_size = opts.insert_batch_size.max(1);
+    let flush_interval = std::time::Duration::from_millis(opts.insert_flush_interval_ms);
+
+    let (events, _) = broadcast::channel(1 << 10);
+    let insert_events = events.clone();
+
+    let (spool, pending) = match &opts.spool_dir {
+        Some(dir) => {
+            info!("Opening spool at {:?}", dir);
+            let (spool, pending) = Spool::open(dir)?;
+            (Some(Arc::new(spool)), pending)
+        }
+        None => (None, Vec::new()),
+    };
+    let insert_spool = spool.clone();
+
     tokio::spawn(async move {
-        handle_insert_measurement_bg_thread(rx, insert_pool, insert_cache).await;
+        handle_insert_measurement_bg_thread(
+            rx,
+            insert_storage,
+            insert_cache,
+            batch_size,
+            flush_interval,
+            insert_spool,
+            insert_events,
+        )
+        .await;
     });
 
-    let refresh_pool = connection.clone();
+    // The insert worker reads them back from the spool before any new ones
+    if !pending.is_empty() {
+        info!("Replaying {} spooled measurements", pending.len());
+    }
+    let queue = MeasurementQueue::new(tx, spool);
+
+    if opts.demo {
+        let demo_storage = storage.clone();
+        let demo_queue = queue.clone();
+        let demo_interval = std::time::Duration::from_secs(opts.demo_interval_secs.max(1));
+
+        tokio::spawn(async move {
+            demo::generate_measurements(demo_storage.as_ref(), demo_queue, demo_interval).await;
+        });
+    }
+
+    if let Some(mqtt_host) = opts.mqtt_host {
+        let mqtt_filters = topic_filters(&opts.mqtt_topics, &opts.mqtt_mappings)?;
+        info!(
+            "Connecting to MQTT broker at {}:{}",
+            mqtt_host, opts.mqtt_port
+        );
+        let mut mqtt_options = MqttOptions::new(opts.mqtt_client_id, mqtt_host, opts.mqtt_port);
+        mqtt_options.set_keep_alive(std::time::Duration::from_secs(30));
+        let mqtt_queue = queue.clone();
+
+        tokio::spawn(mqtt_listener(
+            mqtt_options,
+            mqtt_filters,
+            opts.mqtt_mappings,
+            mqtt_queue,
+        ));
+    }
+
+    let stale_storage = storage.clone();
+    let stale_cache = measurement_cache.clone();
+    let stale_after = chrono::Duration::seconds(opts.stale_after_secs);
+    let stale_check_interval = std::time::Duration::from_secs(opts.stale_check_interval_secs);
 
     tokio::spawn(async move {
-        refresh_views(&refresh_pool).await.unwrap();
+        detect_stale_devices(
+            stale_storage.as_ref(),
+            &stale_cache,
+            stale_after,
+            stale_check_interval,
+        )
+        .await;
     });
 
-    let app = create_router(connection, metrics_handler, measurement_cache, tx);
+    let prune_storage = storage.clone();
+    let retention = opts
+        .retention_days
+        .map(|days| {
+            chrono::Duration::try_days(days)
+                .filter(|retention| *retention > chrono::Duration::zero())
+                .ok_or_else(|| anyhow::anyhow!("Invalid retention of {} days", days))
+        })
+        .transpose()?;
+    let prune_batch_size = opts.retention_batch_size.max(1);
+    let prune_interval = std::time::Duration::from_secs(opts.retention_interval_secs);
+
+    tokio::spawn(async move {
+        prune_measurements(
+            prune_storage.as_ref(),
+            retention,
+            prune_batch_size,
+            opts.retention_rollup,
+            prune_interval,
+        )
+        .await;
+    });
+
+    let refresh_storage = storage.clone();
+
+    tokio::spawn(async move {
+        refresh_views(refresh_storage.as_ref()).await.unwrap();
+    });
+
+    if let Some(pool) = &postgres {
+        let alert_pool = pool.clone();
+        let alert_cache = measurement_cache.clone();
+        let alert_interval = std::time::Duration::from_secs(opts.alert_interval_secs);
+        let alert_stale_after = chrono::Duration::seconds(opts.stale_after_secs);
+        let alert_client = reqwest::Client::builder()
+            .timeout(std::time::Duration::from_secs(10))
+            .build()?;
+
+        tokio::spawn(async move {
+            evaluate_alerts(
+                &alert_pool,
+                &alert_cache,
+                alert_client,
+                opts.alert_webhook_url,
+                alert_stale_after,
+                alert_interval,
+            )
+            .await;
+        });
+
+        let rollup_pool = pool.clone();
+        let rollup_batch_size = opts.rollup_batch_size.max(1);
+        let rollup_interval = std::time::Duration::from_secs(opts.rollup_interval_secs);
+
+        tokio::spawn(async move {
+            update_rollups(&rollup_pool, rollup_batch_size, rollup_interval).await;
+        });
+
+        let partition_pool = pool.clone();
+        let partition_interval = std::time::Duration::from_secs(opts.partition_interval_secs);
+
+        tokio::spawn(async move {
+            manage_partitions(
+                &partition_pool,
+                opts.partition_ahead_months,
+                opts.partition_keep_months,
+                opts.partition_detach,
+                partition_interval,
+            )
+            .await;
+        });
+    }
+
+    let auth = AuthOptions {
+        enabled: opts.auth,
+        public_metrics: opts.public_metrics,
+    };
+    let resolver = Resolver::new(storage.clone(), opts.auto_provision);
+    let prometheus = PrometheusLabels {
+        device: opts.prom_device_label,
+        location: opts.prom_location_label,
+        sensor: opts.prom_sensor_label,
+        unit: opts.prom_unit_label,
+    };
+    let app = create_router(
+        storage,
+        postgres,
+        auth,
+        metrics_handler,
+        measurement_cache,
+        Ingest {
+            queue,
+            resolver,
+            prometheus,
+        },
+        events,
+    );
 
     let listener = TcpListener::bind(&opts.host).await.unwrap();
     axum::serve(listener, app).await.unwrap();
@@ -1,8 +1,11 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use chrono::{DateTime, Utc};
-use serde::{Deserialize, Serialize};
-use sqlx::{FromRow, PgPool};
-use std::fmt;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use sqlx::FromRow;
+use std::{fmt, str::FromStr};
+use tokio::sync::mpsc;
+
+use crate::{devices::NewDevice, sensors::NewSensor, storage::Storage};
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct NewMeasurement {
@@ -22,36 +25,39 @@ impl NewMeasurement {
         }
     }
 
-    pub async fn insert(self, pool: &PgPool) -> Result<()> {
-        match self.timestamp {
-            Some(t) => {
-                sqlx::query("INSERT INTO measurements (ts, device_id, sensor_id, value) VALUES ($1, $2, $3, $4)")
-            .bind(t)
-            .bind(self.device)
-            .bind(self.sensor)
-            .bind(self.measurement)
-            .execute(pool)
-            .await?;
-                Ok(())
-            }
-            None => {
-                sqlx::query("INSERT INTO measurements (ts, device_id, sensor_id, value) VALUES (CURRENT_TIMESTAMP, $1, $2, $3)")
-            .bind(self.device)
-            .bind(self.sensor)
-            .bind(self.measurement)
-            .execute(pool)
-            .await?;
-                Ok(())
-            }
-        }
+    #[cfg(test)]
+    pub async fn insert(self, storage: &dyn Storage) -> Result<()> {
+        storage.insert_measurements(&[self]).await?;
+        Ok(())
+    }
+
+    /// Inserts all measurements at once, returns the number of rows inserted
+    pub async fn insert_many(
+        measurements: &[NewMeasurement],
+        storage: &dyn Storage,
+    ) -> Result<u64> {
+        storage.insert_measurements(measurements).await
     }
 }
 
+/// A measurement referencing its device by name and location and its sensor by name,
+/// for devices that do not know their ids. The sensor unit has to match the unit of an
+/// existing sensor, and is used to create missing ones
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct NamedMeasurement {
+    pub timestamp: Option<DateTime<Utc>>,
+    pub device: NewDevice,
+    pub sensor: NewSensor,
+    pub measurement: f32,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(untagged)]
 pub enum NewMeasurements {
     Measurement(NewMeasurement),
     Measurements(Vec<NewMeasurement>),
+    NamedMeasurement(NamedMeasurement),
+    NamedMeasurements(Vec<NamedMeasurement>),
 }
 
 impl fmt::Display for NewMeasurement {
@@ -70,213 +76,953 @@ pub struct Measurement {
     pub sensor_name: String,
 }
 
+/// A measurement stored by the insert worker, published to live subscribers
+#[derive(Debug, Clone, Serialize)]
+pub struct MeasurementEvent {
+    pub device_id: i32,
+    pub sensor_id: i32,
+    #[serde(flatten)]
+    pub measurement: Measurement,
+}
+
+/// Filters for the live measurement stream, unset filters match everything
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct StreamQuery {
+    pub device: Option<i32>,
+    pub sensor: Option<i32>,
+}
+
+impl StreamQuery {
+    pub fn matches(&self, event: &MeasurementEvent) -> bool {
+        self.device.is_none_or(|device| device == event.device_id)
+            && self.sensor.is_none_or(|sensor| sensor == event.sensor_id)
+    }
+}
+
+/// Statistics over a time window, everything but `count` is unset for an empty window
 #[derive(Debug, Clone, Serialize, FromRow)]
 pub struct MeasurementStats {
-    min: f32,
-    max: f32,
+    min: Option<f32>,
+    max: Option<f32>,
     count: i64,
-    avg: f64,
-    stddev: f64,
-    variance: f64,
+    avg: Option<f64>,
+    stddev: Option<f64>,
+    variance: Option<f64>,
+    median: Option<f64>,
+    p5: Option<f64>,
+    p95: Option<f64>,
+    p99: Option<f64>,
+    first: Option<DateTime<Utc>>,
+    last: Option<DateTime<Utc>>,
+    /// Average where each value is weighted by how long it held, until the next measurement
+    time_weighted_avg: Option<f64>,
+}
+
+impl MeasurementStats {
+    /// Computes the statistics from measurements ordered by time, for backends that can not
+    /// do it in the database
+    pub fn from_values(values: &[(DateTime<Utc>, f32)]) -> Self {
+        let count = values.len();
+        let mut sorted: Vec<f64> = values.iter().map(|(_, v)| f64::from(*v)).collect();
+        sorted.sort_by(f64::total_cmp);
+        let percentile = |p: f64| {
+            // Interpolates between the closest ranks, like percentile_cont
+            let rank = p * (count - 1) as f64;
+            let (lower, upper) = (rank.floor() as usize, rank.ceil() as usize);
+            sorted[lower] + (sorted[upper] - sorted[lower]) * (rank - lower as f64)
+        };
+
+        let avg = sorted.iter().sum::<f64>() / count as f64;
+        let variance = (count > 1)
+            .then(|| sorted.iter().map(|v| (v - avg).powi(2)).sum::<f64>() / (count - 1) as f64);
+        let (weighted, held) = values
+            .windows(2)
+            .map(|pair| {
+                let held = (pair[1].0 - pair[0].0).num_microseconds().unwrap_or(0) as f64 / 1e6;
+                (f64::from(pair[0].1) * held, held)
+            })
+            .fold((0.0, 0.0), |(w, h), (weighted, held)| {
+                (w + weighted, h + held)
+            });
+
+        let present = count > 0;
+        Self {
+            min: values.iter().map(|(_, v)| *v).reduce(f32::min),
+            max: values.iter().map(|(_, v)| *v).reduce(f32::max),
+            count: count as i64,
+            avg: present.then_some(avg),
+            stddev: variance.map(f64::sqrt),
+            variance,
+            median: present.then(|| percentile(0.5)),
+            p5: present.then(|| percentile(0.05)),
+            p95: present.then(|| percentile(0.95)),
+            p99: present.then(|| percentile(0.99)),
+            first: values.first().map(|(ts, _)| *ts),
+            last: values.last().map(|(ts, _)| *ts),
+            time_weighted_avg: (held > 0.0).then(|| weighted / held),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct StatsQuery {
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Order {
+    #[default]
+    Asc,
+    Desc,
+}
+
+/// Position after the last measurement of a page, rendered as `<unix micros>_<id>`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cursor {
+    pub timestamp: DateTime<Utc>,
+    pub id: i32,
+}
+
+impl fmt::Display for Cursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}_{}", self.timestamp.timestamp_micros(), self.id)
+    }
+}
+
+impl FromStr for Cursor {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (micros, id) = s.split_once('_').ok_or_else(|| anyhow!("invalid cursor"))?;
+        let timestamp = DateTime::from_timestamp_micros(micros.parse()?)
+            .ok_or_else(|| anyhow!("invalid cursor timestamp"))?;
+        Ok(Self {
+            timestamp,
+            id: id.parse()?,
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for Cursor {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+impl Serialize for Cursor {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Filters accepted by the measurement list endpoints
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct MeasurementQuery {
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    pub limit: Option<i64>,
+    #[serde(default)]
+    pub order: Order,
+    pub cursor: Option<Cursor>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MeasurementPage {
+    pub measurements: Vec<Measurement>,
+    /// Set when the limit was reached, pass it as `cursor` to read the next page
+    pub next: Option<Cursor>,
+}
+
+/// Most buckets an aggregate query may return
+pub const MAX_BUCKETS: i64 = 100_000;
+
+/// Width of an aggregation bucket, parsed from e.g. `30s`, `15m`, `1h`, `1d` or `1w`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Bucket(chrono::Duration);
+
+impl FromStr for Bucket {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let split = s
+            .find(|c: char| !c.is_ascii_digit())
+            .ok_or_else(|| anyhow!("bucket is missing a unit"))?;
+        let (amount, unit) = s.split_at(split);
+        let amount: i64 = amount.parse()?;
+        let duration = match unit {
+            "s" => chrono::Duration::try_seconds(amount),
+            "m" => chrono::Duration::try_minutes(amount),
+            "h" => chrono::Duration::try_hours(amount),
+            "d" => chrono::Duration::try_days(amount),
+            "w" => chrono::Duration::try_weeks(amount),
+            _ => return Err(anyhow!("unknown bucket unit {unit}")),
+        }
+        .ok_or_else(|| anyhow!("bucket {s} is too long"))?;
+        if duration <= chrono::Duration::zero() {
+            return Err(anyhow!("bucket must be positive"));
+        }
+        Ok(Self(duration))
+    }
+}
+
+impl Bucket {
+    /// Picks a bucket giving a reasonable number of points for a range
+    pub fn for_range(range: Option<chrono::Duration>) -> Self {
+        match range {
+            Some(range) if range <= chrono::Duration::days(1) => Self(chrono::Duration::minutes(5)),
+            Some(range) if range <= chrono::Duration::days(31) => Self(chrono::Duration::hours(1)),
+            _ => Self(chrono::Duration::days(1)),
+        }
+    }
+
+    /// The shortest bucket of whole seconds, no shorter than `min`, that splits `range` into
+    /// at most `max_points` buckets
+    pub fn for_max_points(range: chrono::Duration, max_points: i64, min: chrono::Duration) -> Self {
+        let millis = range.num_milliseconds().max(0) as u64;
+        let per_point = millis.div_ceil(max_points.max(1) as u64);
+        let seconds = (per_point.div_ceil(1000) as i64)
+            .max(min.num_seconds())
+            .max(1);
+        Self(chrono::Duration::seconds(seconds))
+    }
+
+    pub fn duration(&self) -> chrono::Duration {
+        self.0
+    }
+}
+
+impl<'de> Deserialize<'de> for Bucket {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AggregateFunction {
+    Avg,
+    Min,
+    Max,
+    Sum,
+    Count,
+    First,
+    Last,
+}
+
+impl AggregateFunction {
+    pub fn as_sql(&self) -> &'static str {
+        match self {
+            AggregateFunction::Avg => "avg(m.value) AS avg",
+            AggregateFunction::Min => "min(m.value) AS min",
+            AggregateFunction::Max => "max(m.value) AS max",
+            AggregateFunction::Sum => "sum(m.value)::float8 AS sum",
+            AggregateFunction::Count => "count(m.value) AS count",
+            AggregateFunction::First => "(array_agg(m.value ORDER BY m.ts))[1] AS first",
+            AggregateFunction::Last => "(array_agg(m.value ORDER BY m.ts DESC))[1] AS last",
+        }
+    }
+
+    /// The function over rollup rows, if it can be derived from them
+    pub fn as_rollup_sql(&self) -> Option<&'static str> {
+        match self {
+            AggregateFunction::Avg => Some("sum(r.sum) / sum(r.count) AS avg"),
+            AggregateFunction::Min => Some("min(r.min) AS min"),
+            AggregateFunction::Max => Some("max(r.max) AS max"),
+            AggregateFunction::Sum => Some("sum(r.sum) AS sum"),
+            AggregateFunction::Count => Some("sum(r.count)::int8 AS count"),
+            AggregateFunction::First | AggregateFunction::Last => None,
+        }
+    }
+}
+
+impl FromStr for AggregateFunction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "avg" => Ok(AggregateFunction::Avg),
+            "min" => Ok(AggregateFunction::Min),
+            "max" => Ok(AggregateFunction::Max),
+            "sum" => Ok(AggregateFunction::Sum),
+            "count" => Ok(AggregateFunction::Count),
+            "first" => Ok(AggregateFunction::First),
+            "last" => Ok(AggregateFunction::Last),
+            _ => Err(anyhow!("unknown aggregate function {s}")),
+        }
+    }
+}
+
+/// Comma separated list of aggregate functions, defaults to `avg`
+#[derive(Debug, Clone, PartialEq)]
+pub struct AggregateFunctions(pub Vec<AggregateFunction>);
+
+impl Default for AggregateFunctions {
+    fn default() -> Self {
+        Self(vec![AggregateFunction::Avg])
+    }
+}
+
+impl<'de> Deserialize<'de> for AggregateFunctions {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        let mut functions = Vec::new();
+        for function in s.split(',').map(str::trim).filter(|f| !f.is_empty()) {
+            let function = function.parse().map_err(serde::de::Error::custom)?;
+            if !functions.contains(&function) {
+                functions.push(function);
+            }
+        }
+        if functions.is_empty() {
+            return Err(serde::de::Error::custom("no aggregate functions given"));
+        }
+        Ok(Self(functions))
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct AggregateQuery {
+    /// Picked from the range if not set
+    pub bucket: Option<Bucket>,
+    #[serde(rename = "fn", default)]
+    pub functions: AggregateFunctions,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+}
+
+impl AggregateQuery {
+    /// Length of the queried range, unbounded if there is no start
+    pub fn range(&self) -> Option<chrono::Duration> {
+        self.from
+            .map(|from| self.to.unwrap_or_else(Utc::now) - from)
+    }
+
+    /// Picked from the range if not set, widened to stay within `MAX_BUCKETS`
+    pub fn bucket(&self) -> Bucket {
+        self.bucket.unwrap_or_else(|| {
+            let bucket = Bucket::for_range(self.range());
+            match self.range() {
+                Some(range) => Bucket::for_max_points(range, MAX_BUCKETS, bucket.duration()),
+                None => bucket,
+            }
+        })
+    }
+
+    /// Rejects buckets splitting the range into more than `MAX_BUCKETS`, and buckets shorter
+    /// than an hour without a start, which would split the whole history
+    pub fn check(&self) -> Result<()> {
+        let Some(range) = self.range() else {
+            if self.bucket().duration() < chrono::Duration::hours(1) {
+                return Err(anyhow!("buckets shorter than 1h require from"));
+            }
+            return Ok(());
+        };
+        let buckets = range.num_seconds() / self.bucket().duration().num_seconds();
+        if buckets > MAX_BUCKETS {
+            return Err(anyhow!(
+                "bucket splits the range into {buckets} buckets, at most {MAX_BUCKETS} are allowed"
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// One time bucket, only the requested aggregates are set
+#[derive(Debug, Clone, Serialize, FromRow)]
+pub struct MeasurementBucket {
+    pub bucket: DateTime<Utc>,
+    #[sqlx(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub avg: Option<f64>,
+    #[sqlx(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min: Option<f32>,
+    #[sqlx(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max: Option<f32>,
+    #[sqlx(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sum: Option<f64>,
+    #[sqlx(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub count: Option<i64>,
+    #[sqlx(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub first: Option<f32>,
+    #[sqlx(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last: Option<f32>,
+}
+
+#[derive(FromRow)]
+pub struct CursorMeasurement {
+    pub id: i32,
+    #[sqlx(flatten)]
+    pub measurement: Measurement,
+}
+
+impl MeasurementPage {
+    /// Builds a page from rows read in order, with a cursor if the limit was reached
+    pub fn new(rows: Vec<CursorMeasurement>, limit: Option<i64>) -> Self {
+        let next = match (limit, rows.last()) {
+            (Some(limit), Some(last)) if rows.len() as i64 >= limit => Some(Cursor {
+                timestamp: last.measurement.timestamp,
+                id: last.id,
+            }),
+            _ => None,
+        };
+        Self {
+            measurements: rows.into_iter().map(|row| row.measurement).collect(),
+            next,
+        }
+    }
 }
 
 impl Measurement {
-    pub async fn read_all_latest_measurements(pool: &PgPool) -> Result<Vec<Measurement>> {
-        let res = sqlx::query_as::<_, Measurement>(
-               "SELECT DISTINCT ON (m.device_id, m.sensor_id) m.ts AS timestamp, m.value, s.unit, d.name AS device_name, d.location AS device_location, s.name AS sensor_name
-                FROM measurements m
-                JOIN devices d ON m.device_id = d.id
-                JOIN sensors s ON m.sensor_id = s.id
-                ORDER BY m.device_id, m.sensor_id, ts DESC",
-        )
-        .fetch_all(pool)
-        .await?;
-        Ok(res)
+    pub async fn read_all_latest_measurements(storage: &dyn Storage) -> Result<Vec<Measurement>> {
+        storage.read_all_latest_measurements().await
     }
 
     pub async fn read_stats_by_device_id_and_sensor_id(
-        pool: &PgPool,
+        storage: &dyn Storage,
         device_id: i32,
         sensor_id: i32,
+        query: &StatsQuery,
     ) -> Result<MeasurementStats> {
-        let res = sqlx::query_as::<_, MeasurementStats>(
-            "SELECT min(value) as min, max(value) as max, count(value) as count, avg(value) as avg, stddev(value) as stddev, variance(value) as variance FROM measurements WHERE device_id = ($1) AND sensor_id = ($2)",
-        )
-        .bind(device_id)
-        .bind(sensor_id)
-        .fetch_one(pool)
-        .await?;
-        Ok(res)
+        storage.read_stats(device_id, sensor_id, query).await
+    }
+
+    pub async fn read_aggregate_by_device_id_and_sensor_id(
+        storage: &dyn Storage,
+        device_id: i32,
+        sensor_id: i32,
+        query: &AggregateQuery,
+    ) -> Result<Vec<MeasurementBucket>> {
+        storage.read_aggregate(device_id, sensor_id, query).await
     }
 
     pub async fn read_by_device_id_and_sensor_id(
         device_id: i32,
         sensor_id: i32,
-        pool: &PgPool,
-    ) -> Result<Vec<Self>> {
-        let res = sqlx::query_as::<_, Measurement>(
-            "SELECT m.ts AS timestamp, m.value, s.unit, d.name AS device_name, d.location AS device_location, s.name AS sensor_name FROM measurements m JOIN devices d ON d.id = m.device_id JOIN sensors s ON s.id = m.sensor_id where m.device_id = ($1) AND m.sensor_id = ($2) ORDER BY ts",
-        )
-        .bind(device_id)
-        .bind(sensor_id)
-        .fetch_all(pool)
-        .await?;
-        Ok(res)
+        query: &MeasurementQuery,
+        storage: &dyn Storage,
+    ) -> Result<MeasurementPage> {
+        storage
+            .read_measurements(Some(device_id), Some(sensor_id), query)
+            .await
     }
 
     pub async fn read_latest_by_device_id_and_sensor_id(
         device_id: i32,
         sensor_id: i32,
-        pool: &PgPool,
+        storage: &dyn Storage,
     ) -> Result<Self> {
-        let res = sqlx::query_as::<_, Measurement>(
-            "SELECT m.ts AS timestamp, m.value, s.unit, d.name AS device_name, d.location AS device_location, s.name AS sensor_name FROM measurements m JOIN devices d ON d.id = m.device_id JOIN sensors s ON s.id = m.sensor_id where m.device_id = ($1) AND m.sensor_id = ($2) ORDER BY ts desc LIMIT 1",
-        )
-        .bind(device_id)
-        .bind(sensor_id)
-        .fetch_one(pool)
-        .await?;
-        Ok(res)
+        storage
+            .read_latest_measurement_by_device_id_and_sensor_id(device_id, sensor_id)
+            .await
     }
 
-    pub async fn read_by_device_id(device_id: i32, pool: &PgPool) -> Result<Vec<Self>> {
-        let res = sqlx::query_as::<_, Measurement>(
-            "SELECT m.ts AS timestamp, m.value, s.unit, d.name AS device_name, d.location AS device_location, s.name AS sensor_name FROM measurements m JOIN devices d ON d.id = m.device_id JOIN sensors s ON s.id = m.sensor_id where m.device_id = ($1) ORDER BY ts",
-        )
-        .bind(device_id)
-        .fetch_all(pool)
-        .await?;
-        Ok(res)
+    pub async fn read_by_device_id(
+        device_id: i32,
+        query: &MeasurementQuery,
+        storage: &dyn Storage,
+    ) -> Result<MeasurementPage> {
+        storage
+            .read_measurements(Some(device_id), None, query)
+            .await
     }
 
-    pub async fn read_all(pool: &PgPool) -> Result<Vec<Measurement>> {
-        let measurements =
-            sqlx::query_as::<_, Measurement>("SELECT m.ts AS timestamp, m.value, s.unit, d.name AS device_name, d.location AS device_location, s.name AS sensor_name FROM measurements m JOIN devices d ON d.id = m.device_id JOIN sensors s ON s.id = m.sensor_id ORDER BY ts")
-                .fetch_all(pool)
-                .await?;
-        Ok(measurements)
+    pub async fn read_all(
+        storage: &dyn Storage,
+        query: &MeasurementQuery,
+    ) -> Result<MeasurementPage> {
+        storage.read_measurements(None, None, query).await
     }
 
-    pub async fn read_latest(pool: &PgPool) -> Result<Self> {
-        let measurement = sqlx::query_as::<_, Measurement>(
-            "SELECT m.ts AS timestamp, m.value, s.unit, d.name AS device_name, d.location AS device_location, s.name AS sensor_name FROM measurements m JOIN devices d ON d.id = m.device_id JOIN sensors s ON s.id = m.sensor_id ORDER BY ts DESC LIMIT 1",
-        )
-        .fetch_one(pool)
-        .await?;
-        Ok(measurement)
+    /// Sends the measurements matching `query` to `rows` one by one, optionally only those
+    /// of a device or device and sensor pair
+    pub async fn stream(
+        storage: &dyn Storage,
+        device_id: Option<i32>,
+        sensor_id: Option<i32>,
+        query: &MeasurementQuery,
+        rows: &mpsc::Sender<Result<Measurement>>,
+    ) -> Result<()> {
+        storage
+            .stream_measurements(device_id, sensor_id, query, rows)
+            .await
+    }
+
+    pub async fn read_latest(storage: &dyn Storage) -> Result<Self> {
+        storage.read_latest_measurement().await
     }
 
-    pub async fn read_total_measurements(pool: &PgPool) -> Result<i64> {
-        let count = sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM measurements")
-            .fetch_one(pool)
-            .await?;
-        Ok(count)
+    /// Deletes up to `limit` measurements of a sensor older than `before`, returning how many
+    /// were deleted. With `keep_rollups` measurements wait until they are rolled up
+    pub async fn delete_expired(
+        storage: &dyn Storage,
+        sensor_id: i32,
+        before: DateTime<Utc>,
+        limit: i64,
+        keep_rollups: bool,
+    ) -> Result<u64> {
+        storage
+            .delete_expired_measurements(sensor_id, before, limit, keep_rollups)
+            .await
+    }
+
+    pub async fn read_total_measurements(storage: &dyn Storage) -> Result<i64> {
+        storage.read_total_measurements().await
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::sync::Arc;
+
+    use chrono::Duration;
     use sqlx::PgPool;
 
-    use crate::measurements::NewMeasurement;
+    use crate::measurements::{
+        AggregateFunction, AggregateFunctions, AggregateQuery, Bucket, Cursor, MeasurementQuery,
+        NewMeasurement, Order, StatsQuery,
+    };
     use crate::sensors::NewSensor;
+    use crate::storage::{storage_tests, Storage};
     use crate::{devices::NewDevice, measurements::Measurement};
 
-    #[sqlx::test]
-    async fn should_insert_measurements_without_ts(pool: PgPool) {
+    async fn should_insert_measurements_without_ts(storage: Arc<dyn Storage>) {
         let device = NewDevice::new("test".to_string(), "test".to_string());
-        device.insert(&pool).await.unwrap();
+        device.insert(storage.as_ref()).await.unwrap();
         let sensor = NewSensor::new("test".to_string(), "test".to_string());
-        sensor.insert(&pool).await.unwrap();
+        sensor.insert(storage.as_ref()).await.unwrap();
 
         let measurement = NewMeasurement::new(None, 1, 1, 1.0);
-        measurement.insert(&pool).await.unwrap();
+        measurement.insert(storage.as_ref()).await.unwrap();
     }
 
-    #[sqlx::test]
-    async fn should_insert_measurements_with_ts(pool: PgPool) {
+    async fn should_insert_measurements_with_ts(storage: Arc<dyn Storage>) {
         let device = NewDevice::new("test".to_string(), "test".to_string());
-        device.insert(&pool).await.unwrap();
+        device.insert(storage.as_ref()).await.unwrap();
         let sensor = NewSensor::new("test".to_string(), "test".to_string());
-        sensor.insert(&pool).await.unwrap();
+        sensor.insert(storage.as_ref()).await.unwrap();
 
         let ts = chrono::Utc::now();
 
         let measurement = NewMeasurement::new(Some(ts), 1, 1, 1.0);
-        measurement.insert(&pool).await.unwrap();
+        measurement.insert(storage.as_ref()).await.unwrap();
     }
 
-    #[sqlx::test]
-    async fn should_read_measurements(pool: PgPool) {
+    async fn should_insert_many_measurements(storage: Arc<dyn Storage>) {
         let device = NewDevice::new("test".to_string(), "test".to_string());
-        device.insert(&pool).await.unwrap();
+        device.insert(storage.as_ref()).await.unwrap();
         let sensor = NewSensor::new("test".to_string(), "test".to_string());
-        sensor.insert(&pool).await.unwrap();
+        sensor.insert(storage.as_ref()).await.unwrap();
+
+        let measurements = vec![
+            NewMeasurement::new(None, 1, 1, 1.0),
+            NewMeasurement::new(Some(chrono::Utc::now()), 1, 1, 2.0),
+        ];
+        let inserted = NewMeasurement::insert_many(&measurements, storage.as_ref())
+            .await
+            .unwrap();
+        assert_eq!(inserted, 2);
+
+        let count = Measurement::read_total_measurements(storage.as_ref())
+            .await
+            .unwrap();
+        assert_eq!(count, 2);
+    }
+
+    async fn should_read_measurements(storage: Arc<dyn Storage>) {
+        let device = NewDevice::new("test".to_string(), "test".to_string());
+        device.insert(storage.as_ref()).await.unwrap();
+        let sensor = NewSensor::new("test".to_string(), "test".to_string());
+        sensor.insert(storage.as_ref()).await.unwrap();
 
         let measurement = NewMeasurement::new(None, 1, 1, 1.0);
-        measurement.insert(&pool).await.unwrap();
+        measurement.insert(storage.as_ref()).await.unwrap();
 
-        let measurements = Measurement::read_all(&pool).await.unwrap();
-        assert!(!measurements.is_empty());
+        let page = Measurement::read_all(storage.as_ref(), &MeasurementQuery::default())
+            .await
+            .unwrap();
+        assert!(!page.measurements.is_empty());
+        assert!(page.next.is_none());
     }
 
-    #[sqlx::test]
-    async fn should_read_latest_measurements(pool: PgPool) {
+    async fn should_read_latest_measurements(storage: Arc<dyn Storage>) {
         let device = NewDevice::new("test".to_string(), "test".to_string());
-        device.insert(&pool).await.unwrap();
+        device.insert(storage.as_ref()).await.unwrap();
         let sensor = NewSensor::new("test".to_string(), "test".to_string());
-        sensor.insert(&pool).await.unwrap();
+        sensor.insert(storage.as_ref()).await.unwrap();
 
         let measurement = NewMeasurement::new(None, 1, 1, 1.0);
-        measurement.insert(&pool).await.unwrap();
+        measurement.insert(storage.as_ref()).await.unwrap();
 
-        let measurement = Measurement::read_latest(&pool).await.unwrap();
+        let measurement = Measurement::read_latest(storage.as_ref()).await.unwrap();
         assert_eq!(measurement.value, 1.0);
     }
 
-    #[sqlx::test]
-    async fn should_read_measurements_by_device_id(pool: PgPool) {
+    async fn should_read_measurements_by_device_id(storage: Arc<dyn Storage>) {
         let device = NewDevice::new("test".to_string(), "test".to_string());
-        device.insert(&pool).await.unwrap();
+        device.insert(storage.as_ref()).await.unwrap();
         let sensor = NewSensor::new("test".to_string(), "test".to_string());
-        sensor.insert(&pool).await.unwrap();
+        sensor.insert(storage.as_ref()).await.unwrap();
 
         let measurement = NewMeasurement::new(None, 1, 1, 1.0);
-        measurement.insert(&pool).await.unwrap();
+        measurement.insert(storage.as_ref()).await.unwrap();
 
-        let measurements = Measurement::read_by_device_id(1, &pool).await.unwrap();
-        assert!(!measurements.is_empty());
+        let page =
+            Measurement::read_by_device_id(1, &MeasurementQuery::default(), storage.as_ref())
+                .await
+                .unwrap();
+        assert!(!page.measurements.is_empty());
     }
 
-    #[sqlx::test]
-    async fn should_read_measurements_by_device_id_and_sensor_id(pool: PgPool) {
+    async fn should_read_measurements_by_device_id_and_sensor_id(storage: Arc<dyn Storage>) {
         let device = NewDevice::new("test".to_string(), "test".to_string());
-        device.insert(&pool).await.unwrap();
+        device.insert(storage.as_ref()).await.unwrap();
         let sensor = NewSensor::new("test".to_string(), "test".to_string());
-        sensor.insert(&pool).await.unwrap();
+        sensor.insert(storage.as_ref()).await.unwrap();
 
         let measurement = NewMeasurement::new(None, 1, 1, 1.0);
-        measurement.insert(&pool).await.unwrap();
+        measurement.insert(storage.as_ref()).await.unwrap();
+
+        let page = Measurement::read_by_device_id_and_sensor_id(
+            1,
+            1,
+            &MeasurementQuery::default(),
+            storage.as_ref(),
+        )
+        .await
+        .unwrap();
+        assert!(!page.measurements.is_empty());
+    }
+
+    async fn should_read_measurements_in_range(storage: Arc<dyn Storage>) {
+        let device = NewDevice::new("test".to_string(), "test".to_string());
+        device.insert(storage.as_ref()).await.unwrap();
+        let sensor = NewSensor::new("test".to_string(), "test".to_string());
+        sensor.insert(storage.as_ref()).await.unwrap();
+
+        let now = chrono::Utc::now();
+        let measurements: Vec<NewMeasurement> = (0..5)
+            .map(|i| NewMeasurement::new(Some(now - Duration::hours(i)), 1, 1, i as f32))
+            .collect();
+        NewMeasurement::insert_many(&measurements, storage.as_ref())
+            .await
+            .unwrap();
 
-        let measurements = Measurement::read_by_device_id_and_sensor_id(1, 1, &pool)
+        let query = MeasurementQuery {
+            from: Some(now - Duration::minutes(150)),
+            to: Some(now),
+            ..Default::default()
+        };
+        let page = Measurement::read_by_device_id_and_sensor_id(1, 1, &query, storage.as_ref())
             .await
             .unwrap();
-        assert!(!measurements.is_empty());
+        let values: Vec<f32> = page.measurements.iter().map(|m| m.value).collect();
+        assert_eq!(values, vec![2.0, 1.0]);
+
+        let query = MeasurementQuery {
+            order: Order::Desc,
+            ..Default::default()
+        };
+        let page = Measurement::read_by_device_id(1, &query, storage.as_ref())
+            .await
+            .unwrap();
+        assert_eq!(page.measurements[0].value, 0.0);
+    }
+
+    #[test]
+    fn should_parse_aggregate_query() {
+        assert_eq!(
+            "15m".parse::<Bucket>().unwrap(),
+            Bucket(Duration::minutes(15))
+        );
+        assert!("0h".parse::<Bucket>().is_err());
+        assert!("1y".parse::<Bucket>().is_err());
+        assert!("h".parse::<Bucket>().is_err());
+        assert!("99999999999999999w".parse::<Bucket>().is_err());
+        assert!("99999999999999999999s".parse::<Bucket>().is_err());
+
+        let query: AggregateQuery =
+            serde_json::from_str(r#"{"bucket": "1h", "fn": "min,max,min"}"#).unwrap();
+        assert_eq!(
+            query.functions.0,
+            vec![AggregateFunction::Min, AggregateFunction::Max]
+        );
+        let query: AggregateQuery = serde_json::from_str(r#"{"bucket": "1d"}"#).unwrap();
+        assert_eq!(query.functions, AggregateFunctions::default());
+        let query: AggregateQuery = serde_json::from_str("{}").unwrap();
+        assert_eq!(query.bucket, None);
+        assert_eq!(
+            Bucket::for_range(Some(Duration::days(7))),
+            Bucket(Duration::hours(1))
+        );
+        assert_eq!(
+            Bucket::for_max_points(Duration::hours(6), 1000, Duration::seconds(15)),
+            Bucket(Duration::seconds(22))
+        );
+        assert_eq!(
+            Bucket::for_max_points(Duration::hours(1), 1000, Duration::seconds(15)),
+            Bucket(Duration::seconds(15))
+        );
+        assert_eq!(
+            Bucket::for_max_points(Duration::minutes(1), 0, Duration::zero()),
+            Bucket(Duration::minutes(1))
+        );
+        assert_eq!(
+            Bucket::for_max_points(Duration::zero(), 100, Duration::milliseconds(10)),
+            Bucket(Duration::seconds(1))
+        );
+        assert!(
+            serde_json::from_str::<AggregateQuery>(r#"{"bucket": "1h", "fn": "median"}"#).is_err()
+        );
+    }
+
+    #[test]
+    fn should_limit_number_of_buckets() {
+        let query: AggregateQuery = serde_json::from_str(
+            r#"{"bucket": "1s", "from": "2020-01-01T00:00:00Z", "to": "2021-01-01T00:00:00Z"}"#,
+        )
+        .unwrap();
+        assert!(query.check().is_err());
+        let query: AggregateQuery = serde_json::from_str(
+            r#"{"bucket": "1h", "from": "2020-01-01T00:00:00Z", "to": "2021-01-01T00:00:00Z"}"#,
+        )
+        .unwrap();
+        assert!(query.check().is_ok());
+
+        // Without a start, short buckets would cover the whole history
+        let query: AggregateQuery = serde_json::from_str(r#"{"bucket": "1s"}"#).unwrap();
+        assert!(query.check().is_err());
+        let query: AggregateQuery = serde_json::from_str(r#"{"bucket": "1h"}"#).unwrap();
+        assert!(query.check().is_ok());
+        let query: AggregateQuery = serde_json::from_str("{}").unwrap();
+        assert!(query.check().is_ok());
+
+        // Without a bucket, one is picked that stays within the limit
+        let query: AggregateQuery =
+            serde_json::from_str(r#"{"from": "0001-01-01T00:00:00Z"}"#).unwrap();
+        assert!(query.bucket().duration() > Duration::days(1));
+        assert!(query.check().is_ok());
     }
 
     #[sqlx::test]
-    async fn should_read_latest_measurements_by_device_id_and_sensor_id(pool: PgPool) {
+    async fn should_aggregate_measurements_by_bucket(pool: PgPool) {
         let device = NewDevice::new("test".to_string(), "test".to_string());
         device.insert(&pool).await.unwrap();
         let sensor = NewSensor::new("test".to_string(), "test".to_string());
         sensor.insert(&pool).await.unwrap();
 
-        let measurement = NewMeasurement::new(None, 1, 1, 1.0);
-        measurement.insert(&pool).await.unwrap();
+        let start = "2025-01-01T10:00:00Z"
+            .parse::<chrono::DateTime<chrono::Utc>>()
+            .unwrap();
+        let measurements: Vec<NewMeasurement> = [(0, 1.0), (20, 3.0), (40, 2.0), (70, 10.0)]
+            .into_iter()
+            .map(|(minutes, value)| {
+                NewMeasurement::new(Some(start + Duration::minutes(minutes)), 1, 1, value)
+            })
+            .collect();
+        NewMeasurement::insert_many(&measurements, &pool)
+            .await
+            .unwrap();
+        crate::rollups::roll_up(&pool, 100).await.unwrap();
+
+        let query: AggregateQuery =
+            serde_json::from_str(r#"{"bucket": "1h", "fn": "avg,min,max,sum,count,first,last"}"#)
+                .unwrap();
+        let buckets = Measurement::read_aggregate_by_device_id_and_sensor_id(&pool, 1, 1, &query)
+            .await
+            .unwrap();
+        assert_eq!(buckets.len(), 2);
+        assert_eq!(buckets[0].bucket, start);
+        assert_eq!(buckets[0].avg, Some(2.0));
+        assert_eq!(buckets[0].min, Some(1.0));
+        assert_eq!(buckets[0].max, Some(3.0));
+        assert_eq!(buckets[0].sum, Some(6.0));
+        assert_eq!(buckets[0].count, Some(3));
+        assert_eq!(buckets[0].first, Some(1.0));
+        assert_eq!(buckets[0].last, Some(2.0));
+        assert_eq!(buckets[1].bucket, start + Duration::hours(1));
+        assert_eq!(buckets[1].count, Some(1));
+
+        // Without a range this is answered from the hourly rollup
+        let query: AggregateQuery =
+            serde_json::from_str(r#"{"bucket": "1h", "fn": "avg,max"}"#).unwrap();
+        let buckets = Measurement::read_aggregate_by_device_id_and_sensor_id(&pool, 1, 1, &query)
+            .await
+            .unwrap();
+        assert_eq!(buckets.len(), 2);
+        assert_eq!(buckets[0].avg, Some(2.0));
+        assert_eq!(buckets[1].max, Some(10.0));
+        assert_eq!(buckets[1].min, None);
+    }
+
+    async fn should_aggregate_measurements_in_range(storage: Arc<dyn Storage>) {
+        let device = NewDevice::new("test".to_string(), "test".to_string());
+        device.insert(storage.as_ref()).await.unwrap();
+        let sensor = NewSensor::new("test".to_string(), "test".to_string());
+        sensor.insert(storage.as_ref()).await.unwrap();
+
+        let start = "2025-01-01T10:00:00Z"
+            .parse::<chrono::DateTime<chrono::Utc>>()
+            .unwrap();
+        let measurements: Vec<NewMeasurement> = [(5, 4.0), (20, 3.0), (40, 2.0), (70, 10.0)]
+            .into_iter()
+            .map(|(minutes, value)| {
+                NewMeasurement::new(Some(start + Duration::minutes(minutes)), 1, 1, value)
+            })
+            .collect();
+        NewMeasurement::insert_many(&measurements, storage.as_ref())
+            .await
+            .unwrap();
+
+        let query = AggregateQuery {
+            bucket: Some("30m".parse().unwrap()),
+            functions: AggregateFunctions(vec![
+                AggregateFunction::Avg,
+                AggregateFunction::Count,
+                AggregateFunction::First,
+                AggregateFunction::Last,
+            ]),
+            from: Some(start),
+            to: Some(start + Duration::hours(1)),
+        };
+        let buckets =
+            Measurement::read_aggregate_by_device_id_and_sensor_id(storage.as_ref(), 1, 1, &query)
+                .await
+                .unwrap();
+        assert_eq!(buckets.len(), 2);
+        assert_eq!(buckets[0].bucket, start);
+        assert_eq!(buckets[0].avg, Some(3.5));
+        assert_eq!(buckets[0].count, Some(2));
+        assert_eq!(buckets[0].first, Some(4.0));
+        assert_eq!(buckets[0].last, Some(3.0));
+        assert_eq!(buckets[1].bucket, start + Duration::minutes(30));
+        assert_eq!(buckets[1].last, Some(2.0));
+        assert_eq!(buckets[1].min, None);
+    }
+
+    async fn should_read_stats_in_window(storage: Arc<dyn Storage>) {
+        let device = NewDevice::new("test".to_string(), "test".to_string());
+        device.insert(storage.as_ref()).await.unwrap();
+        let sensor = NewSensor::new("test".to_string(), "test".to_string());
+        sensor.insert(storage.as_ref()).await.unwrap();
 
-        let measurement = Measurement::read_latest_by_device_id_and_sensor_id(1, 1, &pool)
+        let start = "2025-01-01T10:00:00Z"
+            .parse::<chrono::DateTime<chrono::Utc>>()
+            .unwrap();
+        // 10 held for 30 minutes and 40 for 10 minutes, the last value has no duration
+        let measurements: Vec<NewMeasurement> = [(0, 10.0), (30, 40.0), (40, 20.0), (120, 0.0)]
+            .into_iter()
+            .map(|(minutes, value)| {
+                NewMeasurement::new(Some(start + Duration::minutes(minutes)), 1, 1, value)
+            })
+            .collect();
+        NewMeasurement::insert_many(&measurements, storage.as_ref())
             .await
             .unwrap();
+
+        let query = StatsQuery {
+            from: Some(start),
+            to: Some(start + Duration::hours(1)),
+        };
+        let stats =
+            Measurement::read_stats_by_device_id_and_sensor_id(storage.as_ref(), 1, 1, &query)
+                .await
+                .unwrap();
+        assert_eq!(stats.count, 3);
+        assert_eq!(stats.min, Some(10.0));
+        assert_eq!(stats.max, Some(40.0));
+        assert_eq!(stats.median, Some(20.0));
+        assert_eq!(stats.first, Some(start));
+        assert_eq!(stats.last, Some(start + Duration::minutes(40)));
+        assert_eq!(stats.time_weighted_avg, Some(17.5));
+        assert!(stats.p5.unwrap() < stats.p95.unwrap());
+
+        let stats = Measurement::read_stats_by_device_id_and_sensor_id(
+            storage.as_ref(),
+            1,
+            1,
+            &StatsQuery {
+                from: Some(start + Duration::days(1)),
+                to: None,
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(stats.count, 0);
+        assert_eq!(stats.avg, None);
+    }
+
+    async fn should_paginate_measurements(storage: Arc<dyn Storage>) {
+        let device = NewDevice::new("test".to_string(), "test".to_string());
+        device.insert(storage.as_ref()).await.unwrap();
+        let sensor = NewSensor::new("test".to_string(), "test".to_string());
+        sensor.insert(storage.as_ref()).await.unwrap();
+
+        // Identical timestamps are ordered by id
+        let ts = chrono::Utc::now();
+        let measurements: Vec<NewMeasurement> = (0..5)
+            .map(|i| NewMeasurement::new(Some(ts), 1, 1, i as f32))
+            .collect();
+        NewMeasurement::insert_many(&measurements, storage.as_ref())
+            .await
+            .unwrap();
+
+        let mut query = MeasurementQuery {
+            limit: Some(2),
+            ..Default::default()
+        };
+        let mut values = Vec::new();
+        loop {
+            let page = Measurement::read_all(storage.as_ref(), &query)
+                .await
+                .unwrap();
+            values.extend(page.measurements.iter().map(|m| m.value));
+            match page.next {
+                Some(next) => {
+                    let next: Cursor = next.to_string().parse().unwrap();
+                    query.cursor = Some(next);
+                }
+                None => break,
+            }
+        }
+        assert_eq!(values, vec![0.0, 1.0, 2.0, 3.0, 4.0]);
+    }
+
+    async fn should_read_latest_measurements_by_device_id_and_sensor_id(storage: Arc<dyn Storage>) {
+        let device = NewDevice::new("test".to_string(), "test".to_string());
+        device.insert(storage.as_ref()).await.unwrap();
+        let sensor = NewSensor::new("test".to_string(), "test".to_string());
+        sensor.insert(storage.as_ref()).await.unwrap();
+
+        let measurement = NewMeasurement::new(None, 1, 1, 1.0);
+        measurement.insert(storage.as_ref()).await.unwrap();
+
+        let measurement =
+            Measurement::read_latest_by_device_id_and_sensor_id(1, 1, storage.as_ref())
+                .await
+                .unwrap();
         assert_eq!(measurement.value, 1.0);
     }
+
+    storage_tests!(
+        should_insert_measurements_without_ts,
+        should_insert_measurements_with_ts,
+        should_insert_many_measurements,
+        should_read_measurements,
+        should_read_latest_measurements,
+        should_read_measurements_by_device_id,
+        should_read_measurements_by_device_id_and_sensor_id,
+        should_read_measurements_in_range,
+        should_aggregate_measurements_in_range,
+        should_read_stats_in_window,
+        should_paginate_measurements,
+        should_read_latest_measurements_by_device_id_and_sensor_id
+    );
 }
@@ -0,0 +1,172 @@
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Result};
+use sqlx::{migrate::Migrator, PgPool};
+use structopt::StructOpt;
+use tracing::{info, warn};
+
+/// Migrations in `backend/migrations`, embedded at build time
+pub static MIGRATOR: Migrator = sqlx::migrate!();
+
+#[derive(Debug, Clone, StructOpt)]
+pub enum MigrateCommand {
+    /// Applies all pending migrations
+    Up,
+    /// Lists all migrations and whether they are applied
+    Status,
+    /// Fails if the schema does not match the migrations of this binary
+    Check,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MigrationState {
+    Applied,
+    Pending,
+    /// Applied, but changed since
+    Modified,
+    /// Applied by a newer version
+    Unknown,
+}
+
+#[derive(Debug, Clone)]
+pub struct MigrationStatus {
+    pub version: i64,
+    pub description: String,
+    pub state: MigrationState,
+}
+
+/// Compares the applied migrations with the embedded ones
+pub async fn read_status(pool: &PgPool) -> Result<Vec<MigrationStatus>> {
+    let exists =
+        sqlx::query_scalar::<_, bool>("SELECT to_regclass('_sqlx_migrations') IS NOT NULL")
+            .fetch_one(pool)
+            .await?;
+    let applied: Vec<(i64, String, Vec<u8>)> = if exists {
+        sqlx::query_as(
+            "SELECT version, description, checksum FROM _sqlx_migrations WHERE success ORDER BY version",
+        )
+        .fetch_all(pool)
+        .await?
+    } else {
+        Vec::new()
+    };
+    let mut applied: HashMap<i64, (String, Vec<u8>)> = applied
+        .into_iter()
+        .map(|(version, description, checksum)| (version, (description, checksum)))
+        .collect();
+
+    let mut status: Vec<MigrationStatus> = MIGRATOR
+        .iter()
+        .filter(|migration| !migration.migration_type.is_down_migration())
+        .map(|migration| {
+            let state = match applied.remove(&migration.version) {
+                Some((_, checksum)) if *checksum == *migration.checksum => MigrationState::Applied,
+                Some(_) => MigrationState::Modified,
+                None => MigrationState::Pending,
+            };
+            MigrationStatus {
+                version: migration.version,
+                description: migration.description.to_string(),
+                state,
+            }
+        })
+        .collect();
+    status.extend(
+        applied
+            .into_iter()
+            .map(|(version, (description, _))| MigrationStatus {
+                version,
+                description,
+                state: MigrationState::Unknown,
+            }),
+    );
+    status.sort_by_key(|migration| migration.version);
+    Ok(status)
+}
+
+/// Fails if the schema is newer than this binary or was migrated with different migrations.
+/// Pending migrations are only reported unless `strict` is set.
+pub async fn check(pool: &PgPool, strict: bool) -> Result<()> {
+    let status = read_status(pool).await?;
+    let count = |state| status.iter().filter(|m| m.state == state).count();
+    if count(MigrationState::Unknown) > 0 {
+        return Err(anyhow!(
+            "Database schema has {} migrations unknown to this version, refusing to start",
+            count(MigrationState::Unknown)
+        ));
+    }
+    if count(MigrationState::Modified) > 0 {
+        return Err(anyhow!(
+            "{} applied migrations differ from this version",
+            count(MigrationState::Modified)
+        ));
+    }
+    let pending = count(MigrationState::Pending);
+    if pending > 0 {
+        if strict {
+            return Err(anyhow!("{} migrations are pending", pending));
+        }
+        warn!(
+            "{} migrations are pending, apply them with `migrate up` or --migrate",
+            pending
+        );
+    }
+    Ok(())
+}
+
+pub async fn run(pool: &PgPool) -> Result<()> {
+    info!("Applying migrations");
+    MIGRATOR.run(pool).await?;
+    Ok(())
+}
+
+pub async fn run_command(pool: &PgPool, command: MigrateCommand) -> Result<()> {
+    match command {
+        MigrateCommand::Up => run(pool).await,
+        MigrateCommand::Status => {
+            for migration in read_status(pool).await? {
+                println!(
+                    "{:<16} {:<10} {}",
+                    migration.version,
+                    format!("{:?}", migration.state).to_lowercase(),
+                    migration.description
+                );
+            }
+            Ok(())
+        }
+        MigrateCommand::Check => check(pool, true).await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::PgPool;
+
+    use super::*;
+
+    #[sqlx::test(migrations = false)]
+    async fn should_report_migration_status(pool: PgPool) {
+        let status = read_status(&pool).await.unwrap();
+        assert!(!status.is_empty());
+        assert!(status.iter().all(|m| m.state == MigrationState::Pending));
+        assert!(check(&pool, false).await.is_ok());
+        assert!(check(&pool, true).await.is_err());
+
+        run(&pool).await.unwrap();
+        let status = read_status(&pool).await.unwrap();
+        assert!(status.iter().all(|m| m.state == MigrationState::Applied));
+        assert!(check(&pool, true).await.is_ok());
+
+        // Applied by a newer version of the binary
+        sqlx::query(
+            "INSERT INTO _sqlx_migrations (version, description, success, checksum, execution_time) \
+            VALUES (99990101000000, 'from the future', TRUE, '\\x00', 0)",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        let status = read_status(&pool).await.unwrap();
+        assert_eq!(status.last().unwrap().state, MigrationState::Unknown);
+        assert!(check(&pool, false).await.is_err());
+    }
+}
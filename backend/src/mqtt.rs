@@ -0,0 +1,276 @@
+use std::{str::FromStr, time::Duration};
+
+use anyhow::{anyhow, Result};
+use rumqttc::{AsyncClient, Event, MqttOptions, Packet, QoS};
+use tracing::{debug, info, warn};
+
+use crate::{
+    measurements::{NewMeasurement, NewMeasurements},
+    queue::MeasurementQueue,
+};
+
+/// Maps an MQTT topic (filter) carrying a bare value to a device and sensor.
+///
+/// Parsed from `topic=device:sensor`, e.g. `home/livingroom/temperature=1:2`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TopicMapping {
+    pub topic: String,
+    pub device: i32,
+    pub sensor: i32,
+}
+
+impl FromStr for TopicMapping {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (topic, ids) = s
+            .rsplit_once('=')
+            .ok_or_else(|| "expected topic=device:sensor".to_string())?;
+        let (device, sensor) = ids
+            .split_once(':')
+            .ok_or_else(|| "expected topic=device:sensor".to_string())?;
+        if topic.is_empty() {
+            return Err("topic can not be empty".to_string());
+        }
+        Ok(Self {
+            topic: topic.to_string(),
+            device: device
+                .parse()
+                .map_err(|_| "invalid device id".to_string())?,
+            sensor: sensor
+                .parse()
+                .map_err(|_| "invalid sensor id".to_string())?,
+        })
+    }
+}
+
+/// Parses an MQTT payload into measurements.
+///
+/// Topics with a mapping carry a single number, everything else is expected to
+/// be the same JSON accepted by the HTTP ingest endpoint.
+pub fn parse_payload(
+    topic: &str,
+    payload: &[u8],
+    mappings: &[TopicMapping],
+) -> Result<Vec<NewMeasurement>> {
+    if let Some(mapping) = mappings.iter().find(|m| rumqttc::matches(topic, &m.topic)) {
+        let value = std::str::from_utf8(payload)?.trim().parse::<f32>()?;
+        return Ok(vec![NewMeasurement::new(
+            None,
+            mapping.device,
+            mapping.sensor,
+            value,
+        )]);
+    }
+    match serde_json::from_slice::<NewMeasurements>(payload)? {
+        NewMeasurements::Measurement(measurement) => Ok(vec![measurement]),
+        NewMeasurements::Measurements(measurements) => Ok(measurements),
+        NewMeasurements::NamedMeasurement(_) | NewMeasurements::NamedMeasurements(_) => Err(
+            anyhow!("Measurements by device and sensor name are only accepted over HTTP"),
+        ),
+    }
+}
+
+/// Topic filters to subscribe to, checked before the listener is started
+pub fn topic_filters(topics: &[String], mappings: &[TopicMapping]) -> Result<Vec<String>> {
+    let filters: Vec<String> = topics
+        .iter()
+        .chain(mappings.iter().map(|m| &m.topic))
+        .cloned()
+        .collect();
+    if filters.is_empty() {
+        return Err(anyhow!(
+            "No MQTT topics configured, set --mqtt-topic or --mqtt-map"
+        ));
+    }
+    if let Some(filter) = filters.iter().find(|filter| !rumqttc::valid_filter(filter)) {
+        return Err(anyhow!("Invalid MQTT topic filter {}", filter));
+    }
+    Ok(filters)
+}
+
+/// Subscribes to `filters` and forwards measurements to the insert queue.
+///
+/// Errors are logged and the listener keeps going, connection errors are retried.
+pub async fn mqtt_listener(
+    options: MqttOptions,
+    filters: Vec<String>,
+    mappings: Vec<TopicMapping>,
+    queue: MeasurementQueue,
+) {
+    let (client, mut eventloop) = AsyncClient::new(options, 64);
+    loop {
+        match eventloop.poll().await {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                info!("Connected to MQTT broker, subscribing to {:?}", filters);
+                for filter in &filters {
+                    if let Err(e) = client.subscribe(filter, QoS::AtLeastOnce).await {
+                        warn!("Failed to subscribe to {}: {}", filter, e);
+                    }
+                }
+            }
+            Ok(Event::Incoming(Packet::Publish(publish))) => {
+                debug!("Received MQTT message on {}", publish.topic);
+                match parse_payload(&publish.topic, &publish.payload, &mappings) {
+                    Ok(measurements) => {
+                        if let Err(e) = queue.send(measurements).await {
+                            warn!("Failed to queue measurements from {}: {}", publish.topic, e);
+                        }
+                    }
+                    Err(e) => warn!("Failed to parse payload on {}: {}", publish.topic, e),
+                }
+            }
+            Ok(_) => {}
+            Err(e) => {
+                warn!("MQTT connection error: {}", e);
+                tokio::time::sleep(Duration::from_secs(5)).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, time::Duration};
+
+    use rumqttc::{AsyncClient, MqttOptions, QoS};
+    use rumqttd::{Broker, Config, ConnectionSettings, RouterConfig, ServerSettings};
+
+    use super::*;
+
+    fn start_broker(port: u16) {
+        let connections = ConnectionSettings {
+            connection_timeout_ms: 5000,
+            max_payload_size: 1 << 16,
+            max_inflight_count: 100,
+            auth: None,
+            external_auth: None,
+            dynamic_filters: true,
+        };
+        let server = ServerSettings {
+            name: "v4".to_string(),
+            listen: ([127, 0, 0, 1], port).into(),
+            tls: None,
+            next_connection_delay_ms: 1,
+            connections,
+        };
+        let config = Config {
+            router: RouterConfig {
+                max_connections: 16,
+                max_outgoing_packet_count: 200,
+                max_segment_size: 1 << 20,
+                max_segment_count: 10,
+                ..Default::default()
+            },
+            v4: Some(HashMap::from([("1".to_string(), server)])),
+            ..Default::default()
+        };
+        std::thread::spawn(move || Broker::new(config).start().unwrap());
+        while std::net::TcpStream::connect(("127.0.0.1", port)).is_err() {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn should_parse_topic_mapping() {
+        let mapping: TopicMapping = "home/+/temperature=1:2".parse().unwrap();
+        assert_eq!(mapping.topic, "home/+/temperature");
+        assert_eq!(mapping.device, 1);
+        assert_eq!(mapping.sensor, 2);
+
+        assert!("home/temperature".parse::<TopicMapping>().is_err());
+        assert!("home/temperature=1".parse::<TopicMapping>().is_err());
+        assert!("=1:2".parse::<TopicMapping>().is_err());
+    }
+
+    #[test]
+    fn should_check_topic_filters() {
+        let mappings = vec!["home/+/temperature=1:2".parse().unwrap()];
+        assert_eq!(
+            topic_filters(&["hemrs/#".to_string()], &mappings).unwrap(),
+            vec!["hemrs/#", "home/+/temperature"]
+        );
+        assert!(topic_filters(&[], &[]).is_err());
+        assert!(topic_filters(&["hemrs/#/measurements".to_string()], &[]).is_err());
+    }
+
+    #[test]
+    fn should_parse_mapped_payload() {
+        let mappings = vec!["home/+/temperature=1:2".parse().unwrap()];
+        let measurements =
+            parse_payload("home/livingroom/temperature", b" 21.5\n", &mappings).unwrap();
+        assert_eq!(measurements.len(), 1);
+        assert_eq!(measurements[0].device, 1);
+        assert_eq!(measurements[0].sensor, 2);
+        assert_eq!(measurements[0].measurement, 21.5);
+
+        assert!(parse_payload("home/livingroom/temperature", b"warm", &mappings).is_err());
+    }
+
+    #[test]
+    fn should_parse_json_payload() {
+        let measurements = parse_payload(
+            "hemrs/measurements",
+            br#"[{"device": 1, "sensor": 1, "measurement": 1.0}, {"device": 1, "sensor": 2, "measurement": 2.0}]"#,
+            &[],
+        )
+        .unwrap();
+        assert_eq!(measurements.len(), 2);
+        assert_eq!(measurements[1].sensor, 2);
+
+        assert!(parse_payload("hemrs/measurements", b"1.0", &[]).is_err());
+    }
+
+    #[tokio::test]
+    async fn should_forward_measurements_from_broker() {
+        let port = 18830;
+        start_broker(port);
+
+        let (tx, mut rx) = tokio::sync::mpsc::channel(16);
+        let listener_options = MqttOptions::new("hemrs-test", "127.0.0.1", port);
+        tokio::spawn(mqtt_listener(
+            listener_options,
+            vec![
+                "hemrs/measurements".to_string(),
+                "home/+/temperature".to_string(),
+            ],
+            vec!["home/+/temperature=1:2".parse().unwrap()],
+            MeasurementQueue::new(tx, None),
+        ));
+
+        let (client, mut eventloop) = AsyncClient::new(
+            MqttOptions::new("hemrs-test-publisher", "127.0.0.1", port),
+            10,
+        );
+        tokio::spawn(async move { while eventloop.poll().await.is_ok() {} });
+
+        // Retained messages are delivered regardless of when the listener subscribes
+        client
+            .publish("home/kitchen/temperature", QoS::AtLeastOnce, true, "19.5")
+            .await
+            .unwrap();
+        client
+            .publish(
+                "hemrs/measurements",
+                QoS::AtLeastOnce,
+                true,
+                r#"{"device": 2, "sensor": 3, "measurement": 42.0}"#,
+            )
+            .await
+            .unwrap();
+
+        let mut received = Vec::new();
+        while received.len() < 2 {
+            let measurement = tokio::time::timeout(Duration::from_secs(10), rx.recv())
+                .await
+                .expect("timed out waiting for measurement")
+                .unwrap();
+            received.push(measurement);
+        }
+        received.sort_by_key(|m| m.device);
+        assert_eq!((received[0].device, received[0].sensor), (1, 2));
+        assert_eq!(received[0].measurement, 19.5);
+        assert_eq!((received[1].device, received[1].sensor), (2, 3));
+        assert_eq!(received[1].measurement, 42.0);
+    }
+}
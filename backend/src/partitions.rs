@@ -0,0 +1,246 @@
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Datelike, Months, NaiveDate, NaiveTime, Utc};
+use sqlx::PgPool;
+use tracing::info;
+
+/// Monthly partition of the measurements table
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Partition {
+    month: NaiveDate,
+}
+
+impl Partition {
+    pub fn containing(ts: DateTime<Utc>) -> Self {
+        let month = NaiveDate::from_ymd_opt(ts.year(), ts.month(), 1)
+            .expect("first day of a month is a valid date");
+        Self { month }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        let month = name.strip_prefix("measurements_")?;
+        let month = NaiveDate::parse_from_str(&format!("{month}_01"), "%Y_%m_%d").ok()?;
+        Some(Self { month })
+    }
+
+    pub fn name(&self) -> String {
+        format!("measurements_{}", self.month.format("%Y_%m"))
+    }
+
+    pub fn start(&self) -> DateTime<Utc> {
+        self.month.and_time(NaiveTime::MIN).and_utc()
+    }
+
+    pub fn end(&self) -> DateTime<Utc> {
+        self.add_months(1).start()
+    }
+
+    fn add_months(&self, months: u32) -> Self {
+        Self {
+            month: self.month + Months::new(months),
+        }
+    }
+
+    fn sub_months(&self, months: u32) -> Self {
+        Self {
+            month: self.month - Months::new(months),
+        }
+    }
+
+    /// Lists the monthly partitions attached to the measurements table
+    pub async fn read(pool: &PgPool) -> Result<Vec<Partition>> {
+        let names = sqlx::query_scalar::<_, String>(
+            "SELECT c.relname::text FROM pg_inherits i JOIN pg_class c ON c.oid = i.inhrelid \
+            WHERE i.inhparent = 'measurements'::regclass",
+        )
+        .fetch_all(pool)
+        .await?;
+        let mut partitions: Vec<Partition> = names
+            .iter()
+            .filter_map(|name| Self::from_name(name))
+            .collect();
+        partitions.sort();
+        Ok(partitions)
+    }
+
+    /// Creates the partition, moving its measurements out of the default partition
+    pub async fn create(&self, pool: &PgPool) -> Result<()> {
+        let name = self.name();
+        let mut tx = pool.begin().await?;
+        sqlx::query(&format!(
+            "CREATE TABLE {name} (LIKE measurements INCLUDING DEFAULTS INCLUDING CONSTRAINTS)"
+        ))
+        .execute(&mut *tx)
+        .await?;
+        let moved = sqlx::query(&format!(
+            "WITH moved AS (DELETE FROM measurements_default WHERE ts >= $1 AND ts < $2 RETURNING *) \
+            INSERT INTO {name} SELECT * FROM moved"
+        ))
+        .bind(self.start())
+        .bind(self.end())
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query(&format!(
+            "ALTER TABLE measurements ATTACH PARTITION {name} FOR VALUES FROM ('{}') TO ('{}')",
+            self.start().to_rfc3339(),
+            self.end().to_rfc3339()
+        ))
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        info!(
+            "Created partition {}, moved {} measurements into it",
+            name,
+            moved.rows_affected()
+        );
+        Ok(())
+    }
+
+    /// Drops or detaches the partition. Partitions holding measurements that are not
+    /// rolled up yet are kept, returns whether the partition was removed.
+    pub async fn remove(&self, pool: &PgPool, detach: bool) -> Result<bool> {
+        let name = self.name();
+        let rolled_up = sqlx::query_scalar::<_, bool>(&format!(
+            "SELECT NOT EXISTS (SELECT 1 FROM {name} WHERE id > (SELECT last_measurement_id FROM rollup_state))"
+        ))
+        .fetch_one(pool)
+        .await?;
+        if !rolled_up {
+            return Ok(false);
+        }
+        let statement = if detach {
+            format!("ALTER TABLE measurements DETACH PARTITION {name}")
+        } else {
+            format!("DROP TABLE {name}")
+        };
+        sqlx::query(&statement).execute(pool).await?;
+        info!(
+            "{} partition {}",
+            if detach { "Detached" } else { "Dropped" },
+            name
+        );
+        Ok(true)
+    }
+}
+
+/// Creates partitions for the next `ahead` months, and removes the ones that ended more than
+/// `keep` months ago if set
+pub async fn maintain_partitions(
+    pool: &PgPool,
+    now: DateTime<Utc>,
+    ahead: u32,
+    keep: Option<u32>,
+    detach: bool,
+) -> Result<()> {
+    let partitions = Partition::read(pool).await?;
+    let current = Partition::containing(now);
+    for months in 0..=ahead {
+        let partition = current.add_months(months);
+        if !partitions.contains(&partition) {
+            partition.create(pool).await?;
+        }
+    }
+
+    let Some(keep) = keep else {
+        return Ok(());
+    };
+    if keep == 0 {
+        return Err(anyhow!("Refusing to remove the current partition"));
+    }
+    let oldest = current.sub_months(keep);
+    for partition in partitions.iter().filter(|p| **p < oldest) {
+        partition.remove(pool, detach).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use sqlx::PgPool;
+
+    use super::*;
+    use crate::{
+        devices::NewDevice,
+        measurements::{Measurement, NewMeasurement},
+        rollups::roll_up,
+        sensors::NewSensor,
+    };
+
+    async fn count(pool: &PgPool, table: &str) -> i64 {
+        sqlx::query_scalar(&format!("SELECT count(*) FROM {table}"))
+            .fetch_one(pool)
+            .await
+            .unwrap()
+    }
+
+    #[test]
+    fn should_name_partitions() {
+        let ts = Utc.with_ymd_and_hms(2025, 12, 31, 23, 59, 59).unwrap();
+        let partition = Partition::containing(ts);
+        assert_eq!(partition.name(), "measurements_2025_12");
+        assert_eq!(
+            partition.end(),
+            Utc.with_ymd_and_hms(2026, 1, 1, 0, 0, 0).unwrap()
+        );
+        assert_eq!(
+            Partition::from_name("measurements_2025_12"),
+            Some(partition)
+        );
+        assert_eq!(Partition::from_name("measurements_default"), None);
+    }
+
+    #[sqlx::test]
+    async fn should_create_and_remove_partitions(pool: PgPool) {
+        let device = NewDevice::new("test".to_string(), "test".to_string());
+        device.insert(&pool).await.unwrap();
+        let sensor = NewSensor::new("test".to_string(), "test".to_string());
+        sensor.insert(&pool).await.unwrap();
+
+        let now = Utc::now();
+        let current = Partition::containing(now);
+        let future = current.add_months(5);
+        let old = current.sub_months(24);
+        NewMeasurement::insert_many(
+            &[
+                NewMeasurement::new(Some(now), 1, 1, 1.0),
+                NewMeasurement::new(Some(future.start()), 1, 1, 2.0),
+                NewMeasurement::new(Some(old.start()), 1, 1, 3.0),
+            ],
+            &pool,
+        )
+        .await
+        .unwrap();
+        assert_eq!(count(&pool, "measurements_default").await, 2);
+
+        maintain_partitions(&pool, now, 6, None, false)
+            .await
+            .unwrap();
+        assert_eq!(count(&pool, &future.name()).await, 1);
+        assert_eq!(count(&pool, "measurements_default").await, 1);
+        old.create(&pool).await.unwrap();
+        assert_eq!(count(&pool, "measurements_default").await, 0);
+        assert_eq!(
+            Measurement::read_total_measurements(&pool).await.unwrap(),
+            3
+        );
+
+        // Partitions are only removed once their measurements are rolled up
+        maintain_partitions(&pool, now, 6, Some(12), false)
+            .await
+            .unwrap();
+        assert!(Partition::read(&pool).await.unwrap().contains(&old));
+        roll_up(&pool, 100).await.unwrap();
+        maintain_partitions(&pool, now, 6, Some(12), true)
+            .await
+            .unwrap();
+        let partitions = Partition::read(&pool).await.unwrap();
+        assert!(!partitions.contains(&old));
+        assert!(partitions.contains(&current));
+        assert_eq!(
+            Measurement::read_total_measurements(&pool).await.unwrap(),
+            2
+        );
+        // Detached partitions are kept as standalone tables
+        assert_eq!(count(&pool, &old.name()).await, 1);
+    }
+}
@@ -0,0 +1,294 @@
+/// Samples per chunk, as in the Prometheus head block
+pub const MAX_SAMPLES: usize = 120;
+
+/// Appends bits most significant first, like the `bstream` of Prometheus
+#[derive(Debug, Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    /// Bits still free in the last byte
+    free: u32,
+}
+
+impl BitWriter {
+    fn write_bit(&mut self, bit: bool) {
+        if self.free == 0 {
+            self.bytes.push(0);
+            self.free = 8;
+        }
+        self.free -= 1;
+        if bit {
+            if let Some(last) = self.bytes.last_mut() {
+                *last |= 1 << self.free;
+            }
+        }
+    }
+
+    /// Writes the lowest `count` bits of `value`
+    fn write_bits(&mut self, value: u64, count: u32) {
+        for i in (0..count).rev() {
+            self.write_bit((value >> i) & 1 == 1);
+        }
+    }
+
+    fn write_uvarint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.write_bits((value as u8 | 0x80).into(), 8);
+            value >>= 7;
+        }
+        self.write_bits(value, 8);
+    }
+
+    fn write_varint(&mut self, value: i64) {
+        self.write_uvarint(((value << 1) ^ (value >> 63)) as u64);
+    }
+}
+
+/// A chunk in the Gorilla-style XOR encoding of Prometheus (`chunkenc.XORChunk`), as sent
+/// in streamed remote read responses. Timestamps are in milliseconds and must not decrease
+#[derive(Debug)]
+pub struct XorChunk {
+    stream: BitWriter,
+    count: u16,
+    min_time: i64,
+    time: i64,
+    time_delta: u64,
+    value: f64,
+    leading: u8,
+    trailing: u8,
+}
+
+impl Default for XorChunk {
+    fn default() -> Self {
+        Self {
+            stream: BitWriter::default(),
+            count: 0,
+            min_time: 0,
+            time: 0,
+            time_delta: 0,
+            value: 0.0,
+            // No value written yet
+            leading: 0xff,
+            trailing: 0,
+        }
+    }
+}
+
+impl XorChunk {
+    pub fn len(&self) -> usize {
+        self.count.into()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len() >= MAX_SAMPLES
+    }
+
+    pub fn min_time(&self) -> i64 {
+        self.min_time
+    }
+
+    pub fn max_time(&self) -> i64 {
+        self.time
+    }
+
+    pub fn append(&mut self, time: i64, value: f64) {
+        match self.count {
+            0 => {
+                self.min_time = time;
+                self.stream.write_varint(time);
+                self.stream.write_bits(value.to_bits(), 64);
+            }
+            1 => {
+                self.time_delta = time.wrapping_sub(self.time) as u64;
+                self.stream.write_uvarint(self.time_delta);
+                self.write_value(value);
+            }
+            _ => {
+                let time_delta = time.wrapping_sub(self.time) as u64;
+                let delta_of_delta = time_delta.wrapping_sub(self.time_delta) as i64;
+                match delta_of_delta {
+                    0 => self.stream.write_bit(false),
+                    dod if fits(dod, 14) => {
+                        self.stream.write_bits(0b10, 2);
+                        self.stream.write_bits(dod as u64, 14);
+                    }
+                    dod if fits(dod, 17) => {
+                        self.stream.write_bits(0b110, 3);
+                        self.stream.write_bits(dod as u64, 17);
+                    }
+                    dod if fits(dod, 20) => {
+                        self.stream.write_bits(0b1110, 4);
+                        self.stream.write_bits(dod as u64, 20);
+                    }
+                    dod => {
+                        self.stream.write_bits(0b1111, 4);
+                        self.stream.write_bits(dod as u64, 64);
+                    }
+                }
+                self.time_delta = time_delta;
+                self.write_value(value);
+            }
+        }
+        self.time = time;
+        self.value = value;
+        self.count += 1;
+    }
+
+    fn write_value(&mut self, value: f64) {
+        let delta = value.to_bits() ^ self.value.to_bits();
+        if delta == 0 {
+            self.stream.write_bit(false);
+            return;
+        }
+        self.stream.write_bit(true);
+        // Clamped to fit into 5 bits
+        let leading = (delta.leading_zeros() as u8).min(31);
+        let trailing = delta.trailing_zeros() as u8;
+        if self.leading != 0xff && leading >= self.leading && trailing >= self.trailing {
+            // Fits into the window of the previous value
+            self.stream.write_bit(false);
+            self.stream.write_bits(
+                delta >> self.trailing,
+                64 - u32::from(self.leading) - u32::from(self.trailing),
+            );
+            return;
+        }
+        self.leading = leading;
+        self.trailing = trailing;
+        self.stream.write_bit(true);
+        self.stream.write_bits(leading.into(), 5);
+        // 64 significant bits are written as 0, a delta of 0 never gets here
+        let significant = 64 - u32::from(leading) - u32::from(trailing);
+        self.stream.write_bits(significant.into(), 6);
+        self.stream.write_bits(delta >> trailing, significant);
+    }
+
+    /// The encoded chunk, the number of samples followed by the bit stream
+    pub fn into_bytes(self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(2 + self.stream.bytes.len());
+        bytes.extend_from_slice(&self.count.to_be_bytes());
+        bytes.extend(self.stream.bytes);
+        bytes
+    }
+}
+
+/// Whether `value` fits into `bits` bits, with the range Prometheus uses
+fn fits(value: i64, bits: u32) -> bool {
+    -((1 << (bits - 1)) - 1) <= value && value <= 1 << (bits - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reads bits like the `bstreamReader` of Prometheus
+    struct BitReader<'a> {
+        bytes: &'a [u8],
+        position: usize,
+    }
+
+    impl BitReader<'_> {
+        fn read_bit(&mut self) -> bool {
+            let bit = self.bytes[self.position / 8] >> (7 - self.position % 8) & 1 == 1;
+            self.position += 1;
+            bit
+        }
+
+        fn read_bits(&mut self, count: u32) -> u64 {
+            (0..count).fold(0, |value, _| value << 1 | u64::from(self.read_bit()))
+        }
+
+        fn read_uvarint(&mut self) -> u64 {
+            let mut value = 0;
+            for shift in (0..).step_by(7) {
+                let byte = self.read_bits(8);
+                value |= (byte & 0x7f) << shift;
+                if byte < 0x80 {
+                    break;
+                }
+            }
+            value
+        }
+    }
+
+    /// Decodes a chunk the way `xorIterator` of Prometheus does
+    fn decode(bytes: &[u8]) -> Vec<(i64, f64)> {
+        let count = u16::from_be_bytes([bytes[0], bytes[1]]);
+        let mut reader = BitReader {
+            bytes: &bytes[2..],
+            position: 0,
+        };
+        let mut samples = Vec::new();
+        let (mut time, mut delta, mut bits) = (0i64, 0u64, 0u64);
+        let (mut leading, mut trailing) = (0u32, 0u32);
+        for i in 0..count {
+            match i {
+                0 => {
+                    let zigzag = reader.read_uvarint();
+                    time = (zigzag >> 1) as i64 ^ -((zigzag & 1) as i64);
+                    bits = reader.read_bits(64);
+                }
+                _ => {
+                    if i == 1 {
+                        delta = reader.read_uvarint();
+                    } else {
+                        let mut prefix = 0;
+                        while prefix < 4 && reader.read_bit() {
+                            prefix += 1;
+                        }
+                        let size = [0, 14, 17, 20, 64][prefix];
+                        let mut dod = reader.read_bits(size) as i64;
+                        if size != 0 && size != 64 && dod > 1 << (size - 1) {
+                            dod -= 1 << size;
+                        }
+                        delta = (delta as i64 + dod) as u64;
+                    }
+                    time += delta as i64;
+                    if reader.read_bit() {
+                        if reader.read_bit() {
+                            leading = reader.read_bits(5) as u32;
+                            let mut significant = reader.read_bits(6) as u32;
+                            if significant == 0 {
+                                significant = 64;
+                            }
+                            trailing = 64 - leading - significant;
+                        }
+                        bits ^= reader.read_bits(64 - leading - trailing) << trailing;
+                    }
+                }
+            }
+            samples.push((time, f64::from_bits(bits)));
+        }
+        samples
+    }
+
+    #[test]
+    fn should_encode_samples_prometheus_can_decode() {
+        let samples = vec![
+            (1_700_000_000_000, 21.5),
+            (1_700_000_015_000, 21.5),
+            (1_700_000_030_000, 21.625),
+            (1_700_000_045_000, -3.0),
+            (1_700_000_045_001, 1e9),
+            (1_700_000_100_000, 0.1),
+            (1_700_010_000_000, 0.1),
+            (1_700_010_000_000, f64::MIN_POSITIVE),
+            (1_800_000_000_000, 42.0),
+        ];
+        let mut chunk = XorChunk::default();
+        for &(time, value) in &samples {
+            chunk.append(time, value);
+        }
+        assert_eq!(chunk.len(), samples.len());
+        assert_eq!(chunk.min_time(), 1_700_000_000_000);
+        assert_eq!(chunk.max_time(), 1_800_000_000_000);
+        assert_eq!(decode(&chunk.into_bytes()), samples);
+
+        let mut chunk = XorChunk::default();
+        chunk.append(-5, 1.0);
+        assert_eq!(decode(&chunk.into_bytes()), vec![(-5, 1.0)]);
+    }
+}
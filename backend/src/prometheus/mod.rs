@@ -0,0 +1,936 @@
+use anyhow::{anyhow, Result};
+use chrono::DateTime;
+use crc::{Crc, CRC_32_ISCSI};
+use prost::{Enumeration, Message};
+use regex::Regex;
+use sqlx::FromRow;
+use tokio::sync::mpsc;
+
+use crate::{
+    devices::NewDevice,
+    measurements::{Measurement, MeasurementQuery, NamedMeasurement},
+    sensors::NewSensor,
+    storage::Storage,
+};
+
+mod chunk;
+
+pub use chunk::XorChunk;
+
+/// Label holding the metric name
+const METRIC_NAME_LABEL: &str = "__name__";
+
+/// Metric name of measurements, as of the gauge on `/metrics`
+const METRIC_NAME: &str = "measurements";
+
+/// Measurements read per query while sending a series
+const PAGE_SIZE: i64 = 10_000;
+
+/// Frames of streamed responses are cut once they are this large, as by Prometheus
+const MAX_FRAME_BYTES: usize = 1024 * 1024;
+
+/// Checksum of streamed response frames
+const CASTAGNOLI: Crc<u32> = Crc::<u32>::new(&CRC_32_ISCSI);
+
+/// Messages of the Prometheus remote write and read protocols (`prompb`), fields hemrs does
+/// not use, like exemplars, histograms and read hints, are skipped while decoding
+#[derive(Clone, PartialEq, Message)]
+pub struct WriteRequest {
+    #[prost(message, repeated, tag = "1")]
+    pub timeseries: Vec<TimeSeries>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct TimeSeries {
+    #[prost(message, repeated, tag = "1")]
+    pub labels: Vec<Label>,
+    #[prost(message, repeated, tag = "2")]
+    pub samples: Vec<Sample>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Label {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(string, tag = "2")]
+    pub value: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Sample {
+    #[prost(double, tag = "1")]
+    pub value: f64,
+    /// Milliseconds since the epoch
+    #[prost(int64, tag = "2")]
+    pub timestamp: i64,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ReadRequest {
+    #[prost(message, repeated, tag = "1")]
+    pub queries: Vec<Query>,
+    /// In order of preference, only `Samples` if empty
+    #[prost(enumeration = "ResponseType", repeated, tag = "2")]
+    pub accepted_response_types: Vec<i32>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Enumeration)]
+#[repr(i32)]
+pub enum ResponseType {
+    /// A snappy-compressed `ReadResponse`
+    Samples = 0,
+    /// A stream of `ChunkedReadResponse` frames
+    StreamedXorChunks = 1,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Query {
+    #[prost(int64, tag = "1")]
+    pub start_timestamp_ms: i64,
+    #[prost(int64, tag = "2")]
+    pub end_timestamp_ms: i64,
+    #[prost(message, repeated, tag = "3")]
+    pub matchers: Vec<LabelMatcher>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct LabelMatcher {
+    #[prost(enumeration = "MatchType", tag = "1")]
+    pub r#type: i32,
+    #[prost(string, tag = "2")]
+    pub name: String,
+    #[prost(string, tag = "3")]
+    pub value: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Enumeration)]
+#[repr(i32)]
+pub enum MatchType {
+    Eq = 0,
+    Neq = 1,
+    Re = 2,
+    Nre = 3,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ReadResponse {
+    #[prost(message, repeated, tag = "1")]
+    pub results: Vec<QueryResult>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct QueryResult {
+    #[prost(message, repeated, tag = "1")]
+    pub timeseries: Vec<TimeSeries>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ChunkedReadResponse {
+    #[prost(message, repeated, tag = "1")]
+    pub chunked_series: Vec<ChunkedSeries>,
+    /// Index of the query in the request the series belong to
+    #[prost(int64, tag = "2")]
+    pub query_index: i64,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ChunkedSeries {
+    #[prost(message, repeated, tag = "1")]
+    pub labels: Vec<Label>,
+    #[prost(message, repeated, tag = "2")]
+    pub chunks: Vec<Chunk>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Chunk {
+    #[prost(int64, tag = "1")]
+    pub min_time_ms: i64,
+    #[prost(int64, tag = "2")]
+    pub max_time_ms: i64,
+    #[prost(enumeration = "Encoding", tag = "3")]
+    pub r#type: i32,
+    #[prost(bytes = "vec", tag = "4")]
+    pub data: Vec<u8>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Enumeration)]
+#[repr(i32)]
+pub enum Encoding {
+    Unknown = 0,
+    Xor = 1,
+}
+
+impl From<XorChunk> for Chunk {
+    fn from(chunk: XorChunk) -> Self {
+        Self {
+            min_time_ms: chunk.min_time(),
+            max_time_ms: chunk.max_time(),
+            r#type: Encoding::Xor.into(),
+            data: chunk.into_bytes(),
+        }
+    }
+}
+
+/// Names of the labels identifying the device and sensor of a series. Defaults to the
+/// labels of the `measurements` gauge on `/metrics`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PrometheusLabels {
+    pub device: String,
+    pub location: String,
+    /// Falls back to the metric name if the series has no such label
+    pub sensor: String,
+    /// Unit of sensors created for a series
+    pub unit: String,
+}
+
+impl Default for PrometheusLabels {
+    fn default() -> Self {
+        Self {
+            device: "device_name".to_string(),
+            location: "device_location".to_string(),
+            sensor: "sensor_name".to_string(),
+            unit: "unit".to_string(),
+        }
+    }
+}
+
+/// Largest decompressed request accepted, the length is declared by the client
+pub const MAX_DECOMPRESSED_SIZE: usize = 32 * 1024 * 1024;
+
+/// Decompresses a snappy block, checking the declared length before allocating for it
+fn decompress(body: &[u8]) -> Result<Vec<u8>> {
+    let len = snap::raw::decompress_len(body)
+        .map_err(|e| anyhow!("invalid snappy compression: {}", e))?;
+    if len > MAX_DECOMPRESSED_SIZE {
+        return Err(anyhow!(
+            "request decompresses to {} bytes, at most {} are accepted",
+            len,
+            MAX_DECOMPRESSED_SIZE
+        ));
+    }
+    snap::raw::Decoder::new()
+        .decompress_vec(body)
+        .map_err(|e| anyhow!("invalid snappy compression: {}", e))
+}
+
+/// Decodes a snappy-compressed remote write request
+pub fn decode_write_request(body: &[u8]) -> Result<WriteRequest> {
+    let decompressed = decompress(body)?;
+    WriteRequest::decode(decompressed.as_slice())
+        .map_err(|e| anyhow!("invalid remote write request: {}", e))
+}
+
+/// Decodes a snappy-compressed remote read request
+pub fn decode_read_request(body: &[u8]) -> Result<ReadRequest> {
+    let decompressed = decompress(body)?;
+    ReadRequest::decode(decompressed.as_slice())
+        .map_err(|e| anyhow!("invalid remote read request: {}", e))
+}
+
+/// Encodes a `ReadResponse` for the `Samples` response type
+pub fn encode_read_response(response: &ReadResponse) -> Result<Vec<u8>> {
+    Ok(snap::raw::Encoder::new().compress_vec(&response.encode_to_vec())?)
+}
+
+/// Frames a message of a streamed response, with its length and checksum
+pub fn encode_frame(response: &ChunkedReadResponse) -> Vec<u8> {
+    let message = response.encode_to_vec();
+    let mut frame = Vec::with_capacity(message.len() + 14);
+    prost::encoding::encode_varint(message.len() as u64, &mut frame);
+    frame.extend_from_slice(&CASTAGNOLI.checksum(&message).to_be_bytes());
+    frame.extend(message);
+    frame
+}
+
+/// A compiled label matcher, regular expressions are fully anchored like in PromQL
+#[derive(Debug, Clone)]
+pub enum Matcher {
+    Equal(String, String),
+    NotEqual(String, String),
+    Regex(String, Regex),
+    NotRegex(String, Regex),
+}
+
+impl TryFrom<&LabelMatcher> for Matcher {
+    type Error = anyhow::Error;
+
+    fn try_from(matcher: &LabelMatcher) -> Result<Self> {
+        let name = matcher.name.clone();
+        let regex = || {
+            Regex::new(&format!("^(?:{})$", matcher.value))
+                .map_err(|e| anyhow!("invalid regular expression {}: {}", matcher.value, e))
+        };
+        Ok(match MatchType::try_from(matcher.r#type) {
+            Ok(MatchType::Eq) => Matcher::Equal(name, matcher.value.clone()),
+            Ok(MatchType::Neq) => Matcher::NotEqual(name, matcher.value.clone()),
+            Ok(MatchType::Re) => Matcher::Regex(name, regex()?),
+            Ok(MatchType::Nre) => Matcher::NotRegex(name, regex()?),
+            Err(_) => return Err(anyhow!("unknown matcher type {}", matcher.r#type)),
+        })
+    }
+}
+
+impl Matcher {
+    /// Missing labels match as empty values
+    pub fn matches(&self, labels: &[Label]) -> bool {
+        let value = |name: &str| {
+            labels
+                .iter()
+                .find(|label| label.name == name)
+                .map_or("", |label| label.value.as_str())
+        };
+        match self {
+            Matcher::Equal(name, expected) => value(name) == expected,
+            Matcher::NotEqual(name, expected) => value(name) != expected,
+            Matcher::Regex(name, regex) => regex.is_match(value(name)),
+            Matcher::NotRegex(name, regex) => !regex.is_match(value(name)),
+        }
+    }
+}
+
+/// A label backed by a column of devices or sensors
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SeriesColumn {
+    DeviceName,
+    DeviceLocation,
+    SensorName,
+    Unit,
+}
+
+impl SeriesColumn {
+    fn from_label(name: &str) -> Option<Self> {
+        match name {
+            "device_name" => Some(SeriesColumn::DeviceName),
+            "device_location" => Some(SeriesColumn::DeviceLocation),
+            "sensor_name" => Some(SeriesColumn::SensorName),
+            "unit" => Some(SeriesColumn::Unit),
+            _ => None,
+        }
+    }
+
+    /// The column, with devices aliased as `d` and sensors as `s`
+    pub fn as_sql(&self) -> &'static str {
+        match self {
+            SeriesColumn::DeviceName => "d.name",
+            SeriesColumn::DeviceLocation => "d.location",
+            SeriesColumn::SensorName => "s.name",
+            SeriesColumn::Unit => "s.unit",
+        }
+    }
+
+    fn value<'a>(&self, row: &'a SeriesRow) -> &'a str {
+        match self {
+            SeriesColumn::DeviceName => &row.device_name,
+            SeriesColumn::DeviceLocation => &row.device_location,
+            SeriesColumn::SensorName => &row.sensor_name,
+            SeriesColumn::Unit => &row.unit,
+        }
+    }
+}
+
+/// An equality matcher on a column, applied by the storage instead of on every series
+#[derive(Debug, Clone, PartialEq)]
+pub struct SeriesFilter {
+    pub column: SeriesColumn,
+    pub value: String,
+    /// `=` if set, `!=` otherwise
+    pub equal: bool,
+}
+
+impl SeriesFilter {
+    /// Only `=` and `!=` matchers on the labels of columns can be filtered on
+    fn from_matcher(matcher: &Matcher) -> Option<Self> {
+        let (name, value, equal) = match matcher {
+            Matcher::Equal(name, value) => (name, value, true),
+            Matcher::NotEqual(name, value) => (name, value, false),
+            Matcher::Regex(..) | Matcher::NotRegex(..) => return None,
+        };
+        Some(Self {
+            column: SeriesColumn::from_label(name)?,
+            value: value.clone(),
+            equal,
+        })
+    }
+
+    pub fn matches(&self, row: &SeriesRow) -> bool {
+        (self.column.value(row) == self.value) == self.equal
+    }
+}
+
+/// A device and sensor pair with measurements
+#[derive(Debug, Clone, PartialEq, FromRow)]
+pub struct SeriesRow {
+    pub device_id: i32,
+    pub device_name: String,
+    pub device_location: String,
+    pub sensor_id: i32,
+    pub sensor_name: String,
+    pub unit: String,
+}
+
+/// The measurements of a device and sensor pair as a Prometheus series, labelled like the
+/// `measurements` gauge on `/metrics`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Series {
+    pub device_id: i32,
+    pub sensor_id: i32,
+    /// Sorted by name, as Prometheus expects
+    pub labels: Vec<Label>,
+}
+
+impl From<SeriesRow> for Series {
+    fn from(row: SeriesRow) -> Self {
+        let label = |name: &str, value: String| Label {
+            name: name.to_string(),
+            value,
+        };
+        Self {
+            device_id: row.device_id,
+            sensor_id: row.sensor_id,
+            labels: vec![
+                label(METRIC_NAME_LABEL, METRIC_NAME.to_string()),
+                label("device_location", row.device_location),
+                label("device_name", row.device_name),
+                label("sensor_name", row.sensor_name),
+                label("unit", row.unit),
+            ],
+        }
+    }
+}
+
+impl Series {
+    /// Every device and sensor pair with measurements that all `matchers` match. Equality
+    /// matchers on the device and sensor labels are filtered on in one query, everything
+    /// else, like regular expressions, is matched on the series read
+    pub async fn find(storage: &dyn Storage, matchers: &[Matcher]) -> Result<Vec<Series>> {
+        let mut filters = Vec::new();
+        let mut rest = Vec::new();
+        for matcher in matchers {
+            match SeriesFilter::from_matcher(matcher) {
+                Some(filter) => filters.push(filter),
+                None => rest.push(matcher),
+            }
+        }
+        Ok(storage
+            .read_series(&filters)
+            .await?
+            .into_iter()
+            .map(Series::from)
+            .filter(|series| rest.iter().all(|matcher| matcher.matches(&series.labels)))
+            .collect())
+    }
+
+    /// Reads the samples between `start` and `end` inclusive, in milliseconds, page by page
+    pub fn samples(&self, start: i64, end: i64) -> SamplePages {
+        SamplePages {
+            device_id: self.device_id,
+            sensor_id: self.sensor_id,
+            query: MeasurementQuery {
+                from: DateTime::from_timestamp_millis(start),
+                to: end.checked_add(1).and_then(DateTime::from_timestamp_millis),
+                limit: Some(PAGE_SIZE),
+                ..Default::default()
+            },
+            done: false,
+        }
+    }
+}
+
+/// Pages of the samples of a series, oldest first
+#[derive(Debug)]
+pub struct SamplePages {
+    device_id: i32,
+    sensor_id: i32,
+    query: MeasurementQuery,
+    done: bool,
+}
+
+impl SamplePages {
+    /// The next page, `None` once all samples were read
+    pub async fn next(&mut self, storage: &dyn Storage) -> Result<Option<Vec<Sample>>> {
+        if self.done {
+            return Ok(None);
+        }
+        let page = Measurement::read_by_device_id_and_sensor_id(
+            self.device_id,
+            self.sensor_id,
+            &self.query,
+            storage,
+        )
+        .await?;
+        self.done = page.next.is_none();
+        self.query.cursor = page.next;
+        let samples: Vec<Sample> = page
+            .measurements
+            .iter()
+            .map(|measurement| Sample {
+                value: measurement.value.into(),
+                timestamp: measurement.timestamp.timestamp_millis(),
+            })
+            .collect();
+        Ok((!samples.is_empty()).then_some(samples))
+    }
+}
+
+/// A remote read query with its matchers compiled
+#[derive(Debug, Clone)]
+pub struct SeriesQuery {
+    /// Milliseconds since the epoch, inclusive
+    pub start: i64,
+    /// Milliseconds since the epoch, inclusive
+    pub end: i64,
+    pub matchers: Vec<Matcher>,
+}
+
+impl TryFrom<&Query> for SeriesQuery {
+    type Error = anyhow::Error;
+
+    fn try_from(query: &Query) -> Result<Self> {
+        Ok(Self {
+            start: query.start_timestamp_ms,
+            end: query.end_timestamp_ms,
+            matchers: query
+                .matchers
+                .iter()
+                .map(Matcher::try_from)
+                .collect::<Result<_>>()?,
+        })
+    }
+}
+
+/// Answers a query with the `Samples` response type, all samples are read into memory
+pub async fn read_samples(storage: &dyn Storage, query: &SeriesQuery) -> Result<QueryResult> {
+    let mut timeseries = Vec::new();
+    for series in Series::find(storage, &query.matchers).await? {
+        let mut pages = series.samples(query.start, query.end);
+        let mut samples = Vec::new();
+        while let Some(page) = pages.next(storage).await? {
+            samples.extend(page);
+        }
+        if !samples.is_empty() {
+            timeseries.push(TimeSeries {
+                labels: series.labels,
+                samples,
+            });
+        }
+    }
+    Ok(QueryResult { timeseries })
+}
+
+/// Answers a query with the `StreamedXorChunks` response type, sending every frame to
+/// `frames`. One frame holds a single series, which is split over several frames once they
+/// reach `MAX_FRAME_BYTES`
+pub async fn stream_chunks(
+    storage: &dyn Storage,
+    query_index: usize,
+    query: &SeriesQuery,
+    frames: &mpsc::Sender<Result<Vec<u8>>>,
+) -> Result<()> {
+    let send = |series: &Series, chunks: Vec<Chunk>| {
+        let frame = encode_frame(&ChunkedReadResponse {
+            chunked_series: vec![ChunkedSeries {
+                labels: series.labels.clone(),
+                chunks,
+            }],
+            query_index: query_index as i64,
+        });
+        async move {
+            frames
+                .send(Ok(frame))
+                .await
+                .map_err(|_| anyhow!("the client disconnected"))
+        }
+    };
+
+    for series in Series::find(storage, &query.matchers).await? {
+        let mut pages = series.samples(query.start, query.end);
+        let mut chunks: Vec<Chunk> = Vec::new();
+        let mut size = 0;
+        let mut chunk = XorChunk::default();
+        while let Some(page) = pages.next(storage).await? {
+            for sample in page {
+                chunk.append(sample.timestamp, sample.value);
+                if !chunk.is_full() {
+                    continue;
+                }
+                let full = Chunk::from(std::mem::take(&mut chunk));
+                size += full.data.len();
+                chunks.push(full);
+                if size >= MAX_FRAME_BYTES {
+                    send(&series, std::mem::take(&mut chunks)).await?;
+                    size = 0;
+                }
+            }
+        }
+        if !chunk.is_empty() {
+            chunks.push(chunk.into());
+        }
+        if !chunks.is_empty() {
+            send(&series, chunks).await?;
+        }
+    }
+    Ok(())
+}
+
+impl TimeSeries {
+    pub fn label(&self, name: &str) -> Option<&str> {
+        self.labels
+            .iter()
+            .find(|label| label.name == name)
+            .map(|label| label.value.as_str())
+    }
+
+    /// The label set in Prometheus notation, to name a series in errors
+    pub fn describe(&self) -> String {
+        let name = self.label(METRIC_NAME_LABEL).unwrap_or_default();
+        let labels: Vec<String> = self
+            .labels
+            .iter()
+            .filter(|label| label.name != METRIC_NAME_LABEL)
+            .map(|label| format!("{}={:?}", label.name, label.value))
+            .collect();
+        format!("{}{{{}}}", name, labels.join(","))
+    }
+
+    /// One measurement per sample, of the device and sensor named by `labels`. Stale
+    /// markers and other NaN samples are skipped
+    pub fn into_named(self, labels: &PrometheusLabels) -> Result<Vec<NamedMeasurement>, String> {
+        let missing = |label: &str| format!("missing label {}", label);
+        let device = NewDevice {
+            name: self
+                .label(&labels.device)
+                .ok_or_else(|| missing(&labels.device))?
+                .to_string(),
+            location: self
+                .label(&labels.location)
+                .ok_or_else(|| missing(&labels.location))?
+                .to_string(),
+        };
+        let sensor = NewSensor {
+            name: self
+                .label(&labels.sensor)
+                .or_else(|| self.label(METRIC_NAME_LABEL))
+                .ok_or_else(|| missing(&labels.sensor))?
+                .to_string(),
+            unit: self.label(&labels.unit).unwrap_or_default().to_string(),
+        };
+
+        self.samples
+            .iter()
+            .filter(|sample| !sample.value.is_nan())
+            .map(|sample| {
+                let timestamp = DateTime::from_timestamp_millis(sample.timestamp)
+                    .ok_or_else(|| format!("invalid timestamp {}", sample.timestamp))?;
+                Ok(NamedMeasurement {
+                    timestamp: Some(timestamp),
+                    device: device.clone(),
+                    sensor: sensor.clone(),
+                    measurement: sample.value as f32,
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{devices::Device, measurements::NewMeasurement, storage::storage_tests};
+
+    pub fn series(labels: &[(&str, &str)], samples: &[(i64, f64)]) -> TimeSeries {
+        TimeSeries {
+            labels: labels
+                .iter()
+                .map(|(name, value)| Label {
+                    name: name.to_string(),
+                    value: value.to_string(),
+                })
+                .collect(),
+            samples: samples
+                .iter()
+                .map(|&(timestamp, value)| Sample { value, timestamp })
+                .collect(),
+        }
+    }
+
+    pub fn encode_write_request(timeseries: Vec<TimeSeries>) -> Vec<u8> {
+        let request = WriteRequest { timeseries };
+        snap::raw::Encoder::new()
+            .compress_vec(&request.encode_to_vec())
+            .unwrap()
+    }
+
+    pub fn matcher(r#type: MatchType, name: &str, value: &str) -> LabelMatcher {
+        LabelMatcher {
+            r#type: r#type.into(),
+            name: name.to_string(),
+            value: value.to_string(),
+        }
+    }
+
+    /// Splits a streamed response into its frames, checking their checksums
+    pub fn decode_frames(mut body: &[u8]) -> Vec<ChunkedReadResponse> {
+        let mut frames = Vec::new();
+        while !body.is_empty() {
+            let length = prost::encoding::decode_varint(&mut body).unwrap() as usize;
+            let (checksum, rest) = body.split_at(4);
+            let (message, rest) = rest.split_at(length);
+            assert_eq!(checksum, CASTAGNOLI.checksum(message).to_be_bytes());
+            frames.push(ChunkedReadResponse::decode(message).unwrap());
+            body = rest;
+        }
+        frames
+    }
+
+    #[test]
+    fn should_decode_write_requests() {
+        let body = encode_write_request(vec![series(
+            &[("__name__", "measurements"), ("device_name", "esp32")],
+            &[(1_700_000_000_000, 21.5)],
+        )]);
+        let request = decode_write_request(&body).unwrap();
+        assert_eq!(request.timeseries.len(), 1);
+        assert_eq!(request.timeseries[0].label("device_name"), Some("esp32"));
+        assert_eq!(request.timeseries[0].samples[0].value, 21.5);
+
+        assert!(decode_write_request(b"not snappy").is_err());
+    }
+
+    #[test]
+    fn should_reject_oversized_write_requests() {
+        // Only the header, declaring u32::MAX bytes
+        let body = [0xff, 0xff, 0xff, 0xff, 0x0f];
+        let error = decode_write_request(&body).unwrap_err();
+        assert!(error.to_string().contains("at most"));
+    }
+
+    #[test]
+    fn should_reject_oversized_read_requests() {
+        let body = [0xff, 0xff, 0xff, 0xff, 0x0f];
+        let error = decode_read_request(&body).unwrap_err();
+        assert!(error.to_string().contains("at most"));
+    }
+
+    #[test]
+    fn should_map_series_to_named_measurements() {
+        let labels = PrometheusLabels::default();
+        let named = series(
+            &[
+                ("__name__", "measurements"),
+                ("device_name", "esp32"),
+                ("device_location", "Attic"),
+                ("sensor_name", "temperature"),
+                ("unit", "°C"),
+            ],
+            &[(1_700_000_000_000, 21.5), (1_700_000_015_000, f64::NAN)],
+        )
+        .into_named(&labels)
+        .unwrap();
+        assert_eq!(named.len(), 1);
+        assert_eq!(named[0].device.name, "esp32");
+        assert_eq!(named[0].device.location, "Attic");
+        assert_eq!(named[0].sensor.name, "temperature");
+        assert_eq!(named[0].sensor.unit, "°C");
+        assert_eq!(named[0].timestamp.unwrap().timestamp(), 1_700_000_000);
+
+        // Node exporter style series, named by the metric
+        let labels = PrometheusLabels {
+            device: "instance".to_string(),
+            location: "room".to_string(),
+            ..Default::default()
+        };
+        let node = series(
+            &[
+                ("__name__", "node_hwmon_temp_celsius"),
+                ("instance", "pi:9100"),
+                ("room", "Attic"),
+            ],
+            &[(1_700_000_000_000, 48.0)],
+        );
+        assert_eq!(
+            node.describe(),
+            r#"node_hwmon_temp_celsius{instance="pi:9100",room="Attic"}"#
+        );
+        let named = node.into_named(&labels).unwrap();
+        assert_eq!(named[0].device.name, "pi:9100");
+        assert_eq!(named[0].sensor.name, "node_hwmon_temp_celsius");
+        assert_eq!(named[0].sensor.unit, "");
+
+        let unlabelled = series(&[("__name__", "up")], &[(0, 1.0)]);
+        assert_eq!(
+            unlabelled.into_named(&labels).unwrap_err(),
+            "missing label instance"
+        );
+    }
+
+    #[test]
+    fn should_match_labels() {
+        let labels = series(&[("device_name", "esp32"), ("unit", "°C")], &[]).labels;
+        let matches = |r#type, name, value| {
+            Matcher::try_from(&matcher(r#type, name, value))
+                .unwrap()
+                .matches(&labels)
+        };
+        assert!(matches(MatchType::Eq, "device_name", "esp32"));
+        assert!(!matches(MatchType::Eq, "device_name", "esp"));
+        assert!(matches(MatchType::Neq, "unit", "%"));
+        assert!(matches(MatchType::Re, "device_name", "esp.*"));
+        // Anchored like in PromQL
+        assert!(!matches(MatchType::Re, "device_name", "esp"));
+        assert!(matches(MatchType::Nre, "device_name", "pi|nano"));
+        // Missing labels are empty
+        assert!(matches(MatchType::Eq, "sensor_name", ""));
+        assert!(Matcher::try_from(&matcher(MatchType::Re, "unit", "(")).is_err());
+    }
+
+    #[test]
+    fn should_frame_streamed_responses() {
+        // The check value of CRC-32C
+        assert_eq!(CASTAGNOLI.checksum(b"123456789"), 0xe306_9283);
+
+        let response = ChunkedReadResponse {
+            chunked_series: vec![],
+            query_index: 3,
+        };
+        let mut body = encode_frame(&response);
+        body.extend(encode_frame(&response));
+        assert_eq!(decode_frames(&body), vec![response.clone(), response]);
+    }
+
+    async fn should_read_matching_series(storage: Arc<dyn Storage>) {
+        for (name, location) in [("esp32", "Kitchen"), ("esp32", "Attic")] {
+            NewDevice::new(name.to_string(), location.to_string())
+                .insert(storage.as_ref())
+                .await
+                .unwrap();
+        }
+        for (name, unit) in [("temperature", "°C"), ("humidity", "%")] {
+            NewSensor::new(name.to_string(), unit.to_string())
+                .insert(storage.as_ref())
+                .await
+                .unwrap();
+        }
+        let start = 1_700_000_000_000;
+        let measurement = |device, sensor, i: i64| {
+            let timestamp = DateTime::from_timestamp_millis(start + i * 15_000);
+            NewMeasurement::new(timestamp, device, sensor, i as f32)
+        };
+        let mut measurements: Vec<NewMeasurement> =
+            (0..130).map(|i| measurement(1, 1, i)).collect();
+        measurements.push(measurement(1, 2, 0));
+        measurements.push(measurement(2, 1, 0));
+        NewMeasurement::insert_many(&measurements, storage.as_ref())
+            .await
+            .unwrap();
+        Device::refresh_device_sensors_view(storage.as_ref())
+            .await
+            .unwrap();
+
+        let query = SeriesQuery::try_from(&Query {
+            start_timestamp_ms: start,
+            end_timestamp_ms: start + 100 * 15_000,
+            matchers: vec![
+                matcher(MatchType::Eq, "__name__", "measurements"),
+                matcher(MatchType::Re, "sensor_name", "temp.*"),
+            ],
+        })
+        .unwrap();
+        let result = read_samples(storage.as_ref(), &query).await.unwrap();
+        assert_eq!(result.timeseries.len(), 2);
+        let kitchen = &result.timeseries[0];
+        assert_eq!(kitchen.label("device_location"), Some("Kitchen"));
+        assert_eq!(kitchen.label("unit"), Some("°C"));
+        // The end is inclusive
+        assert_eq!(kitchen.samples.len(), 101);
+        assert_eq!(kitchen.samples[100].value, 100.0);
+        assert_eq!(kitchen.samples[100].timestamp, start + 100 * 15_000);
+
+        let query = SeriesQuery {
+            end: start + 200 * 15_000,
+            ..query
+        };
+        let (tx, mut rx) = mpsc::channel(16);
+        stream_chunks(storage.as_ref(), 1, &query, &tx)
+            .await
+            .unwrap();
+        drop(tx);
+        let mut frames = Vec::new();
+        while let Some(frame) = rx.recv().await {
+            frames.extend(decode_frames(&frame.unwrap()));
+        }
+        assert_eq!(frames.len(), 2);
+        assert!(frames.iter().all(|frame| frame.query_index == 1));
+        let chunks = &frames[0].chunked_series[0].chunks;
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0].min_time_ms, start);
+        assert_eq!(chunks[0].max_time_ms, start + 119 * 15_000);
+        assert_eq!(chunks[1].max_time_ms, start + 129 * 15_000);
+        assert_eq!(chunks[1].r#type, i32::from(Encoding::Xor));
+    }
+
+    async fn should_filter_series_by_labels(storage: Arc<dyn Storage>) {
+        for (name, location) in [("esp32", "Kitchen"), ("esp32", "Attic"), ("pi", "Attic")] {
+            NewDevice::new(name.to_string(), location.to_string())
+                .insert(storage.as_ref())
+                .await
+                .unwrap();
+        }
+        for (name, unit) in [("temperature", "°C"), ("humidity", "%")] {
+            NewSensor::new(name.to_string(), unit.to_string())
+                .insert(storage.as_ref())
+                .await
+                .unwrap();
+        }
+        let measurements: Vec<NewMeasurement> = [(1, 1), (1, 2), (2, 1), (3, 2)]
+            .into_iter()
+            .map(|(device, sensor)| NewMeasurement::new(None, device, sensor, 1.0))
+            .collect();
+        NewMeasurement::insert_many(&measurements, storage.as_ref())
+            .await
+            .unwrap();
+        Device::refresh_device_sensors_view(storage.as_ref())
+            .await
+            .unwrap();
+
+        let find = |matchers: Vec<LabelMatcher>| {
+            let storage = storage.clone();
+            async move {
+                let matchers: Vec<Matcher> =
+                    matchers.iter().map(|m| m.try_into().unwrap()).collect();
+                Series::find(storage.as_ref(), &matchers)
+                    .await
+                    .unwrap()
+                    .iter()
+                    .map(|series| (series.device_id, series.sensor_id))
+                    .collect::<Vec<_>>()
+            }
+        };
+        assert_eq!(find(vec![]).await, vec![(1, 1), (1, 2), (2, 1), (3, 2)]);
+        assert_eq!(
+            find(vec![
+                matcher(MatchType::Eq, "device_location", "Attic"),
+                matcher(MatchType::Neq, "unit", "%"),
+            ])
+            .await,
+            vec![(2, 1)]
+        );
+        assert_eq!(
+            find(vec![
+                matcher(MatchType::Eq, "device_name", "esp32"),
+                matcher(MatchType::Re, "sensor_name", "hum.*"),
+            ])
+            .await,
+            vec![(1, 2)]
+        );
+        // Labels other than those of devices and sensors are matched as usual
+        assert_eq!(
+            find(vec![matcher(MatchType::Eq, "__name__", "up")]).await,
+            vec![]
+        );
+        assert_eq!(find(vec![matcher(MatchType::Eq, "job", "")]).await.len(), 4);
+    }
+
+    storage_tests!(should_read_matching_series, should_filter_series_by_labels);
+}
@@ -0,0 +1,311 @@
+use std::{error::Error, fmt, sync::Arc, time::Duration};
+
+use anyhow::Result;
+use moka::future::Cache;
+use tokio::sync::Mutex;
+use tracing::info;
+
+use crate::{
+    devices::{Device, NewDevice},
+    measurements::{NamedMeasurement, NewMeasurement},
+    sensors::{NewSensor, Sensor},
+    storage::Storage,
+};
+
+/// How long resolved ids are kept. Changes through the API invalidate them right away, this
+/// only bounds how long changes made elsewhere, like by another instance, take to be picked up
+const CACHE_TTL: Duration = Duration::from_secs(600);
+
+/// A device or sensor that does not exist and was not created
+#[derive(Debug)]
+pub struct Unresolved(pub String);
+
+impl fmt::Display for Unresolved {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Error for Unresolved {}
+
+fn is_not_found(e: &anyhow::Error) -> bool {
+    matches!(
+        e.downcast_ref::<sqlx::Error>(),
+        Some(sqlx::Error::RowNotFound)
+    )
+}
+
+/// Resolves measurements posted by device and sensor name to ids, creating missing devices
+/// and sensors when auto-provisioning is enabled. Resolved ids are cached, so ingest only
+/// looks up names it has not seen recently. A sensor only resolves if the posted unit
+/// matches its own, or none is posted
+#[derive(Debug, Clone)]
+pub struct Resolver {
+    storage: Arc<dyn Storage>,
+    auto_provision: bool,
+    devices: Cache<(String, String), i32>,
+    /// Ids and units of sensors by name
+    sensors: Cache<String, (i32, String)>,
+    /// Held while creating, devices have no unique constraint to catch duplicates
+    lock: Arc<Mutex<()>>,
+}
+
+impl Resolver {
+    pub fn new(storage: Arc<dyn Storage>, auto_provision: bool) -> Self {
+        Self {
+            storage,
+            auto_provision,
+            devices: Cache::builder()
+                .max_capacity(1024)
+                .time_to_live(CACHE_TTL)
+                .build(),
+            sensors: Cache::builder()
+                .max_capacity(1024)
+                .time_to_live(CACHE_TTL)
+                .build(),
+            lock: Arc::new(Mutex::new(())),
+        }
+    }
+
+    /// Forgets all resolved ids, for when devices or sensors are renamed or deleted
+    pub fn invalidate(&self) {
+        self.devices.invalidate_all();
+        self.sensors.invalidate_all();
+    }
+
+    /// Fails with `Unresolved` for unknown names, unless they can be created. New devices and
+    /// sensors are only created if `create` is set
+    pub async fn resolve(
+        &self,
+        measurements: Vec<NamedMeasurement>,
+        create: bool,
+    ) -> Result<Vec<NewMeasurement>> {
+        let mut resolved = Vec::with_capacity(measurements.len());
+        for measurement in measurements {
+            let device = self.device_id(&measurement.device, create).await?;
+            let sensor = self.sensor_id(&measurement.sensor, create).await?;
+            resolved.push(NewMeasurement::new(
+                measurement.timestamp,
+                device,
+                sensor,
+                measurement.measurement,
+            ));
+        }
+        Ok(resolved)
+    }
+
+    async fn device_id(&self, device: &NewDevice, create: bool) -> Result<i32> {
+        let key = (device.name.clone(), device.location.clone());
+        if let Some(id) = self.devices.get(&key).await {
+            return Ok(id);
+        }
+        let storage = self.storage.as_ref();
+        let id = match Device::read_by_name(storage, &device.name, &device.location).await {
+            Ok(found) => found.id,
+            Err(e) if is_not_found(&e) && self.auto_provision && create => {
+                let _guard = self.lock.lock().await;
+                // Created by another request while waiting for the lock
+                match Device::read_by_name(storage, &device.name, &device.location).await {
+                    Ok(found) => found.id,
+                    Err(e) if is_not_found(&e) => {
+                        device.clone().insert(storage).await?;
+                        info!("Created device {} in {}", device.name, device.location);
+                        Device::read_by_name(storage, &device.name, &device.location)
+                            .await?
+                            .id
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+            Err(e) if is_not_found(&e) => {
+                return Err(Unresolved(format!(
+                    "Unknown device {} in {}",
+                    device.name, device.location
+                ))
+                .into())
+            }
+            Err(e) => return Err(e),
+        };
+        self.devices.insert(key, id).await;
+        Ok(id)
+    }
+
+    async fn sensor_id(&self, sensor: &NewSensor, create: bool) -> Result<i32> {
+        let (id, unit) = match self.sensors.get(&sensor.name).await {
+            Some(found) => found,
+            None => {
+                let found = self.read_sensor(sensor, create).await?;
+                let found = (found.id, found.unit);
+                self.sensors
+                    .insert(sensor.name.clone(), found.clone())
+                    .await;
+                found
+            }
+        };
+        if !sensor.unit.is_empty() && sensor.unit != unit {
+            return Err(Unresolved(format!(
+                "Sensor {} measures in {}, not {}",
+                sensor.name, unit, sensor.unit
+            ))
+            .into());
+        }
+        Ok(id)
+    }
+
+    async fn read_sensor(&self, sensor: &NewSensor, create: bool) -> Result<Sensor> {
+        let storage = self.storage.as_ref();
+        match Sensor::read_by_name(storage, &sensor.name).await {
+            Ok(found) => Ok(found),
+            Err(e) if is_not_found(&e) && self.auto_provision && create => {
+                let _guard = self.lock.lock().await;
+                match Sensor::read_by_name(storage, &sensor.name).await {
+                    Ok(found) => Ok(found),
+                    Err(e) if is_not_found(&e) => {
+                        sensor.clone().insert(storage).await?;
+                        info!("Created sensor {} ({})", sensor.name, sensor.unit);
+                        Sensor::read_by_name(storage, &sensor.name).await
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+            Err(e) if is_not_found(&e) => {
+                Err(Unresolved(format!("Unknown sensor {}", sensor.name)).into())
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::storage_tests;
+
+    fn named(device: &str, sensor: &str, value: f32) -> NamedMeasurement {
+        NamedMeasurement {
+            timestamp: None,
+            device: NewDevice::new(device.to_string(), "Kitchen".to_string()),
+            sensor: NewSensor::new(sensor.to_string(), "°C".to_string()),
+            measurement: value,
+        }
+    }
+
+    async fn should_resolve_known_names(storage: Arc<dyn Storage>) {
+        NewDevice::new("esp32".to_string(), "Kitchen".to_string())
+            .insert(storage.as_ref())
+            .await
+            .unwrap();
+        NewSensor::new("temperature".to_string(), "°C".to_string())
+            .insert(storage.as_ref())
+            .await
+            .unwrap();
+        let resolver = Resolver::new(storage.clone(), false);
+
+        let resolved = resolver
+            .resolve(vec![named("esp32", "temperature", 21.5)], true)
+            .await
+            .unwrap();
+        assert_eq!(resolved[0].device, 1);
+        assert_eq!(resolved[0].sensor, 1);
+        assert_eq!(resolved[0].measurement, 21.5);
+
+        let error = resolver
+            .resolve(vec![named("esp32", "humidity", 40.0)], true)
+            .await
+            .unwrap_err();
+        assert!(error.downcast_ref::<Unresolved>().is_some());
+        assert_eq!(Sensor::read(storage.as_ref()).await.unwrap().len(), 1);
+    }
+
+    async fn should_reject_other_units(storage: Arc<dyn Storage>) {
+        let resolver = Resolver::new(storage.clone(), true);
+        resolver
+            .resolve(vec![named("esp32", "temperature", 21.5)], true)
+            .await
+            .unwrap();
+
+        let mut fahrenheit = named("esp32", "temperature", 70.7);
+        fahrenheit.sensor.unit = "°F".to_string();
+        let error = resolver
+            .resolve(vec![fahrenheit.clone()], true)
+            .await
+            .unwrap_err();
+        assert!(error.downcast_ref::<Unresolved>().is_some());
+        assert_eq!(Sensor::read(storage.as_ref()).await.unwrap().len(), 1);
+
+        // No unit, as from line protocol without a unit tag, resolves to any
+        fahrenheit.sensor.unit = String::new();
+        assert!(resolver.resolve(vec![fahrenheit], true).await.is_ok());
+    }
+
+    async fn should_forget_invalidated_names(storage: Arc<dyn Storage>) {
+        let resolver = Resolver::new(storage.clone(), false);
+        NewDevice::new("esp32".to_string(), "Kitchen".to_string())
+            .insert(storage.as_ref())
+            .await
+            .unwrap();
+        NewSensor::new("temperature".to_string(), "°C".to_string())
+            .insert(storage.as_ref())
+            .await
+            .unwrap();
+        resolver
+            .resolve(vec![named("esp32", "temperature", 21.5)], true)
+            .await
+            .unwrap();
+
+        let mut sensor = Sensor::read_by_id(storage.as_ref(), 1).await.unwrap();
+        sensor.name = "indoor temperature".to_string();
+        sensor.update(storage.as_ref()).await.unwrap();
+        // Still cached
+        assert!(resolver
+            .resolve(vec![named("esp32", "temperature", 21.5)], true)
+            .await
+            .is_ok());
+        resolver.invalidate();
+        let error = resolver
+            .resolve(vec![named("esp32", "temperature", 21.5)], true)
+            .await
+            .unwrap_err();
+        assert!(error.downcast_ref::<Unresolved>().is_some());
+    }
+
+    async fn should_provision_unknown_names(storage: Arc<dyn Storage>) {
+        let resolver = Resolver::new(storage.clone(), true);
+
+        let resolved = resolver
+            .resolve(
+                vec![
+                    named("esp32", "temperature", 21.5),
+                    named("esp32", "humidity", 40.0),
+                    named("esp32", "temperature", 21.6),
+                ],
+                true,
+            )
+            .await
+            .unwrap();
+        assert_eq!(Device::read(storage.as_ref()).await.unwrap().len(), 1);
+        assert_eq!(Sensor::read(storage.as_ref()).await.unwrap().len(), 2);
+        assert_eq!(resolved[0].sensor, resolved[2].sensor);
+        assert_ne!(resolved[0].sensor, resolved[1].sensor);
+
+        // Devices and sensors are only created when allowed
+        let error = resolver
+            .resolve(vec![named("esp32-2", "temperature", 21.5)], false)
+            .await
+            .unwrap_err();
+        assert!(error.downcast_ref::<Unresolved>().is_some());
+        let error = resolver
+            .resolve(vec![named("esp32", "pressure", 1013.0)], false)
+            .await
+            .unwrap_err();
+        assert!(error.downcast_ref::<Unresolved>().is_some());
+        assert_eq!(Sensor::read(storage.as_ref()).await.unwrap().len(), 2);
+    }
+
+    storage_tests!(
+        should_resolve_known_names,
+        should_provision_unknown_names,
+        should_reject_other_units,
+        should_forget_invalidated_names
+    );
+}
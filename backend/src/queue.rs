@@ -0,0 +1,101 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use tokio::sync::mpsc::Sender;
+
+use crate::{measurements::NewMeasurement, spool::Spool};
+
+/// Hands accepted measurements over to the insert worker.
+///
+/// When a spool is configured, measurements are written to it and queued without waiting.
+/// Those that do not fit into the queue stay in the spool until the insert worker reads them
+/// back, so ingestion keeps going while the database is down.
+#[derive(Debug, Clone)]
+pub struct MeasurementQueue {
+    tx: Sender<NewMeasurement>,
+    spool: Option<Arc<Spool>>,
+}
+
+impl MeasurementQueue {
+    pub fn new(tx: Sender<NewMeasurement>, spool: Option<Arc<Spool>>) -> Self {
+        Self { tx, spool }
+    }
+
+    pub async fn send(&self, mut measurements: Vec<NewMeasurement>) -> Result<()> {
+        let Some(spool) = &self.spool else {
+            for measurement in measurements {
+                self.tx.send(measurement).await?;
+            }
+            return Ok(());
+        };
+        // Spooled measurements may be replayed much later, so pin the time they were accepted
+        let now = chrono::Utc::now();
+        for measurement in measurements.iter_mut() {
+            measurement.timestamp.get_or_insert(now);
+        }
+        let spool = spool.clone();
+        let tx = self.tx.clone();
+        tokio::task::spawn_blocking(move || {
+            spool.append_and_forward(&measurements, |measurement| {
+                tx.try_send(measurement.clone()).is_ok()
+            })
+        })
+        .await?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn should_spool_measurements_before_queueing() {
+        let dir = std::env::temp_dir().join(format!("hemrs-queue-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let (spool, _) = Spool::open(&dir).unwrap();
+        let (tx, mut rx) = tokio::sync::mpsc::channel(16);
+        let queue = MeasurementQueue::new(tx, Some(Arc::new(spool)));
+
+        queue
+            .send(vec![NewMeasurement::new(None, 1, 1, 1.0)])
+            .await
+            .unwrap();
+        let queued = rx.recv().await.unwrap();
+        assert!(queued.timestamp.is_some());
+        drop(queue);
+
+        let (_spool, pending) = Spool::open(&dir).unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].timestamp, queued.timestamp);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn should_keep_measurements_in_the_spool_when_the_queue_is_full() {
+        let dir = std::env::temp_dir().join(format!("hemrs-queue-full-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let (spool, _) = Spool::open(&dir).unwrap();
+        let spool = Arc::new(spool);
+        let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+        let queue = MeasurementQueue::new(tx, Some(spool.clone()));
+
+        for value in [1.0, 2.0, 3.0] {
+            queue
+                .send(vec![NewMeasurement::new(None, 1, 1, value)])
+                .await
+                .unwrap();
+        }
+        assert_eq!(rx.recv().await.unwrap().measurement, 1.0);
+        assert!(rx.try_recv().is_err());
+
+        spool.ack(1).unwrap();
+        let backlog: Vec<f32> = spool
+            .read_backlog()
+            .unwrap()
+            .iter()
+            .map(|m| m.measurement)
+            .collect();
+        assert_eq!(backlog, vec![2.0, 3.0]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,252 @@
+use anyhow::Result;
+use chrono::Duration;
+use sqlx::PgPool;
+
+use crate::measurements::AggregateFunction;
+
+/// Where aggregates are read from
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Resolution {
+    Raw,
+    Hourly,
+    Daily,
+}
+
+impl Resolution {
+    /// Picks the coarsest resolution that can answer a query.
+    ///
+    /// Rollups only help when the range spans many of their buckets, and can only be used
+    /// when the bucket is a multiple of theirs and all functions can be derived from them.
+    pub fn pick(
+        bucket: Duration,
+        functions: &[AggregateFunction],
+        range: Option<Duration>,
+    ) -> Self {
+        if functions.iter().any(|f| f.as_rollup_sql().is_none()) {
+            return Resolution::Raw;
+        }
+        let spans = |duration: Duration| range.is_none_or(|range| range > duration);
+        [Resolution::Daily, Resolution::Hourly]
+            .into_iter()
+            .find(|resolution| {
+                let size = resolution.bucket().num_seconds();
+                bucket.num_seconds() % size == 0 && spans(resolution.min_range())
+            })
+            .unwrap_or(Resolution::Raw)
+    }
+
+    pub fn bucket(&self) -> Duration {
+        match self {
+            Resolution::Raw => Duration::zero(),
+            Resolution::Hourly => Duration::hours(1),
+            Resolution::Daily => Duration::days(1),
+        }
+    }
+
+    pub fn table(&self) -> &'static str {
+        match self {
+            Resolution::Raw => "measurements",
+            Resolution::Hourly => "measurements_hourly",
+            Resolution::Daily => "measurements_daily",
+        }
+    }
+
+    fn min_range(&self) -> Duration {
+        match self {
+            Resolution::Raw => Duration::zero(),
+            Resolution::Hourly => Duration::days(2),
+            Resolution::Daily => Duration::days(60),
+        }
+    }
+}
+
+/// Advisory lock key taken shared by every transaction inserting measurements and exclusively
+/// by `roll_up`, so no measurement is still being inserted while the watermark moves
+pub const MEASUREMENTS_LOCK: i64 = 0x6865_6d72_735f_6d73;
+
+/// Folds up to `limit` measurements that were not rolled up yet into the hourly and daily
+/// rollups, returning how many were processed.
+///
+/// Progress is tracked by measurement id, so late measurements are picked up like any other
+/// and the hourly and daily buckets they fall into are updated. Ids are handed out before
+/// inserts commit, so writers are held off with `MEASUREMENTS_LOCK` until the watermark moved,
+/// otherwise an id of a concurrent writer, like an archive import, could be skipped.
+pub async fn roll_up(pool: &PgPool, limit: i64) -> Result<u64> {
+    let mut tx = pool.begin().await?;
+    sqlx::query("SELECT pg_advisory_xact_lock($1)")
+        .bind(MEASUREMENTS_LOCK)
+        .execute(&mut *tx)
+        .await?;
+    let last_id =
+        sqlx::query_scalar::<_, i32>("SELECT last_measurement_id FROM rollup_state FOR UPDATE")
+            .fetch_one(&mut *tx)
+            .await?;
+    let (count, upper) = sqlx::query_as::<_, (i64, Option<i32>)>(
+        "SELECT count(*), max(id) FROM (SELECT id FROM measurements WHERE id > $1 ORDER BY id LIMIT $2) m",
+    )
+    .bind(last_id)
+    .bind(limit)
+    .fetch_one(&mut *tx)
+    .await?;
+    let Some(upper) = upper else {
+        return Ok(0);
+    };
+
+    sqlx::query(
+        "INSERT INTO measurements_hourly AS h (device_id, sensor_id, bucket, count, sum, min, max) \
+        SELECT device_id, sensor_id, date_bin('1 hour', ts, TIMESTAMPTZ '2000-01-01'), count(*), sum(value::float8), min(value), max(value) \
+        FROM measurements WHERE id > $1 AND id <= $2 GROUP BY 1, 2, 3 \
+        ON CONFLICT (device_id, sensor_id, bucket) DO UPDATE SET count = h.count + excluded.count, sum = h.sum + excluded.sum, \
+        min = LEAST(h.min, excluded.min), max = GREATEST(h.max, excluded.max)",
+    )
+    .bind(last_id)
+    .bind(upper)
+    .execute(&mut *tx)
+    .await?;
+
+    // Days are re-aggregated from their hours, which also covers hours kept after pruning
+    sqlx::query(
+        "INSERT INTO measurements_daily AS d (device_id, sensor_id, bucket, count, sum, min, max) \
+        SELECT h.device_id, h.sensor_id, date_bin('1 day', h.bucket, TIMESTAMPTZ '2000-01-01') AS day, sum(h.count)::int8, sum(h.sum), min(h.min), max(h.max) \
+        FROM measurements_hourly h \
+        WHERE (h.device_id, h.sensor_id, date_bin('1 day', h.bucket, TIMESTAMPTZ '2000-01-01')) IN \
+        (SELECT device_id, sensor_id, date_bin('1 day', ts, TIMESTAMPTZ '2000-01-01') FROM measurements WHERE id > $1 AND id <= $2) \
+        GROUP BY 1, 2, 3 \
+        ON CONFLICT (device_id, sensor_id, bucket) DO UPDATE SET count = excluded.count, sum = excluded.sum, min = excluded.min, max = excluded.max",
+    )
+    .bind(last_id)
+    .bind(upper)
+    .execute(&mut *tx)
+    .await?;
+
+    sqlx::query("UPDATE rollup_state SET last_measurement_id = $1")
+        .bind(upper)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    Ok(count as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, TimeZone, Utc};
+    use sqlx::PgPool;
+
+    use super::*;
+    use crate::{devices::NewDevice, measurements::NewMeasurement, sensors::NewSensor};
+
+    #[test]
+    fn should_pick_resolution() {
+        let avg = [AggregateFunction::Avg, AggregateFunction::Max];
+        let week = Some(Duration::weeks(1));
+        assert_eq!(
+            Resolution::pick(Duration::hours(1), &avg, week),
+            Resolution::Hourly
+        );
+        assert_eq!(
+            Resolution::pick(Duration::days(1), &avg, week),
+            Resolution::Hourly
+        );
+        assert_eq!(
+            Resolution::pick(Duration::days(1), &avg, None),
+            Resolution::Daily
+        );
+        assert_eq!(
+            Resolution::pick(Duration::minutes(90), &avg, week),
+            Resolution::Raw
+        );
+        assert_eq!(
+            Resolution::pick(Duration::hours(1), &avg, Some(Duration::hours(12))),
+            Resolution::Raw
+        );
+        assert_eq!(
+            Resolution::pick(Duration::hours(1), &[AggregateFunction::Last], week),
+            Resolution::Raw
+        );
+    }
+
+    #[sqlx::test]
+    async fn should_roll_up_late_measurements(pool: PgPool) {
+        let device = NewDevice::new("test".to_string(), "test".to_string());
+        device.insert(&pool).await.unwrap();
+        let sensor = NewSensor::new("test".to_string(), "test".to_string());
+        sensor.insert(&pool).await.unwrap();
+
+        let start = Utc.with_ymd_and_hms(2025, 1, 1, 10, 0, 0).unwrap();
+        let measurements = vec![
+            NewMeasurement::new(Some(start), 1, 1, 1.0),
+            NewMeasurement::new(Some(start + Duration::minutes(30)), 1, 1, 3.0),
+            NewMeasurement::new(Some(start + Duration::hours(1)), 1, 1, 5.0),
+        ];
+        NewMeasurement::insert_many(&measurements, &pool)
+            .await
+            .unwrap();
+        assert_eq!(roll_up(&pool, 2).await.unwrap(), 2);
+        assert_eq!(roll_up(&pool, 2).await.unwrap(), 1);
+        assert_eq!(roll_up(&pool, 2).await.unwrap(), 0);
+
+        // Arrives after its hour was already rolled up
+        NewMeasurement::insert_many(
+            &[NewMeasurement::new(
+                Some(start + Duration::minutes(45)),
+                1,
+                1,
+                -1.0,
+            )],
+            &pool,
+        )
+        .await
+        .unwrap();
+        assert_eq!(roll_up(&pool, 2).await.unwrap(), 1);
+
+        let hours: Vec<(i64, f64, f32, f32)> =
+            sqlx::query_as("SELECT count, sum, min, max FROM measurements_hourly ORDER BY bucket")
+                .fetch_all(&pool)
+                .await
+                .unwrap();
+        assert_eq!(hours, vec![(3, 3.0, -1.0, 3.0), (1, 5.0, 5.0, 5.0)]);
+
+        let days: Vec<(i64, f64, f32, f32)> =
+            sqlx::query_as("SELECT count, sum, min, max FROM measurements_daily")
+                .fetch_all(&pool)
+                .await
+                .unwrap();
+        assert_eq!(days, vec![(4, 8.0, -1.0, 5.0)]);
+    }
+
+    #[sqlx::test]
+    async fn should_wait_for_concurrent_inserts(pool: PgPool) {
+        let device = NewDevice::new("test".to_string(), "test".to_string());
+        device.insert(&pool).await.unwrap();
+        let sensor = NewSensor::new("test".to_string(), "test".to_string());
+        sensor.insert(&pool).await.unwrap();
+
+        // A writer that got the lower id but has not committed yet
+        let mut tx = pool.begin().await.unwrap();
+        sqlx::query("SELECT pg_advisory_xact_lock_shared($1)")
+            .bind(MEASUREMENTS_LOCK)
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+        sqlx::query(
+            "INSERT INTO measurements (ts, device_id, sensor_id, value) VALUES (now(), 1, 1, 1.0)",
+        )
+        .execute(&mut *tx)
+        .await
+        .unwrap();
+        NewMeasurement::insert_many(&[NewMeasurement::new(None, 1, 1, 2.0)], &pool)
+            .await
+            .unwrap();
+
+        let rollup = tokio::spawn({
+            let pool = pool.clone();
+            async move { roll_up(&pool, 10).await.unwrap() }
+        });
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+        assert!(!rollup.is_finished());
+
+        tx.commit().await.unwrap();
+        assert_eq!(rollup.await.unwrap(), 2);
+        assert_eq!(roll_up(&pool, 10).await.unwrap(), 0);
+    }
+}
@@ -1,8 +1,8 @@
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
-use sqlx::{FromRow, PgPool};
+use sqlx::FromRow;
 
-use crate::devices::Device;
+use crate::storage::Storage;
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct NewSensor {
@@ -15,54 +15,51 @@ pub struct Sensor {
     pub id: i32,
     pub name: String,
     pub unit: String,
+    /// How often the sensor is expected to report, overrides the interval of the device
+    #[serde(default)]
+    pub expected_interval_seconds: Option<i32>,
+    /// Days raw measurements are kept, overrides the global retention
+    #[serde(default)]
+    pub retention_days: Option<i32>,
 }
 
 impl Sensor {
     pub fn new(id: i32, name: String, unit: String) -> Self {
-        Self { id, name, unit }
+        Self {
+            id,
+            name,
+            unit,
+            expected_interval_seconds: None,
+            retention_days: None,
+        }
     }
 
-    pub async fn read(pool: &PgPool) -> Result<Vec<Sensor>> {
-        let sensors = sqlx::query_as::<_, Sensor>("SELECT id, name, unit FROM sensors")
-            .fetch_all(pool)
-            .await?;
-        Ok(sensors)
+    pub async fn read(storage: &dyn Storage) -> Result<Vec<Sensor>> {
+        storage.read_sensors().await
     }
 
-    pub async fn read_by_id(pool: &PgPool, sensor_id: i32) -> Result<Sensor> {
-        let sensors =
-            sqlx::query_as::<_, Sensor>("SELECT id, name, unit FROM sensors WHERE id = $1")
-                .bind(sensor_id)
-                .fetch_one(pool)
-                .await?;
-        Ok(sensors)
+    pub async fn read_by_id(storage: &dyn Storage, sensor_id: i32) -> Result<Sensor> {
+        storage.read_sensor_by_id(sensor_id).await
     }
 
-    pub async fn delete(self, pool: &PgPool) -> Result<()> {
-        sqlx::query("DELETE FROM sensors WHERE id = $1")
-            .bind(self.id)
-            .execute(pool)
-            .await?;
-        Ok(())
+    pub async fn read_by_name(storage: &dyn Storage, name: &str) -> Result<Sensor> {
+        storage.read_sensor_by_name(name).await
     }
 
-    pub async fn update(self, pool: &PgPool) -> anyhow::Result<()> {
-        sqlx::query("UPDATE sensors SET name = $1,unit = $2 WHERE id = $3")
-            .bind(self.name)
-            .bind(self.unit)
-            .bind(self.id)
-            .execute(pool)
-            .await?;
-        Device::refresh_device_sensors_view(pool).await?;
-        Ok(())
+    pub async fn read_by_ids(storage: &dyn Storage, sensor_ids: &[i32]) -> Result<Vec<Sensor>> {
+        storage.read_sensors_by_ids(sensor_ids).await
     }
 
-    pub async fn read_by_device_id(pool: &PgPool, device_id: i32) -> Result<Vec<Sensor>> {
-        let sensors = sqlx::query_as::<_, Sensor>("SELECT ds.sensor_id as id, ds.name, ds.unit from device_sensors ds WHERE ds.device_id = $1 order by ds.sensor_id")
-            .bind(device_id)
-            .fetch_all(pool)
-            .await?;
-        Ok(sensors)
+    pub async fn delete(self, storage: &dyn Storage) -> Result<()> {
+        storage.delete_sensor(self.id).await
+    }
+
+    pub async fn update(self, storage: &dyn Storage) -> anyhow::Result<()> {
+        storage.update_sensor(self).await
+    }
+
+    pub async fn read_by_device_id(storage: &dyn Storage, device_id: i32) -> Result<Vec<Sensor>> {
+        storage.read_sensors_by_device_id(device_id).await
     }
 }
 
@@ -71,81 +68,83 @@ impl NewSensor {
         Self { name, unit }
     }
 
-    pub async fn insert(self, pool: &PgPool) -> Result<()> {
-        sqlx::query("INSERT INTO sensors (name, unit) VALUES ($1, $2)")
-            .bind(self.name)
-            .bind(self.unit)
-            .execute(pool)
-            .await?;
-        Device::refresh_device_sensors_view(pool).await?;
-        Ok(())
+    pub async fn insert(self, storage: &dyn Storage) -> Result<()> {
+        storage.insert_sensor(self).await
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use sqlx::PgPool;
+    use std::sync::Arc;
 
     use crate::{
         devices::{Device, NewDevice},
         measurements::NewMeasurement,
         sensors::{NewSensor, Sensor},
+        storage::{storage_tests, Storage},
     };
 
-    #[sqlx::test]
-    async fn insert(pool: PgPool) {
+    async fn insert(storage: Arc<dyn Storage>) {
         let sensor = NewSensor::new("test".to_string(), "test".to_string());
-        sensor.insert(&pool).await.unwrap();
-        let sensors = Sensor::read(&pool).await.unwrap();
+        sensor.insert(storage.as_ref()).await.unwrap();
+        let sensors = Sensor::read(storage.as_ref()).await.unwrap();
 
         assert!(!sensors.is_empty());
         assert_eq!(sensors.last().unwrap().name, "test");
         assert_eq!(sensors.last().unwrap().unit, "test");
     }
 
-    #[sqlx::test]
-    async fn delete(pool: PgPool) {
+    async fn delete(storage: Arc<dyn Storage>) {
         let sensor = NewSensor::new("test".to_string(), "test".to_string());
-        sensor.clone().insert(&pool).await.unwrap();
-        let sensors = Sensor::read(&pool).await.unwrap();
-        let sensor = sensors.last().unwrap().clone().delete(&pool).await;
+        sensor.clone().insert(storage.as_ref()).await.unwrap();
+        let sensors = Sensor::read(storage.as_ref()).await.unwrap();
+        let sensor = sensors
+            .last()
+            .unwrap()
+            .clone()
+            .delete(storage.as_ref())
+            .await;
         assert!(sensor.is_ok());
     }
 
-    #[sqlx::test]
-    async fn update(pool: PgPool) {
+    async fn update(storage: Arc<dyn Storage>) {
         let sensor = NewSensor::new("test".to_string(), "test".to_string());
-        sensor.clone().insert(&pool).await.unwrap();
-        let sensors = Sensor::read(&pool).await.unwrap();
+        sensor.clone().insert(storage.as_ref()).await.unwrap();
+        let sensors = Sensor::read(storage.as_ref()).await.unwrap();
         let sensor = sensors.last().unwrap().clone();
         let sensor = Sensor::new(sensor.id, "test2".to_string(), "test2".to_string());
-        sensor.clone().update(&pool).await.unwrap();
+        sensor.clone().update(storage.as_ref()).await.unwrap();
 
-        let sensors = Sensor::read(&pool).await.unwrap();
+        let sensors = Sensor::read(storage.as_ref()).await.unwrap();
         assert_eq!(sensors.last().unwrap().name, "test2");
         assert_eq!(sensors.last().unwrap().unit, "test2");
     }
 
-    #[sqlx::test]
-    async fn read_by_device_id(pool: PgPool) {
+    async fn read_by_device_id(storage: Arc<dyn Storage>) {
         let device = NewDevice::new("test".to_string(), "test".to_string());
-        device.clone().insert(&pool).await.unwrap();
+        device.clone().insert(storage.as_ref()).await.unwrap();
         let sensor = NewSensor::new("test".to_string(), "test".to_string());
-        sensor.clone().insert(&pool).await.unwrap();
+        sensor.clone().insert(storage.as_ref()).await.unwrap();
         let sensor = NewSensor::new("test2".to_string(), "test".to_string());
-        sensor.clone().insert(&pool).await.unwrap();
+        sensor.clone().insert(storage.as_ref()).await.unwrap();
 
         let measurement = NewMeasurement::new(None, 1, 1, 1.0);
-        measurement.insert(&pool).await.unwrap();
+        measurement.insert(storage.as_ref()).await.unwrap();
         let measurement2 = NewMeasurement::new(None, 1, 2, 1.0);
-        measurement2.insert(&pool).await.unwrap();
+        measurement2.insert(storage.as_ref()).await.unwrap();
         let measurement3 = NewMeasurement::new(None, 1, 2, 1.0);
-        measurement3.insert(&pool).await.unwrap();
+        measurement3.insert(storage.as_ref()).await.unwrap();
 
-        Device::refresh_device_sensors_view(&pool).await.unwrap();
+        Device::refresh_device_sensors_view(storage.as_ref())
+            .await
+            .unwrap();
 
-        let sensors = Sensor::read_by_device_id(&pool, 1).await.unwrap();
+        let sensors = Sensor::read_by_device_id(storage.as_ref(), 1)
+            .await
+            .unwrap();
         assert!(!sensors.is_empty());
         assert_eq!(sensors.len(), 2);
     }
+
+    storage_tests!(insert, delete, update, read_by_device_id);
 }
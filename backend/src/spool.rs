@@ -0,0 +1,406 @@
+use std::{
+    collections::VecDeque,
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::{Mutex, MutexGuard},
+};
+
+use anyhow::{anyhow, Result};
+use tracing::{info, warn};
+
+use crate::measurements::NewMeasurement;
+
+const SEGMENT_MAX_ENTRIES: usize = 1 << 16;
+const POSITION_FILE: &str = "position";
+
+#[derive(Debug)]
+struct Segment {
+    index: u64,
+    entries: usize,
+    acked: usize,
+}
+
+impl Segment {
+    fn is_acked(&self) -> bool {
+        self.acked == self.entries
+    }
+}
+
+#[derive(Debug)]
+struct SpoolState {
+    segments: VecDeque<Segment>,
+    current: File,
+    /// Entries appended, handed to the insert worker and acknowledged since the spool was opened
+    appended: u64,
+    queued: u64,
+    acked: u64,
+}
+
+/// Append-only write-ahead log of measurements that are not yet stored in the database.
+///
+/// Entries are stored as JSON lines in numbered segment files and are consumed in the
+/// order they were appended, so acknowledging is done by count. The acknowledged
+/// position is persisted next to the segments, and a segment is removed once all of
+/// its entries are acknowledged.
+///
+/// Entries are forwarded to the insert worker as they are appended. Once that fails, because
+/// the worker is falling behind, later entries are only kept on disk as the backlog, which the
+/// worker reads back once it caught up.
+#[derive(Debug)]
+pub struct Spool {
+    dir: PathBuf,
+    state: Mutex<SpoolState>,
+}
+
+impl Spool {
+    /// Opens the spool in `dir`, returning it along with all entries that are still pending
+    pub fn open(dir: impl AsRef<Path>) -> Result<(Self, Vec<NewMeasurement>)> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        let mut indexes: Vec<u64> = fs::read_dir(&dir)?
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let name = entry.file_name().into_string().ok()?;
+                name.strip_suffix(".log")?.parse().ok()
+            })
+            .collect();
+        indexes.sort_unstable();
+        let (position_index, position_acked) = read_position(&dir)?.unwrap_or((0, 0));
+
+        let mut pending = Vec::new();
+        let mut segments = VecDeque::new();
+        for index in &indexes {
+            let path = segment_path(&dir, *index);
+            if *index < position_index {
+                fs::remove_file(&path)?;
+                continue;
+            }
+            let entries = read_segment(&path)?;
+            let acked = if *index == position_index {
+                position_acked.min(entries.len())
+            } else {
+                0
+            };
+            if acked == entries.len() {
+                fs::remove_file(&path)?;
+                continue;
+            }
+            info!(
+                "Replaying {} measurements from {:?}",
+                entries.len() - acked,
+                path
+            );
+            segments.push_back(Segment {
+                index: *index,
+                entries: entries.len(),
+                acked,
+            });
+            pending.extend(entries.into_iter().skip(acked));
+        }
+
+        let next_index = indexes
+            .last()
+            .map_or(0, |index| index + 1)
+            .max(position_index + 1);
+        let current = create_segment(&dir, next_index)?;
+        segments.push_back(Segment {
+            index: next_index,
+            entries: 0,
+            acked: 0,
+        });
+
+        let spool = Self {
+            dir,
+            state: Mutex::new(SpoolState {
+                segments,
+                current,
+                appended: pending.len() as u64,
+                queued: 0,
+                acked: 0,
+            }),
+        };
+        Ok((spool, pending))
+    }
+
+    /// Appends measurements and syncs them to disk, leaving them to the backlog
+    #[cfg(test)]
+    pub fn append(&self, measurements: &[NewMeasurement]) -> Result<()> {
+        self.append_and_forward(measurements, |_| false)
+    }
+
+    /// Appends measurements and syncs them to disk, then hands them to `forward` in order until
+    /// it refuses one. Nothing is forwarded while there is a backlog, it has to be read first
+    pub fn append_and_forward(
+        &self,
+        measurements: &[NewMeasurement],
+        mut forward: impl FnMut(&NewMeasurement) -> bool,
+    ) -> Result<()> {
+        let mut buf = Vec::new();
+        for measurement in measurements {
+            serde_json::to_writer(&mut buf, measurement)?;
+            buf.push(b'\n');
+        }
+
+        let mut state = self.lock()?;
+        state.current.write_all(&buf)?;
+        state.current.sync_data()?;
+        let segment = state
+            .segments
+            .back_mut()
+            .ok_or_else(|| anyhow!("Spool has no open segment"))?;
+        segment.entries += measurements.len();
+        let full = segment.entries >= SEGMENT_MAX_ENTRIES;
+        let backlogged = state.queued < state.appended;
+        state.appended += measurements.len() as u64;
+
+        if full {
+            self.rotate(&mut state)?;
+        }
+        if !backlogged {
+            for measurement in measurements {
+                if !forward(measurement) {
+                    break;
+                }
+                state.queued += 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// The oldest backlog entries, up to the end of their segment. Only read once every
+    /// forwarded entry is acknowledged, so entries are still stored in the order they were appended
+    pub fn read_backlog(&self) -> Result<Vec<NewMeasurement>> {
+        let mut state = self.lock()?;
+        if state.acked < state.queued || state.queued == state.appended {
+            return Ok(Vec::new());
+        }
+        let Some(segment) = state.segments.iter().find(|s| !s.is_acked()) else {
+            return Ok(Vec::new());
+        };
+        let entries: Vec<NewMeasurement> = read_segment(&segment_path(&self.dir, segment.index))?
+            .into_iter()
+            .take(segment.entries)
+            .skip(segment.acked)
+            .collect();
+        state.queued += entries.len() as u64;
+        Ok(entries)
+    }
+
+    /// Acknowledges the `count` oldest entries as stored, removing them from the spool
+    pub fn ack(&self, mut count: usize) -> Result<()> {
+        let mut state = self.lock()?;
+        let mut position = None;
+        let mut total = 0;
+        for segment in state.segments.iter_mut() {
+            if count == 0 {
+                break;
+            }
+            let acked = count.min(segment.entries - segment.acked);
+            segment.acked += acked;
+            total += acked as u64;
+            count -= acked;
+            position = Some((segment.index, segment.acked));
+        }
+        state.acked += total;
+        if count > 0 {
+            warn!("Acknowledged {} more measurements than spooled", count);
+        }
+        let Some((index, acked)) = position else {
+            return Ok(());
+        };
+        write_position(&self.dir, index, acked)?;
+
+        if state.segments.iter().all(|s| s.is_acked()) {
+            self.rotate(&mut state)?;
+        }
+        while state.segments.len() > 1 && state.segments.front().is_some_and(|s| s.is_acked()) {
+            if let Some(segment) = state.segments.pop_front() {
+                fs::remove_file(segment_path(&self.dir, segment.index))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn rotate(&self, state: &mut SpoolState) -> Result<()> {
+        let index = state.segments.back().map_or(0, |s| s.index + 1);
+        state.current = create_segment(&self.dir, index)?;
+        state.segments.push_back(Segment {
+            index,
+            entries: 0,
+            acked: 0,
+        });
+        Ok(())
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, SpoolState>> {
+        self.state
+            .lock()
+            .map_err(|_| anyhow!("Spool lock was poisoned"))
+    }
+}
+
+fn segment_path(dir: &Path, index: u64) -> PathBuf {
+    dir.join(format!("{index:020}.log"))
+}
+
+fn create_segment(dir: &Path, index: u64) -> Result<File> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(segment_path(dir, index))?;
+    Ok(file)
+}
+
+fn read_segment(path: &Path) -> Result<Vec<NewMeasurement>> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut entries = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        match serde_json::from_str::<NewMeasurement>(&line) {
+            Ok(entry) => entries.push(entry),
+            // A torn write from a crash can only be the last line of a segment
+            Err(e) => warn!("Skipping unreadable entry in {:?}: {}", path, e),
+        }
+    }
+    Ok(entries)
+}
+
+fn read_position(dir: &Path) -> Result<Option<(u64, usize)>> {
+    let path = dir.join(POSITION_FILE);
+    if !path.exists() {
+        return Ok(None);
+    }
+    let content = fs::read_to_string(&path)?;
+    let (index, acked) = content
+        .trim()
+        .split_once(' ')
+        .ok_or_else(|| anyhow!("Invalid spool position in {:?}", path))?;
+    Ok(Some((index.parse()?, acked.parse()?)))
+}
+
+fn write_position(dir: &Path, index: u64, acked: usize) -> Result<()> {
+    let tmp = dir.join(format!("{POSITION_FILE}.tmp"));
+    let mut file = File::create(&tmp)?;
+    write!(file, "{index} {acked}")?;
+    file.sync_data()?;
+    fs::rename(tmp, dir.join(POSITION_FILE))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spool_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("hemrs-spool-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn values(measurements: &[NewMeasurement]) -> Vec<f32> {
+        measurements.iter().map(|m| m.measurement).collect()
+    }
+
+    #[test]
+    fn should_replay_unacknowledged_entries() {
+        let dir = spool_dir("replay");
+        let (spool, pending) = Spool::open(&dir).unwrap();
+        assert!(pending.is_empty());
+
+        spool
+            .append(&[
+                NewMeasurement::new(None, 1, 1, 1.0),
+                NewMeasurement::new(None, 1, 1, 2.0),
+                NewMeasurement::new(None, 1, 1, 3.0),
+            ])
+            .unwrap();
+        spool.ack(1).unwrap();
+        drop(spool);
+
+        let (spool, pending) = Spool::open(&dir).unwrap();
+        assert_eq!(values(&pending), vec![2.0, 3.0]);
+
+        spool
+            .append(&[NewMeasurement::new(None, 1, 1, 4.0)])
+            .unwrap();
+        spool.ack(1).unwrap();
+        drop(spool);
+
+        let (_spool, pending) = Spool::open(&dir).unwrap();
+        assert_eq!(values(&pending), vec![3.0, 4.0]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn should_remove_acknowledged_segments() {
+        let dir = spool_dir("remove");
+        let (spool, _) = Spool::open(&dir).unwrap();
+        spool
+            .append(&[
+                NewMeasurement::new(None, 1, 1, 1.0),
+                NewMeasurement::new(None, 1, 1, 2.0),
+            ])
+            .unwrap();
+        spool.ack(2).unwrap();
+        spool
+            .append(&[NewMeasurement::new(None, 1, 1, 3.0)])
+            .unwrap();
+        drop(spool);
+
+        let (spool, pending) = Spool::open(&dir).unwrap();
+        assert_eq!(values(&pending), vec![3.0]);
+
+        spool.ack(1).unwrap();
+        drop(spool);
+        let (_spool, pending) = Spool::open(&dir).unwrap();
+        assert!(pending.is_empty());
+
+        // Only the open segment and the position are left
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn should_read_backlog_once_forwarded_entries_are_acknowledged() {
+        let dir = spool_dir("backlog");
+        let (spool, _) = Spool::open(&dir).unwrap();
+        let mut forwarded = Vec::new();
+        spool
+            .append_and_forward(
+                &[
+                    NewMeasurement::new(None, 1, 1, 1.0),
+                    NewMeasurement::new(None, 1, 1, 2.0),
+                ],
+                |measurement| {
+                    forwarded.push(measurement.clone());
+                    forwarded.len() < 2
+                },
+            )
+            .unwrap();
+        assert_eq!(values(&forwarded), vec![1.0, 2.0]);
+
+        // Nothing is forwarded until the backlog is read
+        spool
+            .append_and_forward(&[NewMeasurement::new(None, 1, 1, 3.0)], |_| true)
+            .unwrap();
+        assert!(spool.read_backlog().unwrap().is_empty());
+        spool.ack(1).unwrap();
+        assert_eq!(values(&spool.read_backlog().unwrap()), vec![2.0, 3.0]);
+        assert!(spool.read_backlog().unwrap().is_empty());
+
+        spool.ack(2).unwrap();
+        spool
+            .append_and_forward(&[NewMeasurement::new(None, 1, 1, 4.0)], |_| true)
+            .unwrap();
+        assert!(spool.read_backlog().unwrap().is_empty());
+        drop(spool);
+
+        // Entries pending when the spool is opened are the backlog
+        let (spool, pending) = Spool::open(&dir).unwrap();
+        assert_eq!(values(&pending), vec![4.0]);
+        assert_eq!(values(&spool.read_backlog().unwrap()), vec![4.0]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
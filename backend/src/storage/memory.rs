@@ -0,0 +1,664 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    error::Error,
+    fmt,
+    sync::{Mutex, MutexGuard},
+};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::{DateTime, SubsecRound, TimeZone, Utc};
+use sqlx::error::{DatabaseError, ErrorKind};
+use tokio::sync::mpsc;
+
+use super::Storage;
+use crate::{
+    api_keys::{ApiKey, NewApiKey},
+    devices::{Device, NewDevice},
+    measurements::{
+        AggregateFunction, AggregateQuery, CursorMeasurement, Measurement, MeasurementBucket,
+        MeasurementPage, MeasurementQuery, MeasurementStats, NewMeasurement, Order, StatsQuery,
+    },
+    prometheus::{SeriesFilter, SeriesRow},
+    sensors::{NewSensor, Sensor},
+};
+
+#[derive(Debug, Clone)]
+struct StoredMeasurement {
+    id: i32,
+    ts: DateTime<Utc>,
+    device_id: i32,
+    sensor_id: i32,
+    value: f32,
+}
+
+/// A broken constraint, reported like the databases do so callers can tell it apart
+#[derive(Debug)]
+enum Violation {
+    Unique(String),
+    ForeignKey(String),
+}
+
+impl Violation {
+    fn unique(message: String) -> sqlx::Error {
+        sqlx::Error::Database(Box::new(Violation::Unique(message)))
+    }
+
+    fn foreign_key(message: String) -> sqlx::Error {
+        sqlx::Error::Database(Box::new(Violation::ForeignKey(message)))
+    }
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.message())
+    }
+}
+
+impl Error for Violation {}
+
+impl DatabaseError for Violation {
+    fn message(&self) -> &str {
+        match self {
+            Violation::Unique(message) | Violation::ForeignKey(message) => message,
+        }
+    }
+
+    fn as_error(&self) -> &(dyn Error + Send + Sync + 'static) {
+        self
+    }
+
+    fn as_error_mut(&mut self) -> &mut (dyn Error + Send + Sync + 'static) {
+        self
+    }
+
+    fn into_error(self: Box<Self>) -> Box<dyn Error + Send + Sync + 'static> {
+        self
+    }
+
+    fn kind(&self) -> ErrorKind {
+        match self {
+            Violation::Unique(_) => ErrorKind::UniqueViolation,
+            Violation::ForeignKey(_) => ErrorKind::ForeignKeyViolation,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    devices: BTreeMap<i32, Device>,
+    sensors: BTreeMap<i32, Sensor>,
+    /// Ordered by id
+    measurements: Vec<StoredMeasurement>,
+    /// Hashes of device tokens by device id
+    device_tokens: HashMap<i32, String>,
+    /// Keys with their hashes
+    api_keys: BTreeMap<i32, (ApiKey, String)>,
+    last_device_id: i32,
+    last_sensor_id: i32,
+    last_measurement_id: i32,
+    last_api_key_id: i32,
+}
+
+impl State {
+    fn measurement(&self, stored: &StoredMeasurement) -> Result<Measurement> {
+        let device = self
+            .devices
+            .get(&stored.device_id)
+            .ok_or(sqlx::Error::RowNotFound)?;
+        let sensor = self
+            .sensors
+            .get(&stored.sensor_id)
+            .ok_or(sqlx::Error::RowNotFound)?;
+        Ok(Measurement {
+            timestamp: stored.ts,
+            value: stored.value,
+            unit: sensor.unit.clone(),
+            device_name: device.name.clone(),
+            device_location: device.location.clone(),
+            sensor_name: sensor.name.clone(),
+        })
+    }
+
+    fn latest(&self, filter: impl Fn(&StoredMeasurement) -> bool) -> Option<&StoredMeasurement> {
+        self.measurements
+            .iter()
+            .filter(|m| filter(m))
+            .max_by_key(|m| (m.ts, m.id))
+    }
+
+    /// Values of a device and sensor in `[from, to)`, ordered by time
+    fn values(
+        &self,
+        device_id: i32,
+        sensor_id: i32,
+        from: Option<DateTime<Utc>>,
+        to: Option<DateTime<Utc>>,
+    ) -> Vec<(DateTime<Utc>, f32)> {
+        let mut values: Vec<(DateTime<Utc>, f32)> = self
+            .measurements
+            .iter()
+            .filter(|m| m.device_id == device_id && m.sensor_id == sensor_id)
+            .filter(|m| from.is_none_or(|from| m.ts >= timestamp(from)))
+            .filter(|m| to.is_none_or(|to| m.ts < timestamp(to)))
+            .map(|m| (m.ts, m.value))
+            .collect();
+        values.sort_by_key(|(ts, _)| *ts);
+        values
+    }
+}
+
+/// Keeps everything in memory, for tests and the demo mode.
+///
+/// Mirrors the constraints of the database schema, so it fails where Postgres would.
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    state: Mutex<State>,
+}
+
+impl MemoryStorage {
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Timestamps are kept with the precision of Postgres
+fn timestamp(ts: DateTime<Utc>) -> DateTime<Utc> {
+    ts.trunc_subsecs(6)
+}
+
+/// Start of the bucket containing `ts`, aligned to 2000-01-01 like date_bin in Postgres
+fn bucket_start(ts: DateTime<Utc>, seconds: i64) -> DateTime<Utc> {
+    let origin = Utc.with_ymd_and_hms(2000, 1, 1, 0, 0, 0).unwrap();
+    let offset = (ts - origin).num_seconds().div_euclid(seconds) * seconds;
+    origin + chrono::Duration::seconds(offset)
+}
+
+fn aggregate(
+    bucket: DateTime<Utc>,
+    values: &[(DateTime<Utc>, f32)],
+    functions: &[AggregateFunction],
+) -> MeasurementBucket {
+    let sum: f64 = values.iter().map(|(_, v)| f64::from(*v)).sum();
+    let requested = |function: AggregateFunction| functions.contains(&function);
+    MeasurementBucket {
+        bucket,
+        avg: requested(AggregateFunction::Avg).then(|| sum / values.len() as f64),
+        min: requested(AggregateFunction::Min)
+            .then(|| values.iter().map(|(_, v)| *v).reduce(f32::min))
+            .flatten(),
+        max: requested(AggregateFunction::Max)
+            .then(|| values.iter().map(|(_, v)| *v).reduce(f32::max))
+            .flatten(),
+        sum: requested(AggregateFunction::Sum).then_some(sum),
+        count: requested(AggregateFunction::Count).then_some(values.len() as i64),
+        first: requested(AggregateFunction::First)
+            .then(|| values.first().map(|(_, v)| *v))
+            .flatten(),
+        last: requested(AggregateFunction::Last)
+            .then(|| values.last().map(|(_, v)| *v))
+            .flatten(),
+    }
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    fn pool_size(&self) -> u32 {
+        0
+    }
+
+    async fn read_devices(&self) -> Result<Vec<Device>> {
+        Ok(self.state().devices.values().cloned().collect())
+    }
+
+    async fn read_device_by_id(&self, device_id: i32) -> Result<Device> {
+        let device = self.state().devices.get(&device_id).cloned();
+        Ok(device.ok_or(sqlx::Error::RowNotFound)?)
+    }
+
+    async fn read_devices_by_ids(&self, device_ids: &[i32]) -> Result<Vec<Device>> {
+        let state = self.state();
+        Ok(device_ids
+            .iter()
+            .filter_map(|id| state.devices.get(id).cloned())
+            .collect())
+    }
+
+    async fn read_device_by_name(&self, name: &str, location: &str) -> Result<Device> {
+        let state = self.state();
+        let device = state
+            .devices
+            .values()
+            .find(|d| d.name == name && d.location == location)
+            .cloned();
+        Ok(device.ok_or(sqlx::Error::RowNotFound)?)
+    }
+
+    async fn insert_device(&self, device: NewDevice) -> Result<()> {
+        let mut state = self.state();
+        state.last_device_id += 1;
+        let id = state.last_device_id;
+        state
+            .devices
+            .insert(id, Device::new(id, device.name, device.location));
+        Ok(())
+    }
+
+    async fn update_device(&self, device: Device) -> Result<()> {
+        if let Some(current) = self.state().devices.get_mut(&device.id) {
+            current.name = device.name;
+            current.location = device.location;
+            current.expected_interval_seconds = device.expected_interval_seconds;
+        }
+        Ok(())
+    }
+
+    async fn delete_device(&self, device_id: i32) -> Result<()> {
+        let mut state = self.state();
+        if state.measurements.iter().any(|m| m.device_id == device_id) {
+            return Err(Violation::foreign_key(format!(
+                "device {} still has measurements",
+                device_id
+            ))
+            .into());
+        }
+        state.devices.remove(&device_id);
+        state.device_tokens.remove(&device_id);
+        Ok(())
+    }
+
+    async fn update_last_seen(&self, seen: &[(i32, DateTime<Utc>)]) -> Result<()> {
+        let mut state = self.state();
+        for (device_id, ts) in seen {
+            if let Some(device) = state.devices.get_mut(device_id) {
+                let ts = timestamp(*ts);
+                if device.last_seen.is_none_or(|last_seen| last_seen < ts) {
+                    device.last_seen = Some(ts);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn update_online(&self, device_id: i32, online: bool) -> Result<()> {
+        if let Some(device) = self.state().devices.get_mut(&device_id) {
+            device.online = online;
+        }
+        Ok(())
+    }
+
+    async fn update_device_token(&self, device_id: i32, token_hash: Option<&str>) -> Result<()> {
+        let mut state = self.state();
+        if !state.devices.contains_key(&device_id) {
+            return Err(sqlx::Error::RowNotFound.into());
+        }
+        match token_hash {
+            Some(token_hash) => {
+                state
+                    .device_tokens
+                    .insert(device_id, token_hash.to_string());
+            }
+            None => {
+                state.device_tokens.remove(&device_id);
+            }
+        }
+        Ok(())
+    }
+
+    async fn read_device_by_token_hash(&self, token_hash: &str) -> Result<Device> {
+        let state = self.state();
+        let device = state
+            .device_tokens
+            .iter()
+            .find(|(_, hash)| *hash == token_hash)
+            .and_then(|(device_id, _)| state.devices.get(device_id).cloned());
+        Ok(device.ok_or(sqlx::Error::RowNotFound)?)
+    }
+
+    /// Device sensors are always derived from the current measurements
+    async fn refresh_device_sensors_view(&self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn read_sensors(&self) -> Result<Vec<Sensor>> {
+        Ok(self.state().sensors.values().cloned().collect())
+    }
+
+    async fn read_sensor_by_id(&self, sensor_id: i32) -> Result<Sensor> {
+        let sensor = self.state().sensors.get(&sensor_id).cloned();
+        Ok(sensor.ok_or(sqlx::Error::RowNotFound)?)
+    }
+
+    async fn read_sensors_by_ids(&self, sensor_ids: &[i32]) -> Result<Vec<Sensor>> {
+        let state = self.state();
+        Ok(sensor_ids
+            .iter()
+            .filter_map(|id| state.sensors.get(id).cloned())
+            .collect())
+    }
+
+    async fn read_sensor_by_name(&self, name: &str) -> Result<Sensor> {
+        let state = self.state();
+        let sensor = state.sensors.values().find(|s| s.name == name).cloned();
+        Ok(sensor.ok_or(sqlx::Error::RowNotFound)?)
+    }
+
+    async fn read_sensors_by_device_id(&self, device_id: i32) -> Result<Vec<Sensor>> {
+        let state = self.state();
+        let mut sensor_ids: Vec<i32> = state
+            .measurements
+            .iter()
+            .filter(|m| m.device_id == device_id)
+            .map(|m| m.sensor_id)
+            .collect();
+        sensor_ids.sort_unstable();
+        sensor_ids.dedup();
+        Ok(sensor_ids
+            .iter()
+            .filter_map(|id| state.sensors.get(id).cloned())
+            .collect())
+    }
+
+    async fn read_series(&self, filters: &[SeriesFilter]) -> Result<Vec<SeriesRow>> {
+        let state = self.state();
+        let mut pairs: Vec<(i32, i32)> = state
+            .measurements
+            .iter()
+            .map(|m| (m.device_id, m.sensor_id))
+            .collect();
+        pairs.sort_unstable();
+        pairs.dedup();
+        Ok(pairs
+            .into_iter()
+            .filter_map(|(device_id, sensor_id)| {
+                let device = state.devices.get(&device_id)?;
+                let sensor = state.sensors.get(&sensor_id)?;
+                Some(SeriesRow {
+                    device_id,
+                    device_name: device.name.clone(),
+                    device_location: device.location.clone(),
+                    sensor_id,
+                    sensor_name: sensor.name.clone(),
+                    unit: sensor.unit.clone(),
+                })
+            })
+            .filter(|row| filters.iter().all(|filter| filter.matches(row)))
+            .collect())
+    }
+
+    async fn insert_sensor(&self, sensor: NewSensor) -> Result<()> {
+        let mut state = self.state();
+        if state.sensors.values().any(|s| s.name == sensor.name) {
+            return Err(Violation::unique(format!("sensor {} already exists", sensor.name)).into());
+        }
+        state.last_sensor_id += 1;
+        let id = state.last_sensor_id;
+        state
+            .sensors
+            .insert(id, Sensor::new(id, sensor.name, sensor.unit));
+        Ok(())
+    }
+
+    async fn update_sensor(&self, sensor: Sensor) -> Result<()> {
+        let mut state = self.state();
+        if state
+            .sensors
+            .values()
+            .any(|s| s.id != sensor.id && s.name == sensor.name)
+        {
+            return Err(Violation::unique(format!("sensor {} already exists", sensor.name)).into());
+        }
+        if let Some(current) = state.sensors.get_mut(&sensor.id) {
+            *current = sensor;
+        }
+        Ok(())
+    }
+
+    async fn delete_sensor(&self, sensor_id: i32) -> Result<()> {
+        let mut state = self.state();
+        if state.measurements.iter().any(|m| m.sensor_id == sensor_id) {
+            return Err(Violation::foreign_key(format!(
+                "sensor {} still has measurements",
+                sensor_id
+            ))
+            .into());
+        }
+        state.sensors.remove(&sensor_id);
+        Ok(())
+    }
+
+    async fn insert_measurements(&self, measurements: &[NewMeasurement]) -> Result<u64> {
+        let mut state = self.state();
+        // All or nothing, like the single insert statement in Postgres
+        if let Some(measurement) = measurements.iter().find(|m| {
+            !state.devices.contains_key(&m.device) || !state.sensors.contains_key(&m.sensor)
+        }) {
+            return Err(Violation::foreign_key(format!(
+                "unknown device or sensor in measurement {}",
+                measurement
+            ))
+            .into());
+        }
+        let now = timestamp(Utc::now());
+        for measurement in measurements {
+            state.last_measurement_id += 1;
+            let id = state.last_measurement_id;
+            state.measurements.push(StoredMeasurement {
+                id,
+                ts: measurement.timestamp.map(timestamp).unwrap_or(now),
+                device_id: measurement.device,
+                sensor_id: measurement.sensor,
+                value: measurement.measurement,
+            });
+        }
+        Ok(measurements.len() as u64)
+    }
+
+    async fn read_measurements(
+        &self,
+        device_id: Option<i32>,
+        sensor_id: Option<i32>,
+        query: &MeasurementQuery,
+    ) -> Result<MeasurementPage> {
+        let state = self.state();
+        let mut rows: Vec<&StoredMeasurement> = state
+            .measurements
+            .iter()
+            .filter(|m| device_id.is_none_or(|id| m.device_id == id))
+            .filter(|m| sensor_id.is_none_or(|id| m.sensor_id == id))
+            .filter(|m| query.from.is_none_or(|from| m.ts >= timestamp(from)))
+            .filter(|m| query.to.is_none_or(|to| m.ts < timestamp(to)))
+            .filter(|m| {
+                query.cursor.is_none_or(|cursor| {
+                    let position = (timestamp(cursor.timestamp), cursor.id);
+                    match query.order {
+                        Order::Asc => (m.ts, m.id) > position,
+                        Order::Desc => (m.ts, m.id) < position,
+                    }
+                })
+            })
+            .collect();
+        rows.sort_by_key(|m| (m.ts, m.id));
+        if query.order == Order::Desc {
+            rows.reverse();
+        }
+        if let Some(limit) = query.limit {
+            rows.truncate(limit.max(0) as usize);
+        }
+
+        let rows = rows
+            .into_iter()
+            .map(|m| {
+                Ok(CursorMeasurement {
+                    id: m.id,
+                    measurement: state.measurement(m)?,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(MeasurementPage::new(rows, query.limit))
+    }
+
+    /// Everything is in memory already, so the rows are read as one page
+    async fn stream_measurements(
+        &self,
+        device_id: Option<i32>,
+        sensor_id: Option<i32>,
+        query: &MeasurementQuery,
+        rows: &mpsc::Sender<Result<Measurement>>,
+    ) -> Result<()> {
+        let page = self.read_measurements(device_id, sensor_id, query).await?;
+        for measurement in page.measurements {
+            if rows.send(Ok(measurement)).await.is_err() {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    async fn read_latest_measurement(&self) -> Result<Measurement> {
+        let state = self.state();
+        let latest = state.latest(|_| true).ok_or(sqlx::Error::RowNotFound)?;
+        state.measurement(latest)
+    }
+
+    async fn read_latest_measurement_by_device_id_and_sensor_id(
+        &self,
+        device_id: i32,
+        sensor_id: i32,
+    ) -> Result<Measurement> {
+        let state = self.state();
+        let latest = state
+            .latest(|m| m.device_id == device_id && m.sensor_id == sensor_id)
+            .ok_or(sqlx::Error::RowNotFound)?;
+        state.measurement(latest)
+    }
+
+    async fn read_all_latest_measurements(&self) -> Result<Vec<Measurement>> {
+        let state = self.state();
+        let mut latest: HashMap<(i32, i32), &StoredMeasurement> = HashMap::new();
+        for measurement in &state.measurements {
+            let key = (measurement.device_id, measurement.sensor_id);
+            match latest.get(&key) {
+                Some(current) if (current.ts, current.id) > (measurement.ts, measurement.id) => {}
+                _ => {
+                    latest.insert(key, measurement);
+                }
+            }
+        }
+        let mut latest: Vec<((i32, i32), &StoredMeasurement)> = latest.into_iter().collect();
+        latest.sort_by_key(|(key, _)| *key);
+        latest
+            .into_iter()
+            .map(|(_, measurement)| state.measurement(measurement))
+            .collect()
+    }
+
+    async fn read_total_measurements(&self) -> Result<i64> {
+        Ok(self.state().measurements.len() as i64)
+    }
+
+    async fn read_aggregate(
+        &self,
+        device_id: i32,
+        sensor_id: i32,
+        query: &AggregateQuery,
+    ) -> Result<Vec<MeasurementBucket>> {
+        let seconds = query.bucket().duration().num_seconds();
+        let values = self
+            .state()
+            .values(device_id, sensor_id, query.from, query.to);
+        let mut buckets: BTreeMap<DateTime<Utc>, Vec<(DateTime<Utc>, f32)>> = BTreeMap::new();
+        for (ts, value) in values {
+            buckets
+                .entry(bucket_start(ts, seconds))
+                .or_default()
+                .push((ts, value));
+        }
+        Ok(buckets
+            .into_iter()
+            .map(|(bucket, values)| aggregate(bucket, &values, &query.functions.0))
+            .collect())
+    }
+
+    async fn read_stats(
+        &self,
+        device_id: i32,
+        sensor_id: i32,
+        query: &StatsQuery,
+    ) -> Result<MeasurementStats> {
+        let values = self
+            .state()
+            .values(device_id, sensor_id, query.from, query.to);
+        Ok(MeasurementStats::from_values(&values))
+    }
+
+    async fn delete_expired_measurements(
+        &self,
+        sensor_id: i32,
+        before: DateTime<Utc>,
+        limit: i64,
+        _keep_rollups: bool,
+    ) -> Result<u64> {
+        let mut state = self.state();
+        let mut expired: Vec<(DateTime<Utc>, i32)> = state
+            .measurements
+            .iter()
+            .filter(|m| m.sensor_id == sensor_id && m.ts < timestamp(before))
+            .map(|m| (m.ts, m.id))
+            .collect();
+        expired.sort_unstable();
+        expired.truncate(limit.max(0) as usize);
+        let mut ids: Vec<i32> = expired.into_iter().map(|(_, id)| id).collect();
+        ids.sort_unstable();
+        state
+            .measurements
+            .retain(|m| ids.binary_search(&m.id).is_err());
+        Ok(ids.len() as u64)
+    }
+
+    async fn insert_api_key(&self, key: NewApiKey, prefix: &str, hash: &str) -> Result<ApiKey> {
+        let mut state = self.state();
+        if state.api_keys.values().any(|(_, h)| h == hash) {
+            return Err(Violation::unique("api key already exists".to_string()).into());
+        }
+        state.last_api_key_id += 1;
+        let api_key = ApiKey {
+            id: state.last_api_key_id,
+            name: key.name,
+            scope: key.scope,
+            prefix: prefix.to_string(),
+            created_at: timestamp(Utc::now()),
+        };
+        state
+            .api_keys
+            .insert(api_key.id, (api_key.clone(), hash.to_string()));
+        Ok(api_key)
+    }
+
+    async fn read_api_keys(&self) -> Result<Vec<ApiKey>> {
+        Ok(self
+            .state()
+            .api_keys
+            .values()
+            .map(|(api_key, _)| api_key.clone())
+            .collect())
+    }
+
+    async fn read_api_key_by_hash(&self, hash: &str) -> Result<ApiKey> {
+        let state = self.state();
+        let api_key = state
+            .api_keys
+            .values()
+            .find(|(_, h)| h == hash)
+            .map(|(api_key, _)| api_key.clone());
+        Ok(api_key.ok_or(sqlx::Error::RowNotFound)?)
+    }
+
+    async fn delete_api_key(&self, key_id: i32) -> Result<()> {
+        self.state()
+            .api_keys
+            .remove(&key_id)
+            .ok_or(sqlx::Error::RowNotFound)?;
+        Ok(())
+    }
+}
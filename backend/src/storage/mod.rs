@@ -0,0 +1,146 @@
+use std::fmt;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use tokio::sync::mpsc;
+
+use crate::{
+    api_keys::{ApiKey, NewApiKey},
+    devices::{Device, NewDevice},
+    measurements::{
+        AggregateQuery, Measurement, MeasurementBucket, MeasurementPage, MeasurementQuery,
+        MeasurementStats, NewMeasurement, StatsQuery,
+    },
+    prometheus::{SeriesFilter, SeriesRow},
+    sensors::{NewSensor, Sensor},
+};
+
+mod memory;
+mod postgres;
+mod sqlite;
+
+pub use memory::MemoryStorage;
+pub use sqlite::SQLITE_MIGRATOR;
+
+/// Data access for devices, sensors and measurements.
+///
+/// Implemented for `PgPool`, `SqlitePool` and `MemoryStorage`. Features beyond this, such as
+/// alerts, rollups and partitioning, are only available with Postgres and use the pool directly.
+#[async_trait]
+pub trait Storage: fmt::Debug + Send + Sync {
+    /// Number of open connections
+    fn pool_size(&self) -> u32;
+
+    async fn read_devices(&self) -> Result<Vec<Device>>;
+    async fn read_device_by_id(&self, device_id: i32) -> Result<Device>;
+    async fn read_devices_by_ids(&self, device_ids: &[i32]) -> Result<Vec<Device>>;
+    /// The first device with this name and location
+    async fn read_device_by_name(&self, name: &str, location: &str) -> Result<Device>;
+    async fn insert_device(&self, device: NewDevice) -> Result<()>;
+    async fn update_device(&self, device: Device) -> Result<()>;
+    async fn delete_device(&self, device_id: i32) -> Result<()>;
+    /// Moves `last_seen` forward for every `(device_id, timestamp)` pair
+    async fn update_last_seen(&self, seen: &[(i32, DateTime<Utc>)]) -> Result<()>;
+    async fn update_online(&self, device_id: i32, online: bool) -> Result<()>;
+    /// Sets or, with `None`, clears the ingest token of a device. Fails with `RowNotFound` if
+    /// there is no such device
+    async fn update_device_token(&self, device_id: i32, token_hash: Option<&str>) -> Result<()>;
+    async fn read_device_by_token_hash(&self, token_hash: &str) -> Result<Device>;
+    async fn refresh_device_sensors_view(&self) -> Result<()>;
+
+    async fn read_sensors(&self) -> Result<Vec<Sensor>>;
+    async fn read_sensor_by_id(&self, sensor_id: i32) -> Result<Sensor>;
+    async fn read_sensors_by_ids(&self, sensor_ids: &[i32]) -> Result<Vec<Sensor>>;
+    async fn read_sensor_by_name(&self, name: &str) -> Result<Sensor>;
+    async fn read_sensors_by_device_id(&self, device_id: i32) -> Result<Vec<Sensor>>;
+    /// Device and sensor pairs with measurements matching all `filters`, ordered by their ids
+    async fn read_series(&self, filters: &[SeriesFilter]) -> Result<Vec<SeriesRow>>;
+    async fn insert_sensor(&self, sensor: NewSensor) -> Result<()>;
+    async fn update_sensor(&self, sensor: Sensor) -> Result<()>;
+    async fn delete_sensor(&self, sensor_id: i32) -> Result<()>;
+
+    /// Inserts all measurements at once, returns the number of rows inserted
+    async fn insert_measurements(&self, measurements: &[NewMeasurement]) -> Result<u64>;
+    async fn read_measurements(
+        &self,
+        device_id: Option<i32>,
+        sensor_id: Option<i32>,
+        query: &MeasurementQuery,
+    ) -> Result<MeasurementPage>;
+    /// Sends the measurements matching `query` to `rows` as they are read, without holding
+    /// them all in memory. Stops early once `rows` is closed
+    async fn stream_measurements(
+        &self,
+        device_id: Option<i32>,
+        sensor_id: Option<i32>,
+        query: &MeasurementQuery,
+        rows: &mpsc::Sender<Result<Measurement>>,
+    ) -> Result<()>;
+    async fn read_latest_measurement(&self) -> Result<Measurement>;
+    async fn read_latest_measurement_by_device_id_and_sensor_id(
+        &self,
+        device_id: i32,
+        sensor_id: i32,
+    ) -> Result<Measurement>;
+    /// The latest measurement of every device and sensor pair
+    async fn read_all_latest_measurements(&self) -> Result<Vec<Measurement>>;
+    async fn read_total_measurements(&self) -> Result<i64>;
+    async fn read_aggregate(
+        &self,
+        device_id: i32,
+        sensor_id: i32,
+        query: &AggregateQuery,
+    ) -> Result<Vec<MeasurementBucket>>;
+    async fn read_stats(
+        &self,
+        device_id: i32,
+        sensor_id: i32,
+        query: &StatsQuery,
+    ) -> Result<MeasurementStats>;
+    /// Deletes up to `limit` measurements of a sensor older than `before`, returns how many.
+    /// With `keep_rollups` only measurements that are already rolled up are deleted
+    async fn delete_expired_measurements(
+        &self,
+        sensor_id: i32,
+        before: DateTime<Utc>,
+        limit: i64,
+        keep_rollups: bool,
+    ) -> Result<u64>;
+
+    /// Only the hash and the visible `prefix` of a key are stored
+    async fn insert_api_key(&self, key: NewApiKey, prefix: &str, hash: &str) -> Result<ApiKey>;
+    async fn read_api_keys(&self) -> Result<Vec<ApiKey>>;
+    async fn read_api_key_by_hash(&self, hash: &str) -> Result<ApiKey>;
+    /// Fails with `RowNotFound` if there is no such key
+    async fn delete_api_key(&self, key_id: i32) -> Result<()>;
+}
+
+/// Runs each of the given `async fn(Arc<dyn Storage>)` tests against every backend
+#[cfg(test)]
+macro_rules! storage_tests {
+    ($($name:ident),* $(,)?) => {
+        $(
+            mod $name {
+                #[sqlx::test]
+                async fn postgres(pool: sqlx::PgPool) {
+                    super::$name(std::sync::Arc::new(pool)).await
+                }
+
+                #[sqlx::test(migrator = "crate::storage::SQLITE_MIGRATOR")]
+                async fn sqlite(pool: sqlx::SqlitePool) {
+                    super::$name(std::sync::Arc::new(pool)).await
+                }
+
+                #[tokio::test]
+                async fn memory() {
+                    super::$name(std::sync::Arc::new($crate::storage::MemoryStorage::default()))
+                        .await
+                }
+            }
+        )*
+    };
+}
+
+#[cfg(test)]
+pub(crate) use storage_tests;
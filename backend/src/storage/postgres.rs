@@ -0,0 +1,548 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use futures::TryStreamExt;
+use sqlx::{PgPool, Postgres, QueryBuilder};
+use tokio::sync::mpsc;
+
+use super::Storage;
+use crate::{
+    api_keys::{ApiKey, NewApiKey},
+    devices::{Device, NewDevice},
+    measurements::{
+        AggregateQuery, CursorMeasurement, Measurement, MeasurementBucket, MeasurementPage,
+        MeasurementQuery, MeasurementStats, NewMeasurement, Order, StatsQuery,
+    },
+    prometheus::{SeriesFilter, SeriesRow},
+    rollups::{Resolution, MEASUREMENTS_LOCK},
+    sensors::{NewSensor, Sensor},
+};
+
+/// Selects the measurements matching `query`, with their ids for cursors
+fn measurements_query(
+    device_id: Option<i32>,
+    sensor_id: Option<i32>,
+    query: &MeasurementQuery,
+) -> QueryBuilder<'static, Postgres> {
+    let mut builder: QueryBuilder<Postgres> = QueryBuilder::new(
+        "SELECT m.id, m.ts AS timestamp, m.value, s.unit, d.name AS device_name, d.location AS device_location, s.name AS sensor_name FROM measurements m JOIN devices d ON d.id = m.device_id JOIN sensors s ON s.id = m.sensor_id WHERE TRUE",
+    );
+    if let Some(device_id) = device_id {
+        builder.push(" AND m.device_id = ").push_bind(device_id);
+    }
+    if let Some(sensor_id) = sensor_id {
+        builder.push(" AND m.sensor_id = ").push_bind(sensor_id);
+    }
+    if let Some(from) = query.from {
+        builder.push(" AND m.ts >= ").push_bind(from);
+    }
+    if let Some(to) = query.to {
+        builder.push(" AND m.ts < ").push_bind(to);
+    }
+    let (comparison, direction) = match query.order {
+        Order::Asc => (">", "ASC"),
+        Order::Desc => ("<", "DESC"),
+    };
+    if let Some(cursor) = query.cursor {
+        builder
+            .push(format!(" AND (m.ts, m.id) {comparison} ("))
+            .push_bind(cursor.timestamp)
+            .push(", ")
+            .push_bind(cursor.id)
+            .push(")");
+    }
+    builder.push(format!(" ORDER BY m.ts {direction}, m.id {direction}"));
+    if let Some(limit) = query.limit {
+        builder.push(" LIMIT ").push_bind(limit);
+    }
+    builder
+}
+
+#[async_trait]
+impl Storage for PgPool {
+    fn pool_size(&self) -> u32 {
+        self.size()
+    }
+
+    async fn read_devices(&self) -> Result<Vec<Device>> {
+        let devices = sqlx::query_as::<_, Device>(
+            "SELECT id, name, location, expected_interval_seconds, last_seen, online FROM devices",
+        )
+        .fetch_all(self)
+        .await?;
+        Ok(devices)
+    }
+
+    async fn read_device_by_id(&self, device_id: i32) -> Result<Device> {
+        let device = sqlx::query_as::<_, Device>(
+            "SELECT id, name, location, expected_interval_seconds, last_seen, online FROM devices WHERE id = $1",
+        )
+        .bind(device_id)
+        .fetch_one(self)
+        .await?;
+        Ok(device)
+    }
+
+    async fn read_devices_by_ids(&self, device_ids: &[i32]) -> Result<Vec<Device>> {
+        let devices = sqlx::query_as::<_, Device>(
+            "SELECT id, name, location, expected_interval_seconds, last_seen, online FROM devices WHERE id = ANY($1)",
+        )
+        .bind(device_ids)
+        .fetch_all(self)
+        .await?;
+        Ok(devices)
+    }
+
+    async fn read_device_by_name(&self, name: &str, location: &str) -> Result<Device> {
+        let device = sqlx::query_as::<_, Device>(
+            "SELECT id, name, location, expected_interval_seconds, last_seen, online FROM devices WHERE name = $1 AND location = $2 ORDER BY id LIMIT 1",
+        )
+        .bind(name)
+        .bind(location)
+        .fetch_one(self)
+        .await?;
+        Ok(device)
+    }
+
+    async fn insert_device(&self, device: NewDevice) -> Result<()> {
+        sqlx::query("INSERT INTO devices (name, location) VALUES ($1, $2)")
+            .bind(device.name)
+            .bind(device.location)
+            .execute(self)
+            .await?;
+        self.refresh_device_sensors_view().await?;
+        Ok(())
+    }
+
+    async fn update_device(&self, device: Device) -> Result<()> {
+        sqlx::query(
+            "UPDATE devices SET name = $1,location = $2,expected_interval_seconds = $3 WHERE id = $4",
+        )
+        .bind(device.name)
+        .bind(device.location)
+        .bind(device.expected_interval_seconds)
+        .bind(device.id)
+        .execute(self)
+        .await?;
+        self.refresh_device_sensors_view().await?;
+        Ok(())
+    }
+
+    async fn delete_device(&self, device_id: i32) -> Result<()> {
+        sqlx::query("DELETE FROM devices WHERE id = $1")
+            .bind(device_id)
+            .execute(self)
+            .await?;
+        Ok(())
+    }
+
+    async fn update_last_seen(&self, seen: &[(i32, DateTime<Utc>)]) -> Result<()> {
+        let ids: Vec<i32> = seen.iter().map(|(id, _)| *id).collect();
+        let timestamps: Vec<DateTime<Utc>> = seen.iter().map(|(_, ts)| *ts).collect();
+        sqlx::query(
+            "UPDATE devices d SET last_seen = GREATEST(d.last_seen, s.ts)
+             FROM (SELECT id, max(ts) AS ts FROM UNNEST($1::int[], $2::timestamptz[]) AS u(id, ts) GROUP BY id) s
+             WHERE d.id = s.id",
+        )
+        .bind(ids)
+        .bind(timestamps)
+        .execute(self)
+        .await?;
+        Ok(())
+    }
+
+    async fn update_online(&self, device_id: i32, online: bool) -> Result<()> {
+        sqlx::query("UPDATE devices SET online = $1 WHERE id = $2")
+            .bind(online)
+            .bind(device_id)
+            .execute(self)
+            .await?;
+        Ok(())
+    }
+
+    async fn update_device_token(&self, device_id: i32, token_hash: Option<&str>) -> Result<()> {
+        let res = sqlx::query("UPDATE devices SET token_hash = $1 WHERE id = $2")
+            .bind(token_hash)
+            .bind(device_id)
+            .execute(self)
+            .await?;
+        if res.rows_affected() == 0 {
+            return Err(sqlx::Error::RowNotFound.into());
+        }
+        Ok(())
+    }
+
+    async fn read_device_by_token_hash(&self, token_hash: &str) -> Result<Device> {
+        let device = sqlx::query_as::<_, Device>(
+            "SELECT id, name, location, expected_interval_seconds, last_seen, online FROM devices WHERE token_hash = $1",
+        )
+        .bind(token_hash)
+        .fetch_one(self)
+        .await?;
+        Ok(device)
+    }
+
+    async fn refresh_device_sensors_view(&self) -> Result<()> {
+        sqlx::query("REFRESH MATERIALIZED VIEW device_sensors")
+            .execute(self)
+            .await?;
+        Ok(())
+    }
+
+    async fn read_sensors(&self) -> Result<Vec<Sensor>> {
+        let sensors = sqlx::query_as::<_, Sensor>(
+            "SELECT id, name, unit, expected_interval_seconds, retention_days FROM sensors",
+        )
+        .fetch_all(self)
+        .await?;
+        Ok(sensors)
+    }
+
+    async fn read_sensor_by_id(&self, sensor_id: i32) -> Result<Sensor> {
+        let sensor = sqlx::query_as::<_, Sensor>(
+            "SELECT id, name, unit, expected_interval_seconds, retention_days FROM sensors WHERE id = $1",
+        )
+        .bind(sensor_id)
+        .fetch_one(self)
+        .await?;
+        Ok(sensor)
+    }
+
+    async fn read_sensors_by_ids(&self, sensor_ids: &[i32]) -> Result<Vec<Sensor>> {
+        let sensors = sqlx::query_as::<_, Sensor>(
+            "SELECT id, name, unit, expected_interval_seconds, retention_days FROM sensors WHERE id = ANY($1)",
+        )
+        .bind(sensor_ids)
+        .fetch_all(self)
+        .await?;
+        Ok(sensors)
+    }
+
+    async fn read_sensor_by_name(&self, name: &str) -> Result<Sensor> {
+        let sensor = sqlx::query_as::<_, Sensor>(
+            "SELECT id, name, unit, expected_interval_seconds, retention_days FROM sensors WHERE name = $1",
+        )
+        .bind(name)
+        .fetch_one(self)
+        .await?;
+        Ok(sensor)
+    }
+
+    async fn read_sensors_by_device_id(&self, device_id: i32) -> Result<Vec<Sensor>> {
+        let sensors = sqlx::query_as::<_, Sensor>("SELECT s.id, s.name, s.unit, s.expected_interval_seconds, s.retention_days from device_sensors ds JOIN sensors s ON s.id = ds.sensor_id WHERE ds.device_id = $1 order by ds.sensor_id")
+            .bind(device_id)
+            .fetch_all(self)
+            .await?;
+        Ok(sensors)
+    }
+
+    async fn read_series(&self, filters: &[SeriesFilter]) -> Result<Vec<SeriesRow>> {
+        let mut builder: QueryBuilder<Postgres> = QueryBuilder::new(
+            "SELECT d.id AS device_id, d.name AS device_name, d.location AS device_location, \
+            s.id AS sensor_id, s.name AS sensor_name, s.unit \
+            FROM device_sensors ds JOIN devices d ON d.id = ds.device_id JOIN sensors s ON s.id = ds.sensor_id \
+            WHERE 1 = 1",
+        );
+        for filter in filters {
+            let operator = if filter.equal { " = " } else { " <> " };
+            builder
+                .push(" AND ")
+                .push(filter.column.as_sql())
+                .push(operator)
+                .push_bind(filter.value.clone());
+        }
+        builder.push(" ORDER BY d.id, s.id");
+        let series = builder.build_query_as().fetch_all(self).await?;
+        Ok(series)
+    }
+
+    async fn insert_sensor(&self, sensor: NewSensor) -> Result<()> {
+        sqlx::query("INSERT INTO sensors (name, unit) VALUES ($1, $2)")
+            .bind(sensor.name)
+            .bind(sensor.unit)
+            .execute(self)
+            .await?;
+        self.refresh_device_sensors_view().await?;
+        Ok(())
+    }
+
+    async fn update_sensor(&self, sensor: Sensor) -> Result<()> {
+        sqlx::query(
+            "UPDATE sensors SET name = $1,unit = $2,expected_interval_seconds = $3,retention_days = $4 WHERE id = $5",
+        )
+        .bind(sensor.name)
+        .bind(sensor.unit)
+        .bind(sensor.expected_interval_seconds)
+        .bind(sensor.retention_days)
+        .bind(sensor.id)
+        .execute(self)
+        .await?;
+        self.refresh_device_sensors_view().await?;
+        Ok(())
+    }
+
+    async fn delete_sensor(&self, sensor_id: i32) -> Result<()> {
+        sqlx::query("DELETE FROM sensors WHERE id = $1")
+            .bind(sensor_id)
+            .execute(self)
+            .await?;
+        Ok(())
+    }
+
+    async fn insert_measurements(&self, measurements: &[NewMeasurement]) -> Result<u64> {
+        let timestamps: Vec<Option<DateTime<Utc>>> =
+            measurements.iter().map(|m| m.timestamp).collect();
+        let devices: Vec<i32> = measurements.iter().map(|m| m.device).collect();
+        let sensors: Vec<i32> = measurements.iter().map(|m| m.sensor).collect();
+        let values: Vec<f32> = measurements.iter().map(|m| m.measurement).collect();
+        let mut tx = self.begin().await?;
+        sqlx::query("SELECT pg_advisory_xact_lock_shared($1)")
+            .bind(MEASUREMENTS_LOCK)
+            .execute(&mut *tx)
+            .await?;
+        let res = sqlx::query(
+            "INSERT INTO measurements (ts, device_id, sensor_id, value)
+             SELECT COALESCE(ts, CURRENT_TIMESTAMP), device_id, sensor_id, value
+             FROM UNNEST($1::timestamptz[], $2::int[], $3::int[], $4::real[]) AS m(ts, device_id, sensor_id, value)",
+        )
+        .bind(timestamps)
+        .bind(devices)
+        .bind(sensors)
+        .bind(values)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(res.rows_affected())
+    }
+
+    async fn read_measurements(
+        &self,
+        device_id: Option<i32>,
+        sensor_id: Option<i32>,
+        query: &MeasurementQuery,
+    ) -> Result<MeasurementPage> {
+        let rows = measurements_query(device_id, sensor_id, query)
+            .build_query_as::<CursorMeasurement>()
+            .fetch_all(self)
+            .await?;
+        Ok(MeasurementPage::new(rows, query.limit))
+    }
+
+    async fn stream_measurements(
+        &self,
+        device_id: Option<i32>,
+        sensor_id: Option<i32>,
+        query: &MeasurementQuery,
+        rows: &mpsc::Sender<Result<Measurement>>,
+    ) -> Result<()> {
+        let mut builder = measurements_query(device_id, sensor_id, query);
+        let mut fetched = builder.build_query_as::<CursorMeasurement>().fetch(self);
+        while let Some(row) = fetched.try_next().await? {
+            if rows.send(Ok(row.measurement)).await.is_err() {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    async fn read_latest_measurement(&self) -> Result<Measurement> {
+        let measurement = sqlx::query_as::<_, Measurement>(
+            "SELECT m.ts AS timestamp, m.value, s.unit, d.name AS device_name, d.location AS device_location, s.name AS sensor_name FROM measurements m JOIN devices d ON d.id = m.device_id JOIN sensors s ON s.id = m.sensor_id ORDER BY ts DESC LIMIT 1",
+        )
+        .fetch_one(self)
+        .await?;
+        Ok(measurement)
+    }
+
+    async fn read_latest_measurement_by_device_id_and_sensor_id(
+        &self,
+        device_id: i32,
+        sensor_id: i32,
+    ) -> Result<Measurement> {
+        let res = sqlx::query_as::<_, Measurement>(
+            "SELECT m.ts AS timestamp, m.value, s.unit, d.name AS device_name, d.location AS device_location, s.name AS sensor_name FROM measurements m JOIN devices d ON d.id = m.device_id JOIN sensors s ON s.id = m.sensor_id where m.device_id = ($1) AND m.sensor_id = ($2) ORDER BY ts desc LIMIT 1",
+        )
+        .bind(device_id)
+        .bind(sensor_id)
+        .fetch_one(self)
+        .await?;
+        Ok(res)
+    }
+
+    async fn read_all_latest_measurements(&self) -> Result<Vec<Measurement>> {
+        let res = sqlx::query_as::<_, Measurement>(
+               "SELECT DISTINCT ON (m.device_id, m.sensor_id) m.ts AS timestamp, m.value, s.unit, d.name AS device_name, d.location AS device_location, s.name AS sensor_name
+                FROM measurements m
+                JOIN devices d ON m.device_id = d.id
+                JOIN sensors s ON m.sensor_id = s.id
+                ORDER BY m.device_id, m.sensor_id, ts DESC",
+        )
+        .fetch_all(self)
+        .await?;
+        Ok(res)
+    }
+
+    async fn read_total_measurements(&self) -> Result<i64> {
+        let count = sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM measurements")
+            .fetch_one(self)
+            .await?;
+        Ok(count)
+    }
+
+    /// Reads from the rollups when the range is long enough for them to help, rollup buckets
+    /// are then selected by their start
+    async fn read_aggregate(
+        &self,
+        device_id: i32,
+        sensor_id: i32,
+        query: &AggregateQuery,
+    ) -> Result<Vec<MeasurementBucket>> {
+        let bucket = query.bucket();
+        let resolution = Resolution::pick(bucket.duration(), &query.functions.0, query.range());
+        let ts = match resolution {
+            Resolution::Raw => "m.ts",
+            Resolution::Hourly | Resolution::Daily => "r.bucket",
+        };
+
+        let mut builder: QueryBuilder<Postgres> =
+            QueryBuilder::new("SELECT date_bin(make_interval(secs => ");
+        builder
+            .push_bind(bucket.duration().num_seconds() as f64)
+            .push(format!("), {ts}, TIMESTAMPTZ '2000-01-01') AS bucket"));
+        for function in &query.functions.0 {
+            // Rollups are only picked when all functions can be read from them
+            let sql = match resolution {
+                Resolution::Raw => Some(function.as_sql()),
+                Resolution::Hourly | Resolution::Daily => function.as_rollup_sql(),
+            };
+            builder.push(", ").push(sql.unwrap_or("NULL"));
+        }
+        let alias = match resolution {
+            Resolution::Raw => "m",
+            Resolution::Hourly | Resolution::Daily => "r",
+        };
+        builder
+            .push(format!(
+                " FROM {} {alias} WHERE {alias}.device_id = ",
+                resolution.table()
+            ))
+            .push_bind(device_id)
+            .push(format!(" AND {alias}.sensor_id = "))
+            .push_bind(sensor_id);
+        if let Some(from) = query.from {
+            builder.push(format!(" AND {ts} >= ")).push_bind(from);
+        }
+        if let Some(to) = query.to {
+            builder.push(format!(" AND {ts} < ")).push_bind(to);
+        }
+        builder.push(" GROUP BY 1 ORDER BY 1");
+
+        let res = builder
+            .build_query_as::<MeasurementBucket>()
+            .fetch_all(self)
+            .await?;
+        Ok(res)
+    }
+
+    async fn read_stats(
+        &self,
+        device_id: i32,
+        sensor_id: i32,
+        query: &StatsQuery,
+    ) -> Result<MeasurementStats> {
+        let mut builder: QueryBuilder<Postgres> = QueryBuilder::new(
+            "WITH m AS (SELECT ts, value, EXTRACT(EPOCH FROM lead(ts) OVER (ORDER BY ts) - ts)::float8 AS held FROM measurements WHERE device_id = ",
+        );
+        builder
+            .push_bind(device_id)
+            .push(" AND sensor_id = ")
+            .push_bind(sensor_id);
+        if let Some(from) = query.from {
+            builder.push(" AND ts >= ").push_bind(from);
+        }
+        if let Some(to) = query.to {
+            builder.push(" AND ts < ").push_bind(to);
+        }
+        builder.push(
+            ") SELECT min(value) as min, max(value) as max, count(value) as count, avg(value) as avg, stddev(value) as stddev, variance(value) as variance,
+             percentile_cont(0.5) WITHIN GROUP (ORDER BY value) as median,
+             percentile_cont(0.05) WITHIN GROUP (ORDER BY value) as p5,
+             percentile_cont(0.95) WITHIN GROUP (ORDER BY value) as p95,
+             percentile_cont(0.99) WITHIN GROUP (ORDER BY value) as p99,
+             min(ts) as first, max(ts) as last,
+             sum(value * held) / NULLIF(sum(held), 0) as time_weighted_avg
+             FROM m",
+        );
+        let res = builder
+            .build_query_as::<MeasurementStats>()
+            .fetch_one(self)
+            .await?;
+        Ok(res)
+    }
+
+    /// Measurements that are not rolled up yet are kept until they are
+    async fn delete_expired_measurements(
+        &self,
+        sensor_id: i32,
+        before: DateTime<Utc>,
+        limit: i64,
+        keep_rollups: bool,
+    ) -> Result<u64> {
+        let deleted = sqlx::query_scalar::<_, i64>(
+            "WITH expired AS (SELECT id FROM measurements WHERE sensor_id = $1 AND ts < $2 \
+                AND (NOT $4 OR id <= (SELECT last_measurement_id FROM rollup_state)) ORDER BY ts LIMIT $3), \
+            deleted AS (DELETE FROM measurements m USING expired e WHERE m.id = e.id RETURNING m.id) \
+            SELECT count(*) FROM deleted",
+        )
+        .bind(sensor_id)
+        .bind(before)
+        .bind(limit)
+        .bind(keep_rollups)
+        .fetch_one(self)
+        .await?;
+        Ok(deleted as u64)
+    }
+
+    async fn insert_api_key(&self, key: NewApiKey, prefix: &str, hash: &str) -> Result<ApiKey> {
+        let api_key = sqlx::query_as::<_, ApiKey>(
+            "INSERT INTO api_keys (name, scope, prefix, hash) VALUES ($1, $2, $3, $4) RETURNING id, name, scope, prefix, created_at",
+        )
+        .bind(key.name)
+        .bind(key.scope.to_string())
+        .bind(prefix)
+        .bind(hash)
+        .fetch_one(self)
+        .await?;
+        Ok(api_key)
+    }
+
+    async fn read_api_keys(&self) -> Result<Vec<ApiKey>> {
+        let api_keys = sqlx::query_as::<_, ApiKey>(
+            "SELECT id, name, scope, prefix, created_at FROM api_keys ORDER BY id",
+        )
+        .fetch_all(self)
+        .await?;
+        Ok(api_keys)
+    }
+
+    async fn read_api_key_by_hash(&self, hash: &str) -> Result<ApiKey> {
+        let api_key = sqlx::query_as::<_, ApiKey>(
+            "SELECT id, name, scope, prefix, created_at FROM api_keys WHERE hash = $1",
+        )
+        .bind(hash)
+        .fetch_one(self)
+        .await?;
+        Ok(api_key)
+    }
+
+    async fn delete_api_key(&self, key_id: i32) -> Result<()> {
+        let res = sqlx::query("DELETE FROM api_keys WHERE id = $1")
+            .bind(key_id)
+            .execute(self)
+            .await?;
+        if res.rows_affected() == 0 {
+            return Err(sqlx::Error::RowNotFound.into());
+        }
+        Ok(())
+    }
+}
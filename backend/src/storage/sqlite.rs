@@ -0,0 +1,538 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::{DateTime, SubsecRound, Utc};
+use futures::TryStreamExt;
+use sqlx::{migrate::Migrator, QueryBuilder, Sqlite, SqlitePool};
+use tokio::sync::mpsc;
+
+use super::Storage;
+use crate::{
+    api_keys::{ApiKey, NewApiKey},
+    devices::{Device, NewDevice},
+    measurements::{
+        AggregateFunction, AggregateQuery, CursorMeasurement, Measurement, MeasurementBucket,
+        MeasurementPage, MeasurementQuery, MeasurementStats, NewMeasurement, Order, StatsQuery,
+    },
+    prometheus::{SeriesFilter, SeriesRow},
+    sensors::{NewSensor, Sensor},
+};
+
+/// Migrations in `backend/sqlite_migrations`, embedded at build time
+pub static SQLITE_MIGRATOR: Migrator = sqlx::migrate!("./sqlite_migrations");
+
+/// Rows per insert statement, well below the limit of bound parameters
+const INSERT_CHUNK_SIZE: usize = 1000;
+
+const MEASUREMENT_COLUMNS: &str = "m.ts AS timestamp, m.value, s.unit, d.name AS device_name, d.location AS device_location, s.name AS sensor_name FROM measurements m JOIN devices d ON d.id = m.device_id JOIN sensors s ON s.id = m.sensor_id";
+
+/// Timestamps are compared as text, so they are stored and bound with the precision of
+/// Postgres for equal timestamps to compare equal
+fn timestamp(ts: DateTime<Utc>) -> DateTime<Utc> {
+    ts.trunc_subsecs(6)
+}
+
+fn aggregate_sql(function: &AggregateFunction) -> &'static str {
+    match function {
+        AggregateFunction::Avg => "avg(m.value) AS avg",
+        AggregateFunction::Min => "min(m.value) AS min",
+        AggregateFunction::Max => "max(m.value) AS max",
+        AggregateFunction::Sum => "sum(m.value) AS sum",
+        AggregateFunction::Count => "count(m.value) AS count",
+        AggregateFunction::First => "json_group_array(m.value ORDER BY m.ts) ->> '$[0]' AS first",
+        AggregateFunction::Last => {
+            "json_group_array(m.value ORDER BY m.ts DESC) ->> '$[0]' AS last"
+        }
+    }
+}
+
+/// Selects the measurements matching `query`, with their ids for cursors
+fn measurements_query(
+    device_id: Option<i32>,
+    sensor_id: Option<i32>,
+    query: &MeasurementQuery,
+) -> QueryBuilder<'static, Sqlite> {
+    let mut builder: QueryBuilder<Sqlite> =
+        QueryBuilder::new(format!("SELECT m.id, {MEASUREMENT_COLUMNS} WHERE TRUE"));
+    if let Some(device_id) = device_id {
+        builder.push(" AND m.device_id = ").push_bind(device_id);
+    }
+    if let Some(sensor_id) = sensor_id {
+        builder.push(" AND m.sensor_id = ").push_bind(sensor_id);
+    }
+    if let Some(from) = query.from {
+        builder.push(" AND m.ts >= ").push_bind(timestamp(from));
+    }
+    if let Some(to) = query.to {
+        builder.push(" AND m.ts < ").push_bind(timestamp(to));
+    }
+    let (comparison, direction) = match query.order {
+        Order::Asc => (">", "ASC"),
+        Order::Desc => ("<", "DESC"),
+    };
+    if let Some(cursor) = query.cursor {
+        builder
+            .push(format!(" AND (m.ts, m.id) {comparison} ("))
+            .push_bind(timestamp(cursor.timestamp))
+            .push(", ")
+            .push_bind(cursor.id)
+            .push(")");
+    }
+    builder.push(format!(" ORDER BY m.ts {direction}, m.id {direction}"));
+    if let Some(limit) = query.limit {
+        builder.push(" LIMIT ").push_bind(limit);
+    }
+    builder
+}
+
+#[async_trait]
+impl Storage for SqlitePool {
+    fn pool_size(&self) -> u32 {
+        self.size()
+    }
+
+    async fn read_devices(&self) -> Result<Vec<Device>> {
+        let devices = sqlx::query_as::<_, Device>(
+            "SELECT id, name, location, expected_interval_seconds, last_seen, online FROM devices",
+        )
+        .fetch_all(self)
+        .await?;
+        Ok(devices)
+    }
+
+    async fn read_device_by_id(&self, device_id: i32) -> Result<Device> {
+        let device = sqlx::query_as::<_, Device>(
+            "SELECT id, name, location, expected_interval_seconds, last_seen, online FROM devices WHERE id = ?",
+        )
+        .bind(device_id)
+        .fetch_one(self)
+        .await?;
+        Ok(device)
+    }
+
+    async fn read_devices_by_ids(&self, device_ids: &[i32]) -> Result<Vec<Device>> {
+        let mut builder: QueryBuilder<Sqlite> = QueryBuilder::new(
+            "SELECT id, name, location, expected_interval_seconds, last_seen, online FROM devices WHERE id IN (",
+        );
+        let mut ids = builder.separated(", ");
+        for id in device_ids {
+            ids.push_bind(id);
+        }
+        builder.push(")");
+        let devices = builder.build_query_as::<Device>().fetch_all(self).await?;
+        Ok(devices)
+    }
+
+    async fn read_device_by_name(&self, name: &str, location: &str) -> Result<Device> {
+        let device = sqlx::query_as::<_, Device>(
+            "SELECT id, name, location, expected_interval_seconds, last_seen, online FROM devices WHERE name = ? AND location = ? ORDER BY id LIMIT 1",
+        )
+        .bind(name)
+        .bind(location)
+        .fetch_one(self)
+        .await?;
+        Ok(device)
+    }
+
+    async fn insert_device(&self, device: NewDevice) -> Result<()> {
+        sqlx::query("INSERT INTO devices (name, location) VALUES (?, ?)")
+            .bind(device.name)
+            .bind(device.location)
+            .execute(self)
+            .await?;
+        Ok(())
+    }
+
+    async fn update_device(&self, device: Device) -> Result<()> {
+        sqlx::query(
+            "UPDATE devices SET name = ?, location = ?, expected_interval_seconds = ? WHERE id = ?",
+        )
+        .bind(device.name)
+        .bind(device.location)
+        .bind(device.expected_interval_seconds)
+        .bind(device.id)
+        .execute(self)
+        .await?;
+        Ok(())
+    }
+
+    async fn delete_device(&self, device_id: i32) -> Result<()> {
+        sqlx::query("DELETE FROM devices WHERE id = ?")
+            .bind(device_id)
+            .execute(self)
+            .await?;
+        Ok(())
+    }
+
+    async fn update_last_seen(&self, seen: &[(i32, DateTime<Utc>)]) -> Result<()> {
+        let mut tx = self.begin().await?;
+        for (device_id, ts) in seen {
+            let ts = timestamp(*ts);
+            sqlx::query(
+                "UPDATE devices SET last_seen = ? WHERE id = ? AND (last_seen IS NULL OR last_seen < ?)",
+            )
+            .bind(ts)
+            .bind(device_id)
+            .bind(ts)
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn update_online(&self, device_id: i32, online: bool) -> Result<()> {
+        sqlx::query("UPDATE devices SET online = ? WHERE id = ?")
+            .bind(online)
+            .bind(device_id)
+            .execute(self)
+            .await?;
+        Ok(())
+    }
+
+    async fn update_device_token(&self, device_id: i32, token_hash: Option<&str>) -> Result<()> {
+        let res = sqlx::query("UPDATE devices SET token_hash = ? WHERE id = ?")
+            .bind(token_hash)
+            .bind(device_id)
+            .execute(self)
+            .await?;
+        if res.rows_affected() == 0 {
+            return Err(sqlx::Error::RowNotFound.into());
+        }
+        Ok(())
+    }
+
+    async fn read_device_by_token_hash(&self, token_hash: &str) -> Result<Device> {
+        let device = sqlx::query_as::<_, Device>(
+            "SELECT id, name, location, expected_interval_seconds, last_seen, online FROM devices WHERE token_hash = ?",
+        )
+        .bind(token_hash)
+        .fetch_one(self)
+        .await?;
+        Ok(device)
+    }
+
+    /// `device_sensors` is a plain view in SQLite
+    async fn refresh_device_sensors_view(&self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn read_sensors(&self) -> Result<Vec<Sensor>> {
+        let sensors = sqlx::query_as::<_, Sensor>(
+            "SELECT id, name, unit, expected_interval_seconds, retention_days FROM sensors",
+        )
+        .fetch_all(self)
+        .await?;
+        Ok(sensors)
+    }
+
+    async fn read_sensor_by_id(&self, sensor_id: i32) -> Result<Sensor> {
+        let sensor = sqlx::query_as::<_, Sensor>(
+            "SELECT id, name, unit, expected_interval_seconds, retention_days FROM sensors WHERE id = ?",
+        )
+        .bind(sensor_id)
+        .fetch_one(self)
+        .await?;
+        Ok(sensor)
+    }
+
+    async fn read_sensors_by_ids(&self, sensor_ids: &[i32]) -> Result<Vec<Sensor>> {
+        let mut builder: QueryBuilder<Sqlite> = QueryBuilder::new(
+            "SELECT id, name, unit, expected_interval_seconds, retention_days FROM sensors WHERE id IN (",
+        );
+        let mut ids = builder.separated(", ");
+        for id in sensor_ids {
+            ids.push_bind(id);
+        }
+        builder.push(")");
+        let sensors = builder.build_query_as::<Sensor>().fetch_all(self).await?;
+        Ok(sensors)
+    }
+
+    async fn read_sensor_by_name(&self, name: &str) -> Result<Sensor> {
+        let sensor = sqlx::query_as::<_, Sensor>(
+            "SELECT id, name, unit, expected_interval_seconds, retention_days FROM sensors WHERE name = ?",
+        )
+        .bind(name)
+        .fetch_one(self)
+        .await?;
+        Ok(sensor)
+    }
+
+    async fn read_sensors_by_device_id(&self, device_id: i32) -> Result<Vec<Sensor>> {
+        let sensors = sqlx::query_as::<_, Sensor>("SELECT s.id, s.name, s.unit, s.expected_interval_seconds, s.retention_days FROM device_sensors ds JOIN sensors s ON s.id = ds.sensor_id WHERE ds.device_id = ? ORDER BY ds.sensor_id")
+            .bind(device_id)
+            .fetch_all(self)
+            .await?;
+        Ok(sensors)
+    }
+
+    async fn read_series(&self, filters: &[SeriesFilter]) -> Result<Vec<SeriesRow>> {
+        let mut builder: QueryBuilder<Sqlite> = QueryBuilder::new(
+            "SELECT d.id AS device_id, d.name AS device_name, d.location AS device_location, \
+            s.id AS sensor_id, s.name AS sensor_name, s.unit \
+            FROM device_sensors ds JOIN devices d ON d.id = ds.device_id JOIN sensors s ON s.id = ds.sensor_id \
+            WHERE 1 = 1",
+        );
+        for filter in filters {
+            let operator = if filter.equal { " = " } else { " <> " };
+            builder
+                .push(" AND ")
+                .push(filter.column.as_sql())
+                .push(operator)
+                .push_bind(filter.value.clone());
+        }
+        builder.push(" ORDER BY d.id, s.id");
+        let series = builder.build_query_as().fetch_all(self).await?;
+        Ok(series)
+    }
+
+    async fn insert_sensor(&self, sensor: NewSensor) -> Result<()> {
+        sqlx::query("INSERT INTO sensors (name, unit) VALUES (?, ?)")
+            .bind(sensor.name)
+            .bind(sensor.unit)
+            .execute(self)
+            .await?;
+        Ok(())
+    }
+
+    async fn update_sensor(&self, sensor: Sensor) -> Result<()> {
+        sqlx::query(
+            "UPDATE sensors SET name = ?, unit = ?, expected_interval_seconds = ?, retention_days = ? WHERE id = ?",
+        )
+        .bind(sensor.name)
+        .bind(sensor.unit)
+        .bind(sensor.expected_interval_seconds)
+        .bind(sensor.retention_days)
+        .bind(sensor.id)
+        .execute(self)
+        .await?;
+        Ok(())
+    }
+
+    async fn delete_sensor(&self, sensor_id: i32) -> Result<()> {
+        sqlx::query("DELETE FROM sensors WHERE id = ?")
+            .bind(sensor_id)
+            .execute(self)
+            .await?;
+        Ok(())
+    }
+
+    async fn insert_measurements(&self, measurements: &[NewMeasurement]) -> Result<u64> {
+        let now = Utc::now();
+        let mut inserted = 0;
+        let mut tx = self.begin().await?;
+        for chunk in measurements.chunks(INSERT_CHUNK_SIZE) {
+            let mut builder: QueryBuilder<Sqlite> =
+                QueryBuilder::new("INSERT INTO measurements (ts, device_id, sensor_id, value) ");
+            builder.push_values(chunk, |mut row, measurement| {
+                row.push_bind(timestamp(measurement.timestamp.unwrap_or(now)))
+                    .push_bind(measurement.device)
+                    .push_bind(measurement.sensor)
+                    .push_bind(measurement.measurement);
+            });
+            inserted += builder.build().execute(&mut *tx).await?.rows_affected();
+        }
+        tx.commit().await?;
+        Ok(inserted)
+    }
+
+    async fn read_measurements(
+        &self,
+        device_id: Option<i32>,
+        sensor_id: Option<i32>,
+        query: &MeasurementQuery,
+    ) -> Result<MeasurementPage> {
+        let rows = measurements_query(device_id, sensor_id, query)
+            .build_query_as::<CursorMeasurement>()
+            .fetch_all(self)
+            .await?;
+        Ok(MeasurementPage::new(rows, query.limit))
+    }
+
+    async fn stream_measurements(
+        &self,
+        device_id: Option<i32>,
+        sensor_id: Option<i32>,
+        query: &MeasurementQuery,
+        rows: &mpsc::Sender<Result<Measurement>>,
+    ) -> Result<()> {
+        let mut builder = measurements_query(device_id, sensor_id, query);
+        let mut fetched = builder.build_query_as::<CursorMeasurement>().fetch(self);
+        while let Some(row) = fetched.try_next().await? {
+            if rows.send(Ok(row.measurement)).await.is_err() {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    async fn read_latest_measurement(&self) -> Result<Measurement> {
+        let measurement = sqlx::query_as::<_, Measurement>(&format!(
+            "SELECT {MEASUREMENT_COLUMNS} ORDER BY m.ts DESC LIMIT 1"
+        ))
+        .fetch_one(self)
+        .await?;
+        Ok(measurement)
+    }
+
+    async fn read_latest_measurement_by_device_id_and_sensor_id(
+        &self,
+        device_id: i32,
+        sensor_id: i32,
+    ) -> Result<Measurement> {
+        let measurement = sqlx::query_as::<_, Measurement>(&format!(
+            "SELECT {MEASUREMENT_COLUMNS} WHERE m.device_id = ? AND m.sensor_id = ? ORDER BY m.ts DESC LIMIT 1"
+        ))
+        .bind(device_id)
+        .bind(sensor_id)
+        .fetch_one(self)
+        .await?;
+        Ok(measurement)
+    }
+
+    async fn read_all_latest_measurements(&self) -> Result<Vec<Measurement>> {
+        let measurements = sqlx::query_as::<_, Measurement>(&format!(
+            "SELECT {MEASUREMENT_COLUMNS} WHERE m.id IN (
+                SELECT id FROM (SELECT id, row_number() OVER (PARTITION BY device_id, sensor_id ORDER BY ts DESC) AS n FROM measurements) WHERE n = 1
+            ) ORDER BY m.device_id, m.sensor_id"
+        ))
+        .fetch_all(self)
+        .await?;
+        Ok(measurements)
+    }
+
+    async fn read_total_measurements(&self) -> Result<i64> {
+        let count = sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM measurements")
+            .fetch_one(self)
+            .await?;
+        Ok(count)
+    }
+
+    async fn read_aggregate(
+        &self,
+        device_id: i32,
+        sensor_id: i32,
+        query: &AggregateQuery,
+    ) -> Result<Vec<MeasurementBucket>> {
+        // Buckets are aligned to 2000-01-01 like date_bin in Postgres
+        let seconds = query.bucket().duration().num_seconds();
+        let mut builder: QueryBuilder<Sqlite> = QueryBuilder::new(
+            "SELECT strftime('%Y-%m-%dT%H:%M:%SZ', (unixepoch(m.ts) - 946684800) / ",
+        );
+        builder
+            .push_bind(seconds)
+            .push(" * ")
+            .push_bind(seconds)
+            .push(" + 946684800, 'unixepoch') AS bucket");
+        for function in &query.functions.0 {
+            builder.push(", ").push(aggregate_sql(function));
+        }
+        builder
+            .push(" FROM measurements m WHERE m.device_id = ")
+            .push_bind(device_id)
+            .push(" AND m.sensor_id = ")
+            .push_bind(sensor_id);
+        if let Some(from) = query.from {
+            builder.push(" AND m.ts >= ").push_bind(timestamp(from));
+        }
+        if let Some(to) = query.to {
+            builder.push(" AND m.ts < ").push_bind(timestamp(to));
+        }
+        builder.push(" GROUP BY 1 ORDER BY 1");
+
+        let buckets = builder
+            .build_query_as::<MeasurementBucket>()
+            .fetch_all(self)
+            .await?;
+        Ok(buckets)
+    }
+
+    async fn read_stats(
+        &self,
+        device_id: i32,
+        sensor_id: i32,
+        query: &StatsQuery,
+    ) -> Result<MeasurementStats> {
+        let mut builder: QueryBuilder<Sqlite> =
+            QueryBuilder::new("SELECT ts, value FROM measurements WHERE device_id = ");
+        builder
+            .push_bind(device_id)
+            .push(" AND sensor_id = ")
+            .push_bind(sensor_id);
+        if let Some(from) = query.from {
+            builder.push(" AND ts >= ").push_bind(timestamp(from));
+        }
+        if let Some(to) = query.to {
+            builder.push(" AND ts < ").push_bind(timestamp(to));
+        }
+        builder.push(" ORDER BY ts");
+
+        let values = builder
+            .build_query_as::<(DateTime<Utc>, f32)>()
+            .fetch_all(self)
+            .await?;
+        Ok(MeasurementStats::from_values(&values))
+    }
+
+    async fn delete_expired_measurements(
+        &self,
+        sensor_id: i32,
+        before: DateTime<Utc>,
+        limit: i64,
+        _keep_rollups: bool,
+    ) -> Result<u64> {
+        let res = sqlx::query(
+            "DELETE FROM measurements WHERE id IN (SELECT id FROM measurements WHERE sensor_id = ? AND ts < ? ORDER BY ts LIMIT ?)",
+        )
+        .bind(sensor_id)
+        .bind(timestamp(before))
+        .bind(limit)
+        .execute(self)
+        .await?;
+        Ok(res.rows_affected())
+    }
+
+    async fn insert_api_key(&self, key: NewApiKey, prefix: &str, hash: &str) -> Result<ApiKey> {
+        let api_key = sqlx::query_as::<_, ApiKey>(
+            "INSERT INTO api_keys (name, scope, prefix, hash, created_at) VALUES (?, ?, ?, ?, ?) RETURNING id, name, scope, prefix, created_at",
+        )
+        .bind(key.name)
+        .bind(key.scope.to_string())
+        .bind(prefix)
+        .bind(hash)
+        .bind(timestamp(Utc::now()))
+        .fetch_one(self)
+        .await?;
+        Ok(api_key)
+    }
+
+    async fn read_api_keys(&self) -> Result<Vec<ApiKey>> {
+        let api_keys = sqlx::query_as::<_, ApiKey>(
+            "SELECT id, name, scope, prefix, created_at FROM api_keys ORDER BY id",
+        )
+        .fetch_all(self)
+        .await?;
+        Ok(api_keys)
+    }
+
+    async fn read_api_key_by_hash(&self, hash: &str) -> Result<ApiKey> {
+        let api_key = sqlx::query_as::<_, ApiKey>(
+            "SELECT id, name, scope, prefix, created_at FROM api_keys WHERE hash = ?",
+        )
+        .bind(hash)
+        .fetch_one(self)
+        .await?;
+        Ok(api_key)
+    }
+
+    async fn delete_api_key(&self, key_id: i32) -> Result<()> {
+        let res = sqlx::query("DELETE FROM api_keys WHERE id = ?")
+            .bind(key_id)
+            .execute(self)
+            .await?;
+        if res.rows_affected() == 0 {
+            return Err(sqlx::Error::RowNotFound.into());
+        }
+        Ok(())
+    }
+}